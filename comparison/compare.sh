@@ -12,4 +12,5 @@ cd comparison
 
 time ./rdbus.sh
 time ./rdbus-release.sh
+time ./rdbus-send.sh
 time ./dbus.sh
\ No newline at end of file
@@ -0,0 +1,6 @@
+#! /bin/sh
+
+for i in {0..1000}
+do
+    ../target/release/rdbus-send /some/object this.is.my.signal 2> /dev/null
+done
@@ -0,0 +1,37 @@
+//! Feed arbitrary bytes into dbus_message_demarshal and walk whatever comes out of it
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use librdbus::message::*;
+use librdbus_fuzz::*;
+
+fuzz_target!(|data: &[u8]| {
+    let len = data.len() as libc::c_int;
+    let source = data.as_ptr() as *const libc::c_char;
+
+    let needed = dbus_message_demarshal_bytes_needed(source, len);
+
+    let mut err = new_error();
+    let msg = dbus_message_demarshal(source, len, &mut err);
+    free_error(err);
+    if msg.is_null() {
+        return;
+    }
+    assert!(needed > 0, "demarshalled a message that claims to need more bytes");
+
+    walk_message(msg);
+
+    // whatever we accepted from the wire must survive being sent on again
+    let mut buf: *const libc::c_char = std::ptr::null();
+    let mut buf_len: libc::c_int = 0;
+    if dbus_message_marshal(msg, &mut buf, &mut buf_len) != 0 {
+        let mut err = new_error();
+        let copy = dbus_message_demarshal(buf, buf_len, &mut err);
+        free_error(err);
+        if !copy.is_null() {
+            walk_message(copy);
+            dbus_message_unref(copy);
+        }
+    }
+
+    dbus_message_unref(msg);
+});
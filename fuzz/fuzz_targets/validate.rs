@@ -0,0 +1,29 @@
+//! Feed arbitrary C strings into the name / path validators
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use librdbus::validate::*;
+use librdbus_fuzz::*;
+
+fuzz_target!(|data: &[u8]| {
+    // the validators take C strings, so cut at the first nul byte
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    let s = std::ffi::CString::new(&data[..end]).unwrap();
+    let s = s.as_ptr();
+
+    let validators = [
+        dbus_validate_bus_name,
+        dbus_validate_path,
+        dbus_validate_interface,
+        dbus_validate_member,
+        dbus_validate_error_name,
+        dbus_validate_utf8,
+    ];
+    for validate in &validators {
+        let mut err = new_error();
+        validate(s, &mut err);
+        free_error(err);
+
+        // NULL errors are allowed and must not be touched
+        validate(s, std::ptr::null_mut());
+    }
+});
@@ -0,0 +1,90 @@
+//! Helpers shared by the fuzz targets. Everything goes through the exported C ABI so the
+//! targets exercise the same code paths a C program would.
+
+use librdbus::error::*;
+use librdbus::message::*;
+use librdbus::message_iter::*;
+use std::mem::MaybeUninit;
+
+/// Same limit libdbus puts on the nesting of containers in a message
+const MAX_DEPTH: usize = 64;
+
+pub fn new_error() -> DBusError {
+    let mut err = MaybeUninit::uninit();
+    dbus_error_init(err.as_mut_ptr());
+    unsafe { err.assume_init() }
+}
+
+pub fn free_error(mut err: DBusError) {
+    dbus_error_free(&mut err);
+}
+
+/// Walk all header fields and the whole argument tree of a message the way dbus-monitor would
+pub fn walk_message(msg: *mut DBusMessage) {
+    read_cstr(dbus_message_get_path(msg));
+    read_cstr(dbus_message_get_interface(msg));
+    read_cstr(dbus_message_get_member(msg));
+    read_cstr(dbus_message_get_error_name(msg));
+    read_cstr(dbus_message_get_destination(msg));
+    read_cstr(dbus_message_get_sender(msg));
+    read_cstr(dbus_message_get_signature(msg));
+    dbus_message_get_serial(msg);
+    dbus_message_get_reply_serial(msg);
+    dbus_message_get_type(msg);
+
+    let mut iter = MaybeUninit::<DBusMessageIter>::uninit();
+    if dbus_message_iter_init(msg, iter.as_mut_ptr()) != 0 {
+        walk_iter(iter.as_mut_ptr(), 0);
+    }
+}
+
+/// Recursively read every value the iterator points to
+pub fn walk_iter(iter: *mut DBusMessageIter, depth: usize) {
+    assert!(
+        depth <= MAX_DEPTH,
+        "iterator recursed deeper than the wire format allows"
+    );
+
+    let sig = dbus_message_iter_get_signature(iter);
+    read_cstr(sig);
+    librdbus::dbus_free(sig as *mut std::ffi::c_void);
+    dbus_message_iter_get_element_count(iter);
+
+    loop {
+        let typ = dbus_message_iter_get_arg_type(iter);
+        match typ {
+            librdbus::DBUS_TYPE_INVALID => break,
+            librdbus::DBUS_TYPE_ARRAY
+            | librdbus::DBUS_TYPE_STRUCT
+            | librdbus::DBUS_TYPE_VARIANT
+            | librdbus::DBUS_TYPE_DICTENTRY => {
+                dbus_message_iter_get_element_type(iter);
+                let mut sub = MaybeUninit::<DBusMessageIter>::uninit();
+                dbus_message_iter_recurse(iter, sub.as_mut_ptr());
+                walk_iter(sub.as_mut_ptr(), depth + 1);
+            }
+            librdbus::DBUS_TYPE_STRING
+            | librdbus::DBUS_TYPE_OBJECTPATH
+            | librdbus::DBUS_TYPE_SIGNATURE => {
+                let mut s: *const libc::c_char = std::ptr::null();
+                dbus_message_iter_get_basic(iter, &mut s as *mut _ as *mut std::ffi::c_void);
+                assert!(!s.is_null(), "string typed argument returned NULL");
+                read_cstr(s);
+            }
+            _ => {
+                // large enough for every fixed size type
+                let mut val = 0u64;
+                dbus_message_iter_get_basic(iter, &mut val as *mut _ as *mut std::ffi::c_void);
+            }
+        }
+        if dbus_message_iter_next(iter) == 0 {
+            break;
+        }
+    }
+}
+
+fn read_cstr(s: *const libc::c_char) {
+    if !s.is_null() {
+        std::hint::black_box(unsafe { std::ffi::CStr::from_ptr(s) }.to_bytes());
+    }
+}
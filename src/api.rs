@@ -0,0 +1,537 @@
+//! The Rust API of librdbus. Connection, Message and PendingCall each own one reference to the
+//! objects the C API hands out and run the same code the C functions do, without the FFI.
+
+use crate::connection::{DBusConnection, DBusPendingCall, DBUS_TIMEOUT_INFINITE};
+use crate::error::*;
+use crate::message::DBusMessage;
+use crate::validate::{
+    check_bus_name, check_error_name, check_interface, check_member, check_path,
+};
+use crate::DBusBusType;
+use rustbus::params::{Base, Container, Param};
+use std::ptr::NonNull;
+use std::time::Duration;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Reply timeouts in the libdbus convention, None is the default of 25 seconds
+fn reply_millis(timeout: Option<Duration>) -> libc::c_int {
+    match timeout {
+        None => -1,
+        Some(t) => t.as_millis().min(DBUS_TIMEOUT_INFINITE as u128) as libc::c_int,
+    }
+}
+
+/// A connection to a bus or a peer
+pub struct Connection {
+    raw: NonNull<DBusConnection<'static>>,
+}
+
+impl Connection {
+    fn from_box(con: DBusConnection<'static>) -> Self {
+        Connection {
+            raw: NonNull::from(Box::leak(Box::new(con))),
+        }
+    }
+
+    fn con(&mut self) -> &mut DBusConnection<'static> {
+        unsafe { self.raw.as_mut() }
+    }
+
+    pub fn session() -> Result<Self> {
+        Self::bus(DBusBusType::DBUS_BUS_SESSION)
+    }
+
+    pub fn system() -> Result<Self> {
+        Self::bus(DBusBusType::DBUS_BUS_SYSTEM)
+    }
+
+    /// Connect and register with a well known bus, like dbus_bus_get
+    pub fn bus(bus: DBusBusType) -> Result<Self> {
+        DBusConnection::bus(bus).map(Self::from_box)
+    }
+
+    /// Connect to a D-Bus address like `unix:path=/run/bus`. Call register to use it as a bus.
+    pub fn open(address: &str) -> Result<Self> {
+        DBusConnection::open(address).map(Self::from_box)
+    }
+
+    pub fn register(&mut self) -> Result<()> {
+        self.con().register()
+    }
+
+    /// A copy, another handle to the connection may register it and replace the original
+    pub fn unique_name(&self) -> Option<String> {
+        let con = unsafe { self.raw.as_ref() };
+        con.unique_name
+            .as_ref()
+            .and_then(|name| name.to_str().ok())
+            .map(str::to_owned)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        unsafe { self.raw.as_ref() }.state != crate::connection::ConState::Disconnected
+    }
+
+    /// Queue msg for sending, returns the serial it got
+    pub fn send(&mut self, msg: &mut Message) -> Result<u32> {
+        self.con().send(msg.as_ptr())
+    }
+
+    pub fn send_with_reply(
+        &mut self,
+        msg: &mut Message,
+        timeout: Option<Duration>,
+    ) -> Result<PendingCall> {
+        let pending = self
+            .con()
+            .send_with_reply(msg.as_ptr(), reply_millis(timeout))?;
+        Ok(unsafe { PendingCall::from_raw(pending) })
+    }
+
+    /// Send a method call and wait for its reply. Error replies are returned as Err.
+    pub fn call(&mut self, msg: &mut Message, timeout: Option<Duration>) -> Result<Message> {
+        let reply = self
+            .con()
+            .send_with_reply_and_block(msg.as_ptr(), reply_millis(timeout))?;
+        Ok(unsafe { Message::from_raw(reply) })
+    }
+
+    /// Ask the bus for the messages rule matches and wait until it agreed
+    pub fn add_match(&mut self, rule: &str) -> Result<()> {
+        self.con().add_match(rule, true)
+    }
+
+    pub fn flush(&mut self) {
+        self.con().flush();
+    }
+
+    /// Read and write what the socket allows, waiting up to timeout for it. None waits until
+    /// something happens. False once the connection is closed.
+    pub fn read_write(&mut self, timeout: Option<Duration>) -> bool {
+        let millis = timeout.map_or(-1, |t| {
+            t.as_millis().min(libc::c_int::MAX as u128) as libc::c_int
+        });
+        self.con().read_write(millis)
+    }
+
+    /// The next message that came in, without running filters or object handlers
+    pub fn pop_message(&mut self) -> Option<Message> {
+        self.con()
+            .pop_message()
+            .map(|msg| unsafe { Message::from_raw(msg) })
+    }
+
+    /// Write everything this connection sends and receives to capture from now on, None stops
+    pub fn set_capture(&mut self, capture: Option<crate::pcap::Capture>) {
+        self.con().con.capture = capture.map(std::sync::Arc::new);
+    }
+
+    /// For the C API, the connection stays owned by self
+    pub fn as_ptr(&self) -> *mut DBusConnection<'static> {
+        self.raw.as_ptr()
+    }
+
+    /// Hand the reference over to C code, which has to unref it
+    pub fn into_raw(self) -> *mut DBusConnection<'static> {
+        let raw = self.raw.as_ptr();
+        std::mem::forget(self);
+        raw
+    }
+
+    /// # Safety
+    /// con has to be a valid connection, its reference is taken over
+    pub unsafe fn from_raw(con: *mut DBusConnection<'static>) -> Self {
+        Connection {
+            raw: NonNull::new(con).expect("connection is NULL"),
+        }
+    }
+}
+
+impl Clone for Connection {
+    fn clone(&self) -> Self {
+        crate::connection::dbus_connection_ref(self.raw.as_ptr());
+        Connection { raw: self.raw }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        crate::connection::dbus_connection_unref(self.raw.as_ptr());
+    }
+}
+
+/// A message. It is not Clone: sending sets the serial, which would change it under every other
+/// handle sharing it.
+pub struct Message {
+    raw: NonNull<DBusMessage<'static>>,
+}
+
+impl Message {
+    pub fn new(msg: rustbus::Message<'static, 'static>) -> Self {
+        Message {
+            raw: NonNull::from(Box::leak(Box::new(DBusMessage::new(msg)))),
+        }
+    }
+
+    /// The rustbus message underneath, with all header fields and the params
+    pub fn inner(&self) -> &rustbus::Message<'static, 'static> {
+        &unsafe { self.raw.as_ref() }.msg
+    }
+
+    pub fn typ(&self) -> rustbus::MessageType {
+        self.inner().typ
+    }
+
+    pub fn serial(&self) -> Option<u32> {
+        self.inner().serial
+    }
+
+    pub fn reply_serial(&self) -> Option<u32> {
+        self.inner().response_serial
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.inner().object.as_deref()
+    }
+
+    pub fn interface(&self) -> Option<&str> {
+        self.inner().interface.as_deref()
+    }
+
+    pub fn member(&self) -> Option<&str> {
+        self.inner().member.as_deref()
+    }
+
+    pub fn destination(&self) -> Option<&str> {
+        self.inner().destination.as_deref()
+    }
+
+    pub fn sender(&self) -> Option<&str> {
+        self.inner().sender.as_deref()
+    }
+
+    pub fn error_name(&self) -> Option<&str> {
+        self.inner().error_name.as_deref()
+    }
+
+    pub fn signature(&self) -> String {
+        let mut signature = String::new();
+        for typ in self.inner().sig() {
+            typ.to_str(&mut signature);
+        }
+        signature
+    }
+
+    /// The error of an error reply
+    pub fn error(&self) -> Option<Error> {
+        Error::from_message(self.inner())
+    }
+
+    pub fn args(&self) -> Args<'_> {
+        Args {
+            inner: ArgsInner::Params(self.inner().params.iter()),
+        }
+    }
+
+    /// A builder for the reply to this call
+    pub fn method_return(&self) -> MessageBuilder {
+        MessageBuilder {
+            msg: self.inner().make_response(),
+        }
+    }
+
+    pub fn error_reply(&self, name: &str, text: &str) -> Result<Message> {
+        check_error_name(name).map_err(|e| Error::new(DBUS_ERROR_INVALID_ARGS, e))?;
+        Ok(Message::new(crate::message::make_error_response(
+            self.inner(),
+            name,
+            text,
+        )))
+    }
+
+    /// For the C API, the message stays owned by self
+    pub fn as_ptr(&self) -> *mut DBusMessage<'static> {
+        self.raw.as_ptr()
+    }
+
+    /// Hand the reference over to C code, which has to unref it
+    pub fn into_raw(self) -> *mut DBusMessage<'static> {
+        let raw = self.raw.as_ptr();
+        std::mem::forget(self);
+        raw
+    }
+
+    /// # Safety
+    /// msg has to be a valid message, its reference is taken over
+    pub unsafe fn from_raw(msg: *mut DBusMessage<'static>) -> Self {
+        Message {
+            raw: NonNull::new(msg).expect("message is NULL"),
+        }
+    }
+}
+
+impl Drop for Message {
+    fn drop(&mut self) {
+        crate::message::dbus_message_unref(self.raw.as_ptr());
+    }
+}
+
+/// Like dbus-monitor prints it
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&crate::dump::dump_message(unsafe { self.raw.as_ref() }))
+    }
+}
+
+impl std::fmt::Debug for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.inner().fmt(f)
+    }
+}
+
+/// Builds method calls, signals and method returns. Names are checked by build.
+pub struct MessageBuilder {
+    msg: rustbus::Message<'static, 'static>,
+}
+
+impl MessageBuilder {
+    pub fn method_call(path: &str, member: &str) -> Self {
+        MessageBuilder {
+            msg: rustbus::message_builder::MessageBuilder::new()
+                .call(member.to_owned())
+                .on(path.to_owned())
+                .build(),
+        }
+    }
+
+    pub fn signal(path: &str, interface: &str, member: &str) -> Self {
+        MessageBuilder {
+            msg: rustbus::message_builder::MessageBuilder::new()
+                .signal(interface.to_owned(), member.to_owned(), path.to_owned())
+                .build(),
+        }
+    }
+
+    pub fn interface(mut self, interface: &str) -> Self {
+        self.msg.interface = Some(interface.to_owned());
+        self
+    }
+
+    pub fn destination(mut self, destination: &str) -> Self {
+        self.msg.destination = Some(destination.to_owned());
+        self
+    }
+
+    /// Tell the receiver not to reply
+    pub fn no_reply(mut self) -> Self {
+        self.msg.flags |= crate::connection::FLAG_NO_REPLY_EXPECTED;
+        self
+    }
+
+    pub fn arg<P: Into<Param<'static, 'static>>>(mut self, arg: P) -> Self {
+        self.msg.push_param(arg);
+        self
+    }
+
+    pub fn build(self) -> Result<Message> {
+        let msg = &self.msg;
+        let checks = [
+            (msg.object.as_deref(), check_path as fn(&str) -> _),
+            (msg.interface.as_deref(), check_interface),
+            (msg.member.as_deref(), check_member),
+            (msg.destination.as_deref(), check_bus_name),
+        ];
+        for (value, check) in checks.iter() {
+            if let Some(value) = value {
+                check(value).map_err(|e| Error::new(DBUS_ERROR_INVALID_ARGS, e))?;
+            }
+        }
+        Ok(Message::new(self.msg))
+    }
+}
+
+/// A call waiting for its reply
+pub struct PendingCall {
+    raw: NonNull<DBusPendingCall<'static>>,
+}
+
+impl PendingCall {
+    pub fn completed(&self) -> bool {
+        unsafe { self.raw.as_ref() }.completed()
+    }
+
+    /// Wait for the reply. Timeouts and disconnects end up as error replies.
+    pub fn block(&mut self) {
+        DBusPendingCall::block(self.raw.as_ptr());
+    }
+
+    /// The reply, once it is there. Only the first call gets it.
+    pub fn steal_reply(&mut self) -> Option<Message> {
+        unsafe { self.raw.as_mut() }
+            .steal_reply()
+            .map(|msg| unsafe { Message::from_raw(msg) })
+    }
+
+    /// Stop waiting for the reply
+    pub fn cancel(&mut self) {
+        DBusPendingCall::cancel(self.raw.as_ptr());
+    }
+
+    /// # Safety
+    /// pending has to be a valid pending call, its reference is taken over
+    pub unsafe fn from_raw(pending: *mut DBusPendingCall<'static>) -> Self {
+        PendingCall {
+            raw: NonNull::new(pending).expect("pending call is NULL"),
+        }
+    }
+
+    /// Hand the reference over to C code, which has to unref it
+    pub fn into_raw(self) -> *mut DBusPendingCall<'static> {
+        let raw = self.raw.as_ptr();
+        std::mem::forget(self);
+        raw
+    }
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        crate::connection::dbus_pending_call_unref(self.raw.as_ptr());
+    }
+}
+
+enum Value<'m> {
+    Param(&'m Param<'static, 'static>),
+    Base(&'m Base<'static>),
+    DictEntry(&'m Base<'static>, &'m Param<'static, 'static>),
+}
+
+/// One argument of a message or one value in a container. Dicts hold dict entries, which hold
+/// the key and the value, like with DBusMessageIter.
+pub struct Arg<'m> {
+    value: Value<'m>,
+}
+
+macro_rules! basic_getter {
+    ($name:ident, $typ:ty, $owned:ident, $borrowed:ident) => {
+        pub fn $name(&self) -> Option<$typ> {
+            match self.base()? {
+                Base::$owned(v) => Some(*v),
+                Base::$borrowed(v) => Some(**v),
+                _ => None,
+            }
+        }
+    };
+}
+
+impl<'m> Arg<'m> {
+    fn base(&self) -> Option<&'m Base<'static>> {
+        match self.value {
+            Value::Param(Param::Base(base)) | Value::Base(base) => Some(base),
+            _ => None,
+        }
+    }
+
+    /// The parameter, None for dict keys and dict entries
+    pub fn param(&self) -> Option<&'m Param<'static, 'static>> {
+        match self.value {
+            Value::Param(param) => Some(param),
+            _ => None,
+        }
+    }
+
+    pub fn signature(&self) -> String {
+        let mut signature = String::new();
+        match self.value {
+            Value::Param(param) => param.make_signature(&mut signature),
+            Value::Base(base) => base.make_signature(&mut signature),
+            Value::DictEntry(key, value) => {
+                signature.push('{');
+                key.make_signature(&mut signature);
+                value.make_signature(&mut signature);
+                signature.push('}');
+            }
+        }
+        signature
+    }
+
+    /// Strings, object paths and signatures
+    pub fn as_str(&self) -> Option<&'m str> {
+        match self.base()? {
+            Base::String(s) | Base::ObjectPath(s) | Base::Signature(s) => Some(s),
+            Base::StringRef(s) | Base::ObjectPathRef(s) | Base::SignatureRef(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    basic_getter!(as_bool, bool, Boolean, BooleanRef);
+    basic_getter!(as_byte, u8, Byte, ByteRef);
+    basic_getter!(as_i16, i16, Int16, Int16Ref);
+    basic_getter!(as_u16, u16, Uint16, Uint16Ref);
+    basic_getter!(as_i32, i32, Int32, Int32Ref);
+    basic_getter!(as_u32, u32, Uint32, Uint32Ref);
+    basic_getter!(as_i64, i64, Int64, Int64Ref);
+    basic_getter!(as_u64, u64, Uint64, Uint64Ref);
+    basic_getter!(as_unix_fd, u32, UnixFd, UnixFdRef);
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self.base()? {
+            Base::Double(bits) => Some(f64::from_bits(*bits)),
+            Base::DoubleRef(bits) => Some(f64::from_bits(**bits)),
+            _ => None,
+        }
+    }
+
+    /// What the container holds, nothing for basic types
+    pub fn iter(&self) -> Args<'m> {
+        let inner = match self.value {
+            Value::Param(Param::Container(container)) => match container {
+                Container::Array(array) => ArgsInner::Params(array.values.iter()),
+                Container::ArrayRef(array) => ArgsInner::Params(array.values.iter()),
+                Container::Struct(values) => ArgsInner::Params(values.iter()),
+                Container::StructRef(values) => ArgsInner::Params(values.iter()),
+                Container::Dict(dict) => ArgsInner::Dict(dict.map.iter()),
+                Container::DictRef(dict) => ArgsInner::Dict(dict.map.iter()),
+                Container::Variant(variant) => {
+                    ArgsInner::Params(std::slice::from_ref(&variant.value).iter())
+                }
+            },
+            Value::DictEntry(key, value) => ArgsInner::Entry(Some(key), Some(value)),
+            Value::Param(Param::Base(_)) | Value::Base(_) => ArgsInner::Entry(None, None),
+        };
+        Args { inner }
+    }
+}
+
+enum ArgsInner<'m> {
+    Params(std::slice::Iter<'m, Param<'static, 'static>>),
+    Dict(std::collections::hash_map::Iter<'m, Base<'static>, Param<'static, 'static>>),
+    Entry(
+        Option<&'m Base<'static>>,
+        Option<&'m Param<'static, 'static>>,
+    ),
+}
+
+/// The arguments of a message, or what a container argument holds
+pub struct Args<'m> {
+    inner: ArgsInner<'m>,
+}
+
+impl<'m> Iterator for Args<'m> {
+    type Item = Arg<'m>;
+
+    fn next(&mut self) -> Option<Arg<'m>> {
+        let value = match &mut self.inner {
+            ArgsInner::Params(params) => Value::Param(params.next()?),
+            ArgsInner::Dict(entries) => {
+                let (key, value) = entries.next()?;
+                Value::DictEntry(key, value)
+            }
+            ArgsInner::Entry(key, value) => match key.take() {
+                Some(key) => Value::Base(key),
+                None => Value::Param(value.take()?),
+            },
+        };
+        Some(Arg { value })
+    }
+}
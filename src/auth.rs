@@ -0,0 +1,438 @@
+//! The line based SASL handshake that runs on a fresh socket before the first message. rustbus
+//! only offers the client side and throws away the GUID of the server, so librdbus does its own.
+
+use crate::transport::Error;
+use std::io::{Read, Write};
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixStream;
+
+/// Lines longer than this are not part of any sane handshake
+const MAX_LINE_LEN: usize = 16 * 1024;
+
+/// What the client learned during the handshake
+pub struct ClientAuth {
+    pub server_guid: String,
+    pub unix_fds: bool,
+}
+
+fn write_line(stream: &mut UnixStream, line: &str) -> Result<(), Error> {
+    crate::trace::trace!("auth", "> {}", line);
+    let mut buf = Vec::with_capacity(line.len() + 2);
+    buf.extend(line.bytes());
+    buf.extend(b"\r\n");
+    stream.write_all(&buf)?;
+    Ok(())
+}
+
+/// Read one line without the line ending. Reads byte by byte so nothing that follows the
+/// handshake ends up in a buffer here.
+fn read_line(stream: &mut UnixStream) -> Result<String, Error> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        if line.len() >= MAX_LINE_LEN {
+            return Err(Error::AuthFailed);
+        }
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+    let line = String::from_utf8(line).map_err(|_| Error::AuthFailed)?;
+    crate::trace::trace!("auth", "< {}", line);
+    Ok(line)
+}
+
+/// The spec encodes the authorization identity as hex of the ASCII decimal uid
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Authenticate as the uid of this process with the EXTERNAL mechanism and send BEGIN
+pub fn client_auth(stream: &mut UnixStream, with_unix_fd: bool) -> Result<ClientAuth, Error> {
+    let uid = unsafe { libc::getuid() };
+
+    // the credentials byte
+    stream.write_all(&[0])?;
+    write_line(
+        stream,
+        &format!("AUTH EXTERNAL {}", hex_encode(uid.to_string().as_bytes())),
+    )?;
+    let reply = read_line(stream)?;
+    let server_guid = match reply.strip_prefix("OK ") {
+        Some(guid) => guid.trim().to_owned(),
+        None => return Err(Error::AuthFailed),
+    };
+
+    let mut unix_fds = false;
+    if with_unix_fd {
+        write_line(stream, "NEGOTIATE_UNIX_FD")?;
+        unix_fds = read_line(stream)?.starts_with("AGREE_UNIX_FD");
+    }
+
+    write_line(stream, "BEGIN")?;
+    Ok(ClientAuth {
+        server_guid,
+        unix_fds,
+    })
+}
+
+/// What the server learned about the client during the handshake
+pub struct ServerAuth {
+    /// None if the client authenticated anonymously
+    pub uid: Option<libc::uid_t>,
+    pub unix_fds: bool,
+}
+
+/// Clients that keep failing are dropped instead of being allowed to guess forever
+const MAX_AUTH_FAILURES: usize = 8;
+
+/// A new random GUID for a server, 32 hex digits like libdbus uses
+pub fn new_guid() -> String {
+    let mut bytes = [0u8; 16];
+    if let Ok(mut urandom) = std::fs::File::open("/dev/urandom") {
+        let _ = urandom.read_exact(&mut bytes);
+    }
+    hex_encode(&bytes)
+}
+
+// usize::is_multiple_of is only stable since Rust 1.87
+#[allow(clippy::manual_is_multiple_of)]
+fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
+
+fn mechanisms(allow_anonymous: bool) -> &'static str {
+    if allow_anonymous {
+        "REJECTED EXTERNAL ANONYMOUS"
+    } else {
+        "REJECTED EXTERNAL"
+    }
+}
+
+/// Receive without blocking, fails with TimedOut if there is nothing to read
+fn recv(fd: RawFd, buf: &mut [u8]) -> Result<usize, Error> {
+    let res = unsafe {
+        libc::recv(
+            fd,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if res < 0 {
+        return Err(would_block_error());
+    }
+    Ok(res as usize)
+}
+
+/// Send without blocking, fails with TimedOut if the socket takes nothing
+fn send(fd: RawFd, buf: &[u8]) -> Result<usize, Error> {
+    let flags = libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL;
+    let res = unsafe { libc::send(fd, buf.as_ptr() as *const libc::c_void, buf.len(), flags) };
+    if res < 0 {
+        return Err(would_block_error());
+    }
+    Ok(res as usize)
+}
+
+fn would_block_error() -> Error {
+    let e = std::io::Error::last_os_error();
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock => Error::TimedOut,
+        _ => Error::IoError(e),
+    }
+}
+
+/// The server side of the handshake. It never blocks, advance picks up wherever the client
+/// is, so a client that stops talking halfway only costs its own connection.
+#[derive(Debug)]
+pub struct ServerHandshake {
+    guid: String,
+    /// The credentials byte arrived
+    got_nul: bool,
+    /// The line being read
+    line: Vec<u8>,
+    /// Answers the socket did not take yet
+    out: Vec<u8>,
+    /// Some once the client authenticated, with None for anonymous clients
+    authenticated: Option<Option<libc::uid_t>>,
+    unix_fds: bool,
+    failures: usize,
+    /// Set while an EXTERNAL auth without initial response waits for its DATA line
+    waiting_for_data: bool,
+    /// The client sent BEGIN, done once all answers are written
+    begun: bool,
+}
+
+impl ServerHandshake {
+    pub fn new(guid: String) -> Self {
+        Self {
+            guid,
+            got_nul: false,
+            line: Vec::new(),
+            out: Vec::new(),
+            authenticated: None,
+            unix_fds: false,
+            failures: 0,
+            waiting_for_data: false,
+            begun: false,
+        }
+    }
+
+    /// Answers are waiting for the socket to become writable
+    pub fn wants_write(&self) -> bool {
+        !self.out.is_empty()
+    }
+
+    /// Read and answer whatever the client sent so far. authorize decides about the uid the
+    /// client proved with EXTERNAL, or about an anonymous client (None). Fails with TimedOut
+    /// until the client sent BEGIN and all answers are written.
+    pub fn advance(
+        &mut self,
+        fd: RawFd,
+        allow_anonymous: bool,
+        authorize: &mut dyn FnMut(Option<libc::uid_t>) -> bool,
+    ) -> Result<ServerAuth, Error> {
+        loop {
+            self.write_out(fd)?;
+            if self.begun {
+                if self.wants_write() {
+                    return Err(Error::TimedOut);
+                }
+                return Ok(ServerAuth {
+                    uid: self.authenticated.flatten(),
+                    unix_fds: self.unix_fds,
+                });
+            }
+            let line = self.read_line(fd)?;
+            self.handle_line(&line, fd, allow_anonymous, authorize)?;
+        }
+    }
+
+    fn queue_line(&mut self, line: &str) {
+        crate::trace::trace!("auth", "> {}", line);
+        self.out.extend(line.bytes());
+        self.out.extend(b"\r\n");
+    }
+
+    /// Write as much of the answers as the socket takes
+    fn write_out(&mut self, fd: RawFd) -> Result<(), Error> {
+        while !self.out.is_empty() {
+            match send(fd, &self.out) {
+                Ok(bytes) => drop(self.out.drain(..bytes)),
+                Err(Error::TimedOut) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// The next complete line without the line ending. Reads byte by byte so nothing that
+    /// follows BEGIN ends up in a buffer here.
+    fn read_line(&mut self, fd: RawFd) -> Result<String, Error> {
+        let mut byte = [0u8; 1];
+        while !self.line.ends_with(b"\r\n") {
+            if recv(fd, &mut byte)? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            if !self.got_nul {
+                if byte[0] != 0 {
+                    return Err(Error::AuthFailed);
+                }
+                self.got_nul = true;
+                continue;
+            }
+            if self.line.len() >= MAX_LINE_LEN {
+                return Err(Error::AuthFailed);
+            }
+            self.line.push(byte[0]);
+        }
+        let mut line = std::mem::take(&mut self.line);
+        line.truncate(line.len() - 2);
+        let line = String::from_utf8(line).map_err(|_| Error::AuthFailed)?;
+        crate::trace::trace!("auth", "< {}", line);
+        Ok(line)
+    }
+
+    fn reject(&mut self, fd: RawFd, allow_anonymous: bool) -> Result<(), Error> {
+        self.queue_line(mechanisms(allow_anonymous));
+        // the client is dropped either way, whatever the socket does not take now is lost
+        let _ = self.write_out(fd);
+        Err(Error::AuthFailed)
+    }
+
+    fn handle_line(
+        &mut self,
+        line: &str,
+        fd: RawFd,
+        allow_anonymous: bool,
+        authorize: &mut dyn FnMut(Option<libc::uid_t>) -> bool,
+    ) -> Result<(), Error> {
+        let mut words = line.split(' ');
+        let command = words.next().unwrap_or("");
+
+        // Some(identity) if the line is an attempt to authenticate with EXTERNAL
+        let external = match (command, self.authenticated) {
+            ("AUTH", None) => match (words.next(), words.next()) {
+                (Some("EXTERNAL"), None) => {
+                    self.waiting_for_data = true;
+                    self.queue_line("DATA");
+                    return Ok(());
+                }
+                (Some("EXTERNAL"), Some(identity)) => Some(identity.to_owned()),
+                (Some("ANONYMOUS"), _) if allow_anonymous => {
+                    if !authorize(None) {
+                        return self.reject(fd, allow_anonymous);
+                    }
+                    self.authenticated = Some(None);
+                    self.queue_line(&format!("OK {}", self.guid));
+                    return Ok(());
+                }
+                _ => None,
+            },
+            ("DATA", None) if self.waiting_for_data => {
+                self.waiting_for_data = false;
+                Some(words.next().unwrap_or("").to_owned())
+            }
+            ("NEGOTIATE_UNIX_FD", Some(_)) => {
+                self.unix_fds = true;
+                self.queue_line("AGREE_UNIX_FD");
+                return Ok(());
+            }
+            ("BEGIN", Some(_)) => {
+                self.begun = true;
+                return Ok(());
+            }
+            ("CANCEL", _) | ("ERROR", _) | ("DATA", None) => None,
+            _ => {
+                self.queue_line("ERROR \"Unknown command\"");
+                return Ok(());
+            }
+        };
+
+        if let Some(identity) = external {
+            let cred = crate::transport::peer_credentials(fd)?;
+            // an empty identity means "whoever the kernel says I am"
+            let claimed = hex_decode(&identity);
+            let matches =
+                identity.is_empty() || claimed.as_deref() == Some(cred.uid.to_string().as_bytes());
+            if matches {
+                // the peer is who it claims to be, whether it may connect is not up for retries
+                if !authorize(Some(cred.uid)) {
+                    return self.reject(fd, allow_anonymous);
+                }
+                self.authenticated = Some(Some(cred.uid));
+                self.queue_line(&format!("OK {}", self.guid));
+                return Ok(());
+            }
+        }
+
+        self.failures += 1;
+        self.waiting_for_data = false;
+        if self.failures >= MAX_AUTH_FAILURES {
+            return self.reject(fd, allow_anonymous);
+        }
+        self.queue_line(mechanisms(allow_anonymous));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    /// Drive a handshake to its end, waiting for the socket whenever it needs more
+    fn server_auth(
+        stream: &UnixStream,
+        authorize: &mut dyn FnMut(Option<libc::uid_t>) -> bool,
+    ) -> Result<ServerAuth, Error> {
+        let mut handshake = ServerHandshake::new("0123456789abcdef".to_owned());
+        loop {
+            match handshake.advance(stream.as_raw_fd(), false, authorize) {
+                Err(Error::TimedOut) => {
+                    let mut events = libc::POLLIN;
+                    if handshake.wants_write() {
+                        events |= libc::POLLOUT;
+                    }
+                    let mut pollfd = libc::pollfd {
+                        fd: stream.as_raw_fd(),
+                        events,
+                        revents: 0,
+                    };
+                    unsafe { libc::poll(&mut pollfd, 1, -1) };
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn handshake(
+        authorize: &mut dyn FnMut(Option<libc::uid_t>) -> bool,
+    ) -> (Result<ServerAuth, Error>, Result<ClientAuth, Error>) {
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let client = std::thread::spawn(move || client_auth(&mut client, true));
+        let server = server_auth(&server, authorize);
+        (server, client.join().unwrap())
+    }
+
+    #[test]
+    fn unix_user_function_decides() {
+        let mut seen = None;
+        let (server, client) = handshake(&mut |uid| {
+            seen = uid;
+            true
+        });
+        assert_eq!(seen, Some(unsafe { libc::getuid() }));
+        let server = server.unwrap();
+        assert_eq!(server.uid, seen);
+        assert!(server.unix_fds);
+        assert_eq!(client.unwrap().server_guid, "0123456789abcdef");
+
+        let (server, client) = handshake(&mut |_| false);
+        assert!(matches!(server, Err(Error::AuthFailed)));
+        assert!(matches!(client, Err(Error::AuthFailed)));
+    }
+
+    #[test]
+    fn advances_without_blocking() {
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let fd = server.as_raw_fd();
+        let mut handshake = ServerHandshake::new("0123456789abcdef".to_owned());
+        let mut allow = |_: Option<libc::uid_t>| true;
+
+        // nothing, then half a line
+        assert!(matches!(
+            handshake.advance(fd, false, &mut allow),
+            Err(Error::TimedOut)
+        ));
+        client.write_all(b"\0AUTH EXTERNAL").unwrap();
+        assert!(matches!(
+            handshake.advance(fd, false, &mut allow),
+            Err(Error::TimedOut)
+        ));
+
+        client.write_all(b"\r\nBEGIN\r\n").unwrap();
+        assert!(matches!(
+            handshake.advance(fd, false, &mut allow),
+            Err(Error::TimedOut)
+        ));
+        let mut answer = [0u8; 6];
+        client.read_exact(&mut answer).unwrap();
+        assert_eq!(&answer, b"DATA\r\n");
+
+        // BEGIN before the client authenticated is an unknown command
+        client.write_all(b"DATA\r\nBEGIN\r\n").unwrap();
+        let auth = handshake.advance(fd, false, &mut allow).unwrap();
+        assert_eq!(auth.uid, Some(unsafe { libc::getuid() }));
+        assert!(!auth.unix_fds);
+    }
+}
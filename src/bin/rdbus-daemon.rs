@@ -0,0 +1,58 @@
+//! rdbus-daemon: a small message bus for containers and CI that runs librdbus' own transport and
+//! marshalling code. It never forks, run it in the background if needed.
+
+use librdbus::daemon::{Bus, Config};
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: rdbus-daemon [--session | --system | --config-file=FILE] [--address=ADDRESS] \
+         [--print-address] [--nofork]"
+    );
+    std::process::exit(1)
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("rdbus-daemon: {}", msg);
+    std::process::exit(1)
+}
+
+fn main() {
+    let mut config = None;
+    let mut address = None;
+    let mut print_address = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--session" => config = Some(Config::session()),
+            "--system" => config = Some(Config::system()),
+            "--print-address" => print_address = true,
+            // accepted for compatibility, this never forks anyway
+            "--nofork" | "--nopidfile" => {}
+            _ => {
+                if let Some(file) = arg.strip_prefix("--config-file=") {
+                    let xml = std::fs::read_to_string(file)
+                        .unwrap_or_else(|e| fail(format!("Can not read {}: {}", file, e)));
+                    config = Some(Config::parse(&xml).unwrap_or_else(|e| fail(e)));
+                } else if let Some(addr) = arg.strip_prefix("--address=") {
+                    address = Some(addr.to_owned());
+                } else {
+                    usage();
+                }
+            }
+        }
+    }
+
+    let config = config.unwrap_or_else(Config::session);
+    let address = address
+        .or_else(|| config.listen.first().cloned())
+        .unwrap_or_else(|| fail("No address to listen on".to_owned()));
+    let mut bus = Bus::listen(&address)
+        .unwrap_or_else(|e| fail(format!("Can not listen on {}: {}", address, e)));
+    bus.configure(&config)
+        .unwrap_or_else(|e| fail(format!("Can not configure {}: {}", address, e)));
+    if print_address {
+        println!("{}", bus.address());
+    }
+    if let Err(e) = bus.run() {
+        fail(e.to_string());
+    }
+}
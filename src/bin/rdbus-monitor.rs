@@ -0,0 +1,132 @@
+//! rdbus-monitor: dbus-monitor on librdbus' own connection and message code, no libdbus needed.
+//! Becomes a monitor with BecomeMonitor and falls back to eavesdropping match rules on buses
+//! that do not support it.
+
+use librdbus::api::{Connection, MessageBuilder};
+use librdbus::connection::DBUS_INTERFACE_MONITORING;
+use librdbus::match_rule::MatchRule;
+use librdbus::pcap::Capture;
+use librdbus::DBusBusType;
+use rustbus::params::{Array, Base, Container, Param};
+use std::io::Write;
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: rdbus-monitor [--session | --system | --address=ADDRESS] [--monitor | --pcap \
+         | --pcap=FILE] [MATCH_RULE ...]"
+    );
+    std::process::exit(1)
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("rdbus-monitor: {}", msg);
+    std::process::exit(1)
+}
+
+fn connect(bus: DBusBusType, address: Option<&str>) -> Connection {
+    let con = match address {
+        Some(address) => Connection::open(address).and_then(|mut con| {
+            con.register()?;
+            Ok(con)
+        }),
+        None => Connection::bus(bus),
+    };
+    con.unwrap_or_else(|e| fail(format!("Can not connect to the bus: {}", e)))
+}
+
+/// BecomeMonitor with rules, false if the bus does not know it
+fn become_monitor(con: &mut Connection, rules: &[String]) -> bool {
+    let rules = Array {
+        element_sig: rustbus::signature::Type::Base(rustbus::signature::Base::String),
+        values: rules
+            .iter()
+            .map(|rule| Param::Base(Base::String(rule.clone())))
+            .collect(),
+    };
+    let call = MessageBuilder::method_call("/org/freedesktop/DBus", "BecomeMonitor")
+        .interface(DBUS_INTERFACE_MONITORING)
+        .destination("org.freedesktop.DBus")
+        .arg(Container::Array(rules))
+        .arg(0u32)
+        .build();
+    match call {
+        Ok(mut call) => con.call(&mut call, None).is_ok(),
+        Err(e) => fail(e.to_string()),
+    }
+}
+
+/// The old way for buses without BecomeMonitor: match rules that see unicast messages too
+fn eavesdrop(con: &mut Connection, rules: &[String]) {
+    let rules = if rules.is_empty() {
+        vec!["eavesdrop=true".to_owned()]
+    } else {
+        rules
+            .iter()
+            .map(|rule| format!("eavesdrop=true,{}", rule))
+            .collect()
+    };
+    for rule in rules {
+        if let Err(e) = con.add_match(&rule) {
+            fail(format!("Can not add match {:?}: {}", rule, e));
+        }
+    }
+}
+
+fn main() {
+    let mut bus = DBusBusType::DBUS_BUS_SESSION;
+    let mut address = None;
+    let mut pcap = None;
+    let mut rules = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--session" => bus = DBusBusType::DBUS_BUS_SESSION,
+            "--system" => bus = DBusBusType::DBUS_BUS_SYSTEM,
+            "--monitor" => pcap = None,
+            "--pcap" => {
+                let stdout = std::os::fd::AsFd::as_fd(&std::io::stdout())
+                    .try_clone_to_owned()
+                    .and_then(|fd| Capture::new(fd.into()))
+                    .unwrap_or_else(|e| fail(format!("Can not write to stdout: {}", e)));
+                pcap = Some(stdout);
+            }
+            _ => {
+                if let Some(addr) = arg.strip_prefix("--address=") {
+                    address = Some(addr.to_owned());
+                } else if let Some(file) = arg.strip_prefix("--pcap=") {
+                    let capture = Capture::create(file.as_ref())
+                        .unwrap_or_else(|e| fail(format!("Can not capture to {}: {}", file, e)));
+                    pcap = Some(capture);
+                } else if arg.starts_with("--") {
+                    usage();
+                } else {
+                    MatchRule::parse(&arg).unwrap_or_else(|e| fail(e));
+                    rules.push(arg);
+                }
+            }
+        }
+    }
+
+    let mut con = connect(bus, address.as_deref());
+    if !become_monitor(&mut con, &rules) {
+        eavesdrop(&mut con, &rules);
+    }
+    // the transport captures every message that comes in from now on
+    let text = pcap.is_none();
+    con.set_capture(pcap);
+    let mut stdout = std::io::stdout();
+    let mut connected = true;
+    while connected {
+        connected = con.read_write(None);
+        while let Some(msg) = con.pop_message() {
+            // flush every message, whatever reads a pipe should not wait for a full buffer.
+            // Nobody reading any more ends the monitor.
+            if text
+                && write!(stdout, "{}", msg)
+                    .and_then(|_| stdout.flush())
+                    .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! rdbus-send: dbus-send on librdbus' own connection and message code, no libdbus needed.
+//! Messages are written like for dbus-send, see librdbus::send_syntax.
+
+use librdbus::api::{Connection, Message};
+use librdbus::DBusBusType;
+use std::time::Duration;
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: rdbus-send [--session | --system | --address=ADDRESS] [--dest=NAME] \
+         [--print-reply] [--reply-timeout=MSEC] [--type=TYPE] [--pcap=FILE] \
+         OBJECT_PATH INTERFACE.MEMBER [TYPE:VALUE ...]"
+    );
+    std::process::exit(1)
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("rdbus-send: {}", msg);
+    std::process::exit(1)
+}
+
+fn connect(bus: DBusBusType, address: Option<&str>) -> Connection {
+    let con = match address {
+        Some(address) => Connection::open(address).and_then(|mut con| {
+            con.register()?;
+            Ok(con)
+        }),
+        None => Connection::bus(bus),
+    };
+    con.unwrap_or_else(|e| fail(format!("Can not connect to the bus: {}", e)))
+}
+
+fn main() {
+    let mut bus = DBusBusType::DBUS_BUS_SESSION;
+    let mut address = None;
+    let mut print_reply = false;
+    let mut typ = None;
+    let mut timeout = None;
+    let mut pcap = None;
+    let mut message_args = Vec::new();
+    let mut args = std::env::args().skip(1).peekable();
+    while let Some(arg) = args.next_if(|arg| arg.starts_with("--")) {
+        match arg.as_str() {
+            "--session" => bus = DBusBusType::DBUS_BUS_SESSION,
+            "--system" => bus = DBusBusType::DBUS_BUS_SYSTEM,
+            "--print-reply" => print_reply = true,
+            _ => {
+                if let Some(addr) = arg.strip_prefix("--address=") {
+                    address = Some(addr.to_owned());
+                } else if let Some(millis) = arg.strip_prefix("--reply-timeout=") {
+                    let millis = millis.parse().unwrap_or_else(|_| usage());
+                    timeout = Some(Duration::from_millis(millis));
+                } else if let Some(file) = arg.strip_prefix("--pcap=") {
+                    let capture = librdbus::pcap::Capture::create(file.as_ref())
+                        .unwrap_or_else(|e| fail(format!("Can not capture to {}: {}", file, e)));
+                    pcap = Some(capture);
+                } else if arg.starts_with("--type=") {
+                    typ = Some(arg);
+                } else if arg.starts_with("--dest=") {
+                    message_args.push(arg);
+                } else {
+                    usage();
+                }
+            }
+        }
+    }
+    // like dbus-send, waiting for a reply makes method calls the default
+    if typ.is_none() && print_reply {
+        typ = Some("--type=method_call".to_owned());
+    }
+    message_args.extend(typ);
+    message_args.extend(args);
+    let msg = librdbus::send_syntax::parse_message(&message_args).unwrap_or_else(|e| fail(e));
+    // only method calls get a reply to print
+    let print_reply = print_reply && matches!(msg.typ, rustbus::MessageType::Call);
+    let mut msg = Message::new(msg);
+
+    let mut con = connect(bus, address.as_deref());
+    con.set_capture(pcap);
+    if print_reply {
+        match con.call(&mut msg, timeout) {
+            Ok(reply) => print!("{}", reply),
+            Err(e) => {
+                eprintln!("Error {}: {}", e.name, e.message);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        if let Err(e) = con.send(&mut msg) {
+            fail(format!("Can not send the message: {}", e));
+        }
+        con.flush();
+    }
+}
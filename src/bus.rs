@@ -1,26 +1,81 @@
 use crate::connection::*;
-use crate::*;
 use crate::error::*;
+use crate::trace::trace;
+use crate::*;
+
+impl<'a> DBusConnection<'a> {
+    /// Say Hello to the bus and remember the unique name it assigned
+    pub fn register(&mut self) -> Result<(), Error> {
+        let hello = Box::into_raw(Box::new(DBusMessage::new(
+            rustbus::standard_messages::hello(),
+        )));
+        let reply = self.send_with_reply_and_block(hello, -1);
+        dbus_message_unref(hello);
+        let reply = reply?;
+        let r = unsafe { &*reply };
+        let name = match r.msg.params.first() {
+            Some(rustbus::params::Param::Base(rustbus::params::Base::String(name))) => {
+                Ok(name.clone())
+            }
+            _ => Err(Error::new(
+                DBUS_ERROR_FAILED,
+                "Hello did not return a unique name",
+            )),
+        };
+        dbus_message_unref(reply);
+        let name = name?;
+        trace!("connection", "connected to the bus as {}", name);
+        self.unique_name = std::ffi::CString::new(name).ok();
+        Ok(())
+    }
+
+    /// Ask the bus for the messages rule matches. Like libdbus this only waits for the answer
+    /// of the bus if block is set, otherwise errors go unnoticed.
+    pub fn add_match(&mut self, rule: &str, block: bool) -> Result<(), Error> {
+        // the bus would reject it too, but only after the call returned
+        crate::match_rule::MatchRule::parse(rule)
+            .map_err(|e| Error::new(DBUS_ERROR_MATCH_RULE_INVALID, e))?;
+        let call = Box::into_raw(Box::new(DBusMessage::new(
+            rustbus::standard_messages::add_match(rule.to_owned()),
+        )));
+        let result = if block {
+            self.send_with_reply_and_block(call, -1)
+                .map(|reply| dbus_message_unref(reply))
+        } else {
+            self.send(call).map(|_| ())
+        };
+        dbus_message_unref(call);
+        result
+    }
+}
 
 #[no_mangle]
 pub extern "C" fn dbus_bus_register<'a>(con: *mut DBusConnection<'a>, err: *mut DBusError) -> u32 {
     if con.is_null() {
-        return 0;
+        trace!("abi", "dbus_bus_register called without a connection");
+        return dbus_bool(false);
     }
-    let con = unsafe { &mut *con };
-    let _serial = match con
-        .con
-        .send_message(&mut rustbus::standard_messages::hello(), None)
-    {
-        Ok(sent_serial) => sent_serial,
-        Err(_e) => return dbus_bool(false),
-    };
-
-    // TODO check the message for serial/types etc
-
-    let resp = con.con.get_next_message(None).unwrap();
-    let unique_name = resp.params[0].as_str().unwrap();
-    con.unique_name = Some(std::ffi::CString::new(unique_name).unwrap());
+    match unsafe { &mut *con }.register() {
+        Ok(()) => dbus_bool(true),
+        Err(e) => {
+            e.set(err);
+            dbus_bool(false)
+        }
+    }
+}
 
-    dbus_bool(true)
+#[no_mangle]
+pub extern "C" fn dbus_bus_get_unique_name(con: *mut DBusConnection) -> *const libc::c_char {
+    if con.is_null() {
+        trace!(
+            "abi",
+            "dbus_bus_get_unique_name called without a connection"
+        );
+        return std::ptr::null();
+    }
+    let con = unsafe { &*con };
+    match &con.unique_name {
+        Some(name) => name.as_ptr(),
+        None => std::ptr::null(),
+    }
 }
@@ -1,4 +1,6 @@
 use crate::error::*;
+use crate::trace::trace;
+use crate::transport::Deadline;
 use crate::*;
 use std::collections::VecDeque;
 use std::ops::Add;
@@ -11,7 +13,11 @@ pub enum ConState {
     Disconnected,
 }
 
-pub struct DBusPreallocatedSend {}
+/// Reserves the room for one message in the out_queue of a connection. Rust aborts instead of
+/// failing on allocation errors, so this only keeps sending it from growing the queue.
+pub struct DBusPreallocatedSend<'a> {
+    con: *mut DBusConnection<'a>,
+}
 
 // TODO protect with mutex?
 pub struct DBusPendingCall<'a> {
@@ -19,8 +25,12 @@ pub struct DBusPendingCall<'a> {
     ref_count: u64,
     timeout: Option<std::time::Instant>,
     reply: Option<*mut DBusMessage<'a>>,
+    /// The connection waiting for the reply, NULL once it stopped waiting
+    con: *mut DBusConnection<'a>,
+    #[allow(dead_code)]
     mutex: std::sync::Mutex<()>,
     cond: std::sync::Condvar,
+    pub data: crate::data_slot::DataSlots,
 }
 
 impl<'a> DBusPendingCall<'a> {
@@ -29,9 +39,22 @@ impl<'a> DBusPendingCall<'a> {
             serial,
             ref_count: 1,
             reply: None,
+            con: std::ptr::null_mut(),
             timeout: timeout.map(|timeout| std::time::Instant::now().add(timeout)),
             cond: std::sync::Condvar::new(),
             mutex: std::sync::Mutex::new(()),
+            data: crate::data_slot::DataSlots::default(),
+        }
+    }
+
+    /// Time left until the timeout in the libdbus convention for read_write, -1 for none
+    pub fn remaining_millis(&self) -> libc::c_int {
+        match self.timeout {
+            None => -1,
+            Some(timeout) => {
+                let left = timeout.saturating_duration_since(std::time::Instant::now());
+                left.as_millis().min(libc::c_int::MAX as u128) as libc::c_int
+            }
         }
     }
 
@@ -44,6 +67,133 @@ impl<'a> DBusPendingCall<'a> {
             false
         }
     }
+
+    pub fn completed(&self) -> bool {
+        self.reply.is_some()
+    }
+
+    /// The reply, once. The caller owns the reference.
+    pub fn steal_reply(&mut self) -> Option<*mut DBusMessage<'a>> {
+        self.reply.take()
+    }
+
+    /// Complete the call with an error reply made up locally
+    fn fail(&mut self, name: &str, text: &str) {
+        let mut call = rustbus::Message::new();
+        call.serial = Some(self.serial);
+        let reply = crate::message::make_error_response(&call, name, text);
+        self.reply = Some(Box::into_raw(Box::new(DBusMessage::new(reply))));
+        self.cond.notify_all();
+    }
+
+    /// Read from con until the reply is there. The reply completes pending while con reads, so
+    /// pending is only looked at through the pointer.
+    fn wait(pending: *mut Self, con: &mut DBusConnection<'a>) -> Result<(), Error> {
+        loop {
+            let p = unsafe { &*pending };
+            if p.reply.is_some() {
+                return Ok(());
+            }
+            if p.timed_out() {
+                trace!("pending", "the call with serial {} timed out", p.serial);
+                return Err(Error::new(
+                    DBUS_ERROR_NO_REPLY,
+                    "Did not receive a reply before the timeout expired",
+                ));
+            }
+            if !con.read_write(p.remaining_millis()) && unsafe { &*pending }.reply.is_none() {
+                return Err(Error::new(DBUS_ERROR_DISCONNECTED, "Connection is closed"));
+            }
+        }
+    }
+
+    /// Wait for the reply. Calls that time out complete with a NoReply error, like in libdbus.
+    pub fn block(pending: *mut Self) {
+        let con = unsafe { &*pending }.con;
+        if con.is_null() {
+            return;
+        }
+        let con = unsafe { &mut *con };
+        if let Err(e) = Self::wait(pending, con) {
+            con.forget_pending_call(pending);
+            unsafe { &mut *pending }.fail(&e.name, &e.message);
+        }
+    }
+
+    /// The connection stops waiting for the reply
+    pub fn cancel(pending: *mut Self) {
+        let con = unsafe { &*pending }.con;
+        if !con.is_null() {
+            unsafe { &mut *con }.forget_pending_call(pending);
+        }
+    }
+}
+
+impl<'a> Drop for DBusPendingCall<'a> {
+    fn drop(&mut self) {
+        self.data.clear();
+        if let Some(reply) = self.reply.take() {
+            crate::message::dbus_message_unref(reply);
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_pending_call_ref<'a>(
+    pending: *mut DBusPendingCall<'a>,
+) -> *mut DBusPendingCall<'a> {
+    if pending.is_null() {
+        return pending;
+    }
+    let p = unsafe { &mut *pending };
+    p.ref_count += 1;
+    pending
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_pending_call_unref(pending: *mut DBusPendingCall) {
+    if pending.is_null() {
+        return;
+    }
+    let p = unsafe { &mut *pending };
+    p.ref_count -= 1;
+    if p.ref_count == 0 {
+        drop(unsafe { Box::from_raw(pending) });
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_pending_call_block(pending: *mut DBusPendingCall) {
+    if !pending.is_null() {
+        DBusPendingCall::block(pending);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_pending_call_get_completed(pending: *mut DBusPendingCall) -> u32 {
+    if pending.is_null() {
+        return dbus_bool(false);
+    }
+    dbus_bool(unsafe { &*pending }.completed())
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_pending_call_steal_reply<'a>(
+    pending: *mut DBusPendingCall<'a>,
+) -> *mut DBusMessage<'a> {
+    if pending.is_null() {
+        return std::ptr::null_mut();
+    }
+    unsafe { &mut *pending }
+        .steal_reply()
+        .unwrap_or(std::ptr::null_mut())
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_pending_call_cancel(pending: *mut DBusPendingCall) {
+    if !pending.is_null() {
+        DBusPendingCall::cancel(pending);
+    }
 }
 
 #[repr(C)]
@@ -59,93 +209,805 @@ pub enum DBusDispatchStatus {
 pub struct MessageFilter {
     filter: DBusHandleMessageFunction,
     user_data: *mut std::ffi::c_void,
-    free: crate::DBusFreeFunction,
+    free: Option<crate::DBusFreeFunction>,
+}
+
+impl Drop for MessageFilter {
+    fn drop(&mut self) {
+        if let Some(free) = self.free {
+            free(self.user_data);
+        }
+    }
+}
+
+pub const DBUS_PATH_LOCAL: &str = "/org/freedesktop/DBus/Local";
+pub const DBUS_INTERFACE_LOCAL: &str = "org.freedesktop.DBus.Local";
+pub const DBUS_INTERFACE_PEER: &str = "org.freedesktop.DBus.Peer";
+pub const DBUS_INTERFACE_MONITORING: &str = "org.freedesktop.DBus.Monitoring";
+
+/// NO_REPLY_EXPECTED in the header flags. rustbus' HeaderFlags::is_set can not be trusted.
+pub(crate) const FLAG_NO_REPLY_EXPECTED: u8 = 0x1;
+pub(crate) const FLAG_NO_AUTO_START: u8 = 0x2;
+pub(crate) const FLAG_ALLOW_INTERACTIVE_AUTHORIZATION: u8 = 0x4;
+
+/// Timeout for pending calls that wait forever
+pub const DBUS_TIMEOUT_INFINITE: libc::c_int = 0x7fffffff;
+/// What libdbus waits for replies if the application does not say
+const DEFAULT_TIMEOUT_MILLIS: u64 = 25_000;
+
+/// Same defaults as libdbus
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 128 * 1024 * 1024;
+const DEFAULT_MAX_RECEIVED_SIZE: usize = 63 * 1024 * 1024;
+const DEFAULT_MAX_RECEIVED_UNIX_FDS: usize = 4096;
+
+/// A message in out_queue or in_queue together with what it costs on the wire
+pub struct QueuedMessage<'a> {
+    pub msg: *mut DBusMessage<'a>,
+    pub size: usize,
+    pub unix_fds: usize,
+    /// The marshalled message in out_queue, empty in in_queue
+    pub bytes: Vec<u8>,
+}
+
+/// Decides which uids may connect to the server side of a connection
+pub struct UnixUserFunction {
+    function: crate::DBusAllowUnixUserFunction,
+    user_data: *mut std::ffi::c_void,
+    free: Option<crate::DBusFreeFunction>,
+}
+
+impl Drop for UnixUserFunction {
+    fn drop(&mut self) {
+        if let Some(free) = self.free {
+            free(self.user_data);
+        }
+    }
 }
 
 #[repr(C)]
 pub struct DBusConnection<'a> {
-    pub con: rustbus::client_conn::Conn,
+    pub con: crate::transport::Transport,
     pub ref_count: u64,
     pub state: ConState,
     pub exit_on_disconnect: bool,
+    /// The local Disconnected signal went through dispatch
+    pub disconnect_dispatched: bool,
 
-    pub out_queue: VecDeque<*mut DBusMessage<'a>>,
-    pub in_queue: VecDeque<*mut DBusMessage<'a>>,
+    pub out_queue: VecDeque<QueuedMessage<'a>>,
+    /// Preallocated sends that were not used or freed yet, out_queue has room for all of them
+    pub preallocated_sends: usize,
+    pub in_queue: VecDeque<QueuedMessage<'a>>,
+    /// The front of in_queue was borrowed and has not been returned or stolen yet
+    pub borrowed: bool,
 
     pub pending_calls: Vec<*mut DBusPendingCall<'a>>,
 
     pub unique_name: Option<std::ffi::CString>,
 
     pub route_peer_messages: bool,
+    /// Serial of a BecomeMonitor call that has not been answered yet
+    pub become_monitor_serial: Option<u32>,
+    /// The bus accepted BecomeMonitor. Everything that comes in goes to the filters and the
+    /// connection must not send anything anymore.
+    pub monitor: bool,
 
     pub filters: Vec<MessageFilter>,
+    pub objects: crate::object_tree::ObjectTree,
+
+    pub unix_user_function: Option<UnixUserFunction>,
+    pub allow_anonymous: bool,
+
+    /// Incoming messages larger than this are a protocol violation
+    pub max_message_size: usize,
+    /// Reading stops while in_queue holds this much
+    pub max_received_size: usize,
+    pub max_received_unix_fds: usize,
+
+    pub data: crate::data_slot::DataSlots,
 }
 
 impl<'a> DBusConnection<'a> {
-    pub fn new(con: rustbus::client_conn::Conn) -> Self {
+    pub fn new(mut con: crate::transport::Transport) -> Self {
+        con.capture = crate::pcap::from_env();
+        // accepted sockets still have to go through auth
+        let state = if con.is_server {
+            ConState::NotAuthenticated
+        } else {
+            ConState::Ready
+        };
         Self {
             con,
             ref_count: 1,
-            state: ConState::Ready,
+            state,
             exit_on_disconnect: false,
+            disconnect_dispatched: false,
             out_queue: VecDeque::new(),
+            preallocated_sends: 0,
             in_queue: VecDeque::new(),
+            borrowed: false,
             pending_calls: Vec::new(),
             unique_name: None,
             route_peer_messages: false,
+            become_monitor_serial: None,
+            monitor: false,
             filters: Vec::new(),
+            objects: crate::object_tree::ObjectTree::default(),
+            unix_user_function: None,
+            allow_anonymous: false,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_received_size: DEFAULT_MAX_RECEIVED_SIZE,
+            max_received_unix_fds: DEFAULT_MAX_RECEIVED_UNIX_FDS,
+            data: crate::data_slot::DataSlots::default(),
         }
     }
 
-    pub fn send_next_message(&mut self, timeout: Option<std::time::Duration>) {
-        if let Some(msg) = self.out_queue.pop_front() {
-            if !msg.is_null() {
-                let msg = unsafe { &mut *msg };
-                self.con.send_message(&mut msg.msg, timeout).unwrap();
+    /// Advance the server side auth of connections that have not finished it yet, waiting for
+    /// the client until the deadline. Without a unix user function only the uid this process
+    /// runs as may connect, like in libdbus. Returns true once the connection is ready.
+    pub fn authenticate(&mut self, deadline: Deadline) -> bool {
+        while self.state == ConState::NotAuthenticated {
+            let self_ptr = self as *mut Self;
+            let function = self
+                .unix_user_function
+                .as_ref()
+                .map(|f| (f.function, f.user_data));
+            let mut authorize = |uid: Option<libc::uid_t>| match (uid, function) {
+                // the handshake only offers ANONYMOUS if it is allowed
+                (None, _) => true,
+                (Some(uid), Some((function, user_data))) => {
+                    function(self_ptr, uid as libc::c_ulong, user_data) != 0
+                }
+                (Some(uid), None) => uid == unsafe { libc::getuid() },
+            };
+            match self.con.server_auth(self.allow_anonymous, &mut authorize) {
+                Ok(uid) => {
+                    trace!("connection", "authenticated client with uid {:?}", uid);
+                    self.state = ConState::Ready;
+                }
+                Err(crate::transport::Error::TimedOut) => {
+                    let write = self.con.auth_wants_write();
+                    match self.con.wait(true, write, deadline) {
+                        Ok((false, false)) => return false,
+                        Ok(_) => {}
+                        Err(e) => {
+                            trace!("connection", "waiting for the socket failed: {:?}", e);
+                            self.disconnect();
+                        }
+                    }
+                }
+                Err(e) => {
+                    trace!("connection", "auth failed: {:?}", e);
+                    self.disconnect();
+                }
             }
         }
+        self.state == ConState::Ready
     }
 
-    pub fn dispatch_message(&mut self, mut msg: DBusMessage<'a>) {
-        let self_ptr = self as *mut Self;
+    /// Move out_queue to the socket, waiting for it until the deadline. A message the socket
+    /// only took part of stays at the front of out_queue until the rest is written. Returns true
+    /// once out_queue is empty.
+    pub fn write_queued(&mut self, deadline: Deadline) -> bool {
+        while let Some(queued) = self.out_queue.front_mut() {
+            let msg = queued.msg;
+            if !self.con.has_pending_output() {
+                let bytes = std::mem::take(&mut queued.bytes);
+                self.con.start_marshalled(&unsafe { &*msg }.msg, bytes);
+            }
+            match self.con.flush_output(deadline) {
+                Ok(()) => {
+                    self.out_queue.pop_front();
+                    // drop the reference taken in dbus_connection_send
+                    crate::message::dbus_message_unref(msg);
+                }
+                Err(crate::transport::Error::TimedOut) => return false,
+                Err(e) => {
+                    trace!("connection", "writing failed: {:?}", e);
+                    self.disconnect();
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// The socket is gone. Fail all pending calls and tell the application with the local
+    /// Disconnected signal, like libdbus.
+    pub fn disconnect(&mut self) {
+        if self.state == ConState::Disconnected {
+            return;
+        }
+        trace!("connection", "{:?} -> Disconnected", self.state);
+        self.state = ConState::Disconnected;
+
+        // nothing will ever write these
+        self.con.discard_output();
+        for queued in self.out_queue.drain(..) {
+            crate::message::dbus_message_unref(queued.msg);
+        }
+
+        for pending in self.pending_calls.drain(..) {
+            let p = unsafe { &mut *pending };
+            p.con = std::ptr::null_mut();
+            p.fail(
+                DBUS_ERROR_DISCONNECTED,
+                "Connection was disconnected before a reply was received",
+            );
+            dbus_pending_call_unref(pending);
+        }
+
+        let signal = rustbus::message_builder::MessageBuilder::new()
+            .signal(
+                DBUS_INTERFACE_LOCAL.to_owned(),
+                "Disconnected".to_owned(),
+                DBUS_PATH_LOCAL.to_owned(),
+            )
+            .build();
+        // queued even if in_queue is full, this is the last message anyway
+        self.in_queue.push_back(QueuedMessage {
+            msg: Box::into_raw(Box::new(DBusMessage::new(signal))),
+            size: 0,
+            unix_fds: 0,
+            bytes: Vec::new(),
+        });
+    }
+
+    pub fn outgoing_size(&self) -> usize {
+        self.out_queue.iter().map(|q| q.size).sum()
+    }
+
+    pub fn outgoing_unix_fds(&self) -> usize {
+        self.out_queue.iter().map(|q| q.unix_fds).sum()
+    }
+
+    /// in_queue holds as much as the application allows, reading more has to wait until the
+    /// application took messages out of it
+    pub fn incoming_limit_reached(&self) -> bool {
+        let size: usize = self.in_queue.iter().map(|q| q.size).sum();
+        let unix_fds: usize = self.in_queue.iter().map(|q| q.unix_fds).sum();
+        size >= self.max_received_size || unix_fds >= self.max_received_unix_fds
+    }
+
+    pub fn pop_incoming(&mut self) -> Option<*mut DBusMessage<'a>> {
+        self.in_queue.pop_front().map(|q| q.msg)
+    }
 
-        if let rustbus::MessageType::Reply = msg.msg.typ {
+    /// Hand a reply to the pending call waiting for it. Gives the message back if nobody waits
+    /// for it.
+    fn complete_pending_call(&mut self, msg: DBusMessage<'a>) -> Option<DBusMessage<'a>> {
+        if let rustbus::MessageType::Reply | rustbus::MessageType::Error = msg.msg.typ {
             if let Some(reply_serial) = msg.msg.response_serial {
-                for p in &mut self.pending_calls {
-                    let p = unsafe { &mut **p };
-                    if p.serial == reply_serial {
-                        p.reply = Some(Box::into_raw(Box::new(msg)));
-                        p.cond.notify_all();
-                        return;
-                    }
+                let pos = self
+                    .pending_calls
+                    .iter()
+                    .position(|p| unsafe { &**p }.serial == reply_serial);
+                if let Some(pos) = pos {
+                    trace!("pending", "reply for the call with serial {}", reply_serial);
+                    let pending = self.pending_calls.remove(pos);
+                    let p = unsafe { &mut *pending };
+                    p.con = std::ptr::null_mut();
+                    p.reply = Some(Box::into_raw(Box::new(msg)));
+                    p.cond.notify_all();
+                    // the connection is done with this call
+                    dbus_pending_call_unref(pending);
+                    return None;
                 }
             }
         }
+        Some(msg)
+    }
 
-        for filter in &self.filters {
-            match (filter.filter)(self_ptr, &mut msg, filter.user_data) {
-                DBusHandlerResult::DBUS_HANDLER_RESULT_HANDLED => {
-                    return;
+    /// Switch to monitor mode once the bus answered our BecomeMonitor call
+    fn check_become_monitor_reply(&mut self, msg: &rustbus::Message) {
+        if self.become_monitor_serial.is_none()
+            || msg.response_serial != self.become_monitor_serial
+            || msg.sender.as_deref() != Some(crate::daemon::DBUS_SERVICE_DBUS)
+        {
+            return;
+        }
+        self.become_monitor_serial = None;
+        self.monitor = matches!(msg.typ, rustbus::MessageType::Reply);
+        trace!("connection", "became a monitor: {}", self.monitor);
+    }
+
+    /// Move a message that was completely read from the socket into in_queue. Never blocks.
+    /// Replies to pending calls go straight to the pending call and never show up in the queue.
+    pub fn queue_buffered_message(&mut self) {
+        if !self.con.buffer_contains_whole_message().unwrap_or(false) {
+            return;
+        }
+        let size = self.con.bytes_needed_for_current_message().unwrap_or(0);
+        match self
+            .con
+            .get_next_message(Some(std::time::Duration::from_micros(0)))
+        {
+            Err(e) => {
+                // the stream can not be trusted to continue at a message boundary
+                trace!("connection", "reading a message failed: {:?}", e);
+                self.disconnect();
+            }
+            Ok(msg) if msg.raw_fds.len() > self.con.max_message_unix_fds => {
+                trace!(
+                    "connection",
+                    "received {} fds, only {} are allowed per message",
+                    msg.raw_fds.len(),
+                    self.con.max_message_unix_fds
+                );
+                for fd in &msg.raw_fds {
+                    unsafe { libc::close(*fd) };
                 }
+                self.disconnect();
+            }
+            Ok(msg) => {
+                let msg = DBusMessage::new(msg);
+                let msg = if self.monitor {
+                    // replies a monitor sees are meant for other connections
+                    Some(msg)
+                } else {
+                    self.check_become_monitor_reply(&msg.msg);
+                    self.complete_pending_call(msg)
+                };
+                if let Some(msg) = msg {
+                    let unix_fds = msg.msg.raw_fds.len();
+                    self.in_queue.push_back(QueuedMessage {
+                        msg: Box::into_raw(Box::new(msg)),
+                        size,
+                        unix_fds,
+                        bytes: Vec::new(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Queue a message the connection itself sends, e.g. replies to Peer calls
+    pub fn send_own_message(&mut self, msg: rustbus::Message<'a, 'a>) {
+        let msg = Box::into_raw(Box::new(DBusMessage::new(msg)));
+        dbus_connection_send(self, msg, std::ptr::null_mut());
+        crate::message::dbus_message_unref(msg);
+    }
+
+    /// Answer org.freedesktop.DBus.Peer like libdbus does on every connection. Returns false if
+    /// the message is not for the built-in Peer implementation.
+    fn handle_peer_message(&mut self, msg: &DBusMessage<'a>) -> bool {
+        let call = &msg.msg;
+        if !matches!(call.typ, rustbus::MessageType::Call)
+            || call.interface.as_deref() != Some(DBUS_INTERFACE_PEER)
+        {
+            return false;
+        }
+        // with route_peer_messages the application answers calls that were addressed to it
+        if self.route_peer_messages && call.destination.is_some() {
+            return false;
+        }
+
+        let reply = match call.member.as_deref() {
+            Some("Ping") => call.make_response(),
+            Some("GetMachineId") => match crate::machine_id::local_machine_id() {
+                Ok(id) => {
+                    let mut reply = call.make_response();
+                    reply.push_param(id);
+                    reply
+                }
+                Err(text) => crate::message::make_error_response(call, DBUS_ERROR_FAILED, &text),
+            },
+            member => crate::message::make_error_response(
+                call,
+                DBUS_ERROR_UNKNOWN_METHOD,
+                &format!(
+                    "{} does not understand message {}",
+                    DBUS_INTERFACE_PEER,
+                    member.unwrap_or("")
+                ),
+            ),
+        };
+        if call.flags & FLAG_NO_REPLY_EXPECTED == 0 {
+            self.send_own_message(reply);
+        }
+        true
+    }
+
+    /// Nobody handled the call, so tell the caller instead of letting it wait for its timeout
+    fn reply_unknown_method(&mut self, msg: &DBusMessage<'a>) {
+        let call = &msg.msg;
+        if !matches!(call.typ, rustbus::MessageType::Call)
+            || call.flags & FLAG_NO_REPLY_EXPECTED != 0
+        {
+            return;
+        }
+        let mut signature = String::new();
+        for t in call.sig() {
+            t.to_str(&mut signature);
+        }
+        let text = format!(
+            "Method \"{}\" with signature \"{}\" on interface \"{}\" doesn't exist\n",
+            call.member.as_deref().unwrap_or(""),
+            signature,
+            call.interface.as_deref().unwrap_or("(null)"),
+        );
+        let path = call.object.as_deref().unwrap_or("/");
+        let reply = if self.objects.exists(path) {
+            crate::message::make_error_response(call, DBUS_ERROR_UNKNOWN_METHOD, &text)
+        } else {
+            crate::message::make_error_response(
+                call,
+                DBUS_ERROR_UNKNOWN_OBJECT,
+                &format!("No such object path '{}'", path),
+            )
+        };
+        self.send_own_message(reply);
+    }
+
+    /// Hand the message to the handler registered for its path. Introspect calls the handler
+    /// does not answer get the generated XML of the object tree.
+    fn dispatch_to_object(&mut self, msg: *mut DBusMessage<'a>) -> bool {
+        let self_ptr = self as *mut Self;
+        let m = unsafe { &*msg };
+        let path = match &m.msg.object {
+            Some(path) => path.clone(),
+            None => return false,
+        };
+
+        // the handler may unregister itself, so don't keep a reference into the tree
+        let handler = self
+            .objects
+            .find(&path)
+            .map(|h| (h.vtable.message_function, h.user_data));
+        if let Some((Some(function), user_data)) = handler {
+            match function(self_ptr, msg, user_data) {
+                DBusHandlerResult::DBUS_HANDLER_RESULT_HANDLED => return true,
                 DBusHandlerResult::DBUS_HANDLER_RESULT_NEED_MEMORY => {
-                    panic!("No OOM handling implemented");
+                    // there is nothing to retry with later, so treat it as not handled
+                    trace!("filter", "object handler for {} needs memory", path);
+                }
+                DBusHandlerResult::DBUS_HANDLER_RESULT_NOT_YET_HANDLED => {}
+            }
+        }
+
+        let call = &m.msg;
+        let is_introspect = matches!(call.typ, rustbus::MessageType::Call)
+            && call.member.as_deref() == Some("Introspect")
+            && matches!(
+                call.interface.as_deref(),
+                None | Some(crate::object_tree::DBUS_INTERFACE_INTROSPECTABLE)
+            );
+        if !is_introspect || !self.objects.exists(&path) {
+            return false;
+        }
+        if call.flags & FLAG_NO_REPLY_EXPECTED == 0 {
+            let mut reply = call.make_response();
+            reply.push_param(self.objects.introspect(&path));
+            self.send_own_message(reply);
+        }
+        true
+    }
+
+    /// Run the filters on a message from in_queue. Unhandled messages are dropped like in libdbus.
+    pub fn dispatch_message(&mut self, msg: *mut DBusMessage<'a>) {
+        let self_ptr = self as *mut Self;
+
+        // the built-in handlers run before any filter, like in libdbus. Monitors only pass
+        // messages on to the filters, most calls they see were not meant for them.
+        if !self.monitor && self.handle_peer_message(unsafe { &*msg }) {
+            crate::message::dbus_message_unref(msg);
+            return;
+        }
+
+        // filters may add or remove filters while they run, so don't hold on to the Vec
+        let mut handled = false;
+        let mut idx = 0;
+        while idx < self.filters.len() && !handled {
+            let filter = self.filters[idx].filter;
+            let user_data = self.filters[idx].user_data;
+            idx += 1;
+            let result = filter(self_ptr, msg, user_data);
+            trace!("filter", "filter {} returned {:?}", idx - 1, result);
+            match result {
+                DBusHandlerResult::DBUS_HANDLER_RESULT_HANDLED => {
+                    handled = true;
+                }
+                DBusHandlerResult::DBUS_HANDLER_RESULT_NEED_MEMORY
+                | DBusHandlerResult::DBUS_HANDLER_RESULT_NOT_YET_HANDLED => {
+                    // Ok, the next filter gets a go. NEED_MEMORY can not be retried later.
                 }
-                DBusHandlerResult::DBUS_HANDLER_RESULT_NOT_YET_HANDLED => {
-                    // Ok
+            }
+        }
+
+        if !handled && !self.monitor {
+            handled = self.dispatch_to_object(msg);
+        }
+        if !handled && !self.monitor {
+            self.reply_unknown_method(unsafe { &*msg });
+        }
+
+        crate::message::dbus_message_unref(msg);
+    }
+
+    /// Connect to a D-Bus address. Buses also want a Hello, see register.
+    pub fn open(address: &str) -> Result<Self, Error> {
+        let path = crate::transport::address_path(address).ok_or_else(|| {
+            Error::new(
+                DBUS_ERROR_BAD_ADDRESS,
+                format!("Address {:?} has no unix socket path", address),
+            )
+        })?;
+        Self::connect(&path)
+    }
+
+    fn connect(path: &std::path::Path) -> Result<Self, Error> {
+        match crate::transport::Transport::connect(path, false) {
+            Ok(con) => Ok(DBusConnection::new(con)),
+            Err(e) => Err(Error::new(
+                DBUS_ERROR_FAILED,
+                format!("Could not connect to bus: {:?}", e),
+            )),
+        }
+    }
+
+    /// Connect and register with the session or the system bus
+    pub fn bus(bus: DBusBusType) -> Result<Self, Error> {
+        let path = match bus {
+            DBusBusType::DBUS_BUS_SESSION => rustbus::get_session_bus_path(),
+            DBusBusType::DBUS_BUS_SYSTEM => rustbus::get_system_bus_path(),
+            _ => {
+                return Err(Error::new(
+                    DBUS_ERROR_INVALID_ARGS,
+                    format!("Unknown bus type: {:?}", bus),
+                ))
+            }
+        }
+        .map_err(|e| {
+            Error::new(
+                DBUS_ERROR_FAILED,
+                format!("Could open path for bus: {:?}", e),
+            )
+        })?;
+        let mut con = Self::connect(&path)?;
+        con.register()?;
+        Ok(con)
+    }
+
+    /// Queue msg and give it the next serial. Monitors can not send anything. Like libdbus,
+    /// messages sent after the disconnect are dropped without an error and get no serial.
+    pub fn send(&mut self, msg: *mut DBusMessage<'a>) -> Result<u32, Error> {
+        let m = unsafe { &mut *msg };
+        if self.state == ConState::Disconnected {
+            trace!(
+                "connection",
+                "disconnected, dropping {}",
+                crate::trace::describe(&m.msg)
+            );
+            return Ok(0);
+        }
+        if self.monitor {
+            // the bus disconnects monitors that send anything
+            trace!(
+                "connection",
+                "monitors can not send {}",
+                crate::trace::describe(&m.msg)
+            );
+            return Err(Error::new(DBUS_ERROR_FAILED, "Monitors can not send"));
+        }
+        let serial = self.con.alloc_serial();
+        m.msg.serial = Some(serial);
+        if is_become_monitor(&m.msg) {
+            self.become_monitor_serial = Some(serial);
+        }
+
+        // marshalled up front, so a broken message fails here and out_queue knows its size
+        let mut bytes = Vec::new();
+        if let Err(e) = crate::message::marshal(&m.msg, &mut bytes) {
+            trace!(
+                "message",
+                "can not marshal {}: {:?}",
+                crate::trace::describe(&m.msg),
+                e
+            );
+            return Err(Error::new(
+                DBUS_ERROR_FAILED,
+                format!("Can not marshal the message: {:?}", e),
+            ));
+        }
+
+        // the queue holds a reference until the message is written
+        dbus_message_ref(msg);
+        self.out_queue.push_back(QueuedMessage {
+            msg,
+            size: bytes.len(),
+            unix_fds: m.msg.raw_fds.len(),
+            bytes,
+        });
+        Ok(serial)
+    }
+
+    /// Send msg and track the reply. timeout is in milliseconds, negative for the default and
+    /// DBUS_TIMEOUT_INFINITE for none. The caller owns the returned reference.
+    pub fn send_with_reply(
+        &mut self,
+        msg: *mut DBusMessage<'a>,
+        timeout: libc::c_int,
+    ) -> Result<*mut DBusPendingCall<'a>, Error> {
+        let serial = self.send(msg)?;
+        let timeout = match timeout {
+            DBUS_TIMEOUT_INFINITE => None,
+            t if t < 0 => Some(std::time::Duration::from_millis(DEFAULT_TIMEOUT_MILLIS)),
+            t => Some(std::time::Duration::from_millis(t as u64)),
+        };
+        let mut pending = DBusPendingCall::new(serial, timeout);
+        if self.state == ConState::Disconnected {
+            // the message was dropped, no reply will ever come
+            pending.fail(DBUS_ERROR_DISCONNECTED, "Connection is closed");
+            return Ok(Box::into_raw(Box::new(pending)));
+        }
+        pending.con = self;
+        let pending = Box::into_raw(Box::new(pending));
+        // one reference for the caller, one for the connection until the reply arrives
+        self.pending_calls.push(dbus_pending_call_ref(pending));
+        Ok(pending)
+    }
+
+    /// Send msg and wait for the reply. Error replies, timeouts and disconnects are errors.
+    pub fn send_with_reply_and_block(
+        &mut self,
+        msg: *mut DBusMessage<'a>,
+        timeout: libc::c_int,
+    ) -> Result<*mut DBusMessage<'a>, Error> {
+        let pending = self.send_with_reply(msg, timeout)?;
+        let waited = DBusPendingCall::wait(pending, self);
+        // timed out, the connection is done waiting as well
+        self.forget_pending_call(pending);
+        let reply = unsafe { &mut *pending }.steal_reply();
+        dbus_pending_call_unref(pending);
+        waited?;
+        let reply = reply.expect("wait returns once the reply is there");
+        // like libdbus, error replies only come back as the error
+        match Error::from_message(&unsafe { &*reply }.msg) {
+            Some(e) => {
+                dbus_message_unref(reply);
+                Err(e)
+            }
+            None => Ok(reply),
+        }
+    }
+
+    /// Stop waiting for the reply to pending and drop the reference the connection holds
+    fn forget_pending_call(&mut self, pending: *mut DBusPendingCall<'a>) {
+        if let Some(pos) = self.pending_calls.iter().position(|p| *p == pending) {
+            let pending = self.pending_calls.remove(pos);
+            unsafe { &mut *pending }.con = std::ptr::null_mut();
+            dbus_pending_call_unref(pending);
+        }
+    }
+
+    /// Write everything queued, however long it takes
+    pub fn flush(&mut self) {
+        if self.authenticate(Deadline::never()) {
+            self.write_queued(Deadline::never());
+        }
+    }
+
+    /// Wait up to timeout milliseconds (negative for ever) until the socket can be read or
+    /// written and do it, without dispatching. False once disconnected.
+    pub fn read_write(&mut self, timeout: libc::c_int) -> bool {
+        let deadline = Deadline::from_millis(timeout);
+        if !self.authenticate(deadline) {
+            // the client may still finish the handshake on the next call
+            return self.state != ConState::Disconnected;
+        }
+
+        // a message larger than allowed can not be read without the whole header being valid, so
+        // bail out as soon as the header says how large it is
+        match self.con.bytes_needed_for_current_message() {
+            Ok(needed) if needed <= self.max_message_size => {}
+            _ => {
+                self.disconnect();
+                return false;
+            }
+        }
+        self.queue_buffered_message();
+
+        let read = !self.incoming_limit_reached();
+        let write = !self.out_queue.is_empty();
+        if !read && !write {
+            return true;
+        }
+        let (readable, writable) = match self.con.wait(read, write, deadline) {
+            Ok(ready) => ready,
+            Err(e) => {
+                trace!("connection", "waiting for the socket failed: {:?}", e);
+                self.disconnect();
+                return false;
+            }
+        };
+
+        if writable {
+            // write what goes out without blocking, the rest waits for the next call
+            self.write_queued(Deadline::now());
+        }
+        if readable && self.state != ConState::Disconnected {
+            match self.con.read_once() {
+                Ok(()) | Err(crate::transport::Error::TimedOut) => {}
+                Err(e) => {
+                    // TODO more cleanup
+                    trace!("connection", "reading failed: {:?}", e);
+                    self.disconnect();
+                    return false;
                 }
             }
+            self.queue_buffered_message();
+        }
+
+        self.state != ConState::Disconnected
+    }
+
+    /// Dispatch one message if there is one, otherwise read_write. False once the Disconnected
+    /// signal was dispatched.
+    pub fn read_write_dispatch(&mut self, timeout: libc::c_int) -> bool {
+        self.queue_buffered_message();
+        // like libdbus, only wait for I/O if there is nothing to dispatch
+        if self.in_queue.is_empty() || self.borrowed {
+            self.read_write(timeout);
+        } else {
+            self.dispatch();
         }
+        !self.disconnect_dispatched
+    }
 
-        // TODO implement handlers / fallbacks for object paths
+    /// Run the filters and handlers on the next message in in_queue
+    pub fn dispatch(&mut self) -> DBusDispatchStatus {
+        if self.borrowed {
+            // libdbus does not dispatch while a message is borrowed either
+            return DBusDispatchStatus::DataRemaining;
+        }
+        self.queue_buffered_message();
+        if let Some(msg) = self.pop_incoming() {
+            let m = unsafe { &*msg };
+            let is_disconnect = m.msg.object.as_deref() == Some(DBUS_PATH_LOCAL)
+                && m.msg.interface.as_deref() == Some(DBUS_INTERFACE_LOCAL)
+                && m.msg.member.as_deref() == Some("Disconnected");
+            self.dispatch_message(msg);
+            if is_disconnect {
+                self.disconnect_dispatched = true;
+                if self.exit_on_disconnect {
+                    unsafe { libc::_exit(1) };
+                }
+            }
+        }
+        self.queue_buffered_message();
+        if self.in_queue.is_empty() {
+            DBusDispatchStatus::Complete
+        } else {
+            DBusDispatchStatus::DataRemaining
+        }
+    }
 
-        self.in_queue.push_back(Box::into_raw(Box::new(msg)))
+    /// Take the next message out of in_queue without dispatching it
+    pub fn pop_message(&mut self) -> Option<*mut DBusMessage<'a>> {
+        if self.borrowed {
+            return None;
+        }
+        if self.in_queue.is_empty() {
+            self.queue_buffered_message();
+        }
+        self.pop_incoming()
     }
 }
 
 impl<'a> Drop for DBusConnection<'a> {
     fn drop(&mut self) {
-        for msg in &mut self.out_queue {
-            crate::message::dbus_message_unref(*msg);
+        let self_ptr = self as *mut Self;
+        for handler in self.objects.drain() {
+            if let Some(unregister) = handler.vtable.unregister_function {
+                unregister(self_ptr, handler.user_data);
+            }
+        }
+        self.data.clear();
+        for pending in self.pending_calls.drain(..) {
+            unsafe { &mut *pending }.con = std::ptr::null_mut();
+            dbus_pending_call_unref(pending);
+        }
+        for queued in self.out_queue.drain(..).chain(self.in_queue.drain(..)) {
+            crate::message::dbus_message_unref(queued.msg);
         }
     }
 }
@@ -158,15 +1020,17 @@ pub extern "C" fn dbus_connection_open<'a>(
         assert!(!addr.is_null());
         CStr::from_ptr(addr)
     };
-    let path = addr.to_str().unwrap().to_string();
-
-    match rustbus::client_conn::Conn::connect_to_bus(path.into(), false) {
-        Ok(con) => Box::into_raw(Box::new(DBusConnection::new(con))),
+    let con = match addr.to_str() {
+        Ok(addr) => DBusConnection::open(addr),
+        Err(_) => Err(Error::new(
+            DBUS_ERROR_BAD_ADDRESS,
+            "Address was not valid UTF-8",
+        )),
+    };
+    match con {
+        Ok(con) => Box::into_raw(Box::new(con)),
         Err(e) => {
-            if !err.is_null() {
-                let err: &mut DBusError = unsafe { &mut *err };
-                err.error = Box::new(format!("Could not connect to bus: {:?}", e));
-            }
+            e.set(err);
             std::ptr::null_mut()
         }
     }
@@ -177,42 +1041,13 @@ pub extern "C" fn dbus_bus_get<'a>(
     bus: DBusBusType,
     err: *mut DBusError,
 ) -> *mut DBusConnection<'a> {
-    let path = match bus {
-        DBusBusType::DBUS_BUS_SESSION => rustbus::get_session_bus_path(),
-        DBusBusType::DBUS_BUS_SYSTEM => rustbus::get_system_bus_path(),
-        _ => {
-            let err: &mut DBusError = unsafe { &mut *err };
-            err.error = Box::new(format!("Unknown bus type: {:?}", bus));
-            return std::ptr::null_mut();
-        }
-    };
-    let con = match path {
-        Ok(path) => match rustbus::client_conn::Conn::connect_to_bus(path, false) {
-            Ok(mut con) => {
-                con.send_message(&mut rustbus::standard_messages::hello(), None)
-                    .unwrap();
-
-                // TODO check this for an error response and return as such
-                con.get_next_message(None).unwrap();
-                Box::into_raw(Box::new(DBusConnection::new(con)))
-            }
-            Err(e) => {
-                if !err.is_null() {
-                    let err: &mut DBusError = unsafe { &mut *err };
-                    err.error = Box::new(format!("Could not connect to bus: {:?}", e));
-                }
-                return std::ptr::null_mut();
-            }
-        },
+    match DBusConnection::bus(bus) {
+        Ok(con) => Box::into_raw(Box::new(con)),
         Err(e) => {
-            if !err.is_null() {
-                let err: &mut DBusError = unsafe { &mut *err };
-                err.error = Box::new(format!("Could open path for bus: {:?}", e));
-            }
-            return std::ptr::null_mut();
+            e.set(err);
+            std::ptr::null_mut()
         }
-    };
-    con
+    }
 }
 
 #[no_mangle]
@@ -239,32 +1074,33 @@ pub extern "C" fn dbus_connection_send_hello<'a>(
     }
 }
 
+fn is_become_monitor(msg: &rustbus::Message) -> bool {
+    matches!(msg.typ, rustbus::MessageType::Call)
+        && msg.destination.as_deref() == Some(crate::daemon::DBUS_SERVICE_DBUS)
+        && msg.interface.as_deref() == Some(DBUS_INTERFACE_MONITORING)
+        && msg.member.as_deref() == Some("BecomeMonitor")
+}
+
 #[no_mangle]
 pub extern "C" fn dbus_connection_send<'a>(
     con: *mut DBusConnection<'a>,
     msg: *mut DBusMessage<'a>,
     serial: *mut u32,
 ) -> u32 {
-    if con.is_null() {
+    if con.is_null() || msg.is_null() {
         return dbus_bool(false);
     }
     let con = unsafe { &mut *con };
-    if msg.is_null() {
-        return dbus_bool(false);
+    match con.send(msg) {
+        Ok(new_serial) => {
+            if !serial.is_null() {
+                unsafe { *serial = new_serial };
+            }
+            dbus_bool(true)
+        }
+        Err(_) => dbus_bool(false),
     }
-    let msg = unsafe { &mut *msg };
-    let new_serial = con.con.alloc_serial();
-    msg.msg.serial = Some(new_serial);
-
-    if !serial.is_null() {
-        unsafe { *serial = new_serial };
-    }
-
-    // increase ref counter
-    dbus_message_ref(msg);
-    con.out_queue.push_back(msg);
-    dbus_bool(true)
-}
+}
 
 #[no_mangle]
 pub extern "C" fn dbus_connection_ref(con: *mut DBusConnection) -> *mut DBusConnection {
@@ -273,7 +1109,7 @@ pub extern "C" fn dbus_connection_ref(con: *mut DBusConnection) -> *mut DBusConn
     }
     let con = unsafe { &mut *con };
     con.ref_count += 1;
-    return con;
+    con
 }
 
 #[no_mangle]
@@ -294,7 +1130,7 @@ pub extern "C" fn dbus_connection_close(con: *mut DBusConnection) {
         return;
     }
     unsafe {
-        Box::from_raw(con);
+        drop(Box::from_raw(con));
         //dropped here -> free'd
     }
 }
@@ -304,27 +1140,7 @@ pub extern "C" fn dbus_connection_flush(con: *mut DBusConnection) {
     if con.is_null() {
         return;
     }
-    let con = unsafe { &mut *con };
-    while !con.out_queue.is_empty() {
-        con.send_next_message(None);
-    }
-}
-
-fn calc_remaining_time(
-    start: &std::time::Instant,
-    timeout: &Option<std::time::Duration>,
-) -> Result<Option<std::time::Duration>, ()> {
-    match timeout {
-        None => Ok(None),
-        Some(d) => {
-            let elapsed = start.elapsed();
-            if elapsed >= *d {
-                Err(())
-            } else {
-                Ok(Some(*d - elapsed))
-            }
-        }
-    }
+    unsafe { &mut *con }.flush();
 }
 
 #[no_mangle]
@@ -335,45 +1151,7 @@ pub extern "C" fn dbus_connection_read_write(
     if con.is_null() {
         return dbus_bool(false);
     }
-    let con = unsafe { &mut *con };
-    if con.state == ConState::Disconnected {
-        return dbus_bool(false);
-    }
-
-    let timeout = if timeout < 0 {
-        Some(std::time::Duration::from_millis(timeout as u64))
-    } else {
-        None
-    };
-    // TODO the doc is not exactly clear on the semantics here
-    let start = std::time::Instant::now();
-    while !con.out_queue.is_empty() {
-        if let Ok(timeout) = calc_remaining_time(&start, &timeout) {
-            con.send_next_message(timeout);
-        }
-    }
-
-    match con.con.can_read_from_source() {
-        Ok(true) => {
-            if let Ok(timeout) = calc_remaining_time(&start, &timeout) {
-                if let Err(_e) = con.con.read_once(timeout) {
-                    // TODO more cleanup
-                    con.state = ConState::Disconnected;
-                    return dbus_bool(false);
-                }
-            }
-        }
-        Ok(false) => {
-            // nothing to do
-        }
-        Err(_e) => {
-            // TODO more cleanup
-            con.state = ConState::Disconnected;
-            return dbus_bool(false);
-        }
-    }
-
-    return dbus_bool(true);
+    dbus_bool(unsafe { &mut *con }.read_write(timeout))
 }
 
 #[no_mangle]
@@ -381,10 +1159,10 @@ pub extern "C" fn dbus_connection_read_write_dispatch(
     con: *mut DBusConnection,
     timeout: libc::c_int,
 ) -> u32 {
-    dbus_connection_read_write(con, timeout);
-    dbus_connection_dispatch(con);
-    // TODO check for the Disconnect message and return false
-    dbus_bool(true)
+    if con.is_null() {
+        return dbus_bool(false);
+    }
+    dbus_bool(unsafe { &mut *con }.read_write_dispatch(timeout))
 }
 
 #[no_mangle]
@@ -392,23 +1170,7 @@ pub extern "C" fn dbus_connection_dispatch(con: *mut DBusConnection) -> DBusDisp
     if con.is_null() {
         return DBusDispatchStatus::Complete;
     }
-    let con = unsafe { &mut *con };
-    if con.con.buffer_contains_whole_message().unwrap() {
-        match con
-            .con
-            .get_next_message(Some(std::time::Duration::from_micros(0)))
-        {
-            Err(_e) => {
-                // TODO
-            }
-            Ok(msg) => con.dispatch_message(DBusMessage::new(msg)),
-        }
-    }
-    if con.con.can_read_from_source().unwrap() {
-        DBusDispatchStatus::DataRemaining
-    } else {
-        DBusDispatchStatus::Complete
-    }
+    unsafe { &mut *con }.dispatch()
 }
 
 #[no_mangle]
@@ -418,34 +1180,16 @@ pub extern "C" fn dbus_connection_send_with_reply<'a>(
     pending: *mut *mut DBusPendingCall<'a>,
     timeout: libc::c_int,
 ) -> u32 {
-    if con.is_null() {
+    if con.is_null() || msg.is_null() || pending.is_null() {
         return dbus_bool(false);
     }
-    let con = unsafe { &mut *con };
-    if msg.is_null() {
-        return dbus_bool(false);
-    }
-    let msg = unsafe { &mut *msg };
-    if pending.is_null() {
-        return dbus_bool(false);
-    }
-    let pending = unsafe { &mut *pending };
-
-    let mut serial = 0u32;
-    if dbus_connection_send(con, msg, &mut serial) == dbus_bool(false) {
-        return dbus_bool(false);
+    match unsafe { &mut *con }.send_with_reply(msg, timeout) {
+        Ok(new_pending) => {
+            unsafe { *pending = new_pending };
+            dbus_bool(true)
+        }
+        Err(_) => dbus_bool(false),
     }
-
-    let timeout = if timeout < 0 {
-        // TODO what does libdbus use here as a 'sane' default (as they call it)?
-        Some(std::time::Duration::from_millis(10))
-    } else {
-        Some(std::time::Duration::from_millis(timeout as u64))
-    };
-    let new_pending = Box::into_raw(Box::new(DBusPendingCall::new(serial, timeout)));
-    *pending = new_pending;
-    con.pending_calls.push(new_pending);
-    dbus_bool(true)
 }
 
 #[no_mangle]
@@ -455,28 +1199,16 @@ pub extern "C" fn dbus_connection_send_with_reply_and_block<'a>(
     timeout: libc::c_int,
     err: *mut DBusError,
 ) -> *mut DBusMessage<'a> {
-    if con.is_null() {
+    if con.is_null() || msg.is_null() {
         return std::ptr::null_mut();
     }
-    let con = unsafe { &mut *con };
-    if msg.is_null() {
-        return std::ptr::null_mut();
-    }
-    let msg = unsafe { &mut *msg };
-    let mut pending: *mut DBusPendingCall = std::ptr::null_mut();
-    dbus_connection_send_with_reply(con, msg, &mut pending, timeout);
-
-    // TODO convert error replys to DBusError
-    let _ = err;
-
-    let pending = unsafe { &mut *pending };
-    while !pending.timed_out() {
-        if let Some(reply) = pending.reply {
-            return reply;
+    match unsafe { &mut *con }.send_with_reply_and_block(msg, timeout) {
+        Ok(reply) => reply,
+        Err(e) => {
+            e.set(err);
+            std::ptr::null_mut()
         }
-        dbus_connection_read_write_dispatch(con, timeout);
     }
-    std::ptr::null_mut()
 }
 
 #[no_mangle]
@@ -496,7 +1228,7 @@ pub extern "C" fn dbus_connection_add_filter<'a>(
     con: *mut DBusConnection<'a>,
     filter: DBusHandleMessageFunction,
     user_data: *mut std::ffi::c_void,
-    free: crate::DBusFreeFunction,
+    free: Option<crate::DBusFreeFunction>,
 ) -> u32 {
     if con.is_null() {
         return dbus_bool(false);
@@ -515,21 +1247,1059 @@ pub extern "C" fn dbus_connection_add_filter<'a>(
 pub extern "C" fn dbus_connection_remove_filter<'a>(
     con: *mut DBusConnection<'a>,
     filter: DBusHandleMessageFunction,
-    _user_data: *mut std::ffi::c_void,
+    user_data: *mut std::ffi::c_void,
+) {
+    if con.is_null() {
+        return;
+    }
+    let con = unsafe { &mut *con };
+
+    // this is necessary because the DBusHandleMessageFunctions cannot be compared directly
+    let filter_ptr = filter as *const std::ffi::c_void;
+
+    // libdbus removes the most recently added instance if a filter was added multiple times
+    let idx = con.filters.iter().rposition(|f| {
+        f.filter as *const std::ffi::c_void == filter_ptr && f.user_data == user_data
+    });
+    if let Some(idx) = idx {
+        // dropping the filter calls the free function for the user_data
+        con.filters.remove(idx);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_pop_message<'a>(
+    con: *mut DBusConnection<'a>,
+) -> *mut DBusMessage<'a> {
+    if con.is_null() {
+        return std::ptr::null_mut();
+    }
+    unsafe { &mut *con }
+        .pop_message()
+        .unwrap_or(std::ptr::null_mut())
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_borrow_message<'a>(
+    con: *mut DBusConnection<'a>,
+) -> *mut DBusMessage<'a> {
+    if con.is_null() {
+        return std::ptr::null_mut();
+    }
+    let con = unsafe { &mut *con };
+    if con.borrowed {
+        // only one message can be borrowed at a time
+        return std::ptr::null_mut();
+    }
+    if con.in_queue.is_empty() {
+        con.queue_buffered_message();
+    }
+    match con.in_queue.front() {
+        Some(queued) => {
+            con.borrowed = true;
+            queued.msg
+        }
+        None => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_return_message<'a>(
+    con: *mut DBusConnection<'a>,
+    msg: *mut DBusMessage<'a>,
 ) {
     if con.is_null() {
         return;
     }
     let con = unsafe { &mut *con };
+    if con.borrowed && con.in_queue.front().map(|q| q.msg) == Some(msg) {
+        con.borrowed = false;
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_steal_borrowed_message<'a>(
+    con: *mut DBusConnection<'a>,
+    msg: *mut DBusMessage<'a>,
+) {
+    if con.is_null() {
+        return;
+    }
+    let con = unsafe { &mut *con };
+    if con.borrowed && con.in_queue.front().map(|q| q.msg) == Some(msg) {
+        // the reference of the queue now belongs to the caller
+        con.pop_incoming();
+        con.borrowed = false;
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_preallocate_send<'a>(
+    con: *mut DBusConnection<'a>,
+) -> *mut DBusPreallocatedSend<'a> {
+    if con.is_null() {
+        return std::ptr::null_mut();
+    }
+    let c = unsafe { &mut *con };
+    c.preallocated_sends += 1;
+    c.out_queue.reserve(c.preallocated_sends);
+    Box::into_raw(Box::new(DBusPreallocatedSend { con }))
+}
+
+/// Check that preallocated belongs to con before it is used or freed
+fn check_preallocated<'a>(
+    con: *mut DBusConnection<'a>,
+    preallocated: *mut DBusPreallocatedSend<'a>,
+) -> bool {
+    if con.is_null() || preallocated.is_null() {
+        return false;
+    }
+    if unsafe { &*preallocated }.con != con {
+        trace!(
+            "connection",
+            "preallocated send {:?} belongs to another connection than {:?}",
+            preallocated,
+            con
+        );
+        return false;
+    }
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_free_preallocated_send<'a>(
+    con: *mut DBusConnection<'a>,
+    preallocated: *mut DBusPreallocatedSend<'a>,
+) {
+    if !check_preallocated(con, preallocated) {
+        return;
+    }
+    drop(unsafe { Box::from_raw(preallocated) });
+    let con = unsafe { &mut *con };
+    con.preallocated_sends -= 1;
+}
 
-    // this is necessaey because the DBusHandleMessageFunctions cannot be compared directly
-    let filter_ptr: *const std::ffi::c_void = unsafe { std::mem::transmute(filter) };
-    for f in &con.filters {
-        let f_ptr: *const std::ffi::c_void = unsafe { std::mem::transmute(f.filter) };
-        if f_ptr == filter_ptr {}
+#[no_mangle]
+pub extern "C" fn dbus_connection_send_preallocated<'a>(
+    con: *mut DBusConnection<'a>,
+    preallocated: *mut DBusPreallocatedSend<'a>,
+    msg: *mut DBusMessage<'a>,
+    client_serial: *mut u32,
+) {
+    if msg.is_null() || !check_preallocated(con, preallocated) {
+        return;
     }
+    // the room this reserved is what the message takes in out_queue
+    dbus_connection_free_preallocated_send(con, preallocated);
+    match unsafe { &mut *con }.send(msg) {
+        Ok(serial) => {
+            if !client_serial.is_null() {
+                unsafe { *client_serial = serial };
+            }
+        }
+        Err(e) => trace!(
+            "connection",
+            "sending a preallocated message failed: {}",
+            e.message
+        ),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_is_connected(con: *mut DBusConnection) -> u32 {
+    if con.is_null() {
+        return dbus_bool(false);
+    }
+    let con = unsafe { &mut *con };
+    dbus_bool(con.state != ConState::Disconnected)
+}
 
-    // FIXME what happens with the userdata?
-    // Does it have to be the same as the original userdata?
-    // HUH?
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_server_id(con: *mut DBusConnection) -> *mut libc::c_char {
+    if con.is_null() {
+        return std::ptr::null_mut();
+    }
+    let con = unsafe { &mut *con };
+    // only the client side learns the server GUID during auth
+    match &con.con.server_guid {
+        Some(guid) if !con.con.is_server => crate::malloc_cstring(guid.as_bytes()),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_socket(
+    con: *mut DBusConnection,
+    fd: *mut libc::c_int,
+) -> u32 {
+    if con.is_null() || fd.is_null() {
+        return dbus_bool(false);
+    }
+    let con = unsafe { &*con };
+    if con.state == ConState::Disconnected {
+        return dbus_bool(false);
+    }
+    unsafe { *fd = std::os::unix::io::AsRawFd::as_raw_fd(&con.con) };
+    dbus_bool(true)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_unix_fd(
+    con: *mut DBusConnection,
+    fd: *mut libc::c_int,
+) -> u32 {
+    // librdbus only does unix sockets, so these are the same
+    dbus_connection_get_socket(con, fd)
+}
+
+/// The credentials of the peer, but only once it is authenticated, like in libdbus
+fn peer_credentials(con: *mut DBusConnection) -> Option<crate::transport::PeerCredentials> {
+    if con.is_null() {
+        return None;
+    }
+    let con = unsafe { &mut *con };
+    if con.state != ConState::Ready {
+        return None;
+    }
+    con.con.peer_credentials().ok()
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_unix_user(
+    con: *mut DBusConnection,
+    uid: *mut libc::c_ulong,
+) -> u32 {
+    if uid.is_null() {
+        return dbus_bool(false);
+    }
+    match peer_credentials(con) {
+        Some(cred) => {
+            unsafe { *uid = cred.uid as libc::c_ulong };
+            dbus_bool(true)
+        }
+        None => dbus_bool(false),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_unix_process_id(
+    con: *mut DBusConnection,
+    pid: *mut libc::c_ulong,
+) -> u32 {
+    if pid.is_null() {
+        return dbus_bool(false);
+    }
+    match peer_credentials(con) {
+        // the kernel reports 0 if the peer is in another pid namespace
+        Some(cred) if cred.pid > 0 => {
+            unsafe { *pid = cred.pid as libc::c_ulong };
+            dbus_bool(true)
+        }
+        _ => dbus_bool(false),
+    }
+}
+
+/// Solaris audit data. Linux never has any.
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_adt_audit_session_data(
+    _con: *mut DBusConnection,
+    data: *mut *mut std::ffi::c_void,
+    data_size: *mut i32,
+) -> u32 {
+    if !data.is_null() {
+        unsafe { *data = std::ptr::null_mut() };
+    }
+    if !data_size.is_null() {
+        unsafe { *data_size = 0 };
+    }
+    dbus_bool(false)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_linux_security_label(
+    con: *mut DBusConnection,
+    label: *mut *mut libc::c_char,
+) -> u32 {
+    if con.is_null() || label.is_null() {
+        return dbus_bool(false);
+    }
+    let con = unsafe { &mut *con };
+    if con.state != ConState::Ready {
+        return dbus_bool(false);
+    }
+    match con.con.peer_security_label() {
+        Ok(l) if !l.is_empty() => {
+            unsafe { *label = crate::malloc_cstring(&l) };
+            dbus_bool(true)
+        }
+        _ => dbus_bool(false),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_set_unix_user_function(
+    con: *mut DBusConnection,
+    function: Option<crate::DBusAllowUnixUserFunction>,
+    data: *mut std::ffi::c_void,
+    free_data_function: Option<crate::DBusFreeFunction>,
+) {
+    if con.is_null() {
+        return;
+    }
+    let con = unsafe { &mut *con };
+    // dropping the old function frees its data
+    con.unix_user_function = function.map(|function| UnixUserFunction {
+        function,
+        user_data: data,
+        free: free_data_function,
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_set_allow_anonymous(con: *mut DBusConnection, value: u32) {
+    if con.is_null() {
+        return;
+    }
+    let con = unsafe { &mut *con };
+    con.allow_anonymous = value != 0;
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_set_max_message_size(
+    con: *mut DBusConnection,
+    size: libc::c_long,
+) {
+    if con.is_null() {
+        return;
+    }
+    let con = unsafe { &mut *con };
+    con.max_message_size = size.max(0) as usize;
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_max_message_size(con: *mut DBusConnection) -> libc::c_long {
+    if con.is_null() {
+        return 0;
+    }
+    let con = unsafe { &mut *con };
+    con.max_message_size as libc::c_long
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_set_max_message_unix_fds(
+    con: *mut DBusConnection,
+    n: libc::c_long,
+) {
+    if con.is_null() {
+        return;
+    }
+    let con = unsafe { &mut *con };
+    con.con.max_message_unix_fds = n.max(0) as usize;
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_max_message_unix_fds(
+    con: *mut DBusConnection,
+) -> libc::c_long {
+    if con.is_null() {
+        return 0;
+    }
+    let con = unsafe { &mut *con };
+    con.con.max_message_unix_fds as libc::c_long
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_set_max_received_size(
+    con: *mut DBusConnection,
+    size: libc::c_long,
+) {
+    if con.is_null() {
+        return;
+    }
+    let con = unsafe { &mut *con };
+    con.max_received_size = size.max(0) as usize;
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_max_received_size(con: *mut DBusConnection) -> libc::c_long {
+    if con.is_null() {
+        return 0;
+    }
+    let con = unsafe { &mut *con };
+    con.max_received_size as libc::c_long
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_set_max_received_unix_fds(
+    con: *mut DBusConnection,
+    n: libc::c_long,
+) {
+    if con.is_null() {
+        return;
+    }
+    let con = unsafe { &mut *con };
+    con.max_received_unix_fds = n.max(0) as usize;
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_max_received_unix_fds(
+    con: *mut DBusConnection,
+) -> libc::c_long {
+    if con.is_null() {
+        return 0;
+    }
+    let con = unsafe { &mut *con };
+    con.max_received_unix_fds as libc::c_long
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_outgoing_size(con: *mut DBusConnection) -> libc::c_long {
+    if con.is_null() {
+        return 0;
+    }
+    let con = unsafe { &mut *con };
+    con.outgoing_size() as libc::c_long
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_outgoing_unix_fds(con: *mut DBusConnection) -> libc::c_long {
+    if con.is_null() {
+        return 0;
+    }
+    let con = unsafe { &mut *con };
+    con.outgoing_unix_fds() as libc::c_long
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_has_messages_to_send(con: *mut DBusConnection) -> u32 {
+    if con.is_null() {
+        return dbus_bool(false);
+    }
+    let con = unsafe { &mut *con };
+    dbus_bool(!con.out_queue.is_empty())
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_set_exit_on_disconnect(con: *mut DBusConnection, value: u32) {
+    if con.is_null() {
+        return;
+    }
+    let con = unsafe { &mut *con };
+    con.exit_on_disconnect = value != 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn count_disconnects(
+        _con: *mut DBusConnection,
+        msg: *mut DBusMessage,
+        count: *mut std::ffi::c_void,
+    ) -> DBusHandlerResult {
+        let msg = unsafe { &*msg };
+        if msg.msg.member.as_deref() == Some("Disconnected") {
+            unsafe { *(count as *mut u32) += 1 };
+        }
+        DBusHandlerResult::DBUS_HANDLER_RESULT_NOT_YET_HANDLED
+    }
+
+    #[test]
+    fn hangup_dispatches_disconnected() {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let con = Box::into_raw(Box::new(DBusConnection::new(
+            crate::transport::Transport::from_stream(ours),
+        )));
+        let mut count = 0u32;
+        dbus_connection_add_filter(
+            con,
+            count_disconnects,
+            &mut count as *mut u32 as *mut std::ffi::c_void,
+            None,
+        );
+        drop(theirs);
+
+        assert_eq!(dbus_connection_read_write(con, 0), dbus_bool(false));
+        assert_eq!(dbus_connection_get_is_connected(con), dbus_bool(false));
+        assert_eq!(
+            dbus_connection_read_write_dispatch(con, 0),
+            dbus_bool(false)
+        );
+        assert_eq!(count, 1);
+        dbus_connection_unref(con);
+    }
+
+    #[test]
+    fn sending_after_the_disconnect_drops_the_message() {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let con = Box::into_raw(Box::new(DBusConnection::new(
+            crate::transport::Transport::from_stream(ours),
+        )));
+        drop(theirs);
+        assert_eq!(dbus_connection_read_write(con, 0), dbus_bool(false));
+
+        let msg = Box::into_raw(Box::new(DBusMessage::new(test_signal("Late"))));
+        let mut serial = 7;
+        assert_eq!(dbus_connection_send(con, msg, &mut serial), dbus_bool(true));
+        assert_eq!(serial, 0);
+        assert_eq!(unsafe { &*msg }.msg.serial, None);
+        assert!(unsafe { &*con }.out_queue.is_empty());
+
+        let mut pending = std::ptr::null_mut();
+        dbus_connection_send_with_reply(con, msg, &mut pending, -1);
+        assert_eq!(dbus_pending_call_get_completed(pending), dbus_bool(true));
+        dbus_pending_call_unref(pending);
+        crate::message::dbus_message_unref(msg);
+        dbus_connection_unref(con);
+    }
+
+    extern "C" fn ignore(
+        _con: *mut DBusConnection,
+        _msg: *mut DBusMessage,
+        _data: *mut std::ffi::c_void,
+    ) -> DBusHandlerResult {
+        DBusHandlerResult::DBUS_HANDLER_RESULT_NOT_YET_HANDLED
+    }
+
+    extern "C" fn count_free(count: *mut std::ffi::c_void) {
+        unsafe { *(count as *mut u32) += 1 };
+    }
+
+    #[test]
+    fn filters_are_removed_by_function_and_user_data() {
+        let (con, _peer) = socketpair_connection();
+        let (mut first, mut second) = (0u32, 0u32);
+        let first_ptr = &mut first as *mut u32 as *mut std::ffi::c_void;
+        let second_ptr = &mut second as *mut u32 as *mut std::ffi::c_void;
+        for user_data in [first_ptr, second_ptr, first_ptr] {
+            dbus_connection_add_filter(con, ignore, user_data, Some(count_free));
+        }
+
+        dbus_connection_remove_filter(con, count_disconnects, first_ptr);
+        assert_eq!(unsafe { &*con }.filters.len(), 3);
+
+        // the most recently added one goes first
+        dbus_connection_remove_filter(con, ignore, first_ptr);
+        assert_eq!((first, second), (1, 0));
+        let remaining: Vec<_> = unsafe { &*con }
+            .filters
+            .iter()
+            .map(|f| f.user_data)
+            .collect();
+        assert_eq!(remaining, [first_ptr, second_ptr]);
+
+        // the rest is freed with the connection
+        dbus_connection_unref(con);
+        assert_eq!((first, second), (2, 1));
+    }
+
+    fn socketpair_connection<'a>() -> (*mut DBusConnection<'a>, crate::transport::Transport) {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let con = Box::into_raw(Box::new(DBusConnection::new(
+            crate::transport::Transport::from_stream(ours),
+        )));
+        (con, crate::transport::Transport::from_stream(theirs))
+    }
+
+    fn test_signal<'a>(member: &str) -> rustbus::Message<'a, 'a> {
+        rustbus::message_builder::MessageBuilder::new()
+            .signal(
+                "io.rdbus.Test".to_owned(),
+                member.to_owned(),
+                "/io/rdbus".to_owned(),
+            )
+            .build()
+    }
+
+    fn member(msg: *mut DBusMessage) -> String {
+        unsafe { &*msg }.msg.member.clone().unwrap()
+    }
+
+    /// A non-blocking pipe, the read end sees EOF once every copy of the write end is closed
+    fn pipe() -> (libc::c_int, libc::c_int) {
+        let mut fds = [0; 2];
+        assert_eq!(
+            unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) },
+            0
+        );
+        (fds[0], fds[1])
+    }
+
+    fn writers_closed(read_end: libc::c_int) -> bool {
+        let mut byte = 0u8;
+        unsafe { libc::read(read_end, (&mut byte as *mut u8).cast(), 1) == 0 }
+    }
+
+    fn signal_with_fds<'a>(member: &str, fds: &[libc::c_int]) -> rustbus::Message<'a, 'a> {
+        let mut signal = test_signal(member);
+        signal.raw_fds = fds.to_vec();
+        signal.num_fds = Some(fds.len() as u32);
+        signal
+    }
+
+    /// Read until the connection gives up and return the member of what is left in in_queue
+    fn read_until_disconnected(con: *mut DBusConnection) -> Vec<String> {
+        while dbus_connection_read_write(con, -1) != 0 {}
+        assert_eq!(dbus_connection_get_is_connected(con), dbus_bool(false));
+        let queued = unsafe { &*con }
+            .in_queue
+            .iter()
+            .map(|q| member(q.msg))
+            .collect();
+        dbus_connection_unref(con);
+        queued
+    }
+
+    #[test]
+    fn oversized_messages_disconnect() {
+        let (con, mut peer) = socketpair_connection();
+        dbus_connection_set_max_message_size(con, 1024);
+        assert_eq!(dbus_connection_get_max_message_size(con), 1024);
+        let payload = "x".repeat(2048);
+        let mut signal = test_signal("Large");
+        signal.push_param(payload.as_str());
+        peer.send_message(&mut signal, None).unwrap();
+        assert_eq!(read_until_disconnected(con), vec!["Disconnected"]);
+    }
+
+    #[test]
+    fn too_many_fds_disconnect_and_are_closed() {
+        let (con, mut peer) = socketpair_connection();
+        dbus_connection_set_max_message_unix_fds(con, 1);
+        let (read_end, write_end) = pipe();
+        peer.send_message(&mut signal_with_fds("Fds", &[write_end, write_end]), None)
+            .unwrap();
+        unsafe { libc::close(write_end) };
+
+        assert_eq!(read_until_disconnected(con), vec!["Disconnected"]);
+        assert!(writers_closed(read_end));
+        unsafe { libc::close(read_end) };
+    }
+
+    #[test]
+    fn truncated_fds_disconnect_and_are_closed() {
+        let (con, mut peer) = socketpair_connection();
+        dbus_connection_set_max_message_unix_fds(con, 0);
+        let (read_end, write_end) = pipe();
+        let fds = [write_end, write_end, write_end, write_end];
+        peer.send_message(&mut signal_with_fds("Fds", &fds), None)
+            .unwrap();
+        unsafe { libc::close(write_end) };
+
+        assert_eq!(read_until_disconnected(con), vec!["Disconnected"]);
+        assert!(writers_closed(read_end));
+        unsafe { libc::close(read_end) };
+    }
+
+    #[test]
+    fn broken_messages_disconnect_and_close_their_fds() {
+        let (con, mut peer) = socketpair_connection();
+        let (read_end, write_end) = pipe();
+        // marshals fine, but no string on the wire may contain NUL
+        let mut signal = signal_with_fds("Broken", &[write_end]);
+        signal.push_param("broken\0string");
+        peer.send_message(&mut signal, None).unwrap();
+        unsafe { libc::close(write_end) };
+
+        assert_eq!(read_until_disconnected(con), vec!["Disconnected"]);
+        assert!(writers_closed(read_end));
+        unsafe { libc::close(read_end) };
+    }
+
+    #[test]
+    fn queue_accounting() {
+        let (con, mut peer) = socketpair_connection();
+
+        let (read_end, write_end) = pipe();
+        let mut expected_size = 0;
+        for mut msg in [test_signal("Plain"), signal_with_fds("Fd", &[write_end])] {
+            // the serial send assigns has the same size
+            msg.serial = Some(1);
+            let mut buf = Vec::new();
+            crate::message::marshal(&msg, &mut buf).unwrap();
+            expected_size += buf.len();
+            let msg = Box::into_raw(Box::new(DBusMessage::new(msg)));
+            dbus_connection_send(con, msg, std::ptr::null_mut());
+            crate::message::dbus_message_unref(msg);
+        }
+        assert_eq!(
+            dbus_connection_get_outgoing_size(con),
+            expected_size as libc::c_long
+        );
+        assert_eq!(dbus_connection_get_outgoing_unix_fds(con), 1);
+        dbus_connection_flush(con);
+        assert_eq!(dbus_connection_get_outgoing_size(con), 0);
+        assert_eq!(dbus_connection_get_outgoing_unix_fds(con), 0);
+        peer.get_next_message(None).unwrap();
+        for fd in peer.get_next_message(None).unwrap().raw_fds {
+            unsafe { libc::close(fd) };
+        }
+        unsafe { libc::close(write_end) };
+        unsafe { libc::close(read_end) };
+
+        // one message is all in_queue may hold, the second waits in the socket until it is taken
+        dbus_connection_set_max_received_size(con, 1);
+        for member in &["First", "Second"] {
+            peer.send_message(&mut test_signal(member), None).unwrap();
+        }
+        while unsafe { &*con }.in_queue.is_empty() {
+            assert_eq!(dbus_connection_read_write(con, -1), dbus_bool(true));
+        }
+        for _ in 0..3 {
+            assert_eq!(dbus_connection_read_write(con, 10), dbus_bool(true));
+        }
+        assert_eq!(unsafe { &*con }.in_queue.len(), 1);
+        let first = dbus_connection_pop_message(con);
+        assert_eq!(member(first), "First");
+        crate::message::dbus_message_unref(first);
+        while unsafe { &*con }.in_queue.is_empty() {
+            assert_eq!(dbus_connection_read_write(con, -1), dbus_bool(true));
+        }
+        let second = dbus_connection_pop_message(con);
+        assert_eq!(member(second), "Second");
+        crate::message::dbus_message_unref(second);
+        dbus_connection_unref(con);
+    }
+
+    #[test]
+    fn preallocated_sends() {
+        let (con, mut peer) = socketpair_connection();
+        let (other, _other_peer) = socketpair_connection();
+
+        let first = dbus_connection_preallocate_send(con);
+        let second = dbus_connection_preallocate_send(con);
+        assert_eq!(unsafe { &*con }.preallocated_sends, 2);
+        assert!(unsafe { &*con }.out_queue.capacity() >= 2);
+
+        // belongs to another connection, nothing happens
+        dbus_connection_free_preallocated_send(other, first);
+        let msg = Box::into_raw(Box::new(DBusMessage::new(test_signal("Ignored"))));
+        dbus_connection_send_preallocated(other, first, msg, std::ptr::null_mut());
+        assert!(unsafe { &*other }.out_queue.is_empty());
+        crate::message::dbus_message_unref(msg);
+        assert_eq!(unsafe { &*con }.preallocated_sends, 2);
+
+        let msg = Box::into_raw(Box::new(DBusMessage::new(test_signal("Preallocated"))));
+        let mut serial = 0;
+        dbus_connection_send_preallocated(con, first, msg, &mut serial);
+        crate::message::dbus_message_unref(msg);
+        assert_ne!(serial, 0);
+        assert_eq!(unsafe { &*con }.out_queue.len(), 1);
+        assert_eq!(unsafe { &*con }.preallocated_sends, 1);
+
+        dbus_connection_free_preallocated_send(con, second);
+        assert_eq!(unsafe { &*con }.preallocated_sends, 0);
+
+        dbus_connection_flush(con);
+        let sent = peer.get_next_message(None).unwrap();
+        assert_eq!(sent.serial, Some(serial));
+        assert_eq!(sent.member.as_deref(), Some("Preallocated"));
+        dbus_connection_unref(con);
+        dbus_connection_unref(other);
+    }
+
+    #[test]
+    fn pop_borrow_return_and_steal() {
+        let (con, mut peer) = socketpair_connection();
+        for member in &["First", "Second", "Third"] {
+            peer.send_message(&mut test_signal(member), None).unwrap();
+        }
+        while unsafe { &*con }.in_queue.len() < 3 {
+            assert_eq!(dbus_connection_read_write(con, -1), dbus_bool(true));
+        }
+
+        let first = dbus_connection_borrow_message(con);
+        assert_eq!(member(first), "First");
+        // one borrow at a time, and nothing is popped or dispatched meanwhile
+        assert!(dbus_connection_borrow_message(con).is_null());
+        assert!(dbus_connection_pop_message(con).is_null());
+        assert!(matches!(
+            dbus_connection_dispatch(con),
+            DBusDispatchStatus::DataRemaining
+        ));
+        dbus_connection_return_message(con, first);
+        let popped = dbus_connection_pop_message(con);
+        assert_eq!(popped, first);
+        crate::message::dbus_message_unref(popped);
+
+        let second = dbus_connection_borrow_message(con);
+        assert_eq!(member(second), "Second");
+        // returning or stealing another message than the borrowed one does nothing
+        dbus_connection_return_message(con, std::ptr::null_mut());
+        dbus_connection_steal_borrowed_message(con, std::ptr::null_mut());
+        assert!(unsafe { &*con }.borrowed);
+        dbus_connection_steal_borrowed_message(con, second);
+        assert!(!unsafe { &*con }.borrowed);
+        assert_eq!(unsafe { &*con }.in_queue.len(), 1);
+        crate::message::dbus_message_unref(second);
+
+        let third = dbus_connection_pop_message(con);
+        assert_eq!(member(third), "Third");
+        crate::message::dbus_message_unref(third);
+        assert!(dbus_connection_pop_message(con).is_null());
+        dbus_connection_unref(con);
+    }
+
+    #[test]
+    fn peer_credentials_through_the_abi() {
+        let (con, _peer) = socketpair_connection();
+
+        let mut fd = -1;
+        assert_eq!(dbus_connection_get_socket(con, &mut fd), dbus_bool(true));
+        let mut unix_fd = -1;
+        assert_eq!(
+            dbus_connection_get_unix_fd(con, &mut unix_fd),
+            dbus_bool(true)
+        );
+        assert!(fd >= 0);
+        assert_eq!(fd, unix_fd);
+
+        let mut uid = libc::c_ulong::MAX;
+        assert_eq!(
+            dbus_connection_get_unix_user(con, &mut uid),
+            dbus_bool(true)
+        );
+        assert_eq!(uid, unsafe { libc::getuid() } as libc::c_ulong);
+        let mut pid = 0;
+        assert_eq!(
+            dbus_connection_get_unix_process_id(con, &mut pid),
+            dbus_bool(true)
+        );
+        assert_eq!(pid, std::process::id() as libc::c_ulong);
+
+        let mut data = std::ptr::NonNull::<std::ffi::c_void>::dangling().as_ptr();
+        let mut size = -1;
+        assert_eq!(
+            dbus_connection_get_adt_audit_session_data(con, &mut data, &mut size),
+            dbus_bool(false)
+        );
+        assert!(data.is_null());
+        assert_eq!(size, 0);
+        // only there if an LSM labels unix sockets
+        let mut label = std::ptr::null_mut();
+        if dbus_connection_get_linux_security_label(con, &mut label) != 0 {
+            crate::dbus_free(label.cast());
+        }
+        // nothing authenticated this socketpair, so there is no server GUID
+        assert!(dbus_connection_get_server_id(con).is_null());
+
+        assert_eq!(
+            dbus_connection_get_unix_user(con, std::ptr::null_mut()),
+            dbus_bool(false)
+        );
+        assert_eq!(
+            dbus_connection_get_socket(std::ptr::null_mut(), &mut fd),
+            dbus_bool(false)
+        );
+        dbus_connection_unref(con);
+    }
+
+    #[test]
+    fn getters_do_not_authenticate() {
+        let (ours, _theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let transport = crate::transport::Transport::from_accepted(ours, "0".repeat(32));
+        let con = Box::into_raw(Box::new(DBusConnection::new(transport)));
+
+        // the client never says anything, this must neither block nor start the handshake
+        let mut fd = -1;
+        assert_eq!(dbus_connection_get_socket(con, &mut fd), dbus_bool(true));
+        assert_eq!(dbus_connection_get_unix_fd(con, &mut fd), dbus_bool(true));
+        assert_eq!(unsafe { &*con }.state, ConState::NotAuthenticated);
+        // credentials are only there once the peer authenticated
+        let mut uid = 0;
+        assert_eq!(
+            dbus_connection_get_unix_user(con, &mut uid),
+            dbus_bool(false)
+        );
+        dbus_connection_unref(con);
+    }
+
+    #[test]
+    fn read_write_timeouts() {
+        use std::time::{Duration, Instant};
+        let (con, mut peer) = socketpair_connection();
+
+        let start = Instant::now();
+        assert_eq!(dbus_connection_read_write(con, 0), dbus_bool(true));
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        let start = Instant::now();
+        assert_eq!(dbus_connection_read_write(con, 100), dbus_bool(true));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(unsafe { &*con }.in_queue.is_empty());
+
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            let mut signal = rustbus::message_builder::MessageBuilder::new()
+                .signal(
+                    "io.rdbus.Test".to_owned(),
+                    "Late".to_owned(),
+                    "/io/rdbus".to_owned(),
+                )
+                .build();
+            peer.send_message(&mut signal, None).unwrap();
+            peer
+        });
+        while unsafe { &*con }.in_queue.is_empty() {
+            assert_eq!(dbus_connection_read_write(con, -1), dbus_bool(true));
+        }
+        let _peer = sender.join().unwrap();
+        dbus_connection_unref(con);
+    }
+
+    #[test]
+    fn partial_writes_resume() {
+        use std::time::{Duration, Instant};
+        let (con, mut peer) = socketpair_connection();
+        let payload = "x".repeat(4 * 1024 * 1024);
+        let mut signal = rustbus::message_builder::MessageBuilder::new()
+            .signal(
+                "io.rdbus.Test".to_owned(),
+                "Large".to_owned(),
+                "/io/rdbus".to_owned(),
+            )
+            .build();
+        signal.push_param(payload.as_str());
+        let msg = Box::into_raw(Box::new(DBusMessage::new(signal)));
+        assert_eq!(
+            dbus_connection_send(con, msg, std::ptr::null_mut()),
+            dbus_bool(true)
+        );
+        crate::message::dbus_message_unref(msg);
+
+        // nobody reads on the other end, so this can only write part of it
+        let start = Instant::now();
+        assert_eq!(dbus_connection_read_write(con, 0), dbus_bool(true));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(unsafe { &*con }.con.has_pending_output());
+        assert_eq!(dbus_connection_has_messages_to_send(con), dbus_bool(true));
+
+        let reader = std::thread::spawn(move || {
+            let msg = peer.get_next_message(None).unwrap();
+            msg.params[0].as_str().map(str::len)
+        });
+        dbus_connection_flush(con);
+        assert_eq!(dbus_connection_has_messages_to_send(con), dbus_bool(false));
+        assert_eq!(reader.join().unwrap(), Some(payload.len()));
+        dbus_connection_unref(con);
+    }
+
+    #[test]
+    fn blocking_call_times_out() {
+        use std::time::{Duration, Instant};
+        let (con, _peer) = socketpair_connection();
+        let call = rustbus::message_builder::MessageBuilder::new()
+            .call("Never".to_owned())
+            .on("/io/rdbus".to_owned())
+            .with_interface("io.rdbus.Test".to_owned())
+            .build();
+        let call = Box::into_raw(Box::new(DBusMessage::new(call)));
+        let mut err = std::mem::MaybeUninit::<DBusError>::uninit();
+        dbus_error_init(err.as_mut_ptr());
+        let mut err = unsafe { err.assume_init() };
+
+        let start = Instant::now();
+        let reply = dbus_connection_send_with_reply_and_block(con, call, 100, &mut err);
+        assert!(reply.is_null());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(err.name(), DBUS_ERROR_NO_REPLY);
+        assert!(unsafe { &*con }.pending_calls.is_empty());
+
+        dbus_error_free(&mut err);
+        crate::message::dbus_message_unref(call);
+        dbus_connection_unref(con);
+    }
+
+    /// Send a call to a fresh connection, dispatch it and return whatever the connection answered
+    fn call_and_get_reply<'a>(
+        interface: &str,
+        member: &str,
+        path: &str,
+    ) -> (u32, rustbus::Message<'a, 'a>) {
+        let (con, mut peer) = socketpair_connection();
+
+        let mut call = rustbus::message_builder::MessageBuilder::new()
+            .call(member.to_owned())
+            .on(path.to_owned())
+            .with_interface(interface.to_owned())
+            .build();
+        let serial = peer.send_message(&mut call, None).unwrap();
+
+        while unsafe { &*con }.in_queue.is_empty() {
+            dbus_connection_read_write(con, -1);
+        }
+        dbus_connection_dispatch(con);
+        dbus_connection_flush(con);
+        dbus_connection_unref(con);
+
+        (serial, peer.get_next_message(None).unwrap())
+    }
+
+    #[test]
+    fn answers_peer_ping() {
+        let (serial, reply) = call_and_get_reply(DBUS_INTERFACE_PEER, "Ping", "/");
+        assert!(matches!(reply.typ, rustbus::MessageType::Reply));
+        assert_eq!(reply.response_serial, Some(serial));
+    }
+
+    extern "C" fn need_memory(
+        _con: *mut DBusConnection,
+        _msg: *mut DBusMessage,
+        count: *mut std::ffi::c_void,
+    ) -> DBusHandlerResult {
+        unsafe { *(count as *mut u32) += 1 };
+        DBusHandlerResult::DBUS_HANDLER_RESULT_NEED_MEMORY
+    }
+
+    #[test]
+    fn need_memory_is_not_handled() {
+        let (con, mut peer) = socketpair_connection();
+        let (mut filtered, mut handled) = (0u32, 0u32);
+        dbus_connection_add_filter(
+            con,
+            need_memory,
+            &mut filtered as *mut u32 as *mut std::ffi::c_void,
+            None,
+        );
+        // the reserved fields have to stay None, which is what a zeroed C struct has too
+        let mut vtable: crate::object_tree::DBusObjectPathVTable = unsafe { std::mem::zeroed() };
+        vtable.message_function = Some(need_memory);
+        let path = std::ffi::CString::new("/io/rdbus").unwrap();
+        assert_eq!(
+            crate::object_tree::dbus_connection_register_object_path(
+                con,
+                path.as_ptr(),
+                &vtable,
+                &mut handled as *mut u32 as *mut std::ffi::c_void,
+            ),
+            dbus_bool(true)
+        );
+
+        let mut call = rustbus::message_builder::MessageBuilder::new()
+            .call("Nope".to_owned())
+            .on("/io/rdbus".to_owned())
+            .with_interface("io.rdbus.Test".to_owned())
+            .build();
+        let serial = peer.send_message(&mut call, None).unwrap();
+        while unsafe { &*con }.in_queue.is_empty() {
+            dbus_connection_read_write(con, -1);
+        }
+        dbus_connection_dispatch(con);
+        dbus_connection_flush(con);
+        assert_eq!((filtered, handled), (1, 1));
+
+        let reply = peer.get_next_message(None).unwrap();
+        assert_eq!(reply.error_name.as_deref(), Some(DBUS_ERROR_UNKNOWN_METHOD));
+        assert_eq!(reply.response_serial, Some(serial));
+        dbus_connection_unref(con);
+    }
+
+    #[test]
+    fn unhandled_calls_get_unknown_object() {
+        // nothing is registered on the connection, so there is no object at all
+        let (serial, reply) = call_and_get_reply("io.rdbus.Test", "Nope", "/io/rdbus");
+        assert!(matches!(reply.typ, rustbus::MessageType::Error));
+        assert_eq!(reply.error_name.as_deref(), Some(DBUS_ERROR_UNKNOWN_OBJECT));
+        assert_eq!(reply.response_serial, Some(serial));
+    }
 }
@@ -0,0 +1,1086 @@
+//! A minimal message bus on a unix socket. It answers the driver methods clients need to get
+//! going, routes calls to their destination and signals to the clients whose match rules ask for
+//! them. It runs the same transport and marshalling code as the client side.
+
+use crate::connection::FLAG_NO_REPLY_EXPECTED;
+use crate::error::*;
+use crate::match_rule::MatchRule;
+use crate::trace::trace;
+use crate::transport::{Deadline, Transport};
+use rustbus::params::{Array, Base, Container, Param};
+use std::collections::{BTreeMap, VecDeque};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+
+pub const DBUS_SERVICE_DBUS: &str = "org.freedesktop.DBus";
+pub const DBUS_PATH_DBUS: &str = "/org/freedesktop/DBus";
+pub const DBUS_INTERFACE_DBUS: &str = "org.freedesktop.DBus";
+
+pub const DBUS_NAME_FLAG_ALLOW_REPLACEMENT: u32 = 0x1;
+pub const DBUS_NAME_FLAG_REPLACE_EXISTING: u32 = 0x2;
+pub const DBUS_NAME_FLAG_DO_NOT_QUEUE: u32 = 0x4;
+
+pub const DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER: u32 = 1;
+pub const DBUS_REQUEST_NAME_REPLY_IN_QUEUE: u32 = 2;
+pub const DBUS_REQUEST_NAME_REPLY_EXISTS: u32 = 3;
+pub const DBUS_REQUEST_NAME_REPLY_ALREADY_OWNER: u32 = 4;
+
+pub const DBUS_RELEASE_NAME_REPLY_RELEASED: u32 = 1;
+pub const DBUS_RELEASE_NAME_REPLY_NON_EXISTENT: u32 = 2;
+pub const DBUS_RELEASE_NAME_REPLY_NOT_OWNER: u32 = 3;
+
+pub const DBUS_START_REPLY_ALREADY_RUNNING: u32 = 2;
+
+const DRIVER_INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+"http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.freedesktop.DBus">
+    <method name="Hello"><arg direction="out" type="s"/></method>
+    <method name="RequestName"><arg direction="in" type="s"/><arg direction="in" type="u"/><arg direction="out" type="u"/></method>
+    <method name="ReleaseName"><arg direction="in" type="s"/><arg direction="out" type="u"/></method>
+    <method name="StartServiceByName"><arg direction="in" type="s"/><arg direction="in" type="u"/><arg direction="out" type="u"/></method>
+    <method name="NameHasOwner"><arg direction="in" type="s"/><arg direction="out" type="b"/></method>
+    <method name="ListNames"><arg direction="out" type="as"/></method>
+    <method name="ListActivatableNames"><arg direction="out" type="as"/></method>
+    <method name="AddMatch"><arg direction="in" type="s"/></method>
+    <method name="RemoveMatch"><arg direction="in" type="s"/></method>
+    <method name="GetNameOwner"><arg direction="in" type="s"/><arg direction="out" type="s"/></method>
+    <method name="ListQueuedOwners"><arg direction="in" type="s"/><arg direction="out" type="as"/></method>
+    <method name="GetConnectionUnixUser"><arg direction="in" type="s"/><arg direction="out" type="u"/></method>
+    <method name="GetConnectionUnixProcessID"><arg direction="in" type="s"/><arg direction="out" type="u"/></method>
+    <method name="ReloadConfig"/>
+    <method name="GetId"><arg direction="out" type="s"/></method>
+    <signal name="NameOwnerChanged"><arg type="s"/><arg type="s"/><arg type="s"/></signal>
+    <signal name="NameLost"><arg type="s"/></signal>
+    <signal name="NameAcquired"><arg type="s"/></signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Monitoring">
+    <method name="BecomeMonitor"><arg direction="in" type="as"/><arg direction="in" type="u"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect"><arg direction="out" type="s"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+    <method name="GetMachineId"><arg direction="out" type="s"/></method>
+  </interface>
+</node>
+"#;
+
+type Message = rustbus::Message<'static, 'static>;
+
+/// Reply parameters, or the name and text of the error to reply with
+type DriverResult = Result<Vec<Param<'static, 'static>>, (&'static str, String)>;
+
+fn string_arg(msg: &Message, idx: usize) -> Option<String> {
+    match msg.params.get(idx)? {
+        Param::Base(Base::String(s)) => Some(s.clone()),
+        Param::Base(Base::StringRef(s)) => Some((*s).to_owned()),
+        _ => None,
+    }
+}
+
+fn u32_arg(msg: &Message, idx: usize) -> Option<u32> {
+    match msg.params.get(idx)? {
+        Param::Base(Base::Uint32(v)) => Some(*v),
+        Param::Base(Base::Uint32Ref(v)) => Some(**v),
+        _ => None,
+    }
+}
+
+/// The match rules and flags BecomeMonitor takes
+fn monitor_rules_arg(msg: &Message) -> Result<Vec<MatchRule>, (&'static str, String)> {
+    let rules = match msg.params.first() {
+        Some(Param::Container(Container::Array(Array { values, .. }))) => values,
+        _ => return Err(invalid_args("Expected an array of match rules")),
+    };
+    if u32_arg(msg, 1) != Some(0) {
+        return Err(invalid_args("BecomeMonitor takes no flags"));
+    }
+    rules
+        .iter()
+        .map(|rule| match rule {
+            Param::Base(Base::String(rule)) => MatchRule::parse(rule),
+            Param::Base(Base::StringRef(rule)) => MatchRule::parse(rule),
+            _ => Err("Match rules have to be strings".to_owned()),
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|e| (DBUS_ERROR_MATCH_RULE_INVALID, e))
+}
+
+fn invalid_args(text: &str) -> (&'static str, String) {
+    (DBUS_ERROR_INVALID_ARGS, text.to_owned())
+}
+
+fn no_owner(name: &str) -> (&'static str, String) {
+    (
+        DBUS_ERROR_NAME_HAS_NO_OWNER,
+        format!("Could not get owner of name '{}': no such name", name),
+    )
+}
+
+fn unknown_method(interface: &str, member: &str) -> (&'static str, String) {
+    (
+        DBUS_ERROR_UNKNOWN_METHOD,
+        format!("{} does not understand message {}", interface, member),
+    )
+}
+
+fn string_array(strings: Vec<String>) -> Vec<Param<'static, 'static>> {
+    vec![Container::Array(Array {
+        element_sig: rustbus::signature::Type::Base(rustbus::signature::Base::String),
+        values: strings.into_iter().map(Param::from).collect(),
+    })
+    .into()]
+}
+
+/// The bus only ever closes fds it received, whoever gets a message gets copies
+fn close_fds(msg: Message) {
+    for fd in msg.raw_fds {
+        unsafe { libc::close(fd) };
+    }
+}
+
+fn copy_with_fds(msg: &Message) -> Message {
+    let mut copy = msg.clone();
+    copy.raw_fds = msg
+        .raw_fds
+        .iter()
+        .map(|fd| unsafe { libc::dup(*fd) })
+        .collect();
+    copy
+}
+
+/// What a bus configuration file sets that this bus understands
+pub struct Config {
+    /// "session" or "system"
+    pub bus_type: Option<String>,
+    /// Addresses to listen on, the bus uses the first one
+    pub listen: Vec<String>,
+}
+
+impl Config {
+    /// The settings of session.conf that matter here
+    pub fn session() -> Config {
+        Config {
+            bus_type: Some("session".to_owned()),
+            listen: vec!["unix:tmpdir=/tmp".to_owned()],
+        }
+    }
+
+    pub fn system() -> Config {
+        Config {
+            bus_type: Some("system".to_owned()),
+            listen: vec!["unix:path=/run/dbus/system_bus_socket".to_owned()],
+        }
+    }
+
+    /// Read a busconfig XML file. Policies, limits and everything else are ignored, the type
+    /// decides who may connect, see Bus::configure.
+    pub fn parse(xml: &str) -> Result<Config, String> {
+        let mut text = String::with_capacity(xml.len());
+        let mut rest = xml;
+        while let Some(start) = rest.find("<!--") {
+            text.push_str(&rest[..start]);
+            rest = match rest[start..].find("-->") {
+                Some(end) => &rest[start + end + 3..],
+                None => return Err("Unterminated comment".to_owned()),
+            };
+        }
+        text.push_str(rest);
+        if !text.contains("<busconfig>") {
+            return Err("Not a busconfig file".to_owned());
+        }
+        Ok(Config {
+            bus_type: elements(&text, "type").pop(),
+            listen: elements(&text, "listen"),
+        })
+    }
+}
+
+/// The text of all elements called tag
+fn elements(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                found.push(rest[..end].trim().to_owned());
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    found
+}
+
+/// Errors from accept that only concern the client that connected, or last until other clients
+/// go away. Anything else means the listening socket is broken.
+fn is_transient_accept_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(
+            libc::EMFILE
+                | libc::ENFILE
+                | libc::ENOBUFS
+                | libc::ENOMEM
+                | libc::ECONNABORTED
+                | libc::EPROTO
+                | libc::EPERM
+                | libc::EINTR
+                | libc::EAGAIN
+        )
+    )
+}
+
+/// Whether a client that authenticated as uid may connect, anonymous clients never can
+fn may_connect(any_user: bool, uid: Option<libc::uid_t>) -> bool {
+    match uid {
+        Some(uid) => any_user || uid == unsafe { libc::getuid() },
+        None => false,
+    }
+}
+
+/// A client that owns a name or waits for it, with the flags of its RequestName
+#[derive(Clone, Copy)]
+struct NameOwner {
+    id: u64,
+    flags: u32,
+}
+
+struct Client {
+    transport: Transport,
+    /// Done with the auth handshake, nothing is read as a message before
+    authenticated: bool,
+    /// Assigned by Hello, nothing but Hello is allowed before
+    unique_name: Option<String>,
+    matches: Vec<MatchRule>,
+    /// Called BecomeMonitor: gets copies of everything its rules match, an empty list matches
+    /// all, and is disconnected if it sends anything
+    monitor: bool,
+    outgoing: VecDeque<Message>,
+    /// The message in the outgoing buffer of the transport, kept alive for its fds
+    writing: Option<Message>,
+}
+
+pub struct Bus {
+    listener: UnixListener,
+    path: PathBuf,
+    guid: String,
+    clients: BTreeMap<u64, Client>,
+    /// Well-known names, the first entry of the queue owns the name
+    names: BTreeMap<String, VecDeque<NameOwner>>,
+    next_id: u64,
+    serial: u32,
+    /// Users other than the one running the bus may connect, like on a system bus
+    any_user: bool,
+}
+
+impl Bus {
+    /// Listen on a new socket at path
+    pub fn bind(path: &Path) -> std::io::Result<Bus> {
+        Ok(Self::new(UnixListener::bind(path)?, path.to_owned()))
+    }
+
+    /// Listen on a D-Bus address, see transport::listen
+    pub fn listen(address: &str) -> std::io::Result<Bus> {
+        let (listener, path) = crate::transport::listen(address)?;
+        Ok(Self::new(listener, path))
+    }
+
+    fn new(listener: UnixListener, path: PathBuf) -> Bus {
+        Bus {
+            listener,
+            path,
+            guid: crate::auth::new_guid(),
+            clients: BTreeMap::new(),
+            names: BTreeMap::new(),
+            next_id: 1,
+            serial: 1,
+            any_user: false,
+        }
+    }
+
+    /// Apply config: a system bus lets every user connect, any other bus only the user it runs
+    /// as, which is what dbus-daemon does without policies saying otherwise
+    pub fn configure(&mut self, config: &Config) -> std::io::Result<()> {
+        self.any_user = config.bus_type.as_deref() == Some("system");
+        if self.any_user {
+            // the umask the socket was bound with usually keeps other users out
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&self.path, std::fs::Permissions::from_mode(0o666))?;
+        }
+        Ok(())
+    }
+
+    /// The address clients connect to, e.g. for DBUS_SESSION_BUS_ADDRESS
+    pub fn address(&self) -> String {
+        format!("unix:path={}", self.path.display())
+    }
+
+    /// Serve clients until an error on the listening socket
+    pub fn run(&mut self) -> std::io::Result<()> {
+        loop {
+            self.run_once(Deadline::never())?;
+        }
+    }
+
+    /// Wait for clients until the deadline and handle whatever they sent
+    pub fn run_once(&mut self, deadline: Deadline) -> std::io::Result<()> {
+        let ids: Vec<u64> = self.clients.keys().copied().collect();
+        let mut pollfds = vec![libc::pollfd {
+            fd: self.listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        for client in self.clients.values() {
+            let mut events = libc::POLLIN;
+            if client.transport.has_pending_output()
+                || !client.outgoing.is_empty()
+                || client.transport.auth_wants_write()
+            {
+                events |= libc::POLLOUT;
+            }
+            pollfds.push(libc::pollfd {
+                fd: client.transport.as_raw_fd(),
+                events,
+                revents: 0,
+            });
+        }
+        let res = unsafe {
+            libc::poll(
+                pollfds.as_mut_ptr(),
+                pollfds.len() as libc::nfds_t,
+                deadline.poll_timeout(),
+            )
+        };
+        if res < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(e);
+        }
+
+        if pollfds[0].revents != 0 {
+            self.accept()?;
+        }
+        for (id, pollfd) in ids.iter().zip(&pollfds[1..]) {
+            let authenticated = match self.clients.get(id) {
+                Some(client) => client.authenticated,
+                None => continue,
+            };
+            if !authenticated {
+                if pollfd.revents != 0 {
+                    self.authenticate(*id);
+                }
+            } else if pollfd.revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0 {
+                self.read_from(*id);
+            }
+        }
+        // everything handled above may have queued messages, so try all clients
+        let ids: Vec<u64> = self.clients.keys().copied().collect();
+        for id in ids {
+            self.write_to(id);
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self) -> std::io::Result<()> {
+        let stream = match self.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if is_transient_accept_error(&e) => {
+                // one client failed to connect or fds are short for now, the others go on
+                trace!("daemon", "accepting a client failed: {}", e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        let transport = Transport::from_accepted(stream, self.guid.clone());
+        let id = self.next_id;
+        self.clients.insert(
+            id,
+            Client {
+                transport,
+                authenticated: false,
+                unique_name: None,
+                matches: Vec::new(),
+                monitor: false,
+                outgoing: VecDeque::new(),
+                writing: None,
+            },
+        );
+        self.next_id += 1;
+        // the client may have sent its part of the handshake along with connecting
+        self.authenticate(id);
+        Ok(())
+    }
+
+    /// Take the auth handshake of a new client as far as its socket allows. Clients that fail it
+    /// are dropped, the ones that finish may have sent messages right after BEGIN.
+    fn authenticate(&mut self, id: u64) {
+        let any_user = self.any_user;
+        let client = match self.clients.get_mut(&id) {
+            Some(client) => client,
+            None => return,
+        };
+        match client
+            .transport
+            .server_auth(false, &mut |uid| may_connect(any_user, uid))
+        {
+            Ok(_) => {
+                client.authenticated = true;
+                self.read_from(id);
+            }
+            Err(crate::transport::Error::TimedOut) => {}
+            Err(_e) => self.remove_client(id),
+        }
+    }
+
+    fn remove_client(&mut self, id: u64) {
+        // names go first, the signals about them need to know who owned them
+        self.leave_all_queues(id);
+        let client = match self.clients.remove(&id) {
+            Some(client) => client,
+            None => return,
+        };
+        for msg in client.outgoing.into_iter().chain(client.writing) {
+            close_fds(msg);
+        }
+        // monitors gave up their unique name when they became one
+        if let (Some(unique_name), false) = (client.unique_name, client.monitor) {
+            self.name_owner_changed(&unique_name, &unique_name, "");
+        }
+    }
+
+    /// Release or stop waiting for every name id owns or queues for
+    fn leave_all_queues(&mut self, id: u64) {
+        let names: Vec<String> = self
+            .names
+            .iter()
+            .filter(|(_, queue)| queue.iter().any(|o| o.id == id))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in names {
+            self.leave_queue(&name, id);
+        }
+    }
+
+    fn read_from(&mut self, id: u64) {
+        let client = match self.clients.get_mut(&id) {
+            Some(client) => client,
+            None => return,
+        };
+        let mut received = Vec::new();
+        let mut broken = false;
+        loop {
+            match client.transport.read_once() {
+                Ok(()) => {}
+                Err(crate::transport::Error::TimedOut) => break,
+                Err(_e) => {
+                    broken = true;
+                    break;
+                }
+            }
+            match client.transport.buffer_contains_whole_message() {
+                Ok(false) => {}
+                Ok(true) => match client
+                    .transport
+                    .get_next_message(Some(std::time::Duration::ZERO))
+                {
+                    Ok(msg) => received.push(msg),
+                    Err(_e) => {
+                        broken = true;
+                        break;
+                    }
+                },
+                Err(_e) => {
+                    broken = true;
+                    break;
+                }
+            }
+        }
+        for msg in received {
+            self.handle(id, msg);
+        }
+        if broken {
+            self.remove_client(id);
+        }
+    }
+
+    fn write_to(&mut self, id: u64) {
+        loop {
+            let client = match self.clients.get_mut(&id) {
+                Some(client) => client,
+                None => return,
+            };
+            if !client.transport.has_pending_output() {
+                if let Some(written) = client.writing.take() {
+                    close_fds(written);
+                }
+                let mut msg = match client.outgoing.pop_front() {
+                    Some(msg) => msg,
+                    None => return,
+                };
+                if client.transport.start_message(&mut msg).is_err() {
+                    close_fds(msg);
+                    continue;
+                }
+                client.writing = Some(msg);
+            }
+            match client.transport.write_pending() {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(_e) => {
+                    self.remove_client(id);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn next_serial(&mut self) -> u32 {
+        let serial = self.serial;
+        self.serial += 1;
+        serial
+    }
+
+    /// The client a unique or well-known name belongs to
+    fn resolve(&self, name: &str) -> Option<u64> {
+        if name.starts_with(':') {
+            return self
+                .clients
+                .iter()
+                .find(|(_, c)| !c.monitor && c.unique_name.as_deref() == Some(name))
+                .map(|(id, _)| *id);
+        }
+        self.names
+            .get(name)
+            .and_then(|queue| queue.front())
+            .map(|owner| owner.id)
+    }
+
+    fn unique_name_of(&self, name: &str) -> Option<String> {
+        self.resolve(name)
+            .and_then(|id| self.clients.get(&id))
+            .and_then(|c| c.unique_name.clone())
+    }
+
+    fn queue(&mut self, to: u64, msg: Message) {
+        match self.clients.get_mut(&to) {
+            Some(client) => client.outgoing.push_back(msg),
+            None => close_fds(msg),
+        }
+    }
+
+    fn handle(&mut self, id: u64, mut msg: Message) {
+        let for_driver = msg.destination.as_deref() == Some(DBUS_SERVICE_DBUS);
+        let is_hello = for_driver
+            && matches!(msg.typ, rustbus::MessageType::Call)
+            && msg.member.as_deref() == Some("Hello");
+        let client = match self.clients.get_mut(&id) {
+            Some(client) => client,
+            None => return close_fds(msg),
+        };
+        let sender = match (&client.unique_name, is_hello) {
+            (Some(name), _) => name.clone(),
+            (None, true) => {
+                let name = format!(":1.{}", id);
+                client.unique_name = Some(name.clone());
+                msg.sender = Some(name.clone());
+                let mut reply = msg.make_response();
+                reply.push_param(name.clone());
+                self.send_from_bus(reply);
+                self.name_owner_changed(&name, "", &name);
+                self.name_signal(id, "NameAcquired", &name);
+                return;
+            }
+            (None, false) => {
+                // the spec requires Hello first
+                close_fds(msg);
+                return self.remove_client(id);
+            }
+        };
+        if client.monitor {
+            // monitors are read only
+            close_fds(msg);
+            return self.remove_client(id);
+        }
+        msg.sender = Some(sender);
+
+        if for_driver {
+            self.copy_to_monitors(&msg);
+            return self.driver(id, msg);
+        }
+        match msg.destination.clone() {
+            Some(dest) => match self.resolve(&dest) {
+                Some(to) => self.deliver(Some(to), msg),
+                None => {
+                    self.reply_error(
+                        &msg,
+                        DBUS_ERROR_SERVICE_UNKNOWN,
+                        &format!("The name {} was not provided by any .service files", dest),
+                    );
+                    close_fds(msg);
+                }
+            },
+            None => self.deliver(None, msg),
+        }
+    }
+
+    /// Queue msg for to, and copies for every other client with a matching rule. Messages
+    /// addressed to someone only go to others if their rule asks for eavesdropping.
+    fn deliver(&mut self, to: Option<u64>, msg: Message) {
+        self.copy_to_monitors(&msg);
+        let targets: Vec<u64> = self
+            .clients
+            .iter()
+            .filter(|(id, c)| Some(**id) != to && c.unique_name.is_some() && !c.monitor)
+            .filter(|(_, c)| {
+                c.matches.iter().any(|r| {
+                    (to.is_none() || r.eavesdrop)
+                        && r.matches_resolving(&msg, |name| self.unique_name_of(name))
+                })
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in targets {
+            let copy = copy_with_fds(&msg);
+            self.queue(id, copy);
+        }
+        match to {
+            Some(to) => self.queue(to, msg),
+            None => close_fds(msg),
+        }
+    }
+
+    /// Queue copies of msg for the monitors whose rules match it
+    fn copy_to_monitors(&mut self, msg: &Message) {
+        let targets: Vec<u64> = self
+            .clients
+            .iter()
+            .filter(|(_, c)| c.monitor)
+            .filter(|(_, c)| {
+                c.matches.is_empty()
+                    || c.matches
+                        .iter()
+                        .any(|r| r.matches_resolving(msg, |name| self.unique_name_of(name)))
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in targets {
+            let copy = copy_with_fds(msg);
+            self.queue(id, copy);
+        }
+    }
+
+    fn send_from_bus(&mut self, mut msg: Message) {
+        msg.sender = Some(DBUS_SERVICE_DBUS.to_owned());
+        msg.serial = Some(self.next_serial());
+        match msg.destination.clone() {
+            Some(dest) => {
+                if let Some(to) = self.resolve(&dest) {
+                    self.deliver(Some(to), msg);
+                }
+            }
+            None => self.deliver(None, msg),
+        }
+    }
+
+    /// A signal of the driver, broadcast or to destination
+    fn driver_signal(&mut self, destination: Option<String>, member: &str, args: &[&str]) {
+        let mut signal = rustbus::message_builder::MessageBuilder::new()
+            .signal(
+                DBUS_INTERFACE_DBUS.to_owned(),
+                member.to_owned(),
+                DBUS_PATH_DBUS.to_owned(),
+            )
+            .build();
+        signal.destination = destination;
+        for arg in args {
+            signal.push_param((*arg).to_owned());
+        }
+        self.send_from_bus(signal);
+    }
+
+    fn name_owner_changed(&mut self, name: &str, old_owner: &str, new_owner: &str) {
+        self.driver_signal(None, "NameOwnerChanged", &[name, old_owner, new_owner]);
+    }
+
+    /// NameAcquired or NameLost, they only go to the client concerned
+    fn name_signal(&mut self, id: u64, member: &str, name: &str) {
+        let destination = self.clients.get(&id).and_then(|c| c.unique_name.clone());
+        if destination.is_some() {
+            self.driver_signal(destination, member, &[name]);
+        }
+    }
+
+    fn reply_error(&mut self, call: &Message, name: &str, text: &str) {
+        if !matches!(call.typ, rustbus::MessageType::Call)
+            || call.flags & FLAG_NO_REPLY_EXPECTED != 0
+        {
+            return;
+        }
+        let reply = crate::message::make_error_response(call, name, text);
+        self.send_from_bus(reply);
+    }
+
+    /// Method calls to org.freedesktop.DBus
+    fn driver(&mut self, id: u64, msg: Message) {
+        if !matches!(msg.typ, rustbus::MessageType::Call) {
+            return close_fds(msg);
+        }
+        let interface = msg.interface.clone().unwrap_or_default();
+        let member = msg.member.clone().unwrap_or_default();
+        let mut monitor_rules = None;
+        let result = match (interface.as_str(), member.as_str()) {
+            (crate::connection::DBUS_INTERFACE_MONITORING, "BecomeMonitor") => {
+                monitor_rules_arg(&msg).map(|rules| {
+                    monitor_rules = Some(rules);
+                    Vec::new()
+                })
+            }
+            (crate::connection::DBUS_INTERFACE_PEER, "Ping") => Ok(Vec::new()),
+            (crate::connection::DBUS_INTERFACE_PEER, "GetMachineId") => {
+                crate::machine_id::local_machine_id()
+                    .map(|id| vec![id.into()])
+                    .map_err(|e| (DBUS_ERROR_FAILED, e))
+            }
+            (crate::object_tree::DBUS_INTERFACE_INTROSPECTABLE, "Introspect") => {
+                Ok(vec![DRIVER_INTROSPECTION.to_owned().into()])
+            }
+            ("", _) | (DBUS_INTERFACE_DBUS, _) => self.driver_method(id, &member, &msg),
+            _ => Err(unknown_method(&interface, &member)),
+        };
+        match result {
+            Ok(params) => {
+                if msg.flags & FLAG_NO_REPLY_EXPECTED == 0 {
+                    let mut reply = msg.make_response();
+                    reply.params = params;
+                    self.send_from_bus(reply);
+                }
+            }
+            Err((name, text)) => self.reply_error(&msg, name, &text),
+        }
+        close_fds(msg);
+        // only after the reply, the client still waits for it
+        if let Some(rules) = monitor_rules {
+            self.become_monitor(id, rules);
+        }
+    }
+
+    /// The client loses its names and stops taking part in the bus other than watching it
+    fn become_monitor(&mut self, id: u64, rules: Vec<MatchRule>) {
+        self.leave_all_queues(id);
+        let unique_name = match self.clients.get_mut(&id) {
+            Some(client) => {
+                client.monitor = true;
+                client.matches = rules;
+                client.unique_name.clone().unwrap_or_default()
+            }
+            None => return,
+        };
+        self.name_owner_changed(&unique_name, &unique_name, "");
+    }
+
+    fn driver_method(&mut self, id: u64, member: &str, msg: &Message) -> DriverResult {
+        match member {
+            "Hello" => Err((
+                DBUS_ERROR_FAILED,
+                "Already handled an Hello message".to_owned(),
+            )),
+            "RequestName" => self.request_name(id, msg),
+            "ReleaseName" => self.release_name(id, msg),
+            "AddMatch" => self.add_match(id, msg),
+            "RemoveMatch" => self.remove_match(id, msg),
+            "NameHasOwner" => self.name_has_owner(msg),
+            "ListNames" => Ok(string_array(self.list_names())),
+            "ListActivatableNames" => Ok(string_array(vec![DBUS_SERVICE_DBUS.to_owned()])),
+            "ListQueuedOwners" => self.list_queued_owners(msg),
+            "GetNameOwner" => self.get_name_owner(msg),
+            "GetConnectionUnixUser" => self
+                .connection_credentials(msg)
+                .map(|cred| vec![cred.uid.into()]),
+            "GetConnectionUnixProcessID" => self
+                .connection_credentials(msg)
+                .map(|cred| vec![(cred.pid as u32).into()]),
+            "StartServiceByName" => {
+                let name = string_arg(msg, 0).ok_or_else(|| invalid_args("Expected a name"))?;
+                if self.resolve(&name).is_some() || name == DBUS_SERVICE_DBUS {
+                    Ok(vec![DBUS_START_REPLY_ALREADY_RUNNING.into()])
+                } else {
+                    // there is no activation
+                    Err((
+                        DBUS_ERROR_SERVICE_UNKNOWN,
+                        format!("The name {} was not provided by any .service files", name),
+                    ))
+                }
+            }
+            "ReloadConfig" => Ok(Vec::new()),
+            "GetId" => Ok(vec![self.guid.clone().into()]),
+            _ => Err(unknown_method(DBUS_INTERFACE_DBUS, member)),
+        }
+    }
+
+    /// Names clients may own: valid, not unique and not the bus itself
+    fn check_ownable_name(name: &str) -> Result<(), (&'static str, String)> {
+        crate::validate::check_bus_name(name).map_err(|e| invalid_args(&e))?;
+        if name.starts_with(':') {
+            return Err(invalid_args(&format!(
+                "Cannot acquire a service starting with ':' such as \"{}\"",
+                name
+            )));
+        }
+        if name == DBUS_SERVICE_DBUS {
+            return Err(invalid_args(&format!(
+                "Connection is not allowed to own the service \"{}\" because it is reserved for D-Bus' use only",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    fn unique_name_of_id(&self, id: u64) -> String {
+        self.clients
+            .get(&id)
+            .and_then(|c| c.unique_name.clone())
+            .unwrap_or_default()
+    }
+
+    /// Tell everyone who cares that name moved from old to new
+    fn change_owner(&mut self, name: &str, old: Option<u64>, new: Option<u64>) {
+        let old_name = old.map(|id| self.unique_name_of_id(id)).unwrap_or_default();
+        let new_name = new.map(|id| self.unique_name_of_id(id)).unwrap_or_default();
+        if let Some(old) = old {
+            self.name_signal(old, "NameLost", name);
+        }
+        self.name_owner_changed(name, &old_name, &new_name);
+        if let Some(new) = new {
+            self.name_signal(new, "NameAcquired", name);
+        }
+    }
+
+    /// Take id out of the queue of name. If it owned the name the next in line gets it.
+    fn leave_queue(&mut self, name: &str, id: u64) {
+        let queue = match self.names.get_mut(name) {
+            Some(queue) => queue,
+            None => return,
+        };
+        let pos = match queue.iter().position(|o| o.id == id) {
+            Some(pos) => pos,
+            None => return,
+        };
+        queue.remove(pos);
+        let next = queue.front().map(|o| o.id);
+        if queue.is_empty() {
+            self.names.remove(name);
+        }
+        if pos == 0 {
+            self.change_owner(name, Some(id), next);
+        }
+    }
+
+    fn request_name(&mut self, id: u64, msg: &Message) -> DriverResult {
+        let name = string_arg(msg, 0).ok_or_else(|| invalid_args("Expected a name"))?;
+        let flags = u32_arg(msg, 1).ok_or_else(|| invalid_args("Expected flags"))?;
+        Self::check_ownable_name(&name)?;
+        let requester = NameOwner { id, flags };
+
+        let queue = self.names.entry(name.clone()).or_default();
+        let reply = match queue.front().copied() {
+            None => {
+                queue.push_back(requester);
+                self.change_owner(&name, None, Some(id));
+                DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+            }
+            Some(owner) if owner.id == id => {
+                queue[0].flags = flags;
+                DBUS_REQUEST_NAME_REPLY_ALREADY_OWNER
+            }
+            Some(owner)
+                if flags & DBUS_NAME_FLAG_REPLACE_EXISTING != 0
+                    && owner.flags & DBUS_NAME_FLAG_ALLOW_REPLACEMENT != 0 =>
+            {
+                queue.retain(|o| o.id != id);
+                // the old owner waits right behind the new one unless it does not want to queue
+                if owner.flags & DBUS_NAME_FLAG_DO_NOT_QUEUE != 0 {
+                    queue.pop_front();
+                }
+                queue.push_front(requester);
+                self.change_owner(&name, Some(owner.id), Some(id));
+                DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+            }
+            Some(_) if flags & DBUS_NAME_FLAG_DO_NOT_QUEUE != 0 => {
+                queue.retain(|o| o.id != id);
+                DBUS_REQUEST_NAME_REPLY_EXISTS
+            }
+            Some(_) => {
+                match queue.iter_mut().find(|o| o.id == id) {
+                    Some(waiting) => waiting.flags = flags,
+                    None => queue.push_back(requester),
+                }
+                DBUS_REQUEST_NAME_REPLY_IN_QUEUE
+            }
+        };
+        Ok(vec![reply.into()])
+    }
+
+    fn release_name(&mut self, id: u64, msg: &Message) -> DriverResult {
+        let name = string_arg(msg, 0).ok_or_else(|| invalid_args("Expected a name"))?;
+        Self::check_ownable_name(&name)?;
+        let queue = match self.names.get(&name) {
+            Some(queue) => queue,
+            None => return Ok(vec![DBUS_RELEASE_NAME_REPLY_NON_EXISTENT.into()]),
+        };
+        if !queue.iter().any(|o| o.id == id) {
+            return Ok(vec![DBUS_RELEASE_NAME_REPLY_NOT_OWNER.into()]);
+        }
+        self.leave_queue(&name, id);
+        Ok(vec![DBUS_RELEASE_NAME_REPLY_RELEASED.into()])
+    }
+
+    fn list_queued_owners(&self, msg: &Message) -> DriverResult {
+        let name = string_arg(msg, 0).ok_or_else(|| invalid_args("Expected a name"))?;
+        if name == DBUS_SERVICE_DBUS {
+            return Ok(string_array(vec![DBUS_SERVICE_DBUS.to_owned()]));
+        }
+        match self.names.get(&name) {
+            Some(queue) => Ok(string_array(
+                queue.iter().map(|o| self.unique_name_of_id(o.id)).collect(),
+            )),
+            None if name.starts_with(':') && self.resolve(&name).is_some() => {
+                Ok(string_array(vec![name]))
+            }
+            None => Err(no_owner(&name)),
+        }
+    }
+
+    /// Credentials of the client that owns the name in the first argument
+    fn connection_credentials(
+        &self,
+        msg: &Message,
+    ) -> Result<crate::transport::PeerCredentials, (&'static str, String)> {
+        let name = string_arg(msg, 0).ok_or_else(|| invalid_args("Expected a name"))?;
+        let client = self
+            .resolve(&name)
+            .and_then(|id| self.clients.get(&id))
+            .ok_or_else(|| no_owner(&name))?;
+        client
+            .transport
+            .peer_credentials()
+            .map_err(|e| (DBUS_ERROR_FAILED, e.to_string()))
+    }
+
+    fn add_match(&mut self, id: u64, msg: &Message) -> DriverResult {
+        let rule = string_arg(msg, 0).ok_or_else(|| invalid_args("Expected a match rule"))?;
+        let rule = MatchRule::parse(&rule).map_err(|e| (DBUS_ERROR_MATCH_RULE_INVALID, e))?;
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.matches.push(rule);
+        }
+        Ok(Vec::new())
+    }
+
+    fn remove_match(&mut self, id: u64, msg: &Message) -> DriverResult {
+        let rule = string_arg(msg, 0).ok_or_else(|| invalid_args("Expected a match rule"))?;
+        let client = match self.clients.get_mut(&id) {
+            Some(client) => client,
+            None => return Ok(Vec::new()),
+        };
+        match client.matches.iter().position(|r| r.rule == rule) {
+            Some(pos) => {
+                client.matches.remove(pos);
+                Ok(Vec::new())
+            }
+            None => Err((
+                DBUS_ERROR_MATCH_RULE_NOT_FOUND,
+                "The given match rule wasn't found and can't be removed".to_owned(),
+            )),
+        }
+    }
+
+    fn name_has_owner(&self, msg: &Message) -> DriverResult {
+        let name = string_arg(msg, 0).ok_or_else(|| invalid_args("Expected a name"))?;
+        let has_owner = name == DBUS_SERVICE_DBUS || self.resolve(&name).is_some();
+        Ok(vec![has_owner.into()])
+    }
+
+    fn list_names(&self) -> Vec<String> {
+        std::iter::once(DBUS_SERVICE_DBUS.to_owned())
+            .chain(
+                self.clients
+                    .values()
+                    .filter(|c| !c.monitor)
+                    .filter_map(|c| c.unique_name.clone()),
+            )
+            .chain(self.names.keys().cloned())
+            .collect()
+    }
+
+    fn get_name_owner(&self, msg: &Message) -> DriverResult {
+        let name = string_arg(msg, 0).ok_or_else(|| invalid_args("Expected a name"))?;
+        if name == DBUS_SERVICE_DBUS {
+            return Ok(vec![DBUS_SERVICE_DBUS.to_owned().into()]);
+        }
+        match self.unique_name_of(&name) {
+            Some(owner) => Ok(vec![owner.into()]),
+            None => Err(no_owner(&name)),
+        }
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(
+            "<!DOCTYPE busconfig>\n<busconfig>\n  <type>session</type>\n  \
+             <!-- <listen>unix:path=/commented</listen> -->\n  \
+             <listen>unix:tmpdir=/tmp</listen>\n  <policy context=\"default\"/>\n</busconfig>\n",
+        )
+        .unwrap();
+        assert_eq!(config.bus_type.as_deref(), Some("session"));
+        assert_eq!(config.listen, vec!["unix:tmpdir=/tmp"]);
+        assert!(Config::parse("<node/>").is_err());
+    }
+
+    #[test]
+    fn only_broken_listeners_stop_the_bus() {
+        let os_error = std::io::Error::from_raw_os_error;
+        assert!(is_transient_accept_error(&os_error(libc::EMFILE)));
+        assert!(is_transient_accept_error(&os_error(libc::ECONNABORTED)));
+        assert!(!is_transient_accept_error(&os_error(libc::EBADF)));
+        assert!(!is_transient_accept_error(&os_error(libc::EINVAL)));
+    }
+
+    #[test]
+    fn system_buses_let_every_user_connect() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("rdbus-test-config-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut bus = Bus::bind(&path).unwrap();
+        let (me, other) = (unsafe { libc::getuid() }, unsafe { libc::getuid() } + 1);
+
+        assert!(may_connect(bus.any_user, Some(me)));
+        assert!(!may_connect(bus.any_user, Some(other)));
+        bus.configure(&Config::system()).unwrap();
+        assert!(may_connect(bus.any_user, Some(other)));
+        assert!(!may_connect(bus.any_user, None));
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o666);
+        let session = Config::parse("<busconfig><type>session</type></busconfig>").unwrap();
+        bus.configure(&session).unwrap();
+        assert!(!may_connect(bus.any_user, Some(other)));
+    }
+}
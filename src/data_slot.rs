@@ -1,160 +1,436 @@
 use crate::dbus_bool;
 use crate::DBusFreeFunction;
 
-struct Slot {
-    id: i32,
-    ref_count: i64,
+struct SlotTable {
+    // reference count per slot id, 0 for slots on the free list
+    ref_counts: Vec<u32>,
+    free_list: Vec<i32>,
 }
 
-// TODO lock this with a mutex?
-static mut USED_SLOTS: Vec<Slot> = Vec::new();
+/// One namespace of slot ids. Messages, connections, pending calls and servers each have their
+/// own, so a slot allocated for one kind of object means nothing for the others.
+///
+/// Like in libdbus `*slotp` has to be -1 before the first allocation. Allocating again through the
+/// same variable only takes another reference, and the id is given up (and `*slotp` reset to -1)
+/// once every reference has been freed. Freed ids are reused before new ones are handed out.
+pub struct SlotAllocator {
+    table: std::sync::Mutex<SlotTable>,
+}
+
+pub static MESSAGE_SLOTS: SlotAllocator = SlotAllocator::new();
+pub static CONNECTION_SLOTS: SlotAllocator = SlotAllocator::new();
+pub static PENDING_CALL_SLOTS: SlotAllocator = SlotAllocator::new();
+pub static SERVER_SLOTS: SlotAllocator = SlotAllocator::new();
+
+impl SlotAllocator {
+    pub const fn new() -> Self {
+        Self {
+            table: std::sync::Mutex::new(SlotTable {
+                ref_counts: Vec::new(),
+                free_list: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn allocate(&self, slotp: *mut i32) -> u32 {
+        if slotp.is_null() {
+            return dbus_bool(false);
+        }
+        let slotp = unsafe { &mut *slotp };
+        let mut table = self.table.lock().unwrap();
+
+        if *slotp >= 0 {
+            return match table.ref_counts.get_mut(*slotp as usize) {
+                Some(ref_count) if *ref_count > 0 => {
+                    *ref_count += 1;
+                    dbus_bool(true)
+                }
+                _ => dbus_bool(false),
+            };
+        }
+        if *slotp != -1 {
+            return dbus_bool(false);
+        }
 
-fn find_new_slot_id(slots: &Vec<Slot>) -> Option<i32> {
-    for id in 0..i32::max_value() {
-        let mut found = false;
-        for s in slots {
-            if s.id == id {
-                found = true;
-                break;
+        let id = match table.free_list.pop() {
+            Some(id) => id,
+            None => {
+                if table.ref_counts.len() >= i32::MAX as usize {
+                    return dbus_bool(false);
+                }
+                table.ref_counts.push(0);
+                (table.ref_counts.len() - 1) as i32
             }
+        };
+        table.ref_counts[id as usize] = 1;
+        *slotp = id;
+        dbus_bool(true)
+    }
+
+    pub fn free(&self, slotp: *mut i32) {
+        if slotp.is_null() {
+            return;
         }
-        if !found {
-            return Some(id);
+        let slotp = unsafe { &mut *slotp };
+        if *slotp < 0 {
+            return;
+        }
+        let mut table = self.table.lock().unwrap();
+
+        if let Some(ref_count) = table.ref_counts.get_mut(*slotp as usize) {
+            if *ref_count == 0 {
+                return;
+            }
+            *ref_count -= 1;
+            if *ref_count == 0 {
+                table.free_list.push(*slotp);
+                *slotp = -1;
+            }
         }
     }
-    None
+
+    fn is_allocated(&self, slot: i32) -> bool {
+        if slot < 0 {
+            return false;
+        }
+        let table = self.table.lock().unwrap();
+        table
+            .ref_counts
+            .get(slot as usize)
+            .map(|ref_count| *ref_count > 0)
+            .unwrap_or(false)
+    }
 }
 
-fn insert_new_slot(id: i32, slots: &mut Vec<Slot>) {
-    slots.push(Slot { id, ref_count: 1 })
+impl Default for SlotAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
-fn ref_slot(id: i32, slots: &mut Vec<Slot>) {
-    for s in slots {
-        if s.id == id {
-            s.ref_count += 1;
+
+#[derive(Debug)]
+struct AppData {
+    data: *mut std::ffi::c_void,
+    free: Option<DBusFreeFunction>,
+}
+
+impl Drop for AppData {
+    fn drop(&mut self) {
+        if let Some(free_fn) = self.free {
+            free_fn(self.data);
         }
     }
 }
-fn unref_slot(id: i32, slots: &mut Vec<Slot>) {
-    for s in slots {
-        if s.id == id {
-            s.ref_count -= 1;
-            if s.ref_count <= 0 {
-                s.ref_count = -1;
-            }
+
+/// The data the application attached to one object, indexed by slot id. Free functions run when
+/// the data is replaced or the owning object is finalized.
+#[derive(Debug, Default)]
+pub struct DataSlots {
+    data: Vec<Option<AppData>>,
+}
+
+// the data belongs to exactly one object, copies (e.g. dbus_message_copy) start out empty
+impl Clone for DataSlots {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl DataSlots {
+    /// Fails if the slot was not allocated from the allocator of this kind of object
+    pub fn set(
+        &mut self,
+        allocator: &SlotAllocator,
+        slot: i32,
+        data: *mut std::ffi::c_void,
+        free: Option<DBusFreeFunction>,
+    ) -> bool {
+        if !allocator.is_allocated(slot) {
+            return false;
+        }
+        let slot = slot as usize;
+        if self.data.len() <= slot {
+            self.data.resize_with(slot + 1, || None);
+        }
+        // like libdbus the old free function only runs after the new data is in place
+        let old = self.data[slot].replace(AppData { data, free });
+        drop(old);
+        true
+    }
+
+    pub fn get(&self, slot: i32) -> *mut std::ffi::c_void {
+        if slot < 0 {
+            return std::ptr::null_mut();
+        }
+        match self.data.get(slot as usize) {
+            Some(Some(app_data)) => app_data.data,
+            _ => std::ptr::null_mut(),
         }
     }
+
+    /// Run all free functions, called when the owning object is finalized
+    pub fn clear(&mut self) {
+        // take the data out first, free functions may look at the slots of the object again
+        let data = std::mem::take(&mut self.data);
+        drop(data);
+    }
 }
 
 #[no_mangle]
 pub extern "C" fn dbus_message_allocate_data_slot(slotp: *mut i32) -> u32 {
-    if slotp.is_null() {
+    MESSAGE_SLOTS.allocate(slotp)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_message_free_data_slot(slotp: *mut i32) {
+    MESSAGE_SLOTS.free(slotp)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_message_set_data(
+    msg: *mut crate::DBusMessage,
+    slot: i32,
+    data: *mut std::ffi::c_void,
+    free_data_func: Option<DBusFreeFunction>,
+) -> u32 {
+    if msg.is_null() {
         return dbus_bool(false);
     }
-    let slotp = unsafe { &mut *slotp };
-    if *slotp == -1 {
-        if let Some(new_id) = find_new_slot_id(unsafe { &USED_SLOTS }) {
-            *slotp = new_id;
-            insert_new_slot(new_id, unsafe { &mut USED_SLOTS });
-        } else {
-            return dbus_bool(false);
-        }
-    } else {
-        ref_slot(*slotp, unsafe { &mut USED_SLOTS })
-    }
-    dbus_bool(true)
+    let msg = unsafe { &mut *msg };
+    dbus_bool(msg.data.set(&MESSAGE_SLOTS, slot, data, free_data_func))
 }
 
 #[no_mangle]
-pub extern "C" fn dbus_message_free_data_slot(slotp: *mut i32) {
-    if slotp.is_null() {
-        return;
+pub extern "C" fn dbus_message_get_data(
+    msg: *mut crate::DBusMessage,
+    slot: i32,
+) -> *mut std::ffi::c_void {
+    if msg.is_null() {
+        return std::ptr::null_mut();
     }
-    let slotp = unsafe { *slotp };
+    let msg = unsafe { &mut *msg };
+    msg.data.get(slot)
+}
 
-    unref_slot(slotp, unsafe { &mut USED_SLOTS })
+#[no_mangle]
+pub extern "C" fn dbus_connection_allocate_data_slot(slotp: *mut i32) -> u32 {
+    CONNECTION_SLOTS.allocate(slotp)
 }
 
-#[derive(Clone, Debug)]
-pub struct AppData {
-    pub slot: i32,
-    pub data: *mut std::ffi::c_void,
-    pub free: Option<DBusFreeFunction>,
-    freed: bool,
+#[no_mangle]
+pub extern "C" fn dbus_connection_free_data_slot(slotp: *mut i32) {
+    CONNECTION_SLOTS.free(slotp)
 }
 
-impl Drop for AppData {
-    fn drop(&mut self) {
-        if !self.freed {
-            self.freed = true;
-            if let Some(free_fn) = self.free {
-                free_fn(self.data);
-            }
-        }
+#[no_mangle]
+pub extern "C" fn dbus_connection_set_data(
+    con: *mut crate::connection::DBusConnection,
+    slot: i32,
+    data: *mut std::ffi::c_void,
+    free_data_func: Option<DBusFreeFunction>,
+) -> u32 {
+    if con.is_null() {
+        return dbus_bool(false);
     }
+    let con = unsafe { &mut *con };
+    dbus_bool(con.data.set(&CONNECTION_SLOTS, slot, data, free_data_func))
 }
 
-fn replace_data(old: &mut AppData, new: AppData) {
-    if let Some(free_fn) = old.free {
-        free_fn(old.data);
-        old.freed = true;
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_data(
+    con: *mut crate::connection::DBusConnection,
+    slot: i32,
+) -> *mut std::ffi::c_void {
+    if con.is_null() {
+        return std::ptr::null_mut();
     }
-    *old = new;
+    let con = unsafe { &mut *con };
+    con.data.get(slot)
 }
 
 #[no_mangle]
-pub extern "C" fn dbus_message_set_data(
-    msg: *mut crate::DBusMessage,
+pub extern "C" fn dbus_pending_call_allocate_data_slot(slotp: *mut i32) -> u32 {
+    PENDING_CALL_SLOTS.allocate(slotp)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_pending_call_free_data_slot(slotp: *mut i32) {
+    PENDING_CALL_SLOTS.free(slotp)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_pending_call_set_data(
+    pending: *mut crate::connection::DBusPendingCall,
     slot: i32,
     data: *mut std::ffi::c_void,
-    free_fn: *const DBusFreeFunction,
+    free_data_func: Option<DBusFreeFunction>,
 ) -> u32 {
-    if msg.is_null() {
+    if pending.is_null() {
         return dbus_bool(false);
     }
-    let msg = unsafe { &mut *msg };
+    let pending = unsafe { &mut *pending };
+    dbus_bool(
+        pending
+            .data
+            .set(&PENDING_CALL_SLOTS, slot, data, free_data_func),
+    )
+}
 
-    let free = if free_fn.is_null() {
-        None
-    } else {
-        let free = unsafe { *free_fn };
-        Some(free)
-    };
-
-    let app_data = AppData {
-        slot,
-        data,
-        free,
-        freed: false,
-    };
-
-    for a in &mut msg.app_data {
-        if a.slot == slot {
-            replace_data(a, app_data);
-            return dbus_bool(true);
-        }
+#[no_mangle]
+pub extern "C" fn dbus_pending_call_get_data(
+    pending: *mut crate::connection::DBusPendingCall,
+    slot: i32,
+) -> *mut std::ffi::c_void {
+    if pending.is_null() {
+        return std::ptr::null_mut();
     }
+    let pending = unsafe { &mut *pending };
+    pending.data.get(slot)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_server_allocate_data_slot(slotp: *mut i32) -> u32 {
+    SERVER_SLOTS.allocate(slotp)
+}
 
-    // only get here if not replaced
-    msg.app_data.push(app_data);
+#[no_mangle]
+pub extern "C" fn dbus_server_free_data_slot(slotp: *mut i32) {
+    SERVER_SLOTS.free(slotp)
+}
 
-    dbus_bool(true)
+#[no_mangle]
+pub extern "C" fn dbus_server_set_data(
+    server: *mut crate::server::DBusServer,
+    slot: i32,
+    data: *mut std::ffi::c_void,
+    free_data_func: Option<DBusFreeFunction>,
+) -> u32 {
+    if server.is_null() {
+        return dbus_bool(false);
+    }
+    let server = unsafe { &mut *server };
+    dbus_bool(server.data.set(&SERVER_SLOTS, slot, data, free_data_func))
 }
 
 #[no_mangle]
-pub extern "C" fn dbus_message_get_data(
-    msg: *mut crate::DBusMessage,
+pub extern "C" fn dbus_server_get_data(
+    server: *mut crate::server::DBusServer,
     slot: i32,
 ) -> *mut std::ffi::c_void {
-    if msg.is_null() {
+    if server.is_null() {
         return std::ptr::null_mut();
     }
-    let msg = unsafe { &mut *msg };
+    let server = unsafe { &mut *server };
+    server.data.get(slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn count_free(data: *mut std::ffi::c_void) {
+        unsafe { *(data as *mut u32) += 1 };
+    }
+
+    #[test]
+    fn allocate_ref_free() {
+        let allocator = SlotAllocator::new();
+        let mut slot = -1;
+        assert_eq!(allocator.allocate(&mut slot), 1);
+        assert_eq!(slot, 0);
 
-    for a in &mut msg.app_data {
-        if a.slot == slot {
-            return a.data;
+        // allocating through the same variable only takes another reference
+        assert_eq!(allocator.allocate(&mut slot), 1);
+        assert_eq!(slot, 0);
+        allocator.free(&mut slot);
+        assert_eq!(slot, 0);
+        assert!(allocator.is_allocated(0));
+        allocator.free(&mut slot);
+        assert_eq!(slot, -1);
+        assert!(!allocator.is_allocated(0));
+
+        // freeing an unallocated slot does nothing
+        allocator.free(&mut slot);
+        assert_eq!(slot, -1);
+    }
+
+    #[test]
+    fn freed_slots_are_reused() {
+        let allocator = SlotAllocator::new();
+        let mut slots = [-1; 4];
+        for slot in &mut slots {
+            assert_eq!(allocator.allocate(slot), 1);
         }
+        assert_eq!(slots, [0, 1, 2, 3]);
+
+        allocator.free(&mut slots[1]);
+        allocator.free(&mut slots[2]);
+        assert_eq!(slots, [0, -1, -1, 3]);
+
+        for _ in 0..100 {
+            let mut a = -1;
+            let mut b = -1;
+            allocator.allocate(&mut a);
+            allocator.allocate(&mut b);
+            let mut reused = [a, b];
+            reused.sort_unstable();
+            assert_eq!(reused, [1, 2]);
+            allocator.free(&mut a);
+            allocator.free(&mut b);
+        }
+
+        let mut new = -1;
+        allocator.allocate(&mut new);
+        allocator.allocate(&mut slots[1]);
+        allocator.allocate(&mut slots[2]);
+        let mut all = [slots[0], slots[1], slots[2], slots[3], new];
+        all.sort_unstable();
+        assert_eq!(all, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn reject_invalid_slots() {
+        let allocator = SlotAllocator::new();
+        let mut slot = 5;
+        assert_eq!(allocator.allocate(&mut slot), 0);
+        let mut slot = -2;
+        assert_eq!(allocator.allocate(&mut slot), 0);
+        assert_eq!(allocator.allocate(std::ptr::null_mut()), 0);
+
+        let mut data = DataSlots::default();
+        assert!(!data.set(&allocator, 0, std::ptr::null_mut(), None));
+        assert!(data.get(0).is_null());
+        assert!(data.get(-1).is_null());
+    }
+
+    #[test]
+    fn free_functions_run_once() {
+        let allocator = SlotAllocator::new();
+        let mut slot = -1;
+        allocator.allocate(&mut slot);
+
+        let mut first = 0u32;
+        let mut second = 0u32;
+        let first_ptr = &mut first as *mut u32 as *mut std::ffi::c_void;
+        let second_ptr = &mut second as *mut u32 as *mut std::ffi::c_void;
+
+        let mut data = DataSlots::default();
+        assert!(data.set(&allocator, slot, first_ptr, Some(count_free)));
+        assert_eq!(data.get(slot), first_ptr);
+
+        // replacing frees the old data exactly once
+        assert!(data.set(&allocator, slot, second_ptr, Some(count_free)));
+        assert_eq!(data.get(slot), second_ptr);
+        assert_eq!((first, second), (1, 0));
+
+        // copies do not own the data
+        drop(data.clone());
+        assert_eq!((first, second), (1, 0));
+
+        data.clear();
+        assert!(data.get(slot).is_null());
+        assert_eq!((first, second), (1, 1));
+        drop(data);
+        assert_eq!((first, second), (1, 1));
     }
-    std::ptr::null_mut()
 }
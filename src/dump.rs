@@ -0,0 +1,291 @@
+//! Messages as text, in the format dbus-monitor prints them minus the timestamp. The arguments
+//! are walked with the same iterator functions C callers use, on a read-only iterator.
+
+use crate::message::DBusMessage;
+use crate::message_iter::*;
+use crate::*;
+use rustbus::params::Base;
+use std::mem::MaybeUninit;
+
+fn indent(out: &mut String, depth: usize) {
+    for _ in 0..depth {
+        out.push_str("   ");
+    }
+}
+
+fn dump_base(base: &Base) -> String {
+    match *base {
+        Base::String(ref s) => format!("string \"{}\"", s),
+        Base::StringRef(s) => format!("string \"{}\"", s),
+        Base::ObjectPath(ref s) => format!("object path \"{}\"", s),
+        Base::ObjectPathRef(s) => format!("object path \"{}\"", s),
+        Base::Signature(ref s) => format!("signature \"{}\"", s),
+        Base::SignatureRef(s) => format!("signature \"{}\"", s),
+        Base::Byte(val) | Base::ByteRef(&val) => format!("byte {}", val),
+        Base::Boolean(val) | Base::BooleanRef(&val) => format!("boolean {}", val),
+        Base::Int16(val) | Base::Int16Ref(&val) => format!("int16 {}", val),
+        Base::Uint16(val) | Base::Uint16Ref(&val) => format!("uint16 {}", val),
+        Base::Int32(val) | Base::Int32Ref(&val) => format!("int32 {}", val),
+        Base::Uint32(val) | Base::Uint32Ref(&val) => format!("uint32 {}", val),
+        Base::Int64(val) | Base::Int64Ref(&val) => format!("int64 {}", val),
+        Base::Uint64(val) | Base::Uint64Ref(&val) => format!("uint64 {}", val),
+        Base::Double(bits) | Base::DoubleRef(&bits) => format!("double {}", f64::from_bits(bits)),
+        Base::UnixFd(idx) | Base::UnixFdRef(&idx) => format!("file descriptor {}", idx),
+    }
+}
+
+/// Every argument from the current position of iter on, one per line
+fn dump_args(iter: *mut DBusMessageIter, depth: usize, out: &mut String) {
+    loop {
+        let typ = dbus_message_iter_get_arg_type(iter);
+        if typ == DBUS_TYPE_INVALID {
+            break;
+        }
+        indent(out, depth);
+        dump_value(iter, typ, depth, out);
+        if dbus_message_iter_next(iter) == 0 {
+            break;
+        }
+    }
+}
+
+/// The current argument of iter, starting on a line that is already indented
+fn dump_value(iter: *mut DBusMessageIter, typ: libc::c_int, depth: usize, out: &mut String) {
+    let line = match typ {
+        DBUS_TYPE_VARIANT | DBUS_TYPE_ARRAY | DBUS_TYPE_DICTENTRY | DBUS_TYPE_STRUCT => {
+            let mut sub = MaybeUninit::<DBusMessageIter>::uninit();
+            dbus_message_iter_recurse(iter, sub.as_mut_ptr());
+            let sub = sub.as_mut_ptr();
+            let (open, close) = match typ {
+                DBUS_TYPE_VARIANT => {
+                    // the value goes on the same line, like in dbus-monitor
+                    out.push_str("variant ");
+                    let inner = dbus_message_iter_get_arg_type(sub);
+                    return dump_value(sub, inner, depth, out);
+                }
+                DBUS_TYPE_ARRAY => ("array [", "]"),
+                DBUS_TYPE_DICTENTRY => ("dict entry(", ")"),
+                _ => ("struct {", "}"),
+            };
+            out.push_str(open);
+            out.push('\n');
+            dump_args(sub, depth + 1, out);
+            indent(out, depth);
+            close.to_owned()
+        }
+        other => match iter_get_base(unsafe { &*iter }) {
+            Some(base) => dump_base(base),
+            None => format!("unknown type {}", other),
+        },
+    };
+    out.push_str(&line);
+    out.push('\n');
+}
+
+/// The header line and the argument tree of msg
+pub fn dump_message(msg: &DBusMessage) -> String {
+    let header = {
+        let m = &msg.msg;
+        let or = |field: &Option<String>, default: &str| {
+            field.clone().unwrap_or_else(|| default.to_owned())
+        };
+        let mut header = format!(
+            "{} sender={} -> destination={} serial={}",
+            match m.typ {
+                rustbus::MessageType::Call => "method call",
+                rustbus::MessageType::Reply => "method return",
+                rustbus::MessageType::Error => "error",
+                rustbus::MessageType::Signal => "signal",
+                rustbus::MessageType::Invalid => "invalid",
+            },
+            or(&m.sender, "(null sender)"),
+            or(&m.destination, "(null destination)"),
+            m.serial.unwrap_or(0),
+        );
+        match m.typ {
+            rustbus::MessageType::Call | rustbus::MessageType::Signal => {
+                header.push_str(&format!(
+                    " path={}; interface={}; member={}",
+                    or(&m.object, "(null path)"),
+                    or(&m.interface, "(null interface)"),
+                    or(&m.member, "(null member)"),
+                ));
+            }
+            rustbus::MessageType::Error => {
+                header.push_str(&format!(
+                    " error_name={} reply_serial={}",
+                    or(&m.error_name, "(null error name)"),
+                    m.response_serial.unwrap_or(0),
+                ));
+            }
+            _ => header.push_str(&format!(" reply_serial={}", m.response_serial.unwrap_or(0))),
+        }
+        header
+    };
+
+    let mut out = header;
+    out.push('\n');
+    let mut iter = MaybeUninit::<DBusMessageIter>::uninit();
+    if iter_init_readonly(msg, iter.as_mut_ptr()) {
+        dump_args(iter.as_mut_ptr(), 1, &mut out);
+    }
+    out
+}
+
+/// librdbus extension: msg as text like dbus-monitor prints it. Free the result with dbus_free.
+#[no_mangle]
+pub extern "C" fn rdbus_message_dump(msg: *mut DBusMessage) -> *mut libc::c_char {
+    if msg.is_null() {
+        return std::ptr::null_mut();
+    }
+    let text = dump_message(unsafe { &*msg });
+    malloc_cstring(text.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustbus::params::{Array, Base, Container, Dict, Param, Variant};
+    use rustbus::signature;
+
+    #[test]
+    fn dump_nested_arguments() {
+        let mut call = rustbus::message_builder::MessageBuilder::new()
+            .call("Frob".to_owned())
+            .on("/io/rdbus".to_owned())
+            .with_interface("io.rdbus.Test".to_owned())
+            .at("io.rdbus.Service".to_owned())
+            .build();
+        call.serial = Some(7);
+        call.push_param("text");
+        let mut map = std::collections::HashMap::new();
+        map.insert(
+            Base::String("key".to_owned()),
+            Param::Container(Container::Variant(Box::new(Variant {
+                sig: signature::Type::Base(signature::Base::Int32),
+                value: Param::Base(Base::Int32(-5)),
+            }))),
+        );
+        call.push_param(Param::Container(Container::Dict(Dict {
+            key_sig: signature::Base::String,
+            value_sig: signature::Type::Container(signature::Container::Variant),
+            map,
+        })));
+        call.push_param(Param::Container(Container::Struct(vec![
+            Param::Base(Base::Boolean(true)),
+            Param::Container(Container::Array(Array {
+                element_sig: signature::Type::Base(signature::Base::Uint32),
+                values: vec![Param::Base(Base::Uint32(1)), Param::Base(Base::Uint32(2))],
+            })),
+        ])));
+        call.push_param(Param::Base(Base::ObjectPath("/a".to_owned())));
+
+        let msg = DBusMessage::new(call);
+        assert_eq!(
+            dump_message(&msg),
+            "method call sender=(null sender) -> destination=io.rdbus.Service serial=7 \
+             path=/io/rdbus; interface=io.rdbus.Test; member=Frob
+   string \"text\"
+   array [
+      dict entry(
+         string \"key\"
+         variant int32 -5
+      )
+   ]
+   struct {
+      boolean true
+      array [
+         uint32 1
+         uint32 2
+      ]
+   }
+   object path \"/a\"
+"
+        );
+    }
+
+    #[test]
+    fn dump_headers() {
+        let mut call = rustbus::message_builder::MessageBuilder::new()
+            .call("Frob".to_owned())
+            .on("/io/rdbus".to_owned())
+            .build();
+        call.serial = Some(7);
+        call.sender = Some(":1.2".to_owned());
+
+        let mut reply = call.make_response();
+        reply.serial = Some(8);
+        reply.sender = Some(":1.3".to_owned());
+        reply.push_param(1u32);
+        assert_eq!(
+            dump_message(&DBusMessage::new(reply)),
+            "method return sender=:1.3 -> destination=:1.2 serial=8 reply_serial=7\n   uint32 1\n"
+        );
+
+        let mut error =
+            crate::message::make_error_response(&call, crate::error::DBUS_ERROR_FAILED, "broken");
+        error.serial = Some(9);
+        assert_eq!(
+            dump_message(&DBusMessage::new(error)),
+            "error sender=(null sender) -> destination=:1.2 serial=9 \
+             error_name=org.freedesktop.DBus.Error.Failed reply_serial=7\n   string \"broken\"\n"
+        );
+
+        let mut signal = rustbus::message_builder::MessageBuilder::new()
+            .signal(
+                "io.rdbus.Test".to_owned(),
+                "Changed".to_owned(),
+                "/io/rdbus".to_owned(),
+            )
+            .build();
+        signal.serial = Some(10);
+        assert_eq!(
+            dump_message(&DBusMessage::new(signal)),
+            "signal sender=(null sender) -> destination=(null destination) serial=10 \
+             path=/io/rdbus; interface=io.rdbus.Test; member=Changed\n"
+        );
+    }
+
+    #[test]
+    fn dump_fds_and_containers_in_variants() {
+        let mut signal = rustbus::message_builder::MessageBuilder::new()
+            .signal(
+                "io.rdbus.Test".to_owned(),
+                "Changed".to_owned(),
+                "/io/rdbus".to_owned(),
+            )
+            .build();
+        signal.serial = Some(3);
+        signal.push_param(Param::Base(Base::UnixFd(0)));
+        let strings = Array {
+            element_sig: signature::Type::Base(signature::Base::String),
+            values: vec![Param::Base(Base::String("a".to_owned()))],
+        };
+        signal.push_param(Param::Container(Container::Variant(Box::new(Variant {
+            sig: signature::Type::Container(signature::Container::Array(Box::new(
+                signature::Type::Base(signature::Base::String),
+            ))),
+            value: Param::Container(Container::Array(strings)),
+        }))));
+        signal.push_param(Param::Container(Container::Variant(Box::new(Variant {
+            sig: signature::Type::Container(signature::Container::Struct(vec![
+                signature::Type::Base(signature::Base::Byte),
+            ])),
+            value: Param::Container(Container::Struct(vec![Param::Base(Base::Byte(255))])),
+        }))));
+
+        assert_eq!(
+            dump_message(&DBusMessage::new(signal)),
+            "signal sender=(null sender) -> destination=(null destination) serial=3 \
+             path=/io/rdbus; interface=io.rdbus.Test; member=Changed
+   file descriptor 0
+   variant array [
+      string \"a\"
+   ]
+   variant struct {
+      byte 255
+   }
+"
+        );
+    }
+}
@@ -1,58 +1,191 @@
 use crate::dbus_bool;
 
+pub const DBUS_ERROR_DISCONNECTED: &str = "org.freedesktop.DBus.Error.Disconnected";
+pub const DBUS_ERROR_NO_REPLY: &str = "org.freedesktop.DBus.Error.NoReply";
+pub const DBUS_ERROR_FAILED: &str = "org.freedesktop.DBus.Error.Failed";
+pub const DBUS_ERROR_INVALID_ARGS: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+pub const DBUS_ERROR_INVALID_SIGNATURE: &str = "org.freedesktop.DBus.Error.InvalidSignature";
+pub const DBUS_ERROR_FILE_NOT_FOUND: &str = "org.freedesktop.DBus.Error.FileNotFound";
+pub const DBUS_ERROR_BAD_ADDRESS: &str = "org.freedesktop.DBus.Error.BadAddress";
+pub const DBUS_ERROR_OBJECT_PATH_IN_USE: &str = "org.freedesktop.DBus.Error.ObjectPathInUse";
+pub const DBUS_ERROR_UNKNOWN_OBJECT: &str = "org.freedesktop.DBus.Error.UnknownObject";
+pub const DBUS_ERROR_UNKNOWN_METHOD: &str = "org.freedesktop.DBus.Error.UnknownMethod";
+pub const DBUS_ERROR_SERVICE_UNKNOWN: &str = "org.freedesktop.DBus.Error.ServiceUnknown";
+pub const DBUS_ERROR_NAME_HAS_NO_OWNER: &str = "org.freedesktop.DBus.Error.NameHasNoOwner";
+pub const DBUS_ERROR_MATCH_RULE_INVALID: &str = "org.freedesktop.DBus.Error.MatchRuleInvalid";
+pub const DBUS_ERROR_MATCH_RULE_NOT_FOUND: &str = "org.freedesktop.DBus.Error.MatchRuleNotFound";
+
+/// Same layout as the libdbus struct so C code can read name and message directly
 #[repr(C)]
 pub struct DBusError {
-    pub error: Box<String>,
-    pub name: Box<String>,
-    pub is_set: bool,
+    pub name: *const libc::c_char,
+    pub message: *const libc::c_char,
+    // the dummy bitfields and padding of libdbus
+    dummy: u32,
+    padding: *mut std::ffi::c_void,
+}
+
+impl DBusError {
+    pub fn is_set(&self) -> bool {
+        !self.name.is_null()
+    }
+
+    pub fn name(&self) -> &str {
+        cstr_or_empty(self.name)
+    }
+
+    pub fn message(&self) -> &str {
+        cstr_or_empty(self.message)
+    }
+
+    /// Replace whatever was in here before
+    pub fn set(&mut self, name: &str, message: &str) {
+        self.clear();
+        self.name = to_raw_cstring(name);
+        self.message = to_raw_cstring(message);
+    }
+
+    fn clear(&mut self) {
+        for s in &mut [&mut self.name, &mut self.message] {
+            if !s.is_null() {
+                drop(unsafe { std::ffi::CString::from_raw(**s as *mut libc::c_char) });
+                **s = std::ptr::null();
+            }
+        }
+    }
+}
+
+fn cstr_or_empty<'a>(s: *const libc::c_char) -> &'a str {
+    if s.is_null() {
+        return "";
+    }
+    unsafe { std::ffi::CStr::from_ptr(s) }
+        .to_str()
+        .unwrap_or("")
+}
+
+fn to_raw_cstring(s: &str) -> *const libc::c_char {
+    let s = s.replace('\0', "");
+    std::ffi::CString::new(s).unwrap().into_raw()
+}
+
+/// A D-Bus error on the Rust side. The C functions hand it out as a DBusError.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub name: String,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(name: &str, message: impl Into<String>) -> Self {
+        Error {
+            name: name.to_owned(),
+            message: message.into(),
+        }
+    }
+
+    /// The error an error reply carries, None for every other message
+    pub fn from_message(msg: &rustbus::Message) -> Option<Self> {
+        if !matches!(msg.typ, rustbus::MessageType::Error) {
+            return None;
+        }
+        let message = match msg.params.first() {
+            Some(rustbus::params::Param::Base(rustbus::params::Base::String(text))) => text,
+            Some(rustbus::params::Param::Base(rustbus::params::Base::StringRef(text))) => *text,
+            _ => "",
+        };
+        Some(Error::new(msg.error_name.as_deref().unwrap_or(""), message))
+    }
+
+    /// Copy into err if the caller passed one
+    pub fn set(&self, err: *mut DBusError) {
+        set_error(err, &self.name, &self.message);
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.name, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Set the error if the caller passed one, NULL errors are allowed everywhere in the API
+pub fn set_error(err: *mut DBusError, name: &str, message: &str) {
+    crate::trace::trace!("error", "{}: {}", name, message);
+    if err.is_null() {
+        return;
+    }
+    let err = unsafe { &mut *err };
+    err.set(name, message);
 }
 
 #[no_mangle]
 pub extern "C" fn dbus_error_init(err: *mut DBusError) {
     assert!(!err.is_null());
-    let err = unsafe { &mut *err };
-    let mut new_err = DBusError {
-        error: Box::new(String::new()),
-        name: Box::new(String::new()),
-        is_set: false,
+    unsafe {
+        err.write(DBusError {
+            name: std::ptr::null(),
+            message: std::ptr::null(),
+            dummy: 0,
+            padding: std::ptr::null_mut(),
+        })
     };
-    std::mem::swap(err, &mut new_err);
-    std::mem::forget(new_err);
 }
 #[no_mangle]
 pub extern "C" fn dbus_error_free(err: *mut DBusError) {
-    dbus_error_init(err)
+    assert!(!err.is_null());
+    let err = unsafe { &mut *err };
+    err.clear();
 }
 
 #[no_mangle]
-pub extern "C" fn dbus_error_is_set(err: *mut DBusError) -> libc::c_int {
+pub extern "C" fn dbus_error_is_set(err: *mut DBusError) -> u32 {
     if err.is_null() {
         return 0;
     }
 
     let err: &mut DBusError = unsafe { &mut *err };
-    if err.is_set {
-        1
-    } else {
-        0
-    }
+    dbus_bool(err.is_set())
 }
 
 #[no_mangle]
 pub extern "C" fn dbus_error_has_name(err: *mut DBusError, name: *const libc::c_char) -> u32 {
-    if err.is_null() {
+    if err.is_null() || name.is_null() {
         return 0;
     }
     let err = unsafe { &mut *err };
+    if !err.is_set() {
+        return 0;
+    }
 
-    let c_str = unsafe {
-        assert!(!name.is_null());
-        std::ffi::CStr::from_ptr(name)
-    };
-    let name = c_str.to_str().unwrap();
+    let name = unsafe { std::ffi::CStr::from_ptr(name) };
+    dbus_bool(unsafe { std::ffi::CStr::from_ptr(err.name) }.eq(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_clears_and_allows_reuse() {
+        let mut err = std::mem::MaybeUninit::<DBusError>::uninit();
+        dbus_error_init(err.as_mut_ptr());
+        let mut err = unsafe { err.assume_init() };
+
+        // freeing an error that was never set is fine
+        dbus_error_free(&mut err);
+        assert_eq!(dbus_error_is_set(&mut err), 0);
+
+        set_error(&mut err, DBUS_ERROR_FAILED, "first");
+        dbus_error_free(&mut err);
+        assert_eq!(dbus_error_is_set(&mut err), 0);
+        assert!(err.name.is_null() && err.message.is_null());
 
-    if err.name.as_ref() as *const String == std::ptr::null() {
-        return dbus_bool(false);
+        set_error(&mut err, DBUS_ERROR_NO_REPLY, "second");
+        assert_eq!(err.name(), DBUS_ERROR_NO_REPLY);
+        assert_eq!(err.message(), "second");
+        dbus_error_free(&mut err);
     }
-    dbus_bool(err.name.as_ref().eq(name))
 }
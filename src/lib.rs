@@ -1,27 +1,54 @@
+//! librdbus reimplements the libdbus C ABI on top of rustbus.
+//!
+//! Every exported `dbus_*` function takes raw pointers from C callers and checks them itself,
+//! so they are not marked `unsafe` even though they dereference their arguments.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 #[cfg(test)]
 mod tests {
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn message_type_strings_round_trip() {
+        for typ in 0..=4 {
+            let s = super::dbus_message_type_to_string(typ);
+            assert_eq!(super::dbus_message_type_from_string(s), typ);
+        }
+    }
 }
 
-mod bus;
-mod connection;
+pub mod api;
+pub mod auth;
+pub mod bus;
+pub mod connection;
+pub mod daemon;
 mod data_slot;
-mod error;
-mod message;
-mod message_iter;
+pub mod dump;
+pub mod error;
+pub mod machine_id;
+pub mod match_rule;
+pub mod message;
+pub mod message_iter;
+pub mod object_tree;
+pub mod pcap;
 mod private;
-mod validate;
+pub mod send_syntax;
+pub mod server;
+pub mod signature;
+pub mod trace;
+pub mod transport;
+pub mod validate;
+pub mod watch;
 use message::*;
 use rustbus::params;
 use std::ffi::CStr;
 
-use libc;
-
 #[repr(C)]
 #[derive(Debug)]
+#[allow(non_camel_case_types)]
 pub enum DBusBusType {
     DBUS_BUS_SESSION,
     DBUS_BUS_SYSTEM,
@@ -30,6 +57,7 @@ pub enum DBusBusType {
 
 #[repr(C)]
 #[derive(Debug)]
+#[allow(non_camel_case_types)]
 pub enum DBusHandlerResult {
     DBUS_HANDLER_RESULT_HANDLED,
     DBUS_HANDLER_RESULT_NOT_YET_HANDLED,
@@ -38,17 +66,30 @@ pub enum DBusHandlerResult {
 
 pub type DBusFreeFunction = extern "C" fn(*mut std::ffi::c_void);
 
-pub type DBusHandleMessageFunction = fn(
+pub type DBusHandleMessageFunction = extern "C" fn(
     *mut connection::DBusConnection,
     *mut DBusMessage,
     *mut std::ffi::c_void,
 ) -> DBusHandlerResult;
 
-const METHOD_CALL_STR: &'static str = "method_call";
-const METHOD_RETURN_STR: &'static str = "method_return";
-const SIGNAL_STR: &'static str = "signal";
-const ERROR_STR: &'static str = "error";
-const INVALID_STR: &'static str = "invalid";
+pub type DBusAllowUnixUserFunction =
+    extern "C" fn(*mut connection::DBusConnection, libc::c_ulong, *mut std::ffi::c_void) -> u32;
+
+pub type DBusNewConnectionFunction =
+    extern "C" fn(*mut server::DBusServer, *mut connection::DBusConnection, *mut std::ffi::c_void);
+
+pub type DBusAddWatchFunction = extern "C" fn(*mut watch::DBusWatch, *mut std::ffi::c_void) -> u32;
+
+pub type DBusRemoveWatchFunction = extern "C" fn(*mut watch::DBusWatch, *mut std::ffi::c_void);
+
+pub type DBusWatchToggledFunction = extern "C" fn(*mut watch::DBusWatch, *mut std::ffi::c_void);
+
+// nul terminated so the pointers can be handed out to C directly
+const METHOD_CALL_STR: &str = "method_call\0";
+const METHOD_RETURN_STR: &str = "method_return\0";
+const SIGNAL_STR: &str = "signal\0";
+const ERROR_STR: &str = "error\0";
+const INVALID_STR: &str = "invalid\0";
 
 #[no_mangle]
 pub extern "C" fn dbus_message_type_from_string(typ: *const libc::c_char) -> libc::c_int {
@@ -57,32 +98,31 @@ pub extern "C" fn dbus_message_type_from_string(typ: *const libc::c_char) -> lib
     }
     let cstr = unsafe { std::ffi::CStr::from_ptr(typ) };
 
-    if cstr.to_bytes().eq(METHOD_CALL_STR.as_bytes()) {
+    if cstr.to_bytes_with_nul().eq(METHOD_CALL_STR.as_bytes()) {
         return DBUS_MESSAGE_TYPE_METHOD_CALL;
     }
-    if cstr.to_bytes().eq(METHOD_RETURN_STR.as_bytes()) {
+    if cstr.to_bytes_with_nul().eq(METHOD_RETURN_STR.as_bytes()) {
         return DBUS_MESSAGE_TYPE_METHOD_RETURN;
     }
-    if cstr.to_bytes().eq(SIGNAL_STR.as_bytes()) {
+    if cstr.to_bytes_with_nul().eq(SIGNAL_STR.as_bytes()) {
         return DBUS_MESSAGE_TYPE_SIGNAL;
     }
-    if cstr.to_bytes().eq(ERROR_STR.as_bytes()) {
+    if cstr.to_bytes_with_nul().eq(ERROR_STR.as_bytes()) {
         return DBUS_MESSAGE_TYPE_ERROR;
     }
-    return DBUS_MESSAGE_TYPE_INVALID;
+    DBUS_MESSAGE_TYPE_INVALID
 }
 
 #[no_mangle]
 pub extern "C" fn dbus_message_type_to_string(typ: libc::c_int) -> *const libc::c_char {
-    unsafe {
-        std::mem::transmute(match typ {
-            DBUS_MESSAGE_TYPE_METHOD_CALL => METHOD_CALL_STR.as_ptr(),
-            DBUS_MESSAGE_TYPE_METHOD_RETURN => METHOD_RETURN_STR.as_ptr(),
-            DBUS_MESSAGE_TYPE_SIGNAL => SIGNAL_STR.as_ptr(),
-            DBUS_MESSAGE_TYPE_ERROR => ERROR_STR.as_ptr(),
-            _ => INVALID_STR.as_ptr(),
-        })
-    }
+    let s = match typ {
+        DBUS_MESSAGE_TYPE_METHOD_CALL => METHOD_CALL_STR,
+        DBUS_MESSAGE_TYPE_METHOD_RETURN => METHOD_RETURN_STR,
+        DBUS_MESSAGE_TYPE_SIGNAL => SIGNAL_STR,
+        DBUS_MESSAGE_TYPE_ERROR => ERROR_STR,
+        _ => INVALID_STR,
+    };
+    s.as_ptr() as *const libc::c_char
 }
 
 pub type DbusBool = u32;
@@ -127,26 +167,77 @@ pub extern "C" fn dbus_free(data: *mut std::ffi::c_void) {
     unsafe { libc::free(data) }
 }
 
+/// A NULL terminated array of strings the C side can release with dbus_free_string_array
+pub fn malloc_string_array<S: AsRef<str>>(strings: &[S]) -> *mut *mut libc::c_char {
+    let size = (strings.len() + 1) * std::mem::size_of::<*mut libc::c_char>();
+    let array = unsafe { libc::malloc(size) } as *mut *mut libc::c_char;
+    if array.is_null() {
+        return array;
+    }
+    for (idx, s) in strings.iter().enumerate() {
+        unsafe { *array.add(idx) = malloc_cstring(s.as_ref().as_bytes()) };
+    }
+    unsafe { *array.add(strings.len()) = std::ptr::null_mut() };
+    array
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_free_string_array(array: *mut *mut libc::c_char) {
+    if array.is_null() {
+        return;
+    }
+    let mut idx = 0;
+    loop {
+        let s = unsafe { *array.add(idx) };
+        if s.is_null() {
+            break;
+        }
+        unsafe { libc::free(s as *mut std::ffi::c_void) };
+        idx += 1;
+    }
+    unsafe { libc::free(array as *mut std::ffi::c_void) };
+}
+
+/// Copy a string into memory the C side can release with dbus_free
+pub fn malloc_cstring(s: &[u8]) -> *mut libc::c_char {
+    let ptr = unsafe { libc::malloc(s.len() + 1) } as *mut u8;
+    if ptr.is_null() {
+        return std::ptr::null_mut();
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(s.as_ptr(), ptr, s.len());
+        *ptr.add(s.len()) = 0;
+    }
+    ptr as *mut libc::c_char
+}
+
 #[no_mangle]
 pub extern "C" fn dbus_bus_add_match(
     con: *mut connection::DBusConnection,
     rule: *const libc::c_char,
     err: *mut error::DBusError,
 ) {
-    if con.is_null() {
+    if con.is_null() || rule.is_null() {
+        trace::trace!(
+            "abi",
+            "dbus_bus_add_match called with con={:?} rule={:?}",
+            con,
+            rule
+        );
         return;
     }
     let con = unsafe { &mut *con };
 
-    let c_str = unsafe {
-        assert!(!rule.is_null());
-        std::ffi::CStr::from_ptr(rule)
+    let result = match unsafe { CStr::from_ptr(rule) }.to_str() {
+        Ok(rule) => con.add_match(rule, !err.is_null()),
+        Err(_) => Err(error::Error::new(
+            error::DBUS_ERROR_MATCH_RULE_INVALID,
+            "Match rule was not valid UTF-8",
+        )),
     };
-    let rule = c_str.to_str().unwrap();
-    let mut msg = rustbus::standard_messages::add_match(rule.to_owned());
-    con.con.send_message(&mut msg, None).unwrap();
-
-    // TODO set error
+    if let Err(e) = result {
+        e.set(err);
+    }
 }
 
 pub fn param_from_parts<'a>(
@@ -157,93 +248,93 @@ pub fn param_from_parts<'a>(
         DBUS_TYPE_STRING => {
             let c_str = unsafe {
                 assert!(!arg.is_null());
-                let arg: *const *const libc::c_char = std::mem::transmute(arg);
+                let arg = arg as *const *const libc::c_char;
                 let arg = arg.read();
                 assert!(!arg.is_null());
                 CStr::from_ptr(arg)
             };
-            let arg = c_str.to_str().unwrap().to_owned();
+            let arg = c_str.to_str().ok()?.to_owned();
             arg.into()
         }
         DBUS_TYPE_OBJECTPATH => {
             let c_str = unsafe {
                 assert!(!arg.is_null());
-                let arg: *const *const libc::c_char = std::mem::transmute(arg);
+                let arg = arg as *const *const libc::c_char;
                 let arg = arg.read();
                 assert!(!arg.is_null());
                 CStr::from_ptr(arg)
             };
-            let arg = c_str.to_str().unwrap().to_owned();
+            let arg = c_str.to_str().ok()?.to_owned();
             params::Base::ObjectPath(arg).into()
         }
         DBUS_TYPE_SIGNATURE => {
             let c_str = unsafe {
                 assert!(!arg.is_null());
-                let arg: *const *const libc::c_char = std::mem::transmute(arg);
+                let arg = arg as *const *const libc::c_char;
                 let arg = arg.read();
                 assert!(!arg.is_null());
                 CStr::from_ptr(arg)
             };
-            let arg = c_str.to_str().unwrap().to_owned();
-            params::Base::ObjectPath(arg).into()
+            let arg = c_str.to_str().ok()?.to_owned();
+            params::Base::Signature(arg).into()
         }
         DBUS_TYPE_INT16 => {
             assert!(!arg.is_null());
-            let ptr: *const i16 = unsafe { std::mem::transmute(arg) };
+            let ptr = arg as *const i16;
             let val = unsafe { ptr.read() };
             params::Base::Int16(val).into()
         }
         DBUS_TYPE_UINT16 => {
             assert!(!arg.is_null());
-            let ptr: *const u16 = unsafe { std::mem::transmute(arg) };
+            let ptr = arg as *const u16;
             let val = unsafe { ptr.read() };
             params::Base::Uint16(val).into()
         }
         DBUS_TYPE_INT32 => {
             assert!(!arg.is_null());
-            let ptr: *const i32 = unsafe { std::mem::transmute(arg) };
+            let ptr = arg as *const i32;
             let val = unsafe { ptr.read() };
             params::Base::Int32(val).into()
         }
         DBUS_TYPE_UINT32 => {
             assert!(!arg.is_null());
-            let ptr: *const u32 = unsafe { std::mem::transmute(arg) };
+            let ptr = arg as *const u32;
             let val = unsafe { ptr.read() };
             params::Base::Uint32(val).into()
         }
         DBUS_TYPE_INT64 => {
             assert!(!arg.is_null());
-            let ptr: *const i64 = unsafe { std::mem::transmute(arg) };
+            let ptr = arg as *const i64;
             let val = unsafe { ptr.read() };
             params::Base::Int64(val).into()
         }
         DBUS_TYPE_UINT64 => {
             assert!(!arg.is_null());
-            let ptr: *const u64 = unsafe { std::mem::transmute(arg) };
+            let ptr = arg as *const u64;
             let val = unsafe { ptr.read() };
             params::Base::Uint64(val).into()
         }
         DBUS_TYPE_BOOLEAN => {
             assert!(!arg.is_null());
-            let ptr: *const u32 = unsafe { std::mem::transmute(arg) };
+            let ptr = arg as *const u32;
             let val = unsafe { ptr.read() };
             params::Base::Boolean(val != 0).into()
         }
         DBUS_TYPE_BYTE => {
             assert!(!arg.is_null());
-            let ptr: *const u8 = unsafe { std::mem::transmute(arg) };
+            let ptr = arg as *const u8;
             let val = unsafe { ptr.read() };
             params::Base::Byte(val).into()
         }
         DBUS_TYPE_DOUBLE => {
             assert!(!arg.is_null());
-            let ptr: *const u64 = unsafe { std::mem::transmute(arg) };
+            let ptr = arg as *const u64;
             let val = unsafe { ptr.read() };
             params::Base::Double(val).into()
         }
         DBUS_TYPE_UNIXFD => {
             assert!(!arg.is_null());
-            let ptr: *const u32 = unsafe { std::mem::transmute(arg) };
+            let ptr = arg as *const u32;
             let val = unsafe { ptr.read() };
             params::Base::UnixFd(val).into()
         }
@@ -260,143 +351,143 @@ pub fn write_base_param<'a>(
     match param {
         params::Base::Boolean(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u32 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u32) };
             *mutref = if *val { 1 } else { 0 };
         }
         params::Base::Byte(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u8 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u8) };
             *mutref = *val;
         }
         params::Base::Int16(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut i16 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut i16) };
             *mutref = *val;
         }
         params::Base::Uint16(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u16 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u16) };
             *mutref = *val;
         }
         params::Base::Int32(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut i32 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut i32) };
             *mutref = *val;
         }
         params::Base::Uint32(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u32 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u32) };
             *mutref = *val;
         }
         params::Base::Int64(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut i64 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut i64) };
             *mutref = *val;
         }
         params::Base::Uint64(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u64 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u64) };
             *mutref = *val;
         }
         params::Base::Double(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u64 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u64) };
             *mutref = *val;
         }
         params::Base::UnixFd(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u32 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u32) };
             *mutref = *val;
         }
 
         params::Base::String(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut *const libc::c_char = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut *const libc::c_char) };
 
-            let cstr = crate::get_cstring(string_arena, &val);
-            *mutref = unsafe { std::mem::transmute(cstr.as_ptr()) };
+            let cstr = crate::get_cstring(string_arena, val);
+            *mutref = cstr.as_ptr();
         }
         params::Base::ObjectPath(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut *const libc::c_char = unsafe { std::mem::transmute(arg) };
-            let cstr = crate::get_cstring(string_arena, &val);
-            *mutref = unsafe { std::mem::transmute(cstr.as_ptr()) };
+            let mutref = unsafe { &mut *(arg as *mut *const libc::c_char) };
+            let cstr = crate::get_cstring(string_arena, val);
+            *mutref = cstr.as_ptr();
         }
         params::Base::Signature(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut *const libc::c_char = unsafe { std::mem::transmute(arg) };
-            let cstr = crate::get_cstring(string_arena, &val);
-            *mutref = unsafe { std::mem::transmute(cstr.as_ptr()) };
+            let mutref = unsafe { &mut *(arg as *mut *const libc::c_char) };
+            let cstr = crate::get_cstring(string_arena, val);
+            *mutref = cstr.as_ptr();
         }
         params::Base::BooleanRef(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u32 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u32) };
             *mutref = if **val { 1 } else { 0 };
         }
         params::Base::ByteRef(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u8 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u8) };
             *mutref = **val;
         }
         params::Base::Int16Ref(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut i16 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut i16) };
             *mutref = **val;
         }
         params::Base::Uint16Ref(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u16 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u16) };
             *mutref = **val;
         }
         params::Base::Int32Ref(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut i32 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut i32) };
             *mutref = **val;
         }
         params::Base::Uint32Ref(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u32 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u32) };
             *mutref = **val;
         }
         params::Base::Int64Ref(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut i64 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut i64) };
             *mutref = **val;
         }
         params::Base::Uint64Ref(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u64 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u64) };
             *mutref = **val;
         }
         params::Base::DoubleRef(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u64 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u64) };
             *mutref = **val;
         }
         params::Base::UnixFdRef(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut u32 = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut u32) };
             *mutref = **val;
         }
 
         params::Base::StringRef(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut *const libc::c_char = unsafe { std::mem::transmute(arg) };
+            let mutref = unsafe { &mut *(arg as *mut *const libc::c_char) };
 
-            let cstr = crate::get_cstring(string_arena, &val);
-            *mutref = unsafe { std::mem::transmute(cstr.as_ptr()) };
+            let cstr = crate::get_cstring(string_arena, val);
+            *mutref = cstr.as_ptr();
         }
         params::Base::ObjectPathRef(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut *const libc::c_char = unsafe { std::mem::transmute(arg) };
-            let cstr = crate::get_cstring(string_arena, &val);
-            *mutref = unsafe { std::mem::transmute(cstr.as_ptr()) };
+            let mutref = unsafe { &mut *(arg as *mut *const libc::c_char) };
+            let cstr = crate::get_cstring(string_arena, val);
+            *mutref = cstr.as_ptr();
         }
         params::Base::SignatureRef(val) => {
             assert!(!arg.is_null());
-            let mutref: &mut *const libc::c_char = unsafe { std::mem::transmute(arg) };
-            let cstr = crate::get_cstring(string_arena, &val);
-            *mutref = unsafe { std::mem::transmute(cstr.as_ptr()) };
+            let mutref = unsafe { &mut *(arg as *mut *const libc::c_char) };
+            let cstr = crate::get_cstring(string_arena, val);
+            *mutref = cstr.as_ptr();
         }
     }
 }
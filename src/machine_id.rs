@@ -0,0 +1,43 @@
+//! The machine id that org.freedesktop.DBus.Peer.GetMachineId reports
+
+use crate::error::*;
+
+/// systemd writes the first one, older systems only have the one dbus-uuidgen wrote
+const MACHINE_ID_PATHS: &[&str] = &["/etc/machine-id", "/var/lib/dbus/machine-id"];
+
+fn is_valid_machine_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The first valid id from the usual files
+pub fn local_machine_id() -> Result<String, String> {
+    for path in MACHINE_ID_PATHS {
+        if let Ok(content) = std::fs::read_to_string(path) {
+            let id = content.trim();
+            if is_valid_machine_id(id) {
+                return Ok(id.to_owned());
+            }
+        }
+    }
+    Err(format!(
+        "No valid machine id found in {}",
+        MACHINE_ID_PATHS.join(" or ")
+    ))
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_try_get_local_machine_id(err: *mut DBusError) -> *mut libc::c_char {
+    match local_machine_id() {
+        Ok(id) => crate::malloc_cstring(id.as_bytes()),
+        Err(msg) => {
+            set_error(err, DBUS_ERROR_FILE_NOT_FOUND, &msg);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// libdbus aborts if there is no machine id, returning NULL is friendlier
+#[no_mangle]
+pub extern "C" fn dbus_get_local_machine_id() -> *mut libc::c_char {
+    dbus_try_get_local_machine_id(std::ptr::null_mut())
+}
@@ -0,0 +1,419 @@
+//! Match rules as AddMatch takes them, e.g. "type='signal',interface='org.example.Foo'". The
+//! daemon routes broadcasts with them and clients can check messages against them locally.
+
+use crate::error::*;
+use crate::validate::{check_bus_name, check_interface, check_member, check_path};
+use crate::*;
+use rustbus::params::{Base, Param};
+
+/// argN keys go up to arg63
+const MAX_ARG: usize = 63;
+
+#[derive(Debug, Clone, PartialEq)]
+enum ArgMatch {
+    /// argN: the argument is a string equal to the value
+    String(String),
+    /// argNpath: the argument is a string or object path and one is a path prefix of the other
+    Path(String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MatchRule {
+    /// The rule as it was given, RemoveMatch compares these
+    pub rule: String,
+    msg_type: Option<String>,
+    sender: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    path: Option<String>,
+    path_namespace: Option<String>,
+    destination: Option<String>,
+    args: Vec<(usize, ArgMatch)>,
+    arg0namespace: Option<String>,
+    /// Also match messages that are addressed to other connections
+    pub eavesdrop: bool,
+}
+
+/// Split a rule into key/value pairs. Values may be quoted with apostrophes, outside of quotes
+/// \' stands for an apostrophe.
+fn split(rule: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    let mut chars = rule.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+        let mut key = String::new();
+        loop {
+            match chars.next() {
+                Some('=') => break,
+                Some(c) => key.push(c),
+                None => return Err(format!("Match rule key '{}' has no value", key.trim())),
+            }
+        }
+        let key = key.trim().to_owned();
+        if key.is_empty() {
+            return Err("Match rule has an empty key".to_owned());
+        }
+
+        let mut value = String::new();
+        let mut quoted = false;
+        loop {
+            match chars.next() {
+                Some('\'') => quoted = !quoted,
+                Some('\\') if !quoted && chars.peek() == Some(&'\'') => {
+                    chars.next();
+                    value.push('\'');
+                }
+                Some(',') if !quoted => break,
+                Some(c) => value.push(c),
+                None if quoted => {
+                    return Err(format!("Unterminated quote in the value of '{}'", key))
+                }
+                None => break,
+            }
+        }
+        pairs.push((key, value));
+    }
+    Ok(pairs)
+}
+
+fn type_name(typ: rustbus::MessageType) -> &'static str {
+    match typ {
+        rustbus::MessageType::Call => "method_call",
+        rustbus::MessageType::Reply => "method_return",
+        rustbus::MessageType::Error => "error",
+        rustbus::MessageType::Signal => "signal",
+        rustbus::MessageType::Invalid => "invalid",
+    }
+}
+
+/// Namespaces for arg0namespace may consist of a single element, unlike bus names
+fn check_namespace(namespace: &str) -> Result<(), String> {
+    if namespace.contains('.') {
+        check_bus_name(namespace)
+    } else {
+        check_bus_name(&format!("{}.x", namespace))
+    }
+}
+
+fn arg_key(key: &str) -> Option<(usize, bool)> {
+    let rest = key.strip_prefix("arg")?;
+    let (digits, path) = match rest.strip_suffix("path") {
+        Some(digits) => (digits, true),
+        None => (rest, false),
+    };
+    if digits.is_empty() || digits.len() > 2 || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let idx: usize = digits.parse().ok()?;
+    if idx > MAX_ARG {
+        return None;
+    }
+    Some((idx, path))
+}
+
+fn set_once(field: &mut Option<String>, key: &str, value: String) -> Result<(), String> {
+    if field.is_some() {
+        return Err(format!("Key '{}' appears more than once", key));
+    }
+    *field = Some(value);
+    Ok(())
+}
+
+/// Whether a and b are equal, or one ends in / and is a prefix of the other
+fn path_prefix_match(a: &str, b: &str) -> bool {
+    a == b || (a.ends_with('/') && b.starts_with(a)) || (b.ends_with('/') && a.starts_with(b))
+}
+
+fn string_param<'a>(param: &'a Param) -> Option<&'a str> {
+    match param {
+        Param::Base(Base::String(s)) => Some(s),
+        Param::Base(Base::StringRef(s)) => Some(s),
+        _ => None,
+    }
+}
+
+fn path_param<'a>(param: &'a Param) -> Option<&'a str> {
+    match param {
+        Param::Base(Base::ObjectPath(s)) => Some(s),
+        Param::Base(Base::ObjectPathRef(s)) => Some(s),
+        _ => string_param(param),
+    }
+}
+
+impl MatchRule {
+    pub fn parse(rule: &str) -> Result<MatchRule, String> {
+        let mut parsed = MatchRule {
+            rule: rule.to_owned(),
+            ..MatchRule::default()
+        };
+        let mut seen_eavesdrop = false;
+        for (key, value) in split(rule)? {
+            match key.as_str() {
+                "type" => {
+                    if !["method_call", "method_return", "error", "signal"].contains(&&*value) {
+                        return Err(format!("Invalid message type '{}'", value));
+                    }
+                    set_once(&mut parsed.msg_type, &key, value)?;
+                }
+                "sender" => {
+                    check_bus_name(&value)?;
+                    set_once(&mut parsed.sender, &key, value)?;
+                }
+                "interface" => {
+                    check_interface(&value)?;
+                    set_once(&mut parsed.interface, &key, value)?;
+                }
+                "member" => {
+                    check_member(&value)?;
+                    set_once(&mut parsed.member, &key, value)?;
+                }
+                "path" => {
+                    check_path(&value)?;
+                    set_once(&mut parsed.path, &key, value)?;
+                }
+                "path_namespace" => {
+                    check_path(&value)?;
+                    set_once(&mut parsed.path_namespace, &key, value)?;
+                }
+                "destination" => {
+                    check_bus_name(&value)?;
+                    set_once(&mut parsed.destination, &key, value)?;
+                }
+                "arg0namespace" => {
+                    check_namespace(&value)?;
+                    set_once(&mut parsed.arg0namespace, &key, value)?;
+                }
+                "eavesdrop" => {
+                    if seen_eavesdrop {
+                        return Err("Key 'eavesdrop' appears more than once".to_owned());
+                    }
+                    seen_eavesdrop = true;
+                    parsed.eavesdrop = match value.as_str() {
+                        "true" => true,
+                        "false" => false,
+                        _ => return Err(format!("Invalid value '{}' for eavesdrop", value)),
+                    };
+                }
+                _ => {
+                    let (idx, path) =
+                        arg_key(&key).ok_or_else(|| format!("Unknown match rule key '{}'", key))?;
+                    if parsed.args.iter().any(|(i, _)| *i == idx) {
+                        return Err(format!("Argument {} is matched more than once", idx));
+                    }
+                    let arg = if path {
+                        ArgMatch::Path(value)
+                    } else {
+                        ArgMatch::String(value)
+                    };
+                    parsed.args.push((idx, arg));
+                }
+            }
+        }
+        if parsed.path.is_some() && parsed.path_namespace.is_some() {
+            return Err("Match rules can not have both path and path_namespace".to_owned());
+        }
+        if parsed.arg0namespace.is_some() && parsed.args.iter().any(|(i, _)| *i == 0) {
+            return Err("Match rules can not have both arg0 and arg0namespace".to_owned());
+        }
+        Ok(parsed)
+    }
+
+    /// Whether msg matches. The sender key compares the sender field as is, see
+    /// matches_resolving for rules on well-known names.
+    pub fn matches(&self, msg: &rustbus::Message) -> bool {
+        self.matches_resolving(msg, |_| None)
+    }
+
+    /// Like matches, owner maps a well-known name in the sender key to its current unique name
+    pub fn matches_resolving<F>(&self, msg: &rustbus::Message, owner: F) -> bool
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let header_matches = |want: &Option<String>, have: &Option<String>| match want {
+            Some(want) => have.as_deref() == Some(want.as_str()),
+            None => true,
+        };
+        if let Some(typ) = &self.msg_type {
+            if type_name(msg.typ) != typ {
+                return false;
+            }
+        }
+        if let Some(sender) = &self.sender {
+            let sender_matches = match msg.sender.as_deref() {
+                Some(have) => have == sender || owner(sender).as_deref() == Some(have),
+                None => false,
+            };
+            if !sender_matches {
+                return false;
+            }
+        }
+        if !header_matches(&self.interface, &msg.interface)
+            || !header_matches(&self.member, &msg.member)
+            || !header_matches(&self.path, &msg.object)
+            || !header_matches(&self.destination, &msg.destination)
+        {
+            return false;
+        }
+        if let Some(namespace) = &self.path_namespace {
+            let path_matches = match msg.object.as_deref() {
+                Some(path) => {
+                    namespace == "/"
+                        || path == namespace
+                        || path
+                            .strip_prefix(namespace.as_str())
+                            .is_some_and(|rest| rest.starts_with('/'))
+                }
+                None => false,
+            };
+            if !path_matches {
+                return false;
+            }
+        }
+        if let Some(namespace) = &self.arg0namespace {
+            let arg_matches = match msg.params.first().and_then(string_param) {
+                Some(arg) => {
+                    arg == namespace
+                        || arg
+                            .strip_prefix(namespace.as_str())
+                            .is_some_and(|rest| rest.starts_with('.'))
+                }
+                None => false,
+            };
+            if !arg_matches {
+                return false;
+            }
+        }
+        self.args.iter().all(|(idx, want)| {
+            let param = match msg.params.get(*idx) {
+                Some(param) => param,
+                None => return false,
+            };
+            match want {
+                ArgMatch::String(want) => string_param(param) == Some(want.as_str()),
+                ArgMatch::Path(want) => {
+                    path_param(param).is_some_and(|p| path_prefix_match(p, want))
+                }
+            }
+        })
+    }
+}
+
+/// librdbus extension: whether msg matches the match rule. Sets a MatchRuleInvalid error and
+/// returns FALSE if the rule can not be parsed.
+#[no_mangle]
+pub extern "C" fn rdbus_message_matches_rule(
+    msg: *mut DBusMessage,
+    rule: *const libc::c_char,
+    err: *mut DBusError,
+) -> u32 {
+    if msg.is_null() || rule.is_null() {
+        return dbus_bool(false);
+    }
+    let msg = unsafe { &*msg };
+    let rule = match unsafe { CStr::from_ptr(rule) }.to_str() {
+        Ok(rule) => rule,
+        Err(_) => {
+            set_error(
+                err,
+                DBUS_ERROR_MATCH_RULE_INVALID,
+                "Match rule was not valid UTF-8",
+            );
+            return dbus_bool(false);
+        }
+    };
+    match MatchRule::parse(rule) {
+        Ok(rule) => dbus_bool(rule.matches(&msg.msg)),
+        Err(e) => {
+            set_error(err, DBUS_ERROR_MATCH_RULE_INVALID, &e);
+            dbus_bool(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(path: &str, args: &[&str]) -> rustbus::Message<'static, 'static> {
+        let mut msg = rustbus::message_builder::MessageBuilder::new()
+            .signal(
+                "io.rdbus.Test".to_owned(),
+                "Ping".to_owned(),
+                path.to_owned(),
+            )
+            .build();
+        msg.sender = Some(":1.7".to_owned());
+        for arg in args {
+            msg.push_param((*arg).to_owned());
+        }
+        msg
+    }
+
+    #[test]
+    fn parse_match_rules() {
+        let rule =
+            MatchRule::parse("type='signal', interface='io.rdbus.Test',member=Ping").unwrap();
+        assert_eq!(rule.msg_type.as_deref(), Some("signal"));
+        assert_eq!(rule.interface.as_deref(), Some("io.rdbus.Test"));
+        assert_eq!(rule.member.as_deref(), Some("Ping"));
+        assert_eq!(MatchRule::parse("").unwrap().rule, "");
+
+        let rule = MatchRule::parse("arg0='a,b',arg1=it\\'s,arg2path='/x/'").unwrap();
+        assert_eq!(
+            rule.args,
+            vec![
+                (0, ArgMatch::String("a,b".to_owned())),
+                (1, ArgMatch::String("it's".to_owned())),
+                (2, ArgMatch::Path("/x/".to_owned())),
+            ]
+        );
+
+        for invalid in &[
+            "member='unterminated",
+            "unknown='x'",
+            "type='nonsense'",
+            "member='a.b'",
+            "path='no/slash'",
+            "path='/a',path_namespace='/b'",
+            "arg64='x'",
+            "arg0='x',arg0namespace='a.b'",
+            "eavesdrop='maybe'",
+            "member='A',member='B'",
+            "member",
+        ] {
+            assert!(MatchRule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn match_messages() {
+        let msg = signal("/io/rdbus/Test", &["org.example.Name", "/io/rdbus/"]);
+        let matches = |rule: &str| MatchRule::parse(rule).unwrap().matches(&msg);
+
+        assert!(matches(""));
+        assert!(matches(
+            "type='signal',interface='io.rdbus.Test',member='Ping'"
+        ));
+        assert!(!matches("type='method_call'"));
+        assert!(matches("sender=':1.7'"));
+        assert!(!matches("sender='io.rdbus.Other'"));
+        assert!(matches("path_namespace='/io/rdbus'"));
+        assert!(matches("path_namespace='/'"));
+        assert!(!matches("path_namespace='/io/rd'"));
+        assert!(matches("arg0='org.example.Name'"));
+        assert!(!matches("arg2='anything'"));
+        assert!(matches("arg0namespace='org.example'"));
+        assert!(!matches("arg0namespace='org.exam'"));
+        assert!(matches("arg1path='/io/rdbus/Test'"));
+        assert!(matches("arg1path='/io/'"));
+        assert!(!matches("arg1path='/io'"));
+
+        let rule = MatchRule::parse("sender='io.rdbus.Owner'").unwrap();
+        assert!(rule.matches_resolving(&msg, |_| Some(":1.7".to_owned())));
+    }
+}
@@ -1,5 +1,6 @@
 use crate::dbus_bool;
 use crate::error::*;
+use crate::trace::unimplemented_abi;
 use rustbus::params;
 use std::ffi::CStr;
 
@@ -28,7 +29,7 @@ pub struct DBusMessage<'a> {
     ref_count: u64,
     pub string_arena: StringArena,
     locked: bool,
-    pub app_data: Vec<crate::data_slot::AppData>,
+    pub data: crate::data_slot::DataSlots,
     buffer: Vec<u8>,
 }
 
@@ -39,13 +40,13 @@ impl<'a> DBusMessage<'a> {
             ref_count: 1,
             string_arena: std::collections::HashMap::new(),
             locked: false,
-            app_data: Vec::new(),
+            data: crate::data_slot::DataSlots::default(),
             buffer: Vec::new(),
         }
     }
 
     fn finalize(&mut self) {
-        self.app_data.clear();
+        self.data.clear();
     }
 }
 
@@ -100,6 +101,26 @@ pub extern "C" fn dbus_message_new<'a>(typ: libc::c_int) -> *mut DBusMessage<'a>
     Box::into_raw(Box::new(DBusMessage::new(msg)))
 }
 
+/// NULL and invalid UTF-8 are both None
+fn optional_str<'a>(s: *const libc::c_char) -> Option<&'a str> {
+    if s.is_null() {
+        None
+    } else {
+        unsafe { CStr::from_ptr(s) }.to_str().ok()
+    }
+}
+
+/// What the builder made for C, NULL for invalid names like in libdbus
+fn built<'a>(msg: crate::api::Result<crate::api::Message>) -> *mut DBusMessage<'a> {
+    match msg {
+        Ok(msg) => msg.into_raw().cast(),
+        Err(e) => {
+            crate::trace::trace!("message", "can not build: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn dbus_message_new_method_call<'a>(
     dest: *const libc::c_char,
@@ -107,42 +128,18 @@ pub extern "C" fn dbus_message_new_method_call<'a>(
     interface: *const libc::c_char,
     member: *const libc::c_char,
 ) -> *mut DBusMessage<'a> {
-    let c_str = unsafe {
-        assert!(!dest.is_null());
-
-        CStr::from_ptr(dest)
-    };
-    let dest = c_str.to_str().unwrap().to_owned();
-
-    let c_str = unsafe {
-        assert!(!object.is_null());
-
-        CStr::from_ptr(object)
-    };
-    let object = c_str.to_str().unwrap().to_owned();
-
-    let c_str = unsafe {
-        assert!(!interface.is_null());
-
-        CStr::from_ptr(interface)
-    };
-    let interface = c_str.to_str().unwrap().to_owned();
-
-    let c_str = unsafe {
-        assert!(!member.is_null());
-
-        CStr::from_ptr(member)
+    let (object, member) = match (optional_str(object), optional_str(member)) {
+        (Some(object), Some(member)) => (object, member),
+        _ => return std::ptr::null_mut(),
     };
-    let member = c_str.to_str().unwrap().to_owned();
-
-    Box::into_raw(Box::new(DBusMessage::new(
-        rustbus::message_builder::MessageBuilder::new()
-            .call(member)
-            .at(dest)
-            .on(object)
-            .with_interface(interface)
-            .build(),
-    )))
+    let mut builder = crate::api::MessageBuilder::method_call(object, member);
+    if let Some(dest) = optional_str(dest) {
+        builder = builder.destination(dest);
+    }
+    if let Some(interface) = optional_str(interface) {
+        builder = builder.interface(interface);
+    }
+    built(builder.build())
 }
 #[no_mangle]
 pub extern "C" fn dbus_message_new_signal<'a>(
@@ -150,32 +147,16 @@ pub extern "C" fn dbus_message_new_signal<'a>(
     interface: *const libc::c_char,
     member: *const libc::c_char,
 ) -> *mut DBusMessage<'a> {
-    let c_str = unsafe {
-        assert!(!object.is_null());
-
-        CStr::from_ptr(object)
-    };
-    let object = c_str.to_str().unwrap().to_owned();
-
-    let c_str = unsafe {
-        assert!(!interface.is_null());
-
-        CStr::from_ptr(interface)
-    };
-    let interface = c_str.to_str().unwrap().to_owned();
-
-    let c_str = unsafe {
-        assert!(!member.is_null());
-
-        CStr::from_ptr(member)
-    };
-    let member = c_str.to_str().unwrap().to_owned();
-
-    Box::into_raw(Box::new(DBusMessage::new(
-        rustbus::message_builder::MessageBuilder::new()
-            .signal(interface, member, object)
-            .build(),
-    )))
+    match (
+        optional_str(object),
+        optional_str(interface),
+        optional_str(member),
+    ) {
+        (Some(object), Some(interface), Some(member)) => {
+            built(crate::api::MessageBuilder::signal(object, interface, member).build())
+        }
+        _ => std::ptr::null_mut(),
+    }
 }
 #[no_mangle]
 pub extern "C" fn dbus_message_new_method_return(call: *const DBusMessage) -> *mut DBusMessage {
@@ -186,6 +167,17 @@ pub extern "C" fn dbus_message_new_method_return(call: *const DBusMessage) -> *m
         Box::into_raw(Box::new(DBusMessage::new(call.msg.make_response())))
     }
 }
+/// rustbus' make_error_response marks errors as method returns, so fix the type up
+pub fn make_error_response<'a>(
+    call: &rustbus::Message<'a, 'a>,
+    name: &str,
+    text: &str,
+) -> rustbus::Message<'a, 'a> {
+    let mut msg = call.make_error_response(name.to_owned(), Some(text.to_owned()));
+    msg.typ = rustbus::MessageType::Error;
+    msg
+}
+
 #[no_mangle]
 pub extern "C" fn dbus_message_new_error<'a>(
     call: *const DBusMessage<'a>,
@@ -206,17 +198,17 @@ pub extern "C" fn dbus_message_new_error<'a>(
             CStr::from_ptr(errmsg)
         };
         let errmsg = c_str.to_str().unwrap().to_owned();
-        let msg = call.msg.make_error_response(errname, Some(errmsg));
+        let msg = make_error_response(&call.msg, &errname, &errmsg);
         Box::into_raw(Box::new(DBusMessage::new(msg)))
     }
 }
 #[no_mangle]
 pub extern "C" fn dbus_message_new_error_printf(
-    _call: *const DBusMessage,
-    _errname: *const libc::c_char,
-    _errmsg: *const libc::c_char,
+    call: *const DBusMessage,
+    errname: *const libc::c_char,
+    errmsg: *const libc::c_char,
 ) -> *mut DBusMessage {
-    unimplemented!()
+    unimplemented_abi!("dbus_message_new_error_printf", call, errname, errmsg => std::ptr::null_mut())
 }
 #[no_mangle]
 pub extern "C" fn dbus_message_copy(msg: *const DBusMessage) -> *mut DBusMessage {
@@ -276,8 +268,7 @@ pub extern "C" fn dbus_message_append_args(msg: *mut DBusMessage, typ1: libc::c_
         let mut typ_ptr: *const libc::c_int = &typ1;
 
         // arg pointer always points to directly after the type
-        let mut arg_ptr: *const *mut std::ffi::c_void =
-            unsafe { std::mem::transmute(typ_ptr.add(1)) };
+        let mut arg_ptr: *const *mut std::ffi::c_void = unsafe { typ_ptr.add(1).cast() };
         loop {
             if typ1 == crate::DBUS_TYPE_INVALID {
                 break;
@@ -290,8 +281,8 @@ pub extern "C" fn dbus_message_append_args(msg: *mut DBusMessage, typ1: libc::c_
             }
             // move the pointers so that new typ points directly after old arg
             // and new arg points directly after new typ
-            typ_ptr = unsafe { std::mem::transmute(arg_ptr.add(1)) };
-            arg_ptr = unsafe { std::mem::transmute(typ_ptr.add(1)) };
+            typ_ptr = unsafe { arg_ptr.add(1).cast() };
+            arg_ptr = unsafe { typ_ptr.add(1).cast() };
         }
         1
     }
@@ -365,8 +356,7 @@ pub extern "C" fn dbus_message_get_args<'a>(msg: *mut DBusMessage<'a>, typ1: lib
         let mut typ_ptr: *const libc::c_int = &typ1;
 
         // arg pointer always points to directly after the type
-        let mut arg_ptr: *const *mut std::ffi::c_void =
-            unsafe { std::mem::transmute(typ_ptr.add(1)) };
+        let mut arg_ptr: *const *mut std::ffi::c_void = unsafe { typ_ptr.add(1).cast() };
 
         let mut counter = 0;
         loop {
@@ -387,22 +377,21 @@ pub extern "C" fn dbus_message_get_args<'a>(msg: *mut DBusMessage<'a>, typ1: lib
                     }
                 } else {
                     // TODO What do we do here?!
-                    unimplemented!();
+                    unimplemented_abi!("dbus_message_get_args with mismatched types", counter, typ => 0);
                 }
                 // move the pointers so that new typ points directly after old arg
                 // and new arg points directly after new typ
-                typ_ptr = unsafe { std::mem::transmute(arg_ptr.add(1)) };
-                arg_ptr = unsafe { std::mem::transmute(typ_ptr.add(1)) };
+                typ_ptr = unsafe { arg_ptr.add(1).cast() };
+                arg_ptr = unsafe { typ_ptr.add(1).cast() };
                 counter += 1;
             } else {
                 if typ != crate::DBUS_TYPE_ARRAY {
                     return 0;
                 }
-                let element_type_ptr: *const libc::c_int =
-                    unsafe { std::mem::transmute(arg_ptr.add(1)) };
+                let element_type_ptr: *const libc::c_int = unsafe { arg_ptr.add(1).cast() };
                 let element_type = unsafe { element_type_ptr.read() };
-                arg_ptr = unsafe { std::mem::transmute(element_type_ptr.add(1)) };
-                let array_size_ptr: *const u32 = unsafe { std::mem::transmute(arg_ptr.add(1)) };
+                arg_ptr = unsafe { element_type_ptr.add(1).cast() };
+                let array_size_ptr: *const u32 = unsafe { arg_ptr.add(1).cast() };
 
                 if let Some(base_type) = c_to_rustbus_base_type(element_type) {
                     let array_size = unsafe { array_size_ptr.read() };
@@ -421,19 +410,27 @@ pub extern "C" fn dbus_message_get_args<'a>(msg: *mut DBusMessage<'a>, typ1: lib
                                 }
                             } else {
                                 // TODO What do we do here?!
-                                unimplemented!();
+                                unimplemented_abi!(
+                                    "dbus_message_get_args with mismatched array types",
+                                    counter,
+                                    element_type => 0
+                                );
                             }
                         }
                     } else {
                         // TODO What do we do here?!
-                        unimplemented!();
+                        unimplemented_abi!(
+                            "dbus_message_get_args with a mismatched array",
+                            counter,
+                            typ => 0
+                        );
                     }
                 } else {
                     return 0;
                 }
 
-                typ_ptr = unsafe { std::mem::transmute(array_size_ptr.add(1)) };
-                arg_ptr = unsafe { std::mem::transmute(typ_ptr.add(1)) };
+                typ_ptr = unsafe { array_size_ptr.add(1).cast() };
+                arg_ptr = unsafe { typ_ptr.add(1).cast() };
             }
         }
         1
@@ -450,28 +447,70 @@ pub extern "C" fn dbus_message_get_args_valist(
 }
 
 #[no_mangle]
-pub extern "C" fn dbus_message_set_no_reply(_msg: *mut crate::DBusMessage) {
-    unimplemented!();
+pub extern "C" fn dbus_message_set_no_reply(msg: *mut crate::DBusMessage, no_reply: u32) {
+    if msg.is_null() {
+        return;
+    }
+    let msg = unsafe { &mut *msg };
+    if no_reply != 0 {
+        msg.msg.flags |= crate::connection::FLAG_NO_REPLY_EXPECTED;
+    } else {
+        msg.msg.flags &= !crate::connection::FLAG_NO_REPLY_EXPECTED;
+    }
 }
 #[no_mangle]
-pub extern "C" fn dbus_message_get_no_reply(_msg: *mut crate::DBusMessage) {
-    unimplemented!();
+pub extern "C" fn dbus_message_get_no_reply(msg: *mut crate::DBusMessage) -> u32 {
+    if msg.is_null() {
+        return dbus_bool(false);
+    }
+    let msg = unsafe { &*msg };
+    dbus_bool(msg.msg.flags & crate::connection::FLAG_NO_REPLY_EXPECTED != 0)
 }
 #[no_mangle]
-pub extern "C" fn dbus_message_set_auto_start(_msg: *mut crate::DBusMessage) {
-    unimplemented!();
+pub extern "C" fn dbus_message_set_auto_start(msg: *mut crate::DBusMessage, auto_start: u32) {
+    if msg.is_null() {
+        return;
+    }
+    let msg = unsafe { &mut *msg };
+    // the header flag says the opposite
+    if auto_start != 0 {
+        msg.msg.flags &= !crate::connection::FLAG_NO_AUTO_START;
+    } else {
+        msg.msg.flags |= crate::connection::FLAG_NO_AUTO_START;
+    }
 }
 #[no_mangle]
-pub extern "C" fn dbus_message_get_auto_start(_msg: *mut crate::DBusMessage) {
-    unimplemented!();
+pub extern "C" fn dbus_message_get_auto_start(msg: *mut crate::DBusMessage) -> u32 {
+    if msg.is_null() {
+        return dbus_bool(false);
+    }
+    let msg = unsafe { &*msg };
+    dbus_bool(msg.msg.flags & crate::connection::FLAG_NO_AUTO_START == 0)
 }
 #[no_mangle]
-pub extern "C" fn dbus_message_set_allow_interactive_authorization(_msg: *mut crate::DBusMessage) {
-    unimplemented!();
+pub extern "C" fn dbus_message_set_allow_interactive_authorization(
+    msg: *mut crate::DBusMessage,
+    allow: u32,
+) {
+    if msg.is_null() {
+        return;
+    }
+    let msg = unsafe { &mut *msg };
+    if allow != 0 {
+        msg.msg.flags |= crate::connection::FLAG_ALLOW_INTERACTIVE_AUTHORIZATION;
+    } else {
+        msg.msg.flags &= !crate::connection::FLAG_ALLOW_INTERACTIVE_AUTHORIZATION;
+    }
 }
 #[no_mangle]
-pub extern "C" fn dbus_message_get_allow_interactive_authorization(_msg: *mut crate::DBusMessage) {
-    unimplemented!();
+pub extern "C" fn dbus_message_get_allow_interactive_authorization(
+    msg: *mut crate::DBusMessage,
+) -> u32 {
+    if msg.is_null() {
+        return dbus_bool(false);
+    }
+    let msg = unsafe { &*msg };
+    dbus_bool(msg.msg.flags & crate::connection::FLAG_ALLOW_INTERACTIVE_AUTHORIZATION != 0)
 }
 #[no_mangle]
 pub extern "C" fn dbus_message_get_path(msg: *mut crate::DBusMessage) -> *const libc::c_char {
@@ -481,8 +520,8 @@ pub extern "C" fn dbus_message_get_path(msg: *mut crate::DBusMessage) -> *const
     let msg = unsafe { &mut *msg };
 
     if let Some(s) = &msg.msg.object {
-        let cstr = crate::get_cstring(&mut msg.string_arena, &s);
-        unsafe { std::mem::transmute(cstr.as_ptr()) }
+        let cstr = crate::get_cstring(&mut msg.string_arena, s);
+        cstr.as_ptr()
     } else {
         std::ptr::null()
     }
@@ -541,19 +580,9 @@ pub extern "C" fn dbus_message_get_path_decomposed(
     let msg = unsafe { &*msg };
 
     if let Some(object) = &msg.msg.object {
-        let mut ptr_array = Vec::new();
-        for element in object.split('/') {
-            let cstr = std::ffi::CString::new(element).unwrap();
-            ptr_array.push(cstr.as_ptr());
-            std::mem::forget(cstr);
-        }
-        ptr_array.push(std::ptr::null());
-
-        let boxed = Box::new(ptr_array);
-        let array_ptr = boxed.as_ref().as_ptr();
-        // forget box. Needs to be freed in dbus_free_string_array()
-        let _ptr = Box::into_raw(boxed);
-        unsafe { *output = array_ptr };
+        // "/" has no elements at all
+        let elements: Vec<&str> = object.split('/').filter(|e| !e.is_empty()).collect();
+        unsafe { *output = crate::malloc_string_array(&elements) as *const *const libc::c_char };
     }
     1
 }
@@ -566,8 +595,8 @@ pub extern "C" fn dbus_message_get_interface(msg: *mut crate::DBusMessage) -> *c
     let msg = unsafe { &mut *msg };
 
     if let Some(s) = &msg.msg.interface {
-        let cstr = crate::get_cstring(&mut msg.string_arena, &s);
-        unsafe { std::mem::transmute(cstr.as_ptr()) }
+        let cstr = crate::get_cstring(&mut msg.string_arena, s);
+        cstr.as_ptr()
     } else {
         std::ptr::null()
     }
@@ -622,8 +651,8 @@ pub extern "C" fn dbus_message_get_member(msg: *mut crate::DBusMessage) -> *cons
     let msg = unsafe { &mut *msg };
 
     if let Some(s) = &msg.msg.member {
-        let cstr = crate::get_cstring(&mut msg.string_arena, &s);
-        unsafe { std::mem::transmute(cstr.as_ptr()) }
+        let cstr = crate::get_cstring(&mut msg.string_arena, s);
+        cstr.as_ptr()
     } else {
         std::ptr::null()
     }
@@ -678,8 +707,8 @@ pub extern "C" fn dbus_message_get_error_name(msg: *mut crate::DBusMessage) -> *
     let msg = unsafe { &mut *msg };
 
     if let Some(s) = &msg.msg.error_name {
-        let cstr = crate::get_cstring(&mut msg.string_arena, &s);
-        unsafe { std::mem::transmute(cstr.as_ptr()) }
+        let cstr = crate::get_cstring(&mut msg.string_arena, s);
+        cstr.as_ptr()
     } else {
         std::ptr::null()
     }
@@ -714,8 +743,8 @@ pub extern "C" fn dbus_message_get_destination(
     let msg = unsafe { &mut *msg };
 
     if let Some(s) = &msg.msg.destination {
-        let cstr = crate::get_cstring(&mut msg.string_arena, &s);
-        unsafe { std::mem::transmute(cstr.as_ptr()) }
+        let cstr = crate::get_cstring(&mut msg.string_arena, s);
+        cstr.as_ptr()
     } else {
         std::ptr::null()
     }
@@ -747,8 +776,8 @@ pub extern "C" fn dbus_message_get_sender(msg: *mut crate::DBusMessage) -> *cons
     let msg = unsafe { &mut *msg };
 
     if let Some(s) = &msg.msg.sender {
-        let cstr = crate::get_cstring(&mut msg.string_arena, &s);
-        unsafe { std::mem::transmute(cstr.as_ptr()) }
+        let cstr = crate::get_cstring(&mut msg.string_arena, s);
+        cstr.as_ptr()
     } else {
         std::ptr::null()
     }
@@ -785,7 +814,7 @@ pub extern "C" fn dbus_message_get_signature(msg: *mut crate::DBusMessage) -> *c
     }
 
     let cstr = crate::get_cstring(&mut msg.string_arena, &sig_str);
-    unsafe { std::mem::transmute(cstr.as_ptr()) }
+    cstr.as_ptr()
 }
 #[no_mangle]
 pub extern "C" fn dbus_message_is_method_call(msg: *mut crate::DBusMessage) -> u32 {
@@ -796,11 +825,7 @@ pub extern "C" fn dbus_message_is_method_call(msg: *mut crate::DBusMessage) -> u
 
     // FIXME check for interface etc like in signal
 
-    dbus_bool(if let rustbus::MessageType::Call = msg.msg.typ {
-        true
-    } else {
-        false
-    })
+    dbus_bool(matches!(msg.msg.typ, rustbus::MessageType::Call))
 }
 #[no_mangle]
 pub extern "C" fn dbus_message_is_signal(
@@ -836,11 +861,7 @@ pub extern "C" fn dbus_message_is_error(msg: *mut crate::DBusMessage) -> u32 {
 
     // FIXME check for interface etc like in signal
 
-    dbus_bool(if let rustbus::MessageType::Error = msg.msg.typ {
-        true
-    } else {
-        false
-    })
+    dbus_bool(matches!(msg.msg.typ, rustbus::MessageType::Error))
 }
 #[no_mangle]
 pub extern "C" fn dbus_message_is_reply(msg: *mut crate::DBusMessage) -> u32 {
@@ -851,11 +872,7 @@ pub extern "C" fn dbus_message_is_reply(msg: *mut crate::DBusMessage) -> u32 {
 
     // FIXME check for interface etc like in signal
 
-    dbus_bool(if let rustbus::MessageType::Reply = msg.msg.typ {
-        true
-    } else {
-        false
-    })
+    dbus_bool(matches!(msg.msg.typ, rustbus::MessageType::Reply))
 }
 #[no_mangle]
 pub extern "C" fn dbus_message_has_destination(
@@ -934,34 +951,17 @@ pub extern "C" fn dbus_set_error_from_message(
     err: *mut DBusError,
     msg: *mut crate::DBusMessage,
 ) -> u32 {
-    if msg.is_null() {
+    if msg.is_null() || err.is_null() {
         return 0;
     }
-    let msg = unsafe { &mut *msg };
-    if err.is_null() {
-        return 0;
-    }
-    let err = unsafe { &mut *err };
-
-    dbus_bool(if let rustbus::MessageType::Error = msg.msg.typ {
-        err.is_set = true;
-        err.name = Box::new(msg.msg.error_name.clone().unwrap_or("".to_owned()));
-        if !msg.msg.params.is_empty() {
-            if let rustbus::params::Param::Base(rustbus::params::Base::String(error_msg)) =
-                &msg.msg.params[0]
-            {
-                err.error = Box::new(error_msg.clone());
-            }
-            if let rustbus::params::Param::Base(rustbus::params::Base::StringRef(error_msg)) =
-                &msg.msg.params[0]
-            {
-                err.error = Box::new(error_msg.to_string());
-            }
+    let msg = unsafe { &*msg };
+    match crate::error::Error::from_message(&msg.msg) {
+        Some(e) => {
+            e.set(err);
+            dbus_bool(true)
         }
-        true
-    } else {
-        false
-    })
+        None => dbus_bool(false),
+    }
 }
 
 #[no_mangle]
@@ -976,16 +976,16 @@ pub extern "C" fn dbus_message_contains_unix_fds(msg: *mut crate::DBusMessage) -
 
 #[no_mangle]
 pub extern "C" fn dbus_message_set_container_instance(
-    _msg: *mut crate::DBusMessage,
-    _object: *const libc::c_char,
+    msg: *mut crate::DBusMessage,
+    object: *const libc::c_char,
 ) -> u32 {
-    unimplemented!()
+    unimplemented_abi!("dbus_message_set_container_instance", msg, object => dbus_bool(false))
 }
 #[no_mangle]
 pub extern "C" fn dbus_message_get_container_instance(
-    _msg: *mut crate::DBusMessage,
+    msg: *mut crate::DBusMessage,
 ) -> *const libc::c_char {
-    unimplemented!()
+    unimplemented_abi!("dbus_message_get_container_instance", msg => std::ptr::null())
 }
 #[no_mangle]
 pub extern "C" fn dbus_message_set_serial(msg: *mut crate::DBusMessage, serial: u32) {
@@ -1005,6 +1005,21 @@ pub extern "C" fn dbus_message_lock(msg: *mut crate::DBusMessage) {
     msg.locked = true;
 }
 
+/// rustbus 0.3.2 never writes the error name and sender header fields, so pass them in as
+/// extra fields. Everything that goes on the wire has to be marshalled through here.
+pub fn marshal(msg: &rustbus::Message, buf: &mut Vec<u8>) -> rustbus::message::Result<()> {
+    use rustbus::message::HeaderField;
+
+    let mut fields = Vec::new();
+    if let Some(name) = &msg.error_name {
+        fields.push(HeaderField::ErrorName(name.clone()));
+    }
+    if let Some(sender) = &msg.sender {
+        fields.push(HeaderField::Sender(sender.clone()));
+    }
+    rustbus::wire::marshal::marshal(msg, rustbus::message::ByteOrder::LittleEndian, &fields, buf)
+}
+
 #[no_mangle]
 pub extern "C" fn dbus_message_marshal(
     msg: *mut crate::DBusMessage,
@@ -1026,14 +1041,9 @@ pub extern "C" fn dbus_message_marshal(
 
     // TODO make a buffer pool or something similar
     msg.buffer.clear();
-    match rustbus::wire::marshal::marshal(
-        &msg.msg,
-        rustbus::message::ByteOrder::LittleEndian,
-        &[],
-        &mut msg.buffer,
-    ) {
+    match marshal(&msg.msg, &mut msg.buffer) {
         Ok(()) => {
-            *dest = unsafe { std::mem::transmute(msg.buffer.as_ptr()) };
+            *dest = msg.buffer.as_ptr() as *const libc::c_char;
             *len = msg.buffer.len() as libc::c_int;
             dbus_bool(true)
         }
@@ -1041,88 +1051,434 @@ pub extern "C" fn dbus_message_marshal(
     }
 }
 
-#[no_mangle]
-pub extern "C" fn dbus_message_demarshal<'a>(
-    source: *mut *const libc::c_char,
-    len: libc::c_int,
-    err: *mut DBusError,
-) -> *mut DBusMessage<'a> {
-    // needs to be mem::forget'd before exiting, the memory should not be freed by this function
-    let buf = unsafe { Vec::from_raw_parts(source as *mut u8, len as usize, len as usize) };
+/// Borrow the bytes handed to the demarshal functions without taking ownership of them
+fn demarshal_source<'a>(source: *const libc::c_char, len: libc::c_int) -> &'a [u8] {
+    if source.is_null() || len <= 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(source as *const u8, len as usize) }
+    }
+}
 
-    let (header_bytes, header) = match rustbus::wire::unmarshal::unmarshal_header(&buf, 0) {
-        Ok(h) => h,
-        Err(e) => {
-            if err.is_null() {
-                std::mem::forget(buf);
-                return std::ptr::null_mut();
+fn set_demarshal_error(err: *mut DBusError, e: rustbus::wire::unmarshal::Error) {
+    if err.is_null() {
+        return;
+    }
+    let err = unsafe { &mut *err };
+    err.set(
+        DBUS_ERROR_INVALID_ARGS,
+        &format!("Message could not be demarshalled: {:?}", e),
+    );
+}
+
+/// rustbus 0.3.2 loops forever on header fields it does not know and indexes past the end of
+/// truncated ones, so walk the header field array once before handing the buffer to it.
+/// Returns the offset where the header fields end and the body signature, if there is one.
+fn check_header_fields<'b>(
+    buf: &'b [u8],
+    header: &rustbus::wire::unmarshal::Header,
+) -> Result<(usize, Option<&'b [u8]>), rustbus::wire::unmarshal::Error> {
+    use rustbus::wire::unmarshal::{Error, HEADER_LEN};
+    use rustbus::wire::util::parse_u32;
+
+    let (_, fields_len) = parse_u32(&buf[HEADER_LEN..], header.byteorder)?;
+    let start = HEADER_LEN + 4;
+    let end = start + fields_len as usize;
+    if end > buf.len() {
+        return Err(Error::NotEnoughBytes);
+    }
+
+    let mut body_sig = None;
+    let mut offset = start;
+    while offset < end {
+        offset = (offset + 7) & !7;
+        let field = buf.get(offset..end).ok_or(Error::InvalidHeaderFields)?;
+        let code = *field.first().ok_or(Error::InvalidHeaderFields)?;
+        if code == 0 {
+            return Err(Error::InvalidHeaderField);
+        }
+        if code > 9 {
+            return Err(Error::UnknownHeaderField);
+        }
+        let sig_len = *field.get(1).ok_or(Error::NotEnoughBytes)? as usize;
+        let sig = field.get(2..2 + sig_len).ok_or(Error::NotEnoughBytes)?;
+        offset += 3 + sig_len;
+        match sig {
+            b"s" | b"o" => {
+                offset = (offset + 3) & !3;
+                let len_buf = buf.get(offset..end).ok_or(Error::NotEnoughBytes)?;
+                let (_, len) = parse_u32(len_buf, header.byteorder)?;
+                offset += 4 + len as usize + 1;
+            }
+            b"g" => {
+                let len = *buf.get(offset).ok_or(Error::NotEnoughBytes)? as usize;
+                if code == 8 && body_sig.is_none() {
+                    body_sig = buf.get(offset + 1..offset + 1 + len);
+                }
+                offset += len + 2;
+            }
+            b"u" => offset = ((offset + 3) & !3) + 4,
+            _ => return Err(Error::WrongSignature),
+        }
+        if offset > end {
+            return Err(Error::NotEnoughBytes);
+        }
+    }
+    Ok((end, body_sig))
+}
+
+/// Same limit libdbus puts on the nesting of containers in a message
+const MAX_BODY_DEPTH: usize = 64;
+
+/// rustbus 0.3.2 also slices the body without checking the bounds first. Walk it with the same
+/// offsets rustbus will use, so a truncated or lying message is rejected before it gets there.
+fn check_body(
+    buf: &[u8],
+    header: &rustbus::wire::unmarshal::Header,
+    fields_end: usize,
+    sig: Option<&[u8]>,
+) -> Result<(), rustbus::wire::unmarshal::Error> {
+    use rustbus::wire::unmarshal::Error;
+
+    let offset = check_align(buf, fields_end, 8)?;
+    if header.body_len == 0 {
+        return Ok(());
+    }
+    let sig = std::str::from_utf8(sig.ok_or(Error::InvalidHeaderFields)?)
+        .map_err(|_| Error::InvalidSignature)?;
+    let types =
+        rustbus::signature::Type::parse_description(sig).map_err(|_| Error::InvalidSignature)?;
+    if buf.len() - offset < header.body_len as usize {
+        return Err(Error::NotEnoughBytes);
+    }
+    types.iter().try_fold(offset, |offset, typ| {
+        check_value(buf, header.byteorder, typ, offset, 0)
+    })?;
+    Ok(())
+}
+
+/// Offset after the padding to `align`, if the padding is still inside the buffer
+fn check_align(
+    buf: &[u8],
+    offset: usize,
+    align: usize,
+) -> Result<usize, rustbus::wire::unmarshal::Error> {
+    let offset = (offset + align - 1) & !(align - 1);
+    if offset > buf.len() {
+        return Err(rustbus::wire::unmarshal::Error::NotEnoughBytes);
+    }
+    Ok(offset)
+}
+
+/// Check that one value fits into the buffer and return the offset right after it
+fn check_value(
+    buf: &[u8],
+    byteorder: rustbus::message::ByteOrder,
+    typ: &rustbus::signature::Type,
+    offset: usize,
+    depth: usize,
+) -> Result<usize, rustbus::wire::unmarshal::Error> {
+    use rustbus::signature::{Container, Type};
+    use rustbus::wire::unmarshal::Error;
+    use rustbus::wire::util::parse_u32;
+
+    if depth > MAX_BODY_DEPTH {
+        return Err(Error::InvalidSignature);
+    }
+    match typ {
+        Type::Base(base) => check_base(buf, byteorder, *base, offset),
+        Type::Container(Container::Array(elem)) => {
+            let offset = check_align(buf, offset, 4)?;
+            let (_, len) = parse_u32(&buf[offset..], byteorder)?;
+            let start = check_align(buf, offset + 4, elem.get_alignment())?;
+            let mut offset = start;
+            while offset - start < len as usize {
+                offset = check_value(buf, byteorder, elem, offset, depth + 1)?;
+            }
+            Ok(offset)
+        }
+        Type::Container(Container::Dict(key, val)) => {
+            let offset = check_align(buf, offset, 4)?;
+            let (_, len) = parse_u32(&buf[offset..], byteorder)?;
+            let start = check_align(buf, offset + 4, 8)?;
+            let mut offset = start;
+            while offset - start < len as usize {
+                offset = check_align(buf, offset, 8)?;
+                offset = check_base(buf, byteorder, *key, offset)?;
+                offset = check_value(buf, byteorder, val, offset, depth + 1)?;
             }
-            let err = unsafe { &mut *err };
-            err.name = Box::new("DemarshallingError".to_owned());
-            err.error = Box::new(format!("Demarshalling error: {:?}", e));
-            err.is_set = true;
-            std::mem::forget(buf);
-            return std::ptr::null_mut();
+            Ok(offset)
         }
+        // libdbus rejects empty structs, rustbus would loop forever on an array of them
+        Type::Container(Container::Struct(fields)) if fields.is_empty() => {
+            Err(Error::InvalidSignature)
+        }
+        Type::Container(Container::Struct(fields)) => {
+            let offset = check_align(buf, offset, 8)?;
+            fields.iter().try_fold(offset, |offset, field| {
+                check_value(buf, byteorder, field, offset, depth + 1)
+            })
+        }
+        Type::Container(Container::Variant) => {
+            let len = *buf.get(offset).ok_or(Error::NotEnoughBytes)? as usize;
+            let sig = buf
+                .get(offset + 1..offset + 1 + len)
+                .ok_or(Error::NotEnoughBytes)?;
+            let sig = std::str::from_utf8(sig).map_err(|_| Error::InvalidUtf8)?;
+            let mut types = rustbus::signature::Type::parse_description(sig)
+                .map_err(|_| Error::InvalidSignature)?;
+            if types.len() != 1 {
+                return Err(Error::InvalidSignature);
+            }
+            check_value(
+                buf,
+                byteorder,
+                &types.remove(0),
+                offset + len + 2,
+                depth + 1,
+            )
+        }
+    }
+}
+
+fn check_base(
+    buf: &[u8],
+    byteorder: rustbus::message::ByteOrder,
+    base: rustbus::signature::Base,
+    offset: usize,
+) -> Result<usize, rustbus::wire::unmarshal::Error> {
+    use rustbus::signature::Base;
+    use rustbus::wire::unmarshal::Error;
+    use rustbus::wire::util::parse_u32;
+
+    let offset = check_align(buf, offset, base.get_alignment())?;
+    let end = match base {
+        Base::String | Base::ObjectPath => {
+            let (_, len) = parse_u32(&buf[offset..], byteorder)?;
+            offset + 4 + len as usize + 1
+        }
+        // rustbus reads signatures in the body from the start of the message instead of at
+        // their offset, keep in step with it
+        Base::Signature => offset + *buf.first().ok_or(Error::NotEnoughBytes)? as usize + 2,
+        // fixed size types are as large as their alignment
+        _ => offset + base.get_alignment(),
     };
+    if end > buf.len() {
+        return Err(Error::NotEnoughBytes);
+    }
+    Ok(end)
+}
+
+/// rustbus does not check everything libdbus does when unmarshalling. Strings with embedded
+/// NULs would break the C strings we hand out later, so reject those messages up front.
+fn validate_demarshalled(msg: &rustbus::Message) -> Result<(), rustbus::wire::unmarshal::Error> {
+    use rustbus::wire::unmarshal::Error;
+
+    let header_strings = [
+        &msg.object,
+        &msg.interface,
+        &msg.member,
+        &msg.error_name,
+        &msg.destination,
+        &msg.sender,
+    ];
+    for s in header_strings.iter().filter_map(|s| s.as_ref()) {
+        if s.contains('\0') {
+            return Err(Error::InvalidHeaderField);
+        }
+    }
+    if let Some(path) = &msg.object {
+        rustbus::params::validate_object_path(path).map_err(|_| Error::InvalidHeaderField)?;
+    }
+    msg.params.iter().try_for_each(validate_demarshalled_param)
+}
+
+fn validate_demarshalled_param(
+    param: &params::Param,
+) -> Result<(), rustbus::wire::unmarshal::Error> {
+    use rustbus::wire::unmarshal::Error;
 
-    match rustbus::wire::unmarshal::unmarshal_next_message(&header, &buf, header_bytes) {
-        Ok((_bytes, msg)) => {
-            let msg = Box::new(DBusMessage::new(msg));
-            std::mem::forget(buf);
-            return Box::into_raw(msg);
+    match param {
+        params::Param::Base(base) => validate_demarshalled_base(base),
+        params::Param::Container(params::Container::Array(arr)) => {
+            arr.values.iter().try_for_each(validate_demarshalled_param)
         }
-        Err(e) => {
-            if err.is_null() {
-                std::mem::forget(buf);
-                return std::ptr::null_mut();
-            }
-            let err = unsafe { &mut *err };
-            err.name = Box::new("DemarshallingError".to_owned());
-            err.error = Box::new(format!("Demarshalling error: {:?}", e));
-            err.is_set = true;
-            std::mem::forget(buf);
-            return std::ptr::null_mut();
+        params::Param::Container(params::Container::Struct(fields)) => {
+            fields.iter().try_for_each(validate_demarshalled_param)
+        }
+        params::Param::Container(params::Container::Dict(dict)) => {
+            dict.map.iter().try_for_each(|(k, v)| {
+                validate_demarshalled_base(k)?;
+                validate_demarshalled_param(v)
+            })
+        }
+        params::Param::Container(params::Container::Variant(var)) => {
+            validate_demarshalled_param(&var.value)
         }
+        // the unmarshaller only produces owned containers
+        params::Param::Container(_) => Err(Error::InvalidType),
     }
 }
 
+fn validate_demarshalled_base(base: &params::Base) -> Result<(), rustbus::wire::unmarshal::Error> {
+    use rustbus::wire::unmarshal::Error;
+
+    match base {
+        params::Base::String(s) if s.contains('\0') => Err(Error::InvalidUtf8),
+        params::Base::ObjectPath(s) => {
+            rustbus::params::validate_object_path(s).map_err(|_| Error::InvalidType)
+        }
+        params::Base::Signature(s) => {
+            rustbus::params::validate_signature(s).map_err(|_| Error::InvalidSignature)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Unmarshal one complete message with all the checks rustbus is missing. Used for
+/// dbus_message_demarshal and for everything read from a socket.
+pub fn demarshal<'a>(
+    buf: &[u8],
+) -> Result<rustbus::Message<'a, 'a>, rustbus::wire::unmarshal::Error> {
+    let (header_bytes, header) = rustbus::wire::unmarshal::unmarshal_header(buf, 0)?;
+    let (fields_end, sig) = check_header_fields(buf, &header)?;
+    check_body(buf, &header, fields_end, sig)?;
+
+    let (_bytes, msg) =
+        rustbus::wire::unmarshal::unmarshal_next_message(&header, buf, header_bytes)?;
+    validate_demarshalled(&msg)?;
+    Ok(msg)
+}
+
 #[no_mangle]
-pub extern "C" fn dbus_message_demarshal_bytes_needed(
-    source: *mut *const libc::c_char,
+pub extern "C" fn dbus_message_demarshal<'a>(
+    source: *const libc::c_char,
     len: libc::c_int,
     err: *mut DBusError,
-) -> libc::c_int {
-    // needs to be mem::forget'd before exiting, the memory should not be freed by this function
-    let buf = unsafe { Vec::from_raw_parts(source as *mut u8, len as usize, len as usize) };
-    let (header_bytes, header) = match rustbus::wire::unmarshal::unmarshal_header(&buf, 0) {
-        Ok(h) => h,
+) -> *mut DBusMessage<'a> {
+    match demarshal(demarshal_source(source, len)) {
+        Ok(msg) => Box::into_raw(Box::new(DBusMessage::new(msg))),
         Err(e) => {
-            let ret = match e {
-                rustbus::wire::unmarshal::Error::NotEnoughBytes => 0,
-                _ => -1,
-            };
-            if err.is_null() {
-                std::mem::forget(buf);
-                return ret;
-            }
-            let err = unsafe { &mut *err };
-            err.name = Box::new("DemarshallingError".to_owned());
-            err.error = Box::new(format!("Demarshalling error: {:?}", e));
-            err.is_set = true;
-            std::mem::forget(buf);
-            return ret;
+            set_demarshal_error(err, e);
+            std::ptr::null_mut()
         }
-    };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_message_demarshal_bytes_needed(
+    source: *const libc::c_char,
+    len: libc::c_int,
+) -> libc::c_int {
+    let buf = demarshal_source(source, len);
 
-    // have the body len in the header but still need the len of the header fields
-    if buf.len() < header_bytes + 4 {
-        std::mem::forget(buf);
+    // the fixed header plus the length of the header fields array
+    if buf.len() < rustbus::wire::unmarshal::HEADER_LEN + 4 {
         return 0;
     }
-    let (_, header_field_len) =
-        rustbus::wire::util::parse_u32(&buf[header_bytes..], header.byteorder).unwrap();
+    let header = match rustbus::wire::unmarshal::unmarshal_header(buf, 0) {
+        Ok((_, header)) => header,
+        Err(_) => return -1,
+    };
+    let header_field_len = match rustbus::wire::util::parse_u32(
+        &buf[rustbus::wire::unmarshal::HEADER_LEN..],
+        header.byteorder,
+    ) {
+        Ok((_, len)) => len as u64,
+        Err(_) => return -1,
+    };
 
-    std::mem::forget(buf);
-    (header.body_len + header_field_len + header_bytes as u32) as libc::c_int
+    // the body starts 8 byte aligned after the header fields
+    let header_len = rustbus::wire::unmarshal::HEADER_LEN as u64 + 4 + header_field_len;
+    let header_len = (header_len + 7) & !7;
+    let needed = header_len + header.body_len as u64;
+    if needed > libc::c_int::MAX as u64 {
+        -1
+    } else {
+        needed as libc::c_int
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_reply_flag_survives_marshalling() {
+        let call = rustbus::message_builder::MessageBuilder::new()
+            .call("Notify".to_owned())
+            .on("/io/rdbus".to_owned())
+            .with_interface("io.rdbus.Test".to_owned())
+            .build();
+        let msg = Box::into_raw(Box::new(DBusMessage::new(call)));
+        assert_eq!(dbus_message_get_no_reply(msg), 0);
+        dbus_message_set_no_reply(msg, 1);
+        assert_eq!(dbus_message_get_no_reply(msg), 1);
+
+        let m = unsafe { &mut *msg };
+        m.msg.serial = Some(7);
+        let mut buf = Vec::new();
+        marshal(&m.msg, &mut buf).unwrap();
+        let copy = Box::into_raw(Box::new(DBusMessage::new(demarshal(&buf).unwrap())));
+        assert_eq!(dbus_message_get_no_reply(copy), 1);
+
+        dbus_message_set_no_reply(msg, 0);
+        assert_eq!(dbus_message_get_no_reply(msg), 0);
+        assert_eq!(dbus_message_get_no_reply(std::ptr::null_mut()), 0);
+        dbus_message_unref(copy);
+        dbus_message_unref(msg);
+    }
+
+    #[test]
+    fn demarshal_rejects_body_shorter_than_signature() {
+        let mut call = rustbus::message_builder::MessageBuilder::new()
+            .call("Notify".to_owned())
+            .on("/io/rdbus".to_owned())
+            .build();
+        call.push_param(1u8);
+        call.push_param("hello");
+        call.push_param(7u64);
+        call.serial = Some(1);
+        let mut buf = Vec::new();
+        marshal(&call, &mut buf).unwrap();
+        assert!(demarshal(&buf).is_ok());
+
+        // cut the last half of the u64 off and make the header agree with that
+        buf.truncate(buf.len() - 4);
+        let body_len = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]) - 4;
+        buf[4..8].copy_from_slice(&body_len.to_le_bytes());
+        assert!(demarshal(&buf).is_err());
+    }
+
+    #[test]
+    fn auto_start_and_interactive_authorization() {
+        let call = rustbus::message_builder::MessageBuilder::new()
+            .call("Notify".to_owned())
+            .on("/io/rdbus".to_owned())
+            .build();
+        let msg = Box::into_raw(Box::new(DBusMessage::new(call)));
+        assert_eq!(dbus_message_get_auto_start(msg), 1);
+        assert_eq!(dbus_message_get_allow_interactive_authorization(msg), 0);
+
+        dbus_message_set_auto_start(msg, 0);
+        dbus_message_set_allow_interactive_authorization(msg, 1);
+        assert_eq!(unsafe { &*msg }.msg.flags, 0x6);
+        assert_eq!(dbus_message_get_auto_start(msg), 0);
+        assert_eq!(dbus_message_get_allow_interactive_authorization(msg), 1);
+        assert_eq!(dbus_message_get_no_reply(msg), 0);
+        dbus_message_unref(msg);
+    }
+
+    #[test]
+    fn unimplemented_calls_fail_instead_of_panicking() {
+        let call = rustbus::message_builder::MessageBuilder::new()
+            .call("Notify".to_owned())
+            .on("/io/rdbus".to_owned())
+            .build();
+        let msg = Box::into_raw(Box::new(DBusMessage::new(call)));
+        assert!(dbus_message_get_container_instance(msg).is_null());
+        let object = std::ffi::CString::new("/").unwrap();
+        assert_eq!(dbus_message_set_container_instance(msg, object.as_ptr()), 0);
+        dbus_message_unref(msg);
+    }
 }
@@ -1,17 +1,28 @@
+use crate::dbus_bool;
+use crate::trace::unimplemented_abi;
 use rustbus::params;
 use rustbus::signature;
 use std::ffi::CStr;
 
 pub struct SubAppendIter<'a> {
     params: Vec<params::Param<'a, 'a>>,
-    typ: rustbus::signature::Container,
+    typ: SubAppendType,
 }
 
+enum SubAppendType {
+    Container(rustbus::signature::Container),
+    // only valid within a dict. Pushes (key, value) structs into the parent
+    DictEntry,
+}
+
+// repr(u32) so a zeroed iterator (DBUS_MESSAGE_ITER_INIT_CLOSED in C) is a closed one
+#[repr(u32)]
 enum MessageIterInternal<'a> {
+    Closed,
     // pushes contents into message
     MainAppendIter(*mut crate::DBusMessage<'a>),
     // pushes contents into parent when closed
-    SubAppendIter(SubAppendIter<'a>),
+    SubAppendIter(Box<SubAppendIter<'a>>),
     MainIter(*const crate::DBusMessage<'a>),
     StructIter(*const [params::Param<'a, 'a>]),
     DictIter(
@@ -22,16 +33,19 @@ enum MessageIterInternal<'a> {
     ArrayIter(*const [params::Param<'a, 'a>], *const signature::Type),
     VariantIter(*const params::Variant<'a, 'a>),
     DictEntryIter(*const params::Base<'a>, *const params::Param<'a, 'a>),
-    Closed,
 }
 
+/// The iterator state lives inline so iterating a message does not allocate. C code
+/// allocates these on the stack, so this must never grow beyond libdbus' DBusMessageIter.
 #[repr(C)]
 pub struct DBusMessageIter<'a> {
-    inner: *mut MessageIterInternal<'a>,
+    inner: MessageIterInternal<'a>,
     counter: usize,
     msg: *mut crate::DBusMessage<'a>,
 }
 
+const _: () = assert!(std::mem::size_of::<DBusMessageIter>() <= 72);
+
 #[derive(Debug)]
 enum RustbusTypeOrDictEntry {
     Rustbus(rustbus::signature::Type),
@@ -46,63 +60,145 @@ enum RustbusParamOrDictEntry<'a> {
 }
 
 impl<'a> DBusMessageIter<'a> {
-    fn append(&mut self, param: params::Param<'a, 'a>) {
-        let inner = unsafe { &mut *self.inner };
-        match inner {
+    /// C hands us uninitialized memory, so the old content must not be dropped
+    fn init(
+        iter: *mut DBusMessageIter<'a>,
+        inner: MessageIterInternal<'a>,
+        counter: usize,
+        msg: *mut crate::DBusMessage<'a>,
+    ) {
+        unsafe {
+            std::ptr::write(
+                iter,
+                DBusMessageIter {
+                    inner,
+                    counter,
+                    msg,
+                },
+            )
+        };
+    }
+
+    fn append(&mut self, param: params::Param<'a, 'a>) -> bool {
+        match &mut self.inner {
             MessageIterInternal::MainAppendIter(msg) => {
                 let msg = unsafe { &mut **msg };
                 msg.msg.push_params(vec![param]);
             }
             MessageIterInternal::SubAppendIter(sub) => {
+                let fits = match &sub.typ {
+                    SubAppendType::Container(rustbus::signature::Container::Array(sig)) => {
+                        param.sig().eq(sig.as_ref())
+                    }
+                    // dicts are only filled by closing dict entries
+                    SubAppendType::Container(rustbus::signature::Container::Dict(_, _)) => false,
+                    SubAppendType::Container(rustbus::signature::Container::Variant) => {
+                        sub.params.is_empty()
+                    }
+                    SubAppendType::Container(rustbus::signature::Container::Struct(_)) => true,
+                    SubAppendType::DictEntry => match sub.params.len() {
+                        0 => matches!(param, params::Param::Base(_)),
+                        1 => true,
+                        _ => false,
+                    },
+                };
+                if !fits {
+                    return false;
+                }
                 sub.params.push(param);
             }
             _ => {
-                // NO?!
-                unimplemented!();
+                // Not an append iterator
+                return false;
             }
         }
         self.counter += 1;
+        true
     }
 
-    fn close(&mut self, parent: &mut DBusMessageIter<'a>) {
-        if self.inner.is_null() {
-            return;
-        }
-        let inner = unsafe { &mut *self.inner };
-        match inner {
-            MessageIterInternal::MainAppendIter(_msg) => {
-                // nothing to do here
-            }
+    fn append_dict_entry(&mut self, entry: Vec<params::Param<'a, 'a>>) -> bool {
+        match &mut self.inner {
             MessageIterInternal::SubAppendIter(sub) => match &sub.typ {
-                rustbus::signature::Container::Array(sig) => parent.append(
-                    params::Container::Array(params::Array {
-                        element_sig: sig.as_ref().clone(),
-                        values: sub.params.clone(),
+                SubAppendType::Container(rustbus::signature::Container::Dict(k, v)) => {
+                    if entry.len() != 2
+                        || entry[0].sig() != rustbus::signature::Type::Base(*k)
+                        || entry[1].sig() != *v.as_ref()
+                    {
+                        return false;
+                    }
+                    sub.params.push(params::Container::Struct(entry).into());
+                    self.counter += 1;
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn close(&mut self, parent: &mut DBusMessageIter<'a>) -> bool {
+        let inner = std::mem::replace(&mut self.inner, MessageIterInternal::Closed);
+        let sub = match inner {
+            MessageIterInternal::SubAppendIter(sub) => sub,
+            _ => {
+                // Weird but ok....
+                return true;
+            }
+        };
+        let SubAppendIter { mut params, typ } = *sub;
+        match typ {
+            SubAppendType::Container(rustbus::signature::Container::Array(sig)) => parent.append(
+                params::Container::Array(params::Array {
+                    element_sig: *sig,
+                    values: params,
+                })
+                .into(),
+            ),
+            SubAppendType::Container(rustbus::signature::Container::Dict(key_sig, value_sig)) => {
+                let mut map = params::DictMap::new();
+                for entry in params {
+                    if let params::Param::Container(params::Container::Struct(mut kv)) = entry {
+                        let value = kv.pop();
+                        let key = kv.pop();
+                        if let (Some(params::Param::Base(key)), Some(value)) = (key, value) {
+                            map.insert(key, value);
+                        }
+                    }
+                }
+                parent.append(
+                    params::Container::Dict(params::Dict {
+                        key_sig,
+                        value_sig: *value_sig,
+                        map,
                     })
                     .into(),
-                ),
-                rustbus::signature::Container::Dict(_, _) => unimplemented!(),
-                rustbus::signature::Container::Variant => parent.append(
+                )
+            }
+            SubAppendType::Container(rustbus::signature::Container::Variant) => {
+                if params.len() != 1 {
+                    return false;
+                }
+                let value = params.remove(0);
+                parent.append(
                     params::Container::Variant(Box::new(params::Variant {
-                        sig: sub.params[0].sig(),
-                        value: sub.params[0].clone(),
+                        sig: value.sig(),
+                        value,
                     }))
                     .into(),
-                ),
-                rustbus::signature::Container::Struct(_sigs) => {
-                    parent.append(params::Container::Struct(sub.params.clone()).into())
+                )
+            }
+            SubAppendType::Container(rustbus::signature::Container::Struct(_sigs)) => {
+                if params.is_empty() {
+                    return false;
                 }
-            },
-            _ => {
-                // Weird but ok....
+                parent.append(params::Container::Struct(params).into())
             }
+            SubAppendType::DictEntry => parent.append_dict_entry(params),
         }
-        std::mem::drop(unsafe { Box::from_raw(self.inner) });
     }
 
     fn len(&self) -> usize {
-        let inner = unsafe { &mut *self.inner };
-        match inner {
+        match &self.inner {
             MessageIterInternal::MainAppendIter(_) => 0,
             MessageIterInternal::SubAppendIter(_) => 0,
             MessageIterInternal::MainIter(msg) => {
@@ -141,8 +237,7 @@ impl<'a> DBusMessageIter<'a> {
     }
 
     fn current(&self) -> Option<RustbusParamOrDictEntry<'_>> {
-        let inner = unsafe { &mut *self.inner };
-        match inner {
+        match &self.inner {
             MessageIterInternal::MainAppendIter(_) => None,
             MessageIterInternal::SubAppendIter(_) => None,
             MessageIterInternal::MainIter(msg) => {
@@ -157,17 +252,21 @@ impl<'a> DBusMessageIter<'a> {
             }
             MessageIterInternal::ArrayIter(arr, _) => {
                 let arr = unsafe { &**arr };
-                Some(RustbusParamOrDictEntry::Rustbus(&arr[self.counter]))
+                arr.get(self.counter).map(RustbusParamOrDictEntry::Rustbus)
             }
             MessageIterInternal::DictIter(dict, _, _) => {
                 let dict = unsafe { &**dict };
-                let key = dict.keys().nth(self.counter).unwrap();
-                let val = dict.get(key).unwrap();
-                Some(RustbusParamOrDictEntry::DictEntry(key, val))
+                dict.iter()
+                    .nth(self.counter)
+                    .map(|(key, val)| RustbusParamOrDictEntry::DictEntry(key, val))
             }
             MessageIterInternal::VariantIter(var) => {
-                let var = unsafe { &**var };
-                Some(RustbusParamOrDictEntry::Rustbus(&var.value))
+                if self.counter == 0 {
+                    let var = unsafe { &**var };
+                    Some(RustbusParamOrDictEntry::Rustbus(&var.value))
+                } else {
+                    None
+                }
             }
             MessageIterInternal::DictEntryIter(key, val) => {
                 if self.counter == 0 {
@@ -182,15 +281,16 @@ impl<'a> DBusMessageIter<'a> {
             }
             MessageIterInternal::StructIter(values) => {
                 let values = unsafe { &**values };
-                Some(RustbusParamOrDictEntry::Rustbus(&values[self.counter]))
+                values
+                    .get(self.counter)
+                    .map(RustbusParamOrDictEntry::Rustbus)
             }
             MessageIterInternal::Closed => None,
         }
     }
 
     fn sig(&self) -> Option<Vec<RustbusTypeOrDictEntry>> {
-        let inner = unsafe { &mut *self.inner };
-        match inner {
+        match &self.inner {
             MessageIterInternal::MainAppendIter(_) => None,
             MessageIterInternal::SubAppendIter(_) => None,
             MessageIterInternal::MainIter(msg) => {
@@ -282,7 +382,7 @@ impl<'a> DBusMessageIter<'a> {
                         key_sig,
                         val_sig,
                     )) => Some(RustbusTypeOrDictEntry::DictEntry(
-                        key_sig.clone(),
+                        key_sig,
                         val_sig.as_ref().clone(),
                     )),
                     _ => None,
@@ -300,28 +400,43 @@ pub extern "C" fn dbus_message_iter_init<'a>(
     msg: *mut crate::DBusMessage<'a>,
     args: *mut DBusMessageIter<'a>,
 ) -> u32 {
-    if args.is_null() {
+    if args.is_null() || msg.is_null() {
         return 0;
     }
-    let args = unsafe { &mut *args };
-    *args = DBusMessageIter {
-        inner: Box::into_raw(Box::new(MessageIterInternal::MainIter(msg))),
-        counter: 0,
-        msg,
-    };
-    1
+    DBusMessageIter::init(args, MessageIterInternal::MainIter(msg), 0, msg);
+    let msg = unsafe { &*msg };
+    dbus_bool(!msg.msg.params.is_empty())
+}
+/// Start reading the arguments of a borrowed message. dbus_message_iter_get_basic has to write
+/// to the message and does nothing on such an iterator, iter_get_base reads the values instead.
+pub fn iter_init_readonly<'a>(
+    msg: &crate::DBusMessage<'a>,
+    args: *mut DBusMessageIter<'a>,
+) -> bool {
+    DBusMessageIter::init(
+        args,
+        MessageIterInternal::MainIter(msg),
+        0,
+        std::ptr::null_mut(),
+    );
+    !msg.msg.params.is_empty()
 }
+
+/// The basic value iter points at, if it points at one
+pub fn iter_get_base<'i>(iter: &'i DBusMessageIter) -> Option<&'i params::Base<'i>> {
+    match iter.current()? {
+        RustbusParamOrDictEntry::Rustbus(params::Param::Base(base)) => Some(base),
+        RustbusParamOrDictEntry::RustbusBase(base) => Some(base),
+        _ => None,
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn dbus_message_iter_init_closed<'a>(args: *mut DBusMessageIter<'a>) -> u32 {
     if args.is_null() {
         return 0;
     }
-    let args = unsafe { &mut *args };
-    *args = DBusMessageIter {
-        inner: Box::into_raw(Box::new(MessageIterInternal::Closed)),
-        counter: 0,
-        msg: std::ptr::null_mut(),
-    };
+    DBusMessageIter::init(args, MessageIterInternal::Closed, 0, std::ptr::null_mut());
     1
 }
 
@@ -396,7 +511,6 @@ pub extern "C" fn dbus_message_iter_recurse<'a>(
     if sub.is_null() {
         return;
     }
-    let sub = unsafe { &mut *sub };
 
     let current = parent.current();
 
@@ -436,11 +550,7 @@ pub extern "C" fn dbus_message_iter_recurse<'a>(
         }
     };
 
-    *sub = DBusMessageIter {
-        inner: Box::into_raw(Box::new(iter)),
-        counter: 0,
-        msg: parent.msg,
-    }
+    DBusMessageIter::init(sub, iter, 0, parent.msg);
 }
 
 #[no_mangle]
@@ -470,10 +580,7 @@ pub extern "C" fn dbus_message_iter_get_signature(
         return std::ptr::null();
     }
 
-    let cstr = std::ffi::CString::new(sigs_str.as_str()).unwrap();
-    // needs to be freed somehow in dbus_free
-    let ptr = cstr.into_raw();
-    ptr
+    crate::malloc_cstring(sigs_str.as_bytes())
 }
 
 #[no_mangle]
@@ -484,8 +591,11 @@ pub extern "C" fn dbus_message_iter_get_basic(
     if sub.is_null() {
         return;
     }
-    let string_arena = &mut unsafe { &mut *(&mut *sub).msg }.string_arena;
     let sub = unsafe { &mut *sub };
+    if sub.msg.is_null() {
+        return;
+    }
+    let string_arena = &mut unsafe { &mut *sub.msg }.string_arena;
 
     if let Some(RustbusParamOrDictEntry::Rustbus(params::Param::Base(base_param))) = sub.current() {
         crate::write_base_param(base_param, string_arena, arg);
@@ -505,10 +615,18 @@ pub extern "C" fn dbus_message_iter_get_element_count(sub: *mut DBusMessageIter)
 }
 #[no_mangle]
 pub extern "C" fn dbus_message_iter_get_fixed_array(
-    _sub: *mut DBusMessageIter,
-    _output: *mut std::ffi::c_void,
+    sub: *mut DBusMessageIter,
+    output: *mut std::ffi::c_void,
+    n_elements: *mut libc::c_int,
 ) {
-    unimplemented!();
+    // an empty array is the least surprising thing callers can find
+    if !output.is_null() {
+        unsafe { *(output as *mut *const std::ffi::c_void) = std::ptr::null() };
+    }
+    if !n_elements.is_null() {
+        unsafe { *n_elements = 0 };
+    }
+    unimplemented_abi!("dbus_message_iter_get_fixed_array", sub, output);
     // If this is really needed we need to somehow allocate memory since
     // we cant just point into our message struct.
     // One possibility would be to pass down a ref to the Message and
@@ -520,20 +638,15 @@ pub extern "C" fn dbus_message_iter_get_fixed_array(
 pub extern "C" fn dbus_message_iter_init_append<'a>(
     msg: *mut crate::DBusMessage<'a>,
     args: *mut DBusMessageIter<'a>,
-) -> u32 {
-    if args.is_null() {
-        return 0;
+) {
+    if args.is_null() || msg.is_null() {
+        return;
     }
-    let args = unsafe { &mut *args };
-    *args = DBusMessageIter {
-        inner: Box::into_raw(Box::new(MessageIterInternal::MainAppendIter(msg))),
-        counter: {
-            let msg = unsafe { &*msg };
-            msg.msg.params.len()
-        },
-        msg,
+    let counter = {
+        let msg = unsafe { &*msg };
+        msg.msg.params.len()
     };
-    1
+    DBusMessageIter::init(args, MessageIterInternal::MainAppendIter(msg), counter, msg);
 }
 
 #[no_mangle]
@@ -548,8 +661,7 @@ pub extern "C" fn dbus_message_iter_append_basic(
     let args = unsafe { &mut *args };
 
     if let Some(param) = crate::param_from_parts(argtyp, arg) {
-        args.append(param);
-        1
+        dbus_bool(args.append(param))
     } else {
         0
     }
@@ -561,49 +673,81 @@ pub extern "C" fn dbus_message_iter_open_container<'a>(
     argtyp: libc::c_int,
     argsig: *const libc::c_char,
     sub: *mut DBusMessageIter<'a>,
-) {
+) -> u32 {
     if parent.is_null() {
-        return;
+        return 0;
     }
     let parent = unsafe { &mut *parent };
     if sub.is_null() {
-        return;
+        return 0;
     }
-    let sub = unsafe { &mut *sub };
-    let c_str = unsafe {
-        assert!(!argsig.is_null());
-        CStr::from_ptr(argsig)
-    };
+    // so closing / abandoning the sub iterator is harmless if we fail below
+    DBusMessageIter::init(sub, MessageIterInternal::Closed, 0, parent.msg);
 
-    let argsig = c_str.to_str().unwrap();
-    let mut argsig = rustbus::signature::Type::parse_description(argsig).unwrap();
+    let mut argsig = if argsig.is_null() {
+        Vec::new()
+    } else {
+        let c_str = unsafe { CStr::from_ptr(argsig) };
+        match c_str
+            .to_str()
+            .ok()
+            .and_then(|s| rustbus::signature::Type::parse_description(s).ok())
+        {
+            Some(sig) => sig,
+            None => return 0,
+        }
+    };
     let typ = match argtyp {
-        crate::DBUS_TYPE_ARRAY => rustbus::signature::Container::Array(Box::new(argsig.remove(0))),
-        crate::DBUS_TYPE_STRUCT => rustbus::signature::Container::Struct(argsig),
-        crate::DBUS_TYPE_VARIANT => rustbus::signature::Container::Variant,
-        _ => return,
+        crate::DBUS_TYPE_ARRAY => {
+            if argsig.len() != 1 {
+                return 0;
+            }
+            match argsig.remove(0) {
+                // rustbus parses "{..}" as a whole dict
+                rustbus::signature::Type::Container(
+                    dict @ rustbus::signature::Container::Dict(_, _),
+                ) => SubAppendType::Container(dict),
+                elem => {
+                    SubAppendType::Container(rustbus::signature::Container::Array(Box::new(elem)))
+                }
+            }
+        }
+        crate::DBUS_TYPE_STRUCT => {
+            SubAppendType::Container(rustbus::signature::Container::Struct(argsig))
+        }
+        crate::DBUS_TYPE_VARIANT => {
+            if argsig.len() != 1 {
+                return 0;
+            }
+            SubAppendType::Container(rustbus::signature::Container::Variant)
+        }
+        crate::DBUS_TYPE_DICTENTRY => SubAppendType::DictEntry,
+        _ => return 0,
     };
 
-    *sub = DBusMessageIter {
-        inner: Box::into_raw(Box::new(MessageIterInternal::SubAppendIter(
-            SubAppendIter {
-                params: Vec::new(),
-                typ,
-            },
-        ))),
-        counter: 0,
-        msg: parent.msg,
-    }
+    DBusMessageIter::init(
+        sub,
+        MessageIterInternal::SubAppendIter(Box::new(SubAppendIter {
+            params: Vec::new(),
+            typ,
+        })),
+        0,
+        parent.msg,
+    );
+    1
 }
 
 #[no_mangle]
 pub extern "C" fn dbus_message_iter_close_container<'a>(
     parent: *mut DBusMessageIter<'a>,
     sub: *mut DBusMessageIter<'a>,
-) {
+) -> u32 {
+    if parent.is_null() || sub.is_null() {
+        return 0;
+    }
     let parent = unsafe { &mut *parent };
     let sub = unsafe { &mut *sub };
-    sub.close(parent);
+    dbus_bool(sub.close(parent))
 }
 
 #[no_mangle]
@@ -0,0 +1,349 @@
+//! Handlers registered for object paths, and the Introspect replies libdbus generates for the
+//! nodes in between them.
+
+use crate::connection::DBusConnection;
+use crate::error::*;
+use crate::*;
+use std::collections::BTreeMap;
+
+pub const DBUS_INTERFACE_INTROSPECTABLE: &str = "org.freedesktop.DBus.Introspectable";
+
+const INTROSPECT_DOCTYPE: &str = "<!DOCTYPE node PUBLIC \"-//freedesktop//DTD D-BUS Object Introspection 1.0//EN\"\n\"http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd\">\n";
+
+pub type DBusObjectPathUnregisterFunction =
+    extern "C" fn(*mut DBusConnection, *mut std::ffi::c_void);
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DBusObjectPathVTable {
+    pub unregister_function: Option<DBusObjectPathUnregisterFunction>,
+    pub message_function: Option<DBusHandleMessageFunction>,
+    // reserved in libdbus
+    pad1: Option<extern "C" fn(*mut std::ffi::c_void)>,
+    pad2: Option<extern "C" fn(*mut std::ffi::c_void)>,
+    pad3: Option<extern "C" fn(*mut std::ffi::c_void)>,
+    pad4: Option<extern "C" fn(*mut std::ffi::c_void)>,
+}
+
+pub struct ObjectHandler {
+    pub vtable: DBusObjectPathVTable,
+    pub user_data: *mut std::ffi::c_void,
+    /// Also handles all paths below this one that have no handler of their own
+    pub fallback: bool,
+    /// Interface XML the handler wants in the generated Introspect reply
+    pub introspection: Option<String>,
+}
+
+#[derive(Default)]
+pub struct ObjectTree {
+    handlers: BTreeMap<String, ObjectHandler>,
+}
+
+/// The path one level up, None for the root
+fn parent(path: &str) -> Option<&str> {
+    match path.rfind('/') {
+        _ if path == "/" => None,
+        Some(0) => Some("/"),
+        Some(idx) => Some(&path[..idx]),
+        None => None,
+    }
+}
+
+impl ObjectTree {
+    pub fn register(&mut self, path: &str, handler: ObjectHandler) -> Result<(), ObjectHandler> {
+        if self.handlers.contains_key(path) {
+            return Err(handler);
+        }
+        self.handlers.insert(path.to_owned(), handler);
+        Ok(())
+    }
+
+    pub fn unregister(&mut self, path: &str) -> Option<ObjectHandler> {
+        self.handlers.remove(path)
+    }
+
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut ObjectHandler> {
+        self.handlers.get_mut(path)
+    }
+
+    /// The handler registered at path, or else the closest fallback above it
+    pub fn find(&self, path: &str) -> Option<&ObjectHandler> {
+        if let Some(handler) = self.handlers.get(path) {
+            return Some(handler);
+        }
+        let mut path = parent(path);
+        while let Some(p) = path {
+            match self.handlers.get(p) {
+                Some(handler) if handler.fallback => return Some(handler),
+                _ => path = parent(p),
+            }
+        }
+        None
+    }
+
+    /// Names of the nodes directly below path that lead to registered handlers
+    pub fn children(&self, path: &str) -> Vec<String> {
+        let prefix = if path == "/" {
+            "/".to_owned()
+        } else {
+            format!("{}/", path)
+        };
+        let mut children: Vec<String> = self
+            .handlers
+            .range(prefix.clone()..)
+            .take_while(|(p, _)| p.starts_with(&prefix))
+            .filter_map(|(p, _)| p[prefix.len()..].split('/').next())
+            .filter(|child| !child.is_empty())
+            .map(str::to_owned)
+            .collect();
+        children.dedup();
+        children
+    }
+
+    /// Whether an object or a node leading to objects lives at path
+    pub fn exists(&self, path: &str) -> bool {
+        self.find(path).is_some() || !self.children(path).is_empty()
+    }
+
+    /// The reply to Introspect for a node the handlers did not answer themselves
+    pub fn introspect(&self, path: &str) -> String {
+        let mut xml = String::from(INTROSPECT_DOCTYPE);
+        xml.push_str("<node>\n");
+        if let Some(interfaces) = self
+            .handlers
+            .get(path)
+            .and_then(|h| h.introspection.as_ref())
+        {
+            xml.push_str(interfaces);
+            if !interfaces.ends_with('\n') {
+                xml.push('\n');
+            }
+        }
+        for child in self.children(path) {
+            xml.push_str(&format!("  <node name=\"{}\"/>\n", child));
+        }
+        xml.push_str("</node>\n");
+        xml
+    }
+
+    pub fn drain(&mut self) -> Vec<ObjectHandler> {
+        std::mem::take(&mut self.handlers).into_values().collect()
+    }
+}
+
+fn register(
+    con: *mut DBusConnection,
+    path: *const libc::c_char,
+    vtable: *const DBusObjectPathVTable,
+    user_data: *mut std::ffi::c_void,
+    fallback: bool,
+    err: *mut DBusError,
+) -> u32 {
+    if con.is_null() || vtable.is_null() {
+        return dbus_bool(false);
+    }
+    if crate::validate::dbus_validate_path(path, err) == 0 {
+        return dbus_bool(false);
+    }
+    let con = unsafe { &mut *con };
+    let path = unsafe { CStr::from_ptr(path) }.to_str().unwrap();
+    let handler = ObjectHandler {
+        vtable: unsafe { *vtable },
+        user_data,
+        fallback,
+        introspection: None,
+    };
+    match con.objects.register(path, handler) {
+        Ok(()) => dbus_bool(true),
+        Err(_) => {
+            set_error(
+                err,
+                DBUS_ERROR_OBJECT_PATH_IN_USE,
+                &format!("Object path {} already in use", path),
+            );
+            dbus_bool(false)
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_try_register_object_path(
+    con: *mut DBusConnection,
+    path: *const libc::c_char,
+    vtable: *const DBusObjectPathVTable,
+    user_data: *mut std::ffi::c_void,
+    err: *mut DBusError,
+) -> u32 {
+    register(con, path, vtable, user_data, false, err)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_register_object_path(
+    con: *mut DBusConnection,
+    path: *const libc::c_char,
+    vtable: *const DBusObjectPathVTable,
+    user_data: *mut std::ffi::c_void,
+) -> u32 {
+    register(con, path, vtable, user_data, false, std::ptr::null_mut())
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_try_register_fallback(
+    con: *mut DBusConnection,
+    path: *const libc::c_char,
+    vtable: *const DBusObjectPathVTable,
+    user_data: *mut std::ffi::c_void,
+    err: *mut DBusError,
+) -> u32 {
+    register(con, path, vtable, user_data, true, err)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_register_fallback(
+    con: *mut DBusConnection,
+    path: *const libc::c_char,
+    vtable: *const DBusObjectPathVTable,
+    user_data: *mut std::ffi::c_void,
+) -> u32 {
+    register(con, path, vtable, user_data, true, std::ptr::null_mut())
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_unregister_object_path(
+    con: *mut DBusConnection,
+    path: *const libc::c_char,
+) -> u32 {
+    if con.is_null() || path.is_null() {
+        return dbus_bool(false);
+    }
+    let con_ref = unsafe { &mut *con };
+    let path = unsafe { CStr::from_ptr(path) }.to_str().unwrap_or("");
+    match con_ref.objects.unregister(path) {
+        Some(handler) => {
+            if let Some(unregister) = handler.vtable.unregister_function {
+                unregister(con, handler.user_data);
+            }
+            dbus_bool(true)
+        }
+        None => dbus_bool(false),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_get_object_path_data(
+    con: *mut DBusConnection,
+    path: *const libc::c_char,
+    data: *mut *mut std::ffi::c_void,
+) -> u32 {
+    if con.is_null() || path.is_null() || data.is_null() {
+        return dbus_bool(false);
+    }
+    let con = unsafe { &mut *con };
+    let path = unsafe { CStr::from_ptr(path) }.to_str().unwrap_or("");
+    let user_data = con
+        .objects
+        .find(path)
+        .map(|h| h.user_data)
+        .unwrap_or(std::ptr::null_mut());
+    unsafe { *data = user_data };
+    dbus_bool(true)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_connection_list_registered(
+    con: *mut DBusConnection,
+    parent_path: *const libc::c_char,
+    child_entries: *mut *mut *mut libc::c_char,
+) -> u32 {
+    if con.is_null() || parent_path.is_null() || child_entries.is_null() {
+        return dbus_bool(false);
+    }
+    let con = unsafe { &mut *con };
+    let path = unsafe { CStr::from_ptr(parent_path) }
+        .to_str()
+        .unwrap_or("");
+    let children = con.objects.children(path);
+    unsafe { *child_entries = crate::malloc_string_array(&children) };
+    dbus_bool(true)
+}
+
+/// librdbus extension: interface XML that the generated Introspect reply for path contains. The
+/// handler has to be registered already. NULL removes the XML again.
+#[no_mangle]
+pub extern "C" fn rdbus_connection_set_object_path_introspection(
+    con: *mut DBusConnection,
+    path: *const libc::c_char,
+    xml: *const libc::c_char,
+) -> u32 {
+    if con.is_null() || path.is_null() {
+        return dbus_bool(false);
+    }
+    let con = unsafe { &mut *con };
+    let path = unsafe { CStr::from_ptr(path) }.to_str().unwrap_or("");
+    let xml = if xml.is_null() {
+        None
+    } else {
+        match unsafe { CStr::from_ptr(xml) }.to_str() {
+            Ok(xml) => Some(xml.to_owned()),
+            Err(_) => return dbus_bool(false),
+        }
+    };
+    match con.objects.get_mut(path) {
+        Some(handler) => {
+            handler.introspection = xml;
+            dbus_bool(true)
+        }
+        None => dbus_bool(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn handle(
+        _con: *mut DBusConnection,
+        _msg: *mut DBusMessage,
+        _data: *mut std::ffi::c_void,
+    ) -> DBusHandlerResult {
+        DBusHandlerResult::DBUS_HANDLER_RESULT_NOT_YET_HANDLED
+    }
+
+    fn handler(fallback: bool) -> ObjectHandler {
+        ObjectHandler {
+            vtable: DBusObjectPathVTable {
+                unregister_function: None,
+                message_function: Some(handle),
+                pad1: None,
+                pad2: None,
+                pad3: None,
+                pad4: None,
+            },
+            user_data: std::ptr::null_mut(),
+            fallback,
+            introspection: None,
+        }
+    }
+
+    #[test]
+    fn fallbacks_and_children() {
+        let mut tree = ObjectTree::default();
+        assert!(tree.register("/io/rdbus/a", handler(false)).is_ok());
+        assert!(tree.register("/io/rdbus/b/deep", handler(false)).is_ok());
+        assert!(tree.register("/io/rdbusx", handler(true)).is_ok());
+        assert!(tree.register("/io/rdbus/a", handler(false)).is_err());
+
+        assert_eq!(tree.children("/"), vec!["io"]);
+        assert_eq!(tree.children("/io"), vec!["rdbus", "rdbusx"]);
+        assert_eq!(tree.children("/io/rdbus"), vec!["a", "b"]);
+
+        assert!(tree.find("/io/rdbus/a").is_some());
+        assert!(tree.find("/io/rdbus/a/below").is_none());
+        assert!(tree.find("/io/rdbusx/below/that").is_some());
+        assert!(tree.exists("/io/rdbus/b"));
+        assert!(!tree.exists("/elsewhere"));
+
+        let xml = tree.introspect("/io/rdbus");
+        assert!(xml.contains("<node name=\"a\"/>\n  <node name=\"b\"/>"));
+    }
+}
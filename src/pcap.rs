@@ -0,0 +1,166 @@
+//! Captures of the messages connections send and receive, in the pcap format with LINKTYPE_DBUS
+//! that `dbus-monitor --pcap` writes and Wireshark reads. RDBUS_PCAP=/some/file captures every
+//! connection of the process, rdbus_connection_set_pcap_file single connections.
+
+use crate::connection::DBusConnection;
+use crate::trace::trace;
+use crate::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+pub const LINKTYPE_DBUS: u32 = 231;
+
+/// The largest message the spec allows, nothing gets cut off
+const SNAPLEN: u32 = 128 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct Capture {
+    out: Mutex<BufWriter<File>>,
+}
+
+impl Capture {
+    /// Create or truncate the file and write the pcap header
+    pub fn create(path: &Path) -> std::io::Result<Capture> {
+        Self::new(File::create(path)?)
+    }
+
+    /// Write the pcap header to an open file, stdout for example
+    pub fn new(file: File) -> std::io::Result<Capture> {
+        let mut out = BufWriter::new(file);
+        out.write_all(&0xa1b2_c3d4u32.to_ne_bytes())?;
+        out.write_all(&2u16.to_ne_bytes())?;
+        out.write_all(&4u16.to_ne_bytes())?;
+        // timezone offset and timestamp accuracy
+        out.write_all(&0i32.to_ne_bytes())?;
+        out.write_all(&0u32.to_ne_bytes())?;
+        out.write_all(&SNAPLEN.to_ne_bytes())?;
+        out.write_all(&LINKTYPE_DBUS.to_ne_bytes())?;
+        out.flush()?;
+        Ok(Capture {
+            out: Mutex::new(out),
+        })
+    }
+
+    /// Add one marshalled message. Every record is flushed so captures of crashing programs are
+    /// complete.
+    pub fn write_message(&self, bytes: &[u8]) -> std::io::Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let len = bytes.len() as u32;
+        let mut out = self.out.lock().unwrap();
+        out.write_all(&(now.as_secs() as u32).to_ne_bytes())?;
+        out.write_all(&now.subsec_micros().to_ne_bytes())?;
+        out.write_all(&len.to_ne_bytes())?;
+        out.write_all(&len.to_ne_bytes())?;
+        out.write_all(bytes)?;
+        out.flush()
+    }
+}
+
+/// The capture RDBUS_PCAP asks for. All connections share it, opening the file once per
+/// connection would truncate what the others wrote.
+pub fn from_env() -> Option<Arc<Capture>> {
+    static ENV_CAPTURE: OnceLock<Option<Arc<Capture>>> = OnceLock::new();
+    ENV_CAPTURE
+        .get_or_init(|| {
+            let path = std::env::var_os("RDBUS_PCAP")?;
+            match Capture::create(Path::new(&path)) {
+                Ok(capture) => Some(Arc::new(capture)),
+                Err(e) => {
+                    trace!("pcap", "can not capture to {:?}: {}", path, e);
+                    None
+                }
+            }
+        })
+        .clone()
+}
+
+/// librdbus extension: write everything con sends and receives from now on to a new pcap file
+/// at path. NULL stops capturing.
+#[no_mangle]
+pub extern "C" fn rdbus_connection_set_pcap_file(
+    con: *mut DBusConnection,
+    path: *const libc::c_char,
+) -> u32 {
+    if con.is_null() {
+        return dbus_bool(false);
+    }
+    let con = unsafe { &mut *con };
+    if path.is_null() {
+        con.con.capture = None;
+        return dbus_bool(true);
+    }
+    let path = match unsafe { CStr::from_ptr(path) }.to_str() {
+        Ok(path) => path,
+        Err(_) => return dbus_bool(false),
+    };
+    match Capture::create(Path::new(path)) {
+        Ok(capture) => {
+            con.con.capture = Some(Arc::new(capture));
+            dbus_bool(true)
+        }
+        Err(e) => {
+            trace!("pcap", "can not capture to {:?}: {}", path, e);
+            dbus_bool(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Transport;
+    use std::os::unix::net::UnixStream;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        let mut word = [0u8; 4];
+        word.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_ne_bytes(word)
+    }
+
+    #[test]
+    fn captures_sent_and_received_messages() {
+        let path = std::env::temp_dir().join(format!("rdbus-test-{}.pcap", std::process::id()));
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut a = Transport::from_stream(a);
+        let mut b = Transport::from_stream(b);
+        a.capture = Some(Arc::new(Capture::create(&path).unwrap()));
+
+        let mut ping = rustbus::message_builder::MessageBuilder::new()
+            .signal(
+                "io.rdbus.Test".to_owned(),
+                "Ping".to_owned(),
+                "/io/rdbus".to_owned(),
+            )
+            .build();
+        a.send_message(&mut ping, None).unwrap();
+        let mut pong = ping.clone();
+        pong.serial = None;
+        pong.push_param("pong");
+        b.send_message(&mut pong, None).unwrap();
+        b.get_next_message(None).unwrap();
+        a.get_next_message(None).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(u32_at(&bytes, 0), 0xa1b2_c3d4);
+        assert_eq!(u32_at(&bytes, 20), LINKTYPE_DBUS);
+
+        let mut offset = 24;
+        let mut records = Vec::new();
+        while offset < bytes.len() {
+            let len = u32_at(&bytes, offset + 8) as usize;
+            assert_eq!(u32_at(&bytes, offset + 12) as usize, len);
+            let record = &bytes[offset + 16..offset + 16 + len];
+            records.push(crate::message::demarshal(record).unwrap());
+            offset += 16 + len;
+        }
+        assert_eq!(offset, bytes.len());
+        assert_eq!(records.len(), 2);
+        assert!(records[0].params.is_empty());
+        assert_eq!(records[1].params.len(), 1);
+    }
+}
@@ -0,0 +1,312 @@
+//! Messages built from the arguments dbus-send takes, e.g.
+//! `--dest=org.example.Service /org/example org.example.Iface.Method string:hi array:int32:1,2`.
+//! Only the options that end up in the message are understood here, --print-reply and friends
+//! are for the program that sends it.
+
+use crate::error::*;
+use crate::message::DBusMessage;
+use crate::validate::{check_bus_name, check_interface, check_member, check_path};
+use crate::*;
+use rustbus::params::{Array, Base, Container, Dict, Param, Variant};
+use rustbus::signature;
+
+type Message = rustbus::Message<'static, 'static>;
+
+fn basic_type(name: &str) -> Result<signature::Base, String> {
+    Ok(match name {
+        "string" => signature::Base::String,
+        "objpath" => signature::Base::ObjectPath,
+        "byte" => signature::Base::Byte,
+        "boolean" => signature::Base::Boolean,
+        "int16" => signature::Base::Int16,
+        "uint16" => signature::Base::Uint16,
+        "int32" => signature::Base::Int32,
+        "uint32" => signature::Base::Uint32,
+        "int64" => signature::Base::Int64,
+        "uint64" => signature::Base::Uint64,
+        "double" => signature::Base::Double,
+        _ => return Err(format!("Unknown type \"{}\"", name)),
+    })
+}
+
+fn number<T: std::str::FromStr>(value: &str, typ: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("\"{}\" is not a valid {}", value, typ))
+}
+
+fn basic_value(typ: signature::Base, value: &str) -> Result<Base<'static>, String> {
+    Ok(match typ {
+        signature::Base::String => Base::String(value.to_owned()),
+        signature::Base::ObjectPath => {
+            check_path(value)?;
+            Base::ObjectPath(value.to_owned())
+        }
+        signature::Base::Byte => Base::Byte(number(value, "byte")?),
+        signature::Base::Boolean => match value {
+            "true" => Base::Boolean(true),
+            "false" => Base::Boolean(false),
+            _ => return Err(format!("\"{}\" is not a valid boolean", value)),
+        },
+        signature::Base::Int16 => Base::Int16(number(value, "int16")?),
+        signature::Base::Uint16 => Base::Uint16(number(value, "uint16")?),
+        signature::Base::Int32 => Base::Int32(number(value, "int32")?),
+        signature::Base::Uint32 => Base::Uint32(number(value, "uint32")?),
+        signature::Base::Int64 => Base::Int64(number(value, "int64")?),
+        signature::Base::Uint64 => Base::Uint64(number(value, "uint64")?),
+        signature::Base::Double => Base::Double(number::<f64>(value, "double")?.to_bits()),
+        _ => return Err(format!("Unsupported type {:?}", typ)),
+    })
+}
+
+/// Comma separated values, nothing at all is the empty list
+fn items(values: &str) -> Vec<&str> {
+    if values.is_empty() {
+        Vec::new()
+    } else {
+        values.split(',').collect()
+    }
+}
+
+/// One argument: `type:value`, `array:type:v1,v2`, `dict:ktype:vtype:k1,v1,k2,v2` or
+/// `variant:type:value`. Containers only hold basic types, like in dbus-send.
+// usize::is_multiple_of is only stable since Rust 1.87
+#[allow(clippy::manual_is_multiple_of)]
+pub fn parse_arg(arg: &str) -> Result<Param<'static, 'static>, String> {
+    let (typ, rest) = arg
+        .split_once(':')
+        .ok_or_else(|| format!("Argument \"{}\" has no type", arg))?;
+    match typ {
+        "array" => {
+            let (element, values) = rest
+                .split_once(':')
+                .ok_or_else(|| format!("Array \"{}\" has no element type", arg))?;
+            let element = basic_type(element)?;
+            let values = items(values)
+                .into_iter()
+                .map(|v| basic_value(element, v).map(Param::Base))
+                .collect::<Result<_, _>>()?;
+            Ok(Param::Container(Container::Array(Array {
+                element_sig: signature::Type::Base(element),
+                values,
+            })))
+        }
+        "dict" => {
+            let mut parts = rest.splitn(3, ':');
+            let (key, value, entries) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(key), Some(value), Some(entries)) => (key, value, entries),
+                _ => return Err(format!("Dict \"{}\" needs a key and a value type", arg)),
+            };
+            let (key, value) = (basic_type(key)?, basic_type(value)?);
+            let entries = items(entries);
+            if entries.len() % 2 != 0 {
+                return Err(format!("Dict \"{}\" has a key without value", arg));
+            }
+            let mut map = std::collections::HashMap::new();
+            for pair in entries.chunks(2) {
+                map.insert(
+                    basic_value(key, pair[0])?,
+                    Param::Base(basic_value(value, pair[1])?),
+                );
+            }
+            Ok(Param::Container(Container::Dict(Dict {
+                key_sig: key,
+                value_sig: signature::Type::Base(value),
+                map,
+            })))
+        }
+        "variant" => {
+            let (inner, value) = rest
+                .split_once(':')
+                .ok_or_else(|| format!("Variant \"{}\" has no value type", arg))?;
+            let inner = basic_type(inner)?;
+            Ok(Param::Container(Container::Variant(Box::new(Variant {
+                sig: signature::Type::Base(inner),
+                value: Param::Base(basic_value(inner, value)?),
+            }))))
+        }
+        _ => Ok(Param::Base(basic_value(basic_type(typ)?, rest)?)),
+    }
+}
+
+/// `[--type=method_call|signal] [--dest=NAME] PATH INTERFACE.MEMBER [ARG...]`. Without --type
+/// the message is a signal, like in dbus-send.
+pub fn parse_message<S: AsRef<str>>(args: &[S]) -> Result<Message, String> {
+    let mut call = false;
+    let mut dest = None;
+    let mut args = args.iter().map(AsRef::as_ref).peekable();
+    while let Some(option) = args.next_if(|arg| arg.starts_with("--")) {
+        if let Some(typ) = option.strip_prefix("--type=") {
+            call = match typ {
+                "method_call" => true,
+                "signal" => false,
+                _ => return Err(format!("Message type \"{}\" is not supported", typ)),
+            };
+        } else if let Some(name) = option.strip_prefix("--dest=") {
+            check_bus_name(name)?;
+            dest = Some(name.to_owned());
+        } else {
+            return Err(format!("Unknown option \"{}\"", option));
+        }
+    }
+
+    let path = args.next().ok_or("Expected an object path")?;
+    check_path(path)?;
+    let name = args.next().ok_or("Expected INTERFACE.MEMBER")?;
+    let (interface, member) = name
+        .rsplit_once('.')
+        .ok_or_else(|| format!("\"{}\" is not INTERFACE.MEMBER", name))?;
+    check_interface(interface)?;
+    check_member(member)?;
+
+    let builder = rustbus::message_builder::MessageBuilder::new();
+    let mut msg = if call {
+        builder
+            .call(member.to_owned())
+            .on(path.to_owned())
+            .with_interface(interface.to_owned())
+            .build()
+    } else {
+        builder
+            .signal(interface.to_owned(), member.to_owned(), path.to_owned())
+            .build()
+    };
+    msg.destination = dest;
+    for arg in args {
+        msg.push_param(parse_arg(arg)?);
+    }
+    Ok(msg)
+}
+
+/// librdbus extension: a new message from dbus-send style arguments, see parse_message. Sets an
+/// InvalidArgs error and returns NULL if they do not make a message.
+#[no_mangle]
+pub extern "C" fn rdbus_message_new_from_args<'a>(
+    argc: libc::c_int,
+    argv: *const *const libc::c_char,
+    err: *mut DBusError,
+) -> *mut DBusMessage<'a> {
+    if argc < 0 || (argc > 0 && argv.is_null()) {
+        set_error(err, DBUS_ERROR_INVALID_ARGS, "No argument vector");
+        return std::ptr::null_mut();
+    }
+    let mut args = Vec::with_capacity(argc as usize);
+    for idx in 0..argc as usize {
+        let arg = unsafe { *argv.add(idx) };
+        if arg.is_null() {
+            set_error(
+                err,
+                DBUS_ERROR_INVALID_ARGS,
+                &format!("Argument {} is NULL", idx),
+            );
+            return std::ptr::null_mut();
+        }
+        match unsafe { CStr::from_ptr(arg) }.to_str() {
+            Ok(arg) => args.push(arg),
+            Err(_) => {
+                set_error(err, DBUS_ERROR_INVALID_ARGS, "Argument was not valid UTF-8");
+                return std::ptr::null_mut();
+            }
+        }
+    }
+    match parse_message(&args) {
+        Ok(msg) => Box::into_raw(Box::new(DBusMessage::new(msg))),
+        Err(e) => {
+            set_error(err, DBUS_ERROR_INVALID_ARGS, &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dbus_send_arguments() {
+        let msg = parse_message(&[
+            "--type=method_call",
+            "--dest=io.rdbus.Service",
+            "/io/rdbus",
+            "io.rdbus.Test.Frob",
+            "string:a:b",
+            "int32:-5",
+            "array:string:a,b",
+            "dict:string:int32:one,1",
+            "variant:boolean:true",
+            "objpath:/x",
+            "double:1.5",
+        ])
+        .unwrap();
+        let msg = DBusMessage::new(msg);
+        assert_eq!(
+            crate::dump::dump_message(&msg),
+            "method call sender=(null sender) -> destination=io.rdbus.Service serial=0 \
+             path=/io/rdbus; interface=io.rdbus.Test; member=Frob
+   string \"a:b\"
+   int32 -5
+   array [
+      string \"a\"
+      string \"b\"
+   ]
+   array [
+      dict entry(
+         string \"one\"
+         int32 1
+      )
+   ]
+   variant boolean true
+   object path \"/x\"
+   double 1.5
+"
+        );
+
+        let signal = parse_message(&["/", "io.rdbus.Test.Ping", "array:uint32:"]).unwrap();
+        assert!(matches!(signal.typ, rustbus::MessageType::Signal));
+        assert_eq!(signal.destination, None);
+        assert_eq!(
+            signal.params,
+            vec![Param::Container(Container::Array(Array {
+                element_sig: signature::Type::Base(signature::Base::Uint32),
+                values: Vec::new(),
+            }))]
+        );
+
+        for invalid in &[
+            &["/", "NoMember"][..],
+            &["relative", "io.rdbus.Test.Ping"],
+            &["--print-reply", "/", "io.rdbus.Test.Ping"],
+            &["/", "io.rdbus.Test.Ping", "int32:five"],
+            &["/", "io.rdbus.Test.Ping", "byte:256"],
+            &["/", "io.rdbus.Test.Ping", "dict:string:int32:a"],
+            &["/", "io.rdbus.Test.Ping", "float:1"],
+            &["/", "io.rdbus.Test.Ping", "untyped"],
+        ] {
+            assert!(parse_message(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn new_from_args_reports_bad_vectors() {
+        let path = std::ffi::CString::new("/").unwrap();
+        let member = std::ffi::CString::new("io.rdbus.Test.Ping").unwrap();
+        let valid = [path.as_ptr(), member.as_ptr()];
+        let with_null = [path.as_ptr(), std::ptr::null()];
+        for (argc, argv) in [
+            (-1, valid.as_ptr()),
+            (2, std::ptr::null()),
+            (2, with_null.as_ptr()),
+        ] {
+            let mut err = std::mem::MaybeUninit::<DBusError>::uninit();
+            dbus_error_init(err.as_mut_ptr());
+            let mut err = unsafe { err.assume_init() };
+            assert!(rdbus_message_new_from_args(argc, argv, &mut err).is_null());
+            assert_eq!(err.name(), DBUS_ERROR_INVALID_ARGS, "{}", argc);
+            dbus_error_free(&mut err);
+        }
+
+        let msg = rdbus_message_new_from_args(2, valid.as_ptr(), std::ptr::null_mut());
+        assert!(!msg.is_null());
+        crate::message::dbus_message_unref(msg);
+    }
+}
@@ -0,0 +1,488 @@
+//! The listening side of peer-to-peer connections. The application polls the listening socket
+//! through a watch and gets every accepted client from its new connection function.
+
+use crate::connection::DBusConnection;
+use crate::error::*;
+use crate::trace::trace;
+use crate::watch::{DBusWatch, WatchFunctions};
+use crate::*;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+
+/// Gets the connections the server accepts
+pub struct NewConnectionFunction {
+    function: DBusNewConnectionFunction,
+    user_data: *mut std::ffi::c_void,
+    free: Option<DBusFreeFunction>,
+}
+
+impl Drop for NewConnectionFunction {
+    fn drop(&mut self) {
+        if let Some(free) = self.free {
+            free(self.user_data);
+        }
+    }
+}
+
+pub struct DBusServer {
+    ref_count: u64,
+    /// None once disconnected
+    listener: Option<UnixListener>,
+    path: PathBuf,
+    guid: String,
+    pub new_connection_function: Option<NewConnectionFunction>,
+    pub watch_functions: Option<WatchFunctions>,
+    /// The watch on the listener while the watch functions have it
+    watch: Option<Box<DBusWatch>>,
+    pub data: crate::data_slot::DataSlots,
+}
+
+impl DBusServer {
+    /// Listen on the first entry of a `;` separated address list that works
+    pub fn listen(address: &str) -> Result<Self, Error> {
+        let mut error = Error::new(DBUS_ERROR_BAD_ADDRESS, "Empty address");
+        for entry in address.split(';').filter(|entry| !entry.is_empty()) {
+            match crate::transport::listen(entry) {
+                Ok((listener, path)) => {
+                    // dbus_watch_handle must not block if another process took the client
+                    if let Err(e) = listener.set_nonblocking(true) {
+                        let _ = std::fs::remove_file(&path);
+                        return Err(Error::new(DBUS_ERROR_FAILED, e.to_string()));
+                    }
+                    return Ok(Self {
+                        ref_count: 1,
+                        listener: Some(listener),
+                        path,
+                        guid: crate::auth::new_guid(),
+                        new_connection_function: None,
+                        watch_functions: None,
+                        watch: None,
+                        data: crate::data_slot::DataSlots::default(),
+                    });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                    error = Error::new(DBUS_ERROR_BAD_ADDRESS, e.to_string());
+                }
+                Err(e) => {
+                    error = Error::new(
+                        DBUS_ERROR_FAILED,
+                        format!("Can not listen on {}: {}", entry, e),
+                    );
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// Hand a watch on the listener to the add watch function. False if the function failed.
+    fn add_watch(&mut self) -> bool {
+        let owner = self as *mut Self as *mut std::ffi::c_void;
+        let (listener, functions) = match (&self.listener, &self.watch_functions) {
+            (Some(listener), Some(functions)) => (listener, functions),
+            _ => return true,
+        };
+        let mut watch = Box::new(DBusWatch::new(
+            listener.as_raw_fd(),
+            crate::watch::DBUS_WATCH_READABLE,
+            handle_watch,
+            owner,
+        ));
+        if (functions.add)(&mut *watch, functions.data) == 0 {
+            return false;
+        }
+        self.watch = Some(watch);
+        true
+    }
+
+    fn remove_watch(&mut self) {
+        if let Some(mut watch) = self.watch.take() {
+            if let Some(functions) = &self.watch_functions {
+                if let Some(remove) = functions.remove {
+                    remove(&mut *watch, functions.data);
+                }
+            }
+        }
+    }
+
+    /// Accept a waiting client and hand it to the new connection function. Like in libdbus the
+    /// connection is dropped again unless the function takes a reference.
+    fn accept(&mut self) {
+        let stream = match self.listener.as_ref().map(UnixListener::accept) {
+            Some(Ok((stream, _))) => stream,
+            Some(Err(e)) => {
+                trace!("server", "accepting a client failed: {}", e);
+                return;
+            }
+            None => return,
+        };
+        let transport = crate::transport::Transport::from_accepted(stream, self.guid.clone());
+        let con = Box::into_raw(Box::new(DBusConnection::new(transport)));
+        let function = self
+            .new_connection_function
+            .as_ref()
+            .map(|f| (f.function, f.user_data));
+        match function {
+            Some((function, user_data)) => function(self, con, user_data),
+            None => trace!("server", "no new connection function, dropping the client"),
+        }
+        crate::connection::dbus_connection_unref(con);
+    }
+
+    /// Stop listening and remove the socket
+    pub fn disconnect(&mut self) {
+        self.remove_watch();
+        if self.listener.take().is_some() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    pub fn address(&self) -> String {
+        format!("unix:path={},guid={}", self.path.display(), self.guid)
+    }
+}
+
+/// dbus_watch_handle on the watch of the listener
+fn handle_watch(server: *mut std::ffi::c_void, _flags: u32) -> bool {
+    let server = server as *mut DBusServer;
+    // the new connection function may drop the last reference the application holds
+    dbus_server_ref(server);
+    unsafe { &mut *server }.accept();
+    dbus_server_unref(server);
+    true
+}
+
+impl Drop for DBusServer {
+    fn drop(&mut self) {
+        self.disconnect();
+        self.data.clear();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_server_listen(
+    address: *const libc::c_char,
+    err: *mut DBusError,
+) -> *mut DBusServer {
+    if address.is_null() {
+        set_error(err, DBUS_ERROR_INVALID_ARGS, "Address must not be NULL");
+        return std::ptr::null_mut();
+    }
+    let server = match unsafe { CStr::from_ptr(address) }.to_str() {
+        Ok(address) => DBusServer::listen(address),
+        Err(_) => Err(Error::new(
+            DBUS_ERROR_BAD_ADDRESS,
+            "Address was not valid UTF-8",
+        )),
+    };
+    match server {
+        Ok(server) => Box::into_raw(Box::new(server)),
+        Err(e) => {
+            e.set(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_server_ref(server: *mut DBusServer) -> *mut DBusServer {
+    if server.is_null() {
+        return server;
+    }
+    let s = unsafe { &mut *server };
+    s.ref_count += 1;
+    server
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_server_unref(server: *mut DBusServer) {
+    if server.is_null() {
+        return;
+    }
+    let s = unsafe { &mut *server };
+    s.ref_count -= 1;
+    if s.ref_count == 0 {
+        drop(unsafe { Box::from_raw(server) });
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_server_disconnect(server: *mut DBusServer) {
+    if server.is_null() {
+        return;
+    }
+    unsafe { &mut *server }.disconnect();
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_server_get_is_connected(server: *mut DBusServer) -> u32 {
+    if server.is_null() {
+        return dbus_bool(false);
+    }
+    dbus_bool(unsafe { &*server }.listener.is_some())
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_server_set_new_connection_function(
+    server: *mut DBusServer,
+    function: Option<DBusNewConnectionFunction>,
+    data: *mut std::ffi::c_void,
+    free_data_function: Option<DBusFreeFunction>,
+) {
+    if server.is_null() {
+        return;
+    }
+    let server = unsafe { &mut *server };
+    // dropping the old function frees its data
+    server.new_connection_function = function.map(|function| NewConnectionFunction {
+        function,
+        user_data: data,
+        free: free_data_function,
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_server_set_watch_functions(
+    server: *mut DBusServer,
+    add_function: Option<DBusAddWatchFunction>,
+    remove_function: Option<DBusRemoveWatchFunction>,
+    toggled_function: Option<DBusWatchToggledFunction>,
+    data: *mut std::ffi::c_void,
+    free_data_function: Option<DBusFreeFunction>,
+) -> u32 {
+    if server.is_null() {
+        return dbus_bool(false);
+    }
+    let server = unsafe { &mut *server };
+    server.remove_watch();
+    server.watch_functions = add_function.map(|add| WatchFunctions {
+        add,
+        remove: remove_function,
+        toggled: toggled_function,
+        data,
+        free: free_data_function,
+    });
+    dbus_bool(server.add_watch())
+}
+
+/// The address clients can connect to, free it with dbus_free
+#[no_mangle]
+pub extern "C" fn dbus_server_get_address(server: *mut DBusServer) -> *mut libc::c_char {
+    if server.is_null() {
+        return std::ptr::null_mut();
+    }
+    crate::malloc_cstring(unsafe { &*server }.address().as_bytes())
+}
+
+/// The GUID of the server, free it with dbus_free
+#[no_mangle]
+pub extern "C" fn dbus_server_get_id(server: *mut DBusServer) -> *mut libc::c_char {
+    if server.is_null() {
+        return std::ptr::null_mut();
+    }
+    crate::malloc_cstring(unsafe { &*server }.guid.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    fn c_string(ptr: *mut libc::c_char) -> String {
+        let s = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_owned();
+        crate::dbus_free(ptr.cast());
+        s
+    }
+
+    #[test]
+    fn listen_and_disconnect() {
+        let dir = std::env::temp_dir();
+        let address = CString::new(format!("unix:tmpdir={}", dir.display())).unwrap();
+        let server = dbus_server_listen(address.as_ptr(), std::ptr::null_mut());
+        assert!(!server.is_null());
+        assert_eq!(dbus_server_get_is_connected(server), 1);
+
+        let id = c_string(dbus_server_get_id(server));
+        assert_eq!(id.len(), 32);
+        let address = c_string(dbus_server_get_address(server));
+        assert!(address.ends_with(&format!(",guid={}", id)), "{}", address);
+        let path = crate::transport::address_path(&address).unwrap();
+        assert!(path.starts_with(&dir));
+        std::os::unix::net::UnixStream::connect(&path).unwrap();
+
+        dbus_server_disconnect(server);
+        assert_eq!(dbus_server_get_is_connected(server), 0);
+        assert!(!path.exists());
+        dbus_server_unref(server);
+    }
+
+    #[test]
+    fn listen_fails_on_bad_addresses() {
+        let mut err = std::mem::MaybeUninit::<DBusError>::uninit();
+        dbus_error_init(err.as_mut_ptr());
+        let mut err = unsafe { err.assume_init() };
+
+        let address = CString::new("tcp:host=localhost,port=0").unwrap();
+        assert!(dbus_server_listen(address.as_ptr(), &mut err).is_null());
+        assert_eq!(err.name(), DBUS_ERROR_BAD_ADDRESS);
+        dbus_error_free(&mut err);
+
+        let address = CString::new("unix:path=/nonexistent/dir/socket").unwrap();
+        assert!(dbus_server_listen(address.as_ptr(), &mut err).is_null());
+        assert_eq!(err.name(), DBUS_ERROR_FAILED);
+        dbus_error_free(&mut err);
+    }
+
+    extern "C" fn add_watch(watch: *mut DBusWatch, slot: *mut std::ffi::c_void) -> u32 {
+        unsafe { *(slot as *mut *mut DBusWatch) = watch };
+        1
+    }
+
+    extern "C" fn remove_watch(_watch: *mut DBusWatch, slot: *mut std::ffi::c_void) {
+        unsafe { *(slot as *mut *mut DBusWatch) = std::ptr::null_mut() };
+    }
+
+    extern "C" fn keep_connection(
+        _server: *mut DBusServer,
+        con: *mut DBusConnection,
+        slot: *mut std::ffi::c_void,
+    ) {
+        let con = crate::connection::dbus_connection_ref(con);
+        unsafe { *(slot as *mut *mut DBusConnection) = con };
+    }
+
+    /// What the unix user function saw and what it answers
+    struct Authorization {
+        allow: bool,
+        uid: Option<libc::c_ulong>,
+    }
+
+    extern "C" fn authorize(
+        _con: *mut DBusConnection,
+        uid: libc::c_ulong,
+        authorization: *mut std::ffi::c_void,
+    ) -> u32 {
+        let authorization = unsafe { &mut *(authorization as *mut Authorization) };
+        authorization.uid = Some(uid);
+        dbus_bool(authorization.allow)
+    }
+
+    /// A server on a fresh socket whose watch and new connections end up in the slots
+    fn server(
+        watch: &mut *mut DBusWatch,
+        con: &mut *mut DBusConnection<'static>,
+    ) -> *mut DBusServer {
+        let address =
+            CString::new(format!("unix:tmpdir={}", std::env::temp_dir().display())).unwrap();
+        let server = dbus_server_listen(address.as_ptr(), std::ptr::null_mut());
+        let watch: *mut *mut DBusWatch = watch;
+        let con: *mut *mut DBusConnection = con;
+        assert_eq!(
+            dbus_server_set_watch_functions(
+                server,
+                Some(add_watch),
+                Some(remove_watch),
+                None,
+                watch.cast(),
+                None
+            ),
+            1
+        );
+        dbus_server_set_new_connection_function(server, Some(keep_connection), con.cast(), None);
+        server
+    }
+
+    /// Wait for a client on the watch and accept it
+    fn accept(watch: *mut DBusWatch) {
+        let mut pollfd = libc::pollfd {
+            fd: crate::watch::dbus_watch_get_unix_fd(watch),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pollfd, 1, -1) };
+        assert_eq!(
+            crate::watch::dbus_watch_handle(watch, crate::watch::DBUS_WATCH_READABLE),
+            1
+        );
+    }
+
+    /// Connect to address, true if the server let the client in
+    fn connect(address: &str) -> std::thread::JoinHandle<bool> {
+        let address = CString::new(address).unwrap();
+        std::thread::spawn(move || {
+            let con =
+                crate::connection::dbus_connection_open(address.as_ptr(), std::ptr::null_mut());
+            let connected = !con.is_null();
+            crate::connection::dbus_connection_unref(con);
+            connected
+        })
+    }
+
+    #[test]
+    fn unix_user_function_decides_who_connects() {
+        for allow in [true, false].iter().copied() {
+            let mut watch = std::ptr::null_mut();
+            let mut con = std::ptr::null_mut();
+            let server = server(&mut watch, &mut con);
+            assert!(!watch.is_null());
+            let address = c_string(dbus_server_get_address(server));
+            let client = connect(&address);
+
+            accept(watch);
+            assert!(!con.is_null());
+            let mut authorization = Authorization { allow, uid: None };
+            crate::connection::dbus_connection_set_unix_user_function(
+                con,
+                Some(authorize),
+                (&mut authorization as *mut Authorization).cast(),
+                None,
+            );
+            while crate::connection::dbus_connection_read_write_dispatch(con, 10) != 0
+                && !client.is_finished()
+            {}
+
+            let connected = client.join().unwrap();
+            assert_eq!(
+                authorization.uid,
+                Some(unsafe { libc::getuid() } as libc::c_ulong)
+            );
+            assert_eq!(connected, allow);
+            if !allow {
+                assert_eq!(crate::connection::dbus_connection_get_is_connected(con), 0);
+            }
+            crate::connection::dbus_connection_unref(con);
+            dbus_server_unref(server);
+            // disconnecting removed the watch
+            assert!(watch.is_null());
+        }
+    }
+
+    #[test]
+    fn silent_clients_do_not_block() {
+        use std::time::{Duration, Instant};
+        let mut watch = std::ptr::null_mut();
+        let mut con = std::ptr::null_mut();
+        let server = server(&mut watch, &mut con);
+        let address = c_string(dbus_server_get_address(server));
+        let path = crate::transport::address_path(&address).unwrap();
+
+        let _silent = std::os::unix::net::UnixStream::connect(&path).unwrap();
+        accept(watch);
+        let start = Instant::now();
+        assert_eq!(crate::connection::dbus_connection_read_write(con, 50), 1);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(crate::connection::dbus_connection_get_is_connected(con), 1);
+        crate::connection::dbus_connection_unref(con);
+
+        // the server still takes other clients
+        let client = connect(&address);
+        accept(watch);
+        while crate::connection::dbus_connection_read_write_dispatch(con, 10) != 0
+            && !client.is_finished()
+        {}
+        assert!(client.join().unwrap());
+        crate::connection::dbus_connection_unref(con);
+        dbus_server_unref(server);
+    }
+}
@@ -0,0 +1,529 @@
+use crate::dbus_bool;
+use crate::error::*;
+use crate::trace::trace;
+use crate::*;
+use rustbus::signature::{Base, Container, Type};
+
+/// Walks a signature string without copying it, like libdbus the caller has to keep the
+/// signature alive while the iterator is used.
+#[repr(C)]
+pub struct DBusSignatureIter {
+    sig: *const libc::c_char,
+    // offset of the current complete type in sig
+    pos: u32,
+    // iterators over the element type of an array only ever have one complete type
+    in_array: u32,
+    finished: u32,
+}
+
+// must fit into the DBusSignatureIter C programs allocate on their stack
+const _: () = assert!(std::mem::size_of::<DBusSignatureIter>() <= 32);
+
+/// A complete type in a parsed signature. rustbus has no type for the dict entry inside of
+/// a dict, so it gets its own variant.
+#[derive(Clone)]
+enum Node {
+    Type(Type),
+    DictEntry(Base, Type),
+}
+
+impl Node {
+    fn signature(&self) -> String {
+        let mut buf = String::new();
+        match self {
+            Node::Type(t) => t.to_str(&mut buf),
+            Node::DictEntry(key, value) => {
+                buf.push('{');
+                Type::Base(*key).to_str(&mut buf);
+                value.to_str(&mut buf);
+                buf.push('}');
+            }
+        }
+        buf
+    }
+
+    fn type_code(&self) -> libc::c_int {
+        match self {
+            Node::Type(Type::Container(Container::Array(_)))
+            | Node::Type(Type::Container(Container::Dict(_, _))) => DBUS_TYPE_ARRAY,
+            Node::Type(Type::Container(Container::Struct(_))) => DBUS_TYPE_STRUCT,
+            Node::Type(Type::Container(Container::Variant)) => DBUS_TYPE_VARIANT,
+            Node::Type(Type::Base(_)) => self.signature().as_bytes()[0] as libc::c_int,
+            Node::DictEntry(_, _) => DBUS_TYPE_DICTENTRY,
+        }
+    }
+
+    /// The complete types the signature iterator can recurse into
+    fn children(&self) -> Option<Vec<Node>> {
+        match self {
+            Node::Type(Type::Container(Container::Array(element))) => {
+                Some(vec![Node::Type((**element).clone())])
+            }
+            Node::Type(Type::Container(Container::Dict(key, value))) => {
+                Some(vec![Node::DictEntry(*key, (**value).clone())])
+            }
+            Node::Type(Type::Container(Container::Struct(fields))) => {
+                Some(fields.iter().cloned().map(Node::Type).collect())
+            }
+            Node::DictEntry(key, value) => Some(vec![
+                Node::Type(Type::Base(*key)),
+                Node::Type(value.clone()),
+            ]),
+            Node::Type(Type::Container(Container::Variant)) | Node::Type(Type::Base(_)) => None,
+        }
+    }
+}
+
+/// Finds the complete type starting at pos. Returns it with its siblings and its index.
+fn locate(nodes: Vec<Node>, mut start: usize, pos: usize) -> Option<(Vec<Node>, usize)> {
+    for idx in 0..nodes.len() {
+        let len = nodes[idx].signature().len();
+        if pos == start {
+            return Some((nodes, idx));
+        }
+        if pos < start + len {
+            return locate(nodes[idx].children()?, start + 1, pos);
+        }
+        start += len;
+    }
+    None
+}
+
+impl DBusSignatureIter {
+    fn init(ptr: *mut DBusSignatureIter, sig: *const libc::c_char, pos: u32, in_array: bool) {
+        let mut iter = DBusSignatureIter {
+            sig,
+            pos,
+            in_array: dbus_bool(in_array),
+            finished: dbus_bool(false),
+        };
+        iter.finished = dbus_bool(iter.locate().is_none());
+        unsafe { ptr.write(iter) };
+    }
+
+    fn locate(&self) -> Option<(Vec<Node>, usize)> {
+        if self.finished != 0 {
+            return None;
+        }
+        let types = parse_signature(self.sig)?;
+        locate(
+            types.into_iter().map(Node::Type).collect(),
+            0,
+            self.pos as usize,
+        )
+    }
+
+    fn current(&self) -> Option<Node> {
+        self.locate().map(|(mut nodes, idx)| nodes.swap_remove(idx))
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_signature_iter_init(
+    iter: *mut DBusSignatureIter,
+    signature: *const libc::c_char,
+) {
+    if iter.is_null() {
+        return;
+    }
+    DBusSignatureIter::init(iter, signature, 0, false);
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_signature_iter_get_current_type(
+    iter: *const DBusSignatureIter,
+) -> libc::c_int {
+    if iter.is_null() {
+        return DBUS_TYPE_INVALID;
+    }
+    let iter = unsafe { &*iter };
+    iter.current()
+        .map(|node| node.type_code())
+        .unwrap_or(DBUS_TYPE_INVALID)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_signature_iter_get_signature(
+    iter: *const DBusSignatureIter,
+) -> *mut libc::c_char {
+    if iter.is_null() {
+        return std::ptr::null_mut();
+    }
+    let iter = unsafe { &*iter };
+    let sig = iter
+        .current()
+        .map(|node| node.signature())
+        .unwrap_or_default();
+    crate::malloc_cstring(sig.as_bytes())
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_signature_iter_get_element_type(
+    iter: *const DBusSignatureIter,
+) -> libc::c_int {
+    if iter.is_null() {
+        return DBUS_TYPE_INVALID;
+    }
+    let iter = unsafe { &*iter };
+    match iter.current() {
+        Some(Node::Type(Type::Container(Container::Array(element)))) => {
+            Node::Type(*element).type_code()
+        }
+        Some(Node::Type(Type::Container(Container::Dict(_, _)))) => DBUS_TYPE_DICTENTRY,
+        _ => DBUS_TYPE_INVALID,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_signature_iter_next(iter: *mut DBusSignatureIter) -> u32 {
+    if iter.is_null() {
+        return dbus_bool(false);
+    }
+    let iter = unsafe { &mut *iter };
+    match iter.locate() {
+        Some((nodes, idx)) if iter.in_array == 0 && idx + 1 < nodes.len() => {
+            iter.pos += nodes[idx].signature().len() as u32;
+            dbus_bool(true)
+        }
+        _ => {
+            iter.finished = dbus_bool(true);
+            dbus_bool(false)
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_signature_iter_recurse(
+    iter: *const DBusSignatureIter,
+    subiter: *mut DBusSignatureIter,
+) {
+    if iter.is_null() || subiter.is_null() {
+        return;
+    }
+    let iter = unsafe { &*iter };
+    let current = match iter.current() {
+        Some(current) => current,
+        None => {
+            trace!("signature", "can not recurse, the iterator is finished");
+            return;
+        }
+    };
+    // the contained type of a variant is not part of the signature
+    if current.children().is_none() {
+        trace!(
+            "signature",
+            "can not recurse into non-container type {}",
+            current.signature()
+        );
+        return;
+    }
+    let in_array = current.type_code() == DBUS_TYPE_ARRAY;
+    DBusSignatureIter::init(subiter, iter.sig, iter.pos + 1, in_array);
+}
+
+fn set_invalid_signature(err: *mut DBusError, message: &str) {
+    set_error(err, DBUS_ERROR_INVALID_SIGNATURE, message);
+}
+
+/// Empty signatures are valid in the spec but rejected by rustbus. rustbus also accepts empty
+/// structs and dict entries outside of arrays, the latter do not come back out of to_str
+/// unchanged.
+fn parse_signature(signature: *const libc::c_char) -> Option<Vec<Type>> {
+    if signature.is_null() {
+        return None;
+    }
+    let sig = unsafe { CStr::from_ptr(signature) }.to_str().ok()?;
+    if sig.is_empty() {
+        return Some(Vec::new());
+    }
+    let types = Type::parse_description(sig).ok()?;
+    let mut buf = String::new();
+    types.iter().for_each(|t| t.to_str(&mut buf));
+    if buf != sig || types.iter().any(has_empty_struct) {
+        return None;
+    }
+    Some(types)
+}
+
+fn has_empty_struct(t: &Type) -> bool {
+    match t {
+        Type::Container(Container::Struct(fields)) => {
+            fields.is_empty() || fields.iter().any(has_empty_struct)
+        }
+        Type::Container(Container::Array(element))
+        | Type::Container(Container::Dict(_, element)) => has_empty_struct(element),
+        _ => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_signature_validate(
+    signature: *const libc::c_char,
+    err: *mut DBusError,
+) -> u32 {
+    if parse_signature(signature).is_none() {
+        set_invalid_signature(err, "Corrupt type signature");
+        return dbus_bool(false);
+    }
+    dbus_bool(true)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_signature_validate_single(
+    signature: *const libc::c_char,
+    err: *mut DBusError,
+) -> u32 {
+    match parse_signature(signature) {
+        Some(types) if types.len() == 1 => dbus_bool(true),
+        Some(_) => {
+            set_invalid_signature(err, "Exactly one complete type required in signature");
+            dbus_bool(false)
+        }
+        None => {
+            set_invalid_signature(err, "Corrupt type signature");
+            dbus_bool(false)
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_type_is_valid(typecode: libc::c_int) -> u32 {
+    dbus_bool(dbus_type_is_basic(typecode) != 0 || dbus_type_is_container(typecode) != 0)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_type_is_basic(typecode: libc::c_int) -> u32 {
+    dbus_bool(
+        dbus_type_is_fixed(typecode) != 0
+            || matches!(
+                typecode,
+                DBUS_TYPE_STRING | DBUS_TYPE_OBJECTPATH | DBUS_TYPE_SIGNATURE
+            ),
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_type_is_container(typecode: libc::c_int) -> u32 {
+    dbus_bool(matches!(
+        typecode,
+        DBUS_TYPE_ARRAY | DBUS_TYPE_STRUCT | DBUS_TYPE_DICTENTRY | DBUS_TYPE_VARIANT
+    ))
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_type_is_fixed(typecode: libc::c_int) -> u32 {
+    dbus_bool(matches!(
+        typecode,
+        DBUS_TYPE_BYTE
+            | DBUS_TYPE_BOOLEAN
+            | DBUS_TYPE_INT16
+            | DBUS_TYPE_UINT16
+            | DBUS_TYPE_INT32
+            | DBUS_TYPE_UINT32
+            | DBUS_TYPE_INT64
+            | DBUS_TYPE_UINT64
+            | DBUS_TYPE_DOUBLE
+            | DBUS_TYPE_UNIXFD
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    fn iter(sig: &CString) -> DBusSignatureIter {
+        let mut iter = std::mem::MaybeUninit::<DBusSignatureIter>::uninit();
+        dbus_signature_iter_init(iter.as_mut_ptr(), sig.as_ptr());
+        unsafe { iter.assume_init() }
+    }
+
+    fn recurse(iter: &DBusSignatureIter) -> DBusSignatureIter {
+        let mut sub = std::mem::MaybeUninit::<DBusSignatureIter>::uninit();
+        dbus_signature_iter_recurse(iter, sub.as_mut_ptr());
+        unsafe { sub.assume_init() }
+    }
+
+    fn current_signature(iter: &DBusSignatureIter) -> String {
+        let ptr = dbus_signature_iter_get_signature(iter);
+        let sig = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_owned();
+        crate::dbus_free(ptr.cast());
+        sig
+    }
+
+    /// Type codes and signatures of all complete types on this level
+    fn walk(mut iter: DBusSignatureIter) -> Vec<(u8, String)> {
+        let mut types = Vec::new();
+        loop {
+            let code = dbus_signature_iter_get_current_type(&iter);
+            if code == DBUS_TYPE_INVALID {
+                return types;
+            }
+            types.push((code as u8, current_signature(&iter)));
+            if dbus_signature_iter_next(&mut iter) == 0 {
+                return types;
+            }
+        }
+    }
+
+    #[test]
+    fn walks_top_level_types() {
+        let sig = CString::new("ia(su)a{sv}v").unwrap();
+        let iter = iter(&sig);
+        assert_eq!(
+            walk(iter),
+            vec![
+                (b'i', "i".to_owned()),
+                (b'a', "a(su)".to_owned()),
+                (b'a', "a{sv}".to_owned()),
+                (b'v', "v".to_owned()),
+            ]
+        );
+
+        let empty = CString::new("").unwrap();
+        assert!(walk(self::iter(&empty)).is_empty());
+    }
+
+    #[test]
+    fn recurses_into_nested_arrays() {
+        let sig = CString::new("aaiy").unwrap();
+        let outer = iter(&sig);
+        assert_eq!(
+            dbus_signature_iter_get_element_type(&outer),
+            DBUS_TYPE_ARRAY
+        );
+
+        let inner = recurse(&outer);
+        // the element type of an array is its only complete type
+        assert_eq!(walk(recurse(&outer)), vec![(b'a', "ai".to_owned())]);
+        assert_eq!(
+            dbus_signature_iter_get_element_type(&inner),
+            DBUS_TYPE_INT32
+        );
+        assert_eq!(walk(recurse(&inner)), vec![(b'i', "i".to_owned())]);
+    }
+
+    #[test]
+    fn recurses_into_dicts_and_structs() {
+        let sig = CString::new("a{s(iab)}t").unwrap();
+        let dict = iter(&sig);
+        assert_eq!(
+            dbus_signature_iter_get_element_type(&dict),
+            DBUS_TYPE_DICTENTRY
+        );
+
+        let entry = recurse(&dict);
+        assert_eq!(walk(recurse(&dict)), vec![(b'e', "{s(iab)}".to_owned())]);
+        let mut fields = recurse(&entry);
+        assert_eq!(
+            walk(recurse(&entry)),
+            vec![(b's', "s".to_owned()), (b'r', "(iab)".to_owned())]
+        );
+
+        assert_eq!(dbus_signature_iter_next(&mut fields), 1);
+        assert_eq!(
+            walk(recurse(&fields)),
+            vec![(b'i', "i".to_owned()), (b'a', "ab".to_owned())]
+        );
+    }
+
+    #[test]
+    fn refuses_to_recurse_into_non_containers() {
+        let sig = CString::new("iv").unwrap();
+        let mut iter = iter(&sig);
+        let mut sub = std::mem::MaybeUninit::<DBusSignatureIter>::zeroed();
+        dbus_signature_iter_recurse(&iter, sub.as_mut_ptr());
+        assert!(unsafe { sub.assume_init_ref() }.sig.is_null());
+
+        assert_eq!(dbus_signature_iter_next(&mut iter), 1);
+        dbus_signature_iter_recurse(&iter, sub.as_mut_ptr());
+        assert!(unsafe { sub.assume_init_ref() }.sig.is_null());
+    }
+
+    #[test]
+    fn invalid_signatures() {
+        for sig in &["a", "(i", "i)", "a{vs}", "{sv}", "()", "x!", "ai}"] {
+            let sig = CString::new(*sig).unwrap();
+            assert_eq!(
+                dbus_signature_validate(sig.as_ptr(), std::ptr::null_mut()),
+                0,
+                "{:?}",
+                sig
+            );
+            assert_eq!(
+                dbus_signature_iter_get_current_type(&iter(&sig)),
+                DBUS_TYPE_INVALID
+            );
+        }
+        assert_eq!(
+            dbus_signature_validate(std::ptr::null(), std::ptr::null_mut()),
+            0
+        );
+
+        let mut err = std::mem::MaybeUninit::<DBusError>::uninit();
+        dbus_error_init(err.as_mut_ptr());
+        let mut err = unsafe { err.assume_init() };
+        let sig = CString::new("a{").unwrap();
+        dbus_signature_validate(sig.as_ptr(), &mut err);
+        assert_eq!(err.name(), DBUS_ERROR_INVALID_SIGNATURE);
+        dbus_error_free(&mut err);
+    }
+
+    #[test]
+    fn validate_single() {
+        for (sig, valid) in &[
+            ("i", true),
+            ("a{sv}", true),
+            ("(ia(sv))", true),
+            ("", false),
+            ("ii", false),
+            ("a", false),
+        ] {
+            let sig = CString::new(*sig).unwrap();
+            assert_eq!(
+                dbus_signature_validate_single(sig.as_ptr(), std::ptr::null_mut()),
+                dbus_bool(*valid),
+                "{:?}",
+                sig
+            );
+        }
+    }
+
+    #[test]
+    fn type_classes() {
+        // (code, basic, container, fixed)
+        let table = [
+            (DBUS_TYPE_BYTE, true, false, true),
+            (DBUS_TYPE_BOOLEAN, true, false, true),
+            (DBUS_TYPE_INT16, true, false, true),
+            (DBUS_TYPE_UINT64, true, false, true),
+            (DBUS_TYPE_DOUBLE, true, false, true),
+            (DBUS_TYPE_UNIXFD, true, false, true),
+            (DBUS_TYPE_STRING, true, false, false),
+            (DBUS_TYPE_OBJECTPATH, true, false, false),
+            (DBUS_TYPE_SIGNATURE, true, false, false),
+            (DBUS_TYPE_ARRAY, false, true, false),
+            (DBUS_TYPE_STRUCT, false, true, false),
+            (DBUS_TYPE_DICTENTRY, false, true, false),
+            (DBUS_TYPE_VARIANT, false, true, false),
+            (DBUS_TYPE_INVALID, false, false, false),
+            (b'(' as libc::c_int, false, false, false),
+        ];
+        for (code, basic, container, fixed) in table.iter().copied() {
+            assert_eq!(dbus_type_is_basic(code), dbus_bool(basic), "{}", code);
+            assert_eq!(
+                dbus_type_is_container(code),
+                dbus_bool(container),
+                "{}",
+                code
+            );
+            assert_eq!(dbus_type_is_fixed(code), dbus_bool(fixed), "{}", code);
+            assert_eq!(
+                dbus_type_is_valid(code),
+                dbus_bool(basic || container),
+                "{}",
+                code
+            );
+        }
+    }
+}
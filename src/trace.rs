@@ -0,0 +1,83 @@
+//! Debug output on stderr, for when a call fails with nothing but FALSE. Set RDBUS_VERBOSE (or
+//! DBUS_VERBOSE, which libdbus' debug builds use) to anything but 0 to turn it on.
+
+use std::sync::OnceLock;
+
+pub fn enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| {
+        ["RDBUS_VERBOSE", "DBUS_VERBOSE"]
+            .iter()
+            .any(|var| matches!(std::env::var(var).as_deref(), Ok(v) if !v.is_empty() && v != "0"))
+    })
+}
+
+pub fn log(category: &str, args: std::fmt::Arguments) {
+    eprintln!("librdbus[{}] {}: {}", std::process::id(), category, args);
+}
+
+/// Type, serial, path, interface, member and signature of a message on one line
+pub fn describe(msg: &rustbus::Message) -> String {
+    let typ = match msg.typ {
+        rustbus::MessageType::Call => "method_call",
+        rustbus::MessageType::Reply => "method_return",
+        rustbus::MessageType::Error => "error",
+        rustbus::MessageType::Signal => "signal",
+        rustbus::MessageType::Invalid => "invalid",
+    };
+    let mut signature = String::new();
+    for typ in msg.sig() {
+        typ.to_str(&mut signature);
+    }
+    let mut line = format!(
+        "{} serial={} signature=\"{}\"",
+        typ,
+        msg.serial.unwrap_or(0),
+        signature
+    );
+    let fields = [
+        ("reply_serial", msg.response_serial.map(|s| s.to_string())),
+        ("sender", msg.sender.clone()),
+        ("destination", msg.destination.clone()),
+        ("path", msg.object.clone()),
+        ("interface", msg.interface.clone()),
+        ("member", msg.member.clone()),
+        ("error_name", msg.error_name.clone()),
+    ];
+    for (name, value) in fields.iter() {
+        if let Some(value) = value {
+            line.push_str(&format!(" {}={}", name, value));
+        }
+    }
+    line
+}
+
+/// `trace!("category", "format", args...)` logs if tracing is enabled. The arguments are only
+/// evaluated then.
+macro_rules! trace {
+    ($category:expr, $($arg:tt)+) => {
+        if $crate::trace::enabled() {
+            $crate::trace::log($category, format_args!($($arg)+))
+        }
+    };
+}
+pub(crate) use trace;
+
+/// For the parts of the libdbus ABI librdbus lacks: traces the call with its arguments and
+/// returns from the function like a failed libdbus call, with the value after `=>` (FALSE, NULL
+/// or 0) or with nothing. Callers never get a panic across the C ABI.
+macro_rules! unimplemented_abi {
+    ($name:expr $(, $arg:expr)* => $ret:expr) => {{
+        $crate::trace::trace!(
+            "abi",
+            "{}({}) is not implemented",
+            $name,
+            [$(format!("{}={:?}", stringify!($arg), $arg)),*].join(", ")
+        );
+        return $ret;
+    }};
+    ($name:expr $(, $arg:expr)*) => {
+        $crate::trace::unimplemented_abi!($name $(, $arg)* => ())
+    };
+}
+pub(crate) use unimplemented_abi;
@@ -0,0 +1,651 @@
+//! The socket side of a DBusConnection. This follows rustbus' Conn, but keeps the socket
+//! accessible because libdbus hands out the fd and the credentials of the peer.
+
+use crate::trace::trace;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Same default as libdbus
+const DEFAULT_MAX_MESSAGE_UNIX_FDS: usize = 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(std::io::Error),
+    UnmarshalError(rustbus::wire::unmarshal::Error),
+    MarshalError(rustbus::message::Error),
+    AuthFailed,
+    TimedOut,
+    /// The peer sent more fds than max_message_unix_fds at once
+    TooManyFds,
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::IoError(e)
+    }
+}
+
+impl From<rustbus::wire::unmarshal::Error> for Error {
+    fn from(e: rustbus::wire::unmarshal::Error) -> Error {
+        Error::UnmarshalError(e)
+    }
+}
+
+impl From<rustbus::message::Error> for Error {
+    fn from(e: rustbus::message::Error) -> Error {
+        Error::MarshalError(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The point in time all waiting of one operation has to be done by, so reads and writes share
+/// one timeout instead of each getting a fresh one
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub fn never() -> Self {
+        Deadline(None)
+    }
+
+    /// Already passed, waiting with this only checks what is ready right now
+    pub fn now() -> Self {
+        Deadline(Some(Instant::now()))
+    }
+
+    /// None waits forever
+    pub fn after(timeout: Option<Duration>) -> Self {
+        Deadline(timeout.map(|t| Instant::now() + t))
+    }
+
+    /// The libdbus convention: negative waits forever, 0 does not wait at all
+    pub fn from_millis(timeout: libc::c_int) -> Self {
+        if timeout < 0 {
+            Self::never()
+        } else {
+            Self::after(Some(Duration::from_millis(timeout as u64)))
+        }
+    }
+
+    /// Time left until the deadline, zero once it passed. None if there is no deadline.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.0
+            .map(|end| end.saturating_duration_since(Instant::now()))
+    }
+
+    /// The timeout for poll(2), rounded up so a wait never returns just before the deadline
+    pub fn poll_timeout(&self) -> libc::c_int {
+        match self.remaining() {
+            None => -1,
+            Some(left) => {
+                let millis = left.as_nanos().div_ceil(1_000_000);
+                millis.min(libc::c_int::MAX as u128) as libc::c_int
+            }
+        }
+    }
+}
+
+/// The socket path of a D-Bus address like `unix:path=/run/bus,guid=...`. Of a list separated
+/// by `;` the first unix:path= entry wins. Anything without a `:` is taken as a plain path, which
+/// is what librdbus used to accept.
+pub fn address_path(address: &str) -> Option<std::path::PathBuf> {
+    if !address.contains(':') {
+        return Some(address.into());
+    }
+    address
+        .split(';')
+        .filter_map(|entry| entry.strip_prefix("unix:"))
+        .flat_map(|keys| keys.split(','))
+        .find_map(|key| key.strip_prefix("path="))
+        .map(Into::into)
+}
+
+/// Bind a socket for a D-Bus address. unix:path= binds that path, unix:tmpdir= and unix:dir= a
+/// new socket in that directory. Returns the listener and the path it is bound to.
+pub fn listen(address: &str) -> std::io::Result<(UnixListener, std::path::PathBuf)> {
+    let unsupported = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Unsupported address {}", address),
+        )
+    };
+    let params = address.strip_prefix("unix:").ok_or_else(unsupported)?;
+    for param in params.split(',') {
+        if let Some(path) = param.strip_prefix("path=") {
+            return Ok((UnixListener::bind(path)?, path.into()));
+        }
+        if let Some(dir) = param
+            .strip_prefix("tmpdir=")
+            .or_else(|| param.strip_prefix("dir="))
+        {
+            let name = format!("dbus-{}", &crate::auth::new_guid()[..10]);
+            let path = Path::new(dir).join(name);
+            return Ok((UnixListener::bind(&path)?, path));
+        }
+    }
+    Err(unsupported())
+}
+
+/// Credentials of the process on the other end of the socket
+pub struct PeerCredentials {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+#[derive(Debug)]
+pub struct Transport {
+    stream: UnixStream,
+    /// The GUID of the server. On the client side this is what the server sent during auth.
+    pub server_guid: Option<String>,
+    pub is_server: bool,
+    pub unix_fds: bool,
+    /// How many fds one message may carry. This is also all the room a read has for fds.
+    pub max_message_unix_fds: usize,
+    /// The auth handshake of an accepted socket, until it is done
+    auth: Option<crate::auth::ServerHandshake>,
+
+    msg_buf_in: Vec<u8>,
+    fds_in: Vec<RawFd>,
+    /// The message currently being written, whatever part of it the socket did not take yet
+    msg_buf_out: Vec<u8>,
+    written_out: usize,
+    /// fds of the message in msg_buf_out that still have to go out with its first byte. They
+    /// belong to the message, which has to stay alive until they are written.
+    fds_out: Vec<RawFd>,
+
+    serial_counter: u32,
+
+    /// Gets a copy of every message that goes in or out
+    pub capture: Option<std::sync::Arc<crate::pcap::Capture>>,
+}
+
+impl Transport {
+    /// Connect to a unix socket and authenticate as the current user
+    pub fn connect(path: &Path, with_unix_fd: bool) -> Result<Self> {
+        let mut stream = UnixStream::connect(path)?;
+        let auth = crate::auth::client_auth(&mut stream, with_unix_fd)?;
+        let mut transport = Self::from_stream(stream);
+        transport.server_guid = Some(auth.server_guid);
+        transport.unix_fds = auth.unix_fds;
+        Ok(transport)
+    }
+
+    /// Wrap a socket that is already past the auth handshake
+    pub fn from_stream(stream: UnixStream) -> Self {
+        Self {
+            stream,
+            server_guid: None,
+            is_server: false,
+            unix_fds: false,
+            max_message_unix_fds: DEFAULT_MAX_MESSAGE_UNIX_FDS,
+            auth: None,
+            msg_buf_in: Vec::new(),
+            fds_in: Vec::new(),
+            msg_buf_out: Vec::new(),
+            written_out: 0,
+            fds_out: Vec::new(),
+            serial_counter: 1,
+            capture: None,
+        }
+    }
+
+    /// Wrap a socket a server accepted. server_auth has to finish before it carries messages.
+    pub fn from_accepted(stream: UnixStream, guid: String) -> Self {
+        let mut transport = Self::from_stream(stream);
+        transport.auth = Some(crate::auth::ServerHandshake::new(guid.clone()));
+        transport.server_guid = Some(guid);
+        transport.is_server = true;
+        transport
+    }
+
+    /// Advance the auth handshake of an accepted socket without blocking. Returns the uid the
+    /// client authenticated as, None for anonymous clients. Fails with TimedOut while the
+    /// handshake waits for the socket, see auth_wants_write.
+    pub fn server_auth(
+        &mut self,
+        allow_anonymous: bool,
+        authorize: &mut dyn FnMut(Option<libc::uid_t>) -> bool,
+    ) -> Result<Option<libc::uid_t>> {
+        let fd = self.as_raw_fd();
+        // not accepted, or done already
+        let handshake = self.auth.as_mut().ok_or(Error::AuthFailed)?;
+        let auth = handshake.advance(fd, allow_anonymous, authorize)?;
+        self.auth = None;
+        self.unix_fds = auth.unix_fds;
+        Ok(auth.uid)
+    }
+
+    /// The auth handshake has answers the socket did not take yet
+    pub fn auth_wants_write(&self) -> bool {
+        matches!(&self.auth, Some(auth) if auth.wants_write())
+    }
+
+    pub fn alloc_serial(&mut self) -> u32 {
+        let serial = self.serial_counter;
+        self.serial_counter += 1;
+        serial
+    }
+
+    /// Wait until the socket is readable or writable, whichever was asked for, or until the
+    /// deadline passed. Returns (readable, writable). A hangup or socket error counts as both, the
+    /// next read or write reports it.
+    pub fn wait(&self, read: bool, write: bool, deadline: Deadline) -> Result<(bool, bool)> {
+        let mut pollfd = libc::pollfd {
+            fd: self.as_raw_fd(),
+            events: 0,
+            revents: 0,
+        };
+        if read {
+            pollfd.events |= libc::POLLIN;
+        }
+        if write {
+            pollfd.events |= libc::POLLOUT;
+        }
+        loop {
+            let res = unsafe { libc::poll(&mut pollfd, 1, deadline.poll_timeout()) };
+            if res >= 0 {
+                break;
+            }
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                return Err(e.into());
+            }
+        }
+        let broken = pollfd.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0;
+        Ok((
+            read && (broken || pollfd.revents & libc::POLLIN != 0),
+            write && (broken || pollfd.revents & libc::POLLOUT != 0),
+        ))
+    }
+
+    pub fn bytes_needed_for_current_message(&self) -> Result<usize> {
+        if self.msg_buf_in.len() < rustbus::wire::unmarshal::HEADER_LEN + 4 {
+            return Ok(rustbus::wire::unmarshal::HEADER_LEN + 4);
+        }
+        let needed = crate::message::dbus_message_demarshal_bytes_needed(
+            self.msg_buf_in.as_ptr() as *const libc::c_char,
+            self.msg_buf_in.len() as libc::c_int,
+        );
+        if needed < 0 {
+            return Err(Error::UnmarshalError(
+                rustbus::wire::unmarshal::Error::InvalidHeaderFields,
+            ));
+        }
+        Ok(needed as usize)
+    }
+
+    pub fn buffer_contains_whole_message(&self) -> Result<bool> {
+        if self.msg_buf_in.len() < rustbus::wire::unmarshal::HEADER_LEN + 4 {
+            return Ok(false);
+        }
+        Ok(self.msg_buf_in.len() >= self.bytes_needed_for_current_message()?)
+    }
+
+    /// Read from the socket once without blocking, but never past the end of the current
+    /// message, so fds are always attached to the message they were sent with. Fails with
+    /// TimedOut if there was nothing to read.
+    pub fn read_once(&mut self) -> Result<()> {
+        let bytes_to_read = self.bytes_needed_for_current_message()? - self.msg_buf_in.len();
+        if bytes_to_read == 0 {
+            return Ok(());
+        }
+
+        let mut buf = [0u8; 512];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: usize::min(bytes_to_read, buf.len()),
+        };
+        let fds_len = self.max_message_unix_fds * std::mem::size_of::<RawFd>();
+        let cmsg_space = unsafe { libc::CMSG_SPACE(fds_len as u32) };
+        // u64 to get the alignment a cmsghdr needs
+        let mut cmsg_buf = vec![0u64; (cmsg_space as usize).div_ceil(8)];
+
+        let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = cmsg_space as _;
+
+        let flags = libc::MSG_CMSG_CLOEXEC | libc::MSG_DONTWAIT;
+        let bytes = unsafe { libc::recvmsg(self.as_raw_fd(), &mut hdr, flags) };
+        if bytes < 0 {
+            let e = std::io::Error::last_os_error();
+            return Err(match e.kind() {
+                std::io::ErrorKind::WouldBlock => Error::TimedOut,
+                _ => Error::IoError(e),
+            });
+        }
+        if bytes == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&hdr) };
+        while !cmsg.is_null() {
+            let c = unsafe { &*cmsg };
+            if c.cmsg_level == libc::SOL_SOCKET && c.cmsg_type == libc::SCM_RIGHTS {
+                let data = unsafe { libc::CMSG_DATA(cmsg) } as *const RawFd;
+                let len = c.cmsg_len as usize - unsafe { libc::CMSG_LEN(0) } as usize;
+                for idx in 0..len / std::mem::size_of::<RawFd>() {
+                    self.fds_in.push(unsafe { data.add(idx).read_unaligned() });
+                }
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&hdr, cmsg) };
+        }
+        if hdr.msg_flags & libc::MSG_CTRUNC != 0 {
+            // the kernel dropped the fds that did not fit, the message can not be delivered
+            for fd in self.fds_in.drain(..) {
+                unsafe { libc::close(fd) };
+            }
+            return Err(Error::TooManyFds);
+        }
+
+        self.msg_buf_in.extend_from_slice(&buf[..bytes as usize]);
+        Ok(())
+    }
+
+    /// Blocks until a message has been read or the timeout has been reached
+    pub fn get_next_message<'a>(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<rustbus::Message<'a, 'a>> {
+        let deadline = Deadline::after(timeout);
+        while !self.buffer_contains_whole_message()? {
+            if !self.wait(true, false, deadline)?.0 {
+                return Err(Error::TimedOut);
+            }
+            match self.read_once() {
+                // woken up without anything to read
+                Err(Error::TimedOut) => {}
+                res => res?,
+            }
+        }
+        self.capture_message(false);
+        // a broken message must not block the ones after it
+        let msg = crate::message::demarshal(&self.msg_buf_in);
+        self.msg_buf_in.clear();
+        let mut msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                trace!("message", "received a broken message: {:?}", e);
+                // the fds came with the broken message, they must not end up on the next one
+                for fd in self.fds_in.drain(..) {
+                    unsafe { libc::close(fd) };
+                }
+                return Err(e.into());
+            }
+        };
+        msg.raw_fds.append(&mut self.fds_in);
+        trace!("message", "received {}", crate::trace::describe(&msg));
+        Ok(msg)
+    }
+
+    /// Whether a message was started and not completely written yet
+    pub fn has_pending_output(&self) -> bool {
+        !self.msg_buf_out.is_empty()
+    }
+
+    /// Marshal a message into the outgoing buffer. Messages without a serial get the next one.
+    /// The previous message has to be written completely first.
+    pub fn start_message(&mut self, msg: &mut rustbus::Message) -> Result<u32> {
+        debug_assert!(!self.has_pending_output());
+        let serial = match msg.serial {
+            Some(serial) => serial,
+            None => {
+                let serial = self.alloc_serial();
+                msg.serial = Some(serial);
+                serial
+            }
+        };
+
+        self.discard_output();
+        if let Err(e) = crate::message::marshal(msg, &mut self.msg_buf_out) {
+            self.discard_output();
+            return Err(e.into());
+        }
+        self.started_message(msg);
+        Ok(serial)
+    }
+
+    /// Like start_message, for a message that already has its serial and was marshalled into
+    /// bytes
+    pub fn start_marshalled(&mut self, msg: &rustbus::Message, bytes: Vec<u8>) {
+        debug_assert!(!self.has_pending_output());
+        self.discard_output();
+        self.msg_buf_out = bytes;
+        self.started_message(msg);
+    }
+
+    fn started_message(&mut self, msg: &rustbus::Message) {
+        self.fds_out.extend_from_slice(&msg.raw_fds);
+        self.capture_message(true);
+        trace!("message", "sending {}", crate::trace::describe(msg));
+    }
+
+    /// Hand the message in the outgoing or incoming buffer to the capture, if there is one
+    fn capture_message(&self, outgoing: bool) {
+        let bytes = if outgoing {
+            &self.msg_buf_out
+        } else {
+            &self.msg_buf_in
+        };
+        if let Some(capture) = &self.capture {
+            // a capture that can not be written must not break the connection
+            let _ = capture.write_message(bytes);
+        }
+    }
+
+    /// Write as much of the outgoing buffer as the socket takes without blocking. Returns true
+    /// once the whole message is out.
+    pub fn write_pending(&mut self) -> Result<bool> {
+        while self.written_out < self.msg_buf_out.len() {
+            match self.send_with_fds(&self.msg_buf_out[self.written_out..], &self.fds_out) {
+                Ok(bytes) => {
+                    self.written_out += bytes;
+                    // the fds travel with the first byte of the message
+                    self.fds_out.clear();
+                }
+                Err(Error::TimedOut) => return Ok(false),
+                Err(e) => {
+                    self.discard_output();
+                    return Err(e);
+                }
+            }
+        }
+        self.discard_output();
+        Ok(true)
+    }
+
+    /// Write the rest of the outgoing buffer, waiting for the socket until the deadline. On
+    /// TimedOut the rest stays buffered for the next call.
+    pub fn flush_output(&mut self, deadline: Deadline) -> Result<()> {
+        while !self.write_pending()? {
+            if !self.wait(false, true, deadline)?.1 {
+                return Err(Error::TimedOut);
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the message being written, e.g. because the socket is gone
+    pub fn discard_output(&mut self) {
+        self.msg_buf_out.clear();
+        self.written_out = 0;
+        self.fds_out.clear();
+    }
+
+    /// Marshal and write a whole message, after whatever an earlier call left in the outgoing
+    /// buffer. Fails with TimedOut if the socket did not take all of it in time. If nothing of
+    /// the message was written by then it is dropped, otherwise the rest stays buffered.
+    pub fn send_message(
+        &mut self,
+        msg: &mut rustbus::Message,
+        timeout: Option<Duration>,
+    ) -> Result<u32> {
+        let deadline = Deadline::after(timeout);
+        self.flush_output(deadline)?;
+        let serial = self.start_message(msg)?;
+        if let Err(e) = self.flush_output(deadline) {
+            if self.written_out == 0 {
+                self.discard_output();
+            }
+            return Err(e);
+        }
+        Ok(serial)
+    }
+
+    fn send_with_fds(&self, buf: &[u8], fds: &[RawFd]) -> Result<usize> {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+
+        let fds_len = std::mem::size_of_val(fds);
+        let cmsg_space = unsafe { libc::CMSG_SPACE(fds_len as u32) } as usize;
+        let mut cmsg_buf = vec![0u64; cmsg_space.div_ceil(8)];
+        if !fds.is_empty() {
+            hdr.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = cmsg_space as _;
+            unsafe {
+                let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+                (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+                std::ptr::copy_nonoverlapping(
+                    fds.as_ptr() as *const u8,
+                    libc::CMSG_DATA(cmsg),
+                    fds_len,
+                );
+            }
+        }
+
+        let flags = libc::MSG_NOSIGNAL | libc::MSG_DONTWAIT;
+        let bytes = unsafe { libc::sendmsg(self.as_raw_fd(), &hdr, flags) };
+        if bytes < 0 {
+            let e = std::io::Error::last_os_error();
+            return Err(match e.kind() {
+                std::io::ErrorKind::WouldBlock => Error::TimedOut,
+                _ => Error::IoError(e),
+            });
+        }
+        Ok(bytes as usize)
+    }
+
+    pub fn peer_credentials(&self) -> std::io::Result<PeerCredentials> {
+        peer_credentials(self.as_raw_fd())
+    }
+
+    /// The LSM label of the peer, e.g. the SELinux context. Fails if no LSM provides one.
+    pub fn peer_security_label(&self) -> std::io::Result<Vec<u8>> {
+        let mut label = vec![0u8; 256];
+        loop {
+            let mut len = label.len() as libc::socklen_t;
+            let res = unsafe {
+                libc::getsockopt(
+                    self.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_PEERSEC,
+                    label.as_mut_ptr() as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if res == 0 {
+                label.truncate(len as usize);
+                // some kernels include the terminating NUL
+                while label.last() == Some(&0) {
+                    label.pop();
+                }
+                return Ok(label);
+            }
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ERANGE) && (len as usize) > label.len() {
+                label.resize(len as usize, 0);
+                continue;
+            }
+            return Err(e);
+        }
+    }
+}
+
+/// SO_PEERCRED of a connected unix socket
+pub fn peer_credentials(fd: RawFd) -> std::io::Result<PeerCredentials> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        pid: cred.pid,
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+impl AsRawFd for Transport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socketpair_roundtrip_and_credentials() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut a = Transport::from_stream(a);
+        let mut b = Transport::from_stream(b);
+
+        let mut msg = rustbus::message_builder::MessageBuilder::new()
+            .signal(
+                "io.rdbus.Test".to_owned(),
+                "Ping".to_owned(),
+                "/io/rdbus".to_owned(),
+            )
+            .build();
+        msg.push_param("hello");
+        let serial = a.send_message(&mut msg, None).unwrap();
+
+        let received = b.get_next_message(None).unwrap();
+        assert_eq!(received.serial, Some(serial));
+        assert_eq!(received.member.as_deref(), Some("Ping"));
+        assert_eq!(received.params[0].as_str(), Some("hello"));
+
+        let cred = b.peer_credentials().unwrap();
+        assert_eq!(cred.pid, std::process::id() as libc::pid_t);
+        assert_eq!(cred.uid, unsafe { libc::getuid() });
+    }
+
+    #[test]
+    fn socket_paths_of_addresses() {
+        let path = |addr| address_path(addr).map(|p| p.display().to_string());
+        assert_eq!(path("/tmp/bus").as_deref(), Some("/tmp/bus"));
+        assert_eq!(path("unix:path=/tmp/bus").as_deref(), Some("/tmp/bus"));
+        assert_eq!(
+            path("tcp:host=localhost;unix:guid=0123,path=/tmp/bus").as_deref(),
+            Some("/tmp/bus")
+        );
+        assert_eq!(path("unix:abstract=/tmp/bus"), None);
+        assert_eq!(path("tcp:host=localhost,port=1234"), None);
+    }
+}
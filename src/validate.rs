@@ -1,69 +1,319 @@
-use crate::*;
 use crate::error::*;
+use crate::*;
 
-#[no_mangle]
-pub extern "C" fn dbus_validate_bus_name(s: *const libc::c_char, _err: *mut DBusError) -> u32 {
-    let c_str = unsafe {
-        assert!(!s.is_null());
-        CStr::from_ptr(s)
+/// Longest bus, interface, member and error name the spec allows
+const MAX_NAME_LEN: usize = 255;
+
+type ValidateResult = Result<(), String>;
+
+/// Shared part of the exported validators: NULL and UTF-8 handling and filling the error
+fn validate(
+    s: *const libc::c_char,
+    err: *mut DBusError,
+    what: &str,
+    check: fn(&str) -> ValidateResult,
+) -> u32 {
+    if s.is_null() {
+        return dbus_bool(false);
+    }
+    let s = match unsafe { CStr::from_ptr(s) }.to_str() {
+        Ok(s) => s,
+        Err(_) => {
+            set_error(
+                err,
+                DBUS_ERROR_INVALID_ARGS,
+                &format!("{} was not valid UTF-8", what),
+            );
+            return dbus_bool(false);
+        }
     };
-    let s = c_str.to_str().unwrap();
 
-    // TODO set error
-    dbus_bool(rustbus::params::validate_busname(s).is_ok())
+    match check(s) {
+        Ok(()) => dbus_bool(true),
+        Err(msg) => {
+            set_error(err, DBUS_ERROR_INVALID_ARGS, &msg);
+            dbus_bool(false)
+        }
+    }
 }
 
-#[no_mangle]
-pub extern "C" fn dbus_validate_bus_path(s: *const libc::c_char, _err: *mut DBusError) -> u32 {
-    let c_str = unsafe {
-        assert!(!s.is_null());
-        CStr::from_ptr(s)
-    };
-    let s = c_str.to_str().unwrap();
+pub(crate) fn check_path(path: &str) -> ValidateResult {
+    if !path.starts_with('/') {
+        return Err("Path must start with /".to_owned());
+    }
+    if path == "/" {
+        return Ok(());
+    }
+    if path.ends_with('/') {
+        return Err("Path must not end with /".to_owned());
+    }
+    for element in path[1..].split('/') {
+        if element.is_empty() {
+            return Err("Path must not contain empty elements".to_owned());
+        }
+        if !element
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'_')
+        {
+            return Err("Path contains invalid character".to_owned());
+        }
+    }
+    Ok(())
+}
 
-    // TODO set error
-    dbus_bool(rustbus::params::validate_object_path(s).is_ok())
+fn check_len(name: &str, what: &str) -> ValidateResult {
+    if name.is_empty() {
+        return Err(format!("{} must not be empty", what));
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(format!(
+            "{} must not be longer than {} bytes",
+            what, MAX_NAME_LEN
+        ));
+    }
+    Ok(())
+}
+
+/// Interface, error and bus names are all made of at least two elements separated by dots
+fn check_dotted_name(
+    name: &str,
+    what: &str,
+    allow_hyphen: bool,
+    allow_leading_digit: bool,
+) -> ValidateResult {
+    check_len(name, what)?;
+    if name.starts_with('.') {
+        return Err(format!("{} must not start with '.'", what));
+    }
+    if !name.contains('.') {
+        return Err(format!("{} must contain at least one '.'", what));
+    }
+    for element in name.split('.') {
+        if element.is_empty() {
+            return Err(format!("{} must not contain empty elements", what));
+        }
+        if !allow_leading_digit && element.as_bytes()[0].is_ascii_digit() {
+            return Err(format!("{} element must not start with a digit", what));
+        }
+        let valid_char =
+            |c: u8| c.is_ascii_alphanumeric() || c == b'_' || (allow_hyphen && c == b'-');
+        if !element.bytes().all(valid_char) {
+            return Err(format!("{} contains invalid character", what));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn check_bus_name(name: &str) -> ValidateResult {
+    if let Some(unique) = name.strip_prefix(':') {
+        check_len(name, "Bus name")?;
+        // the elements of unique names may start with digits, e.g. ":1.42"
+        return check_dotted_name(unique, "Bus name", true, true);
+    }
+    check_dotted_name(name, "Bus name", true, false)
 }
-#[no_mangle]
-pub extern "C" fn dbus_validate_interface(s: *const libc::c_char, _err: *mut DBusError) -> u32 {
-    let c_str = unsafe {
-        assert!(!s.is_null());
-        CStr::from_ptr(s)
-    };
-    let s = c_str.to_str().unwrap();
 
-    // TODO set error
-    dbus_bool(rustbus::params::validate_interface(s).is_ok())
+pub(crate) fn check_interface(name: &str) -> ValidateResult {
+    check_dotted_name(name, "Interface name", false, false)
 }
+
+pub(crate) fn check_error_name(name: &str) -> ValidateResult {
+    check_dotted_name(name, "Error name", false, false)
+}
+
+pub(crate) fn check_member(name: &str) -> ValidateResult {
+    check_len(name, "Member name")?;
+    if name.as_bytes()[0].is_ascii_digit() {
+        return Err("Member name must not start with a digit".to_owned());
+    }
+    if !name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'_') {
+        return Err("Member name contains invalid character".to_owned());
+    }
+    Ok(())
+}
+
 #[no_mangle]
-pub extern "C" fn dbus_validate_member(s: *const libc::c_char, _err: *mut DBusError) -> u32 {
-    let c_str = unsafe {
-        assert!(!s.is_null());
-        CStr::from_ptr(s)
-    };
-    let s = c_str.to_str().unwrap();
+pub extern "C" fn dbus_validate_path(path: *const libc::c_char, err: *mut DBusError) -> u32 {
+    validate(path, err, "Object path", check_path)
+}
 
-    // TODO set error
-    dbus_bool(rustbus::params::validate_membername(s).is_ok())
+#[no_mangle]
+pub extern "C" fn dbus_validate_interface(name: *const libc::c_char, err: *mut DBusError) -> u32 {
+    validate(name, err, "Interface name", check_interface)
 }
+
 #[no_mangle]
-pub extern "C" fn dbus_validate_error_name(s: *const libc::c_char, _err: *mut DBusError) -> u32 {
-    let c_str = unsafe {
-        assert!(!s.is_null());
-        CStr::from_ptr(s)
-    };
-    let s = c_str.to_str().unwrap();
+pub extern "C" fn dbus_validate_member(name: *const libc::c_char, err: *mut DBusError) -> u32 {
+    validate(name, err, "Member name", check_member)
+}
 
-    // TODO set error
-    dbus_bool(rustbus::params::validate_errorname(s).is_ok())
+#[no_mangle]
+pub extern "C" fn dbus_validate_error_name(name: *const libc::c_char, err: *mut DBusError) -> u32 {
+    validate(name, err, "Error name", check_error_name)
 }
+
 #[no_mangle]
-pub extern "C" fn dbus_validate_utf8(s: *const libc::c_char, _err: *mut DBusError) -> u32 {
-    let c_str = unsafe {
-        assert!(!s.is_null());
-        CStr::from_ptr(s)
-    };
+pub extern "C" fn dbus_validate_bus_name(name: *const libc::c_char, err: *mut DBusError) -> u32 {
+    validate(name, err, "Bus name", check_bus_name)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_validate_utf8(
+    alleged_utf8: *const libc::c_char,
+    err: *mut DBusError,
+) -> u32 {
+    validate(alleged_utf8, err, "String", |_| Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    /// A dotted name of exactly len bytes
+    fn long_name(len: usize) -> String {
+        let mut name = "a.".to_owned();
+        name.push_str(&"b".repeat(len - 2));
+        name
+    }
+
+    fn check_table(check: fn(&str) -> ValidateResult, table: &[(&str, bool)]) {
+        for (name, valid) in table {
+            assert_eq!(check(name).is_ok(), *valid, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn paths() {
+        check_table(
+            check_path,
+            &[
+                ("/", true),
+                ("/a", true),
+                ("/org/freedesktop/DBus", true),
+                ("/_1/a_b/0", true),
+                ("", false),
+                ("a", false),
+                ("a/b", false),
+                ("//", false),
+                ("/a//b", false),
+                ("/a/", false),
+                ("/a-b", false),
+                ("/a.b", false),
+            ],
+        );
+    }
+
+    #[test]
+    fn interfaces_and_error_names() {
+        let (max, too_long) = (long_name(255), long_name(256));
+        let table = [
+            ("org.freedesktop.DBus", true),
+            ("a.b", true),
+            ("_a.b_1", true),
+            (max.as_str(), true),
+            (too_long.as_str(), false),
+            ("", false),
+            ("a", false),
+            (".a.b", false),
+            ("a.b.", false),
+            ("a..b", false),
+            ("a.1b", false),
+            ("1a.b", false),
+            ("a-b.c", false),
+            (":1.42", false),
+        ];
+        check_table(check_interface, &table);
+        check_table(check_error_name, &table);
+    }
+
+    #[test]
+    fn members() {
+        check_table(
+            check_member,
+            &[
+                ("Hello", true),
+                ("_get_1", true),
+                ("a".repeat(255).as_str(), true),
+                ("a".repeat(256).as_str(), false),
+                ("", false),
+                ("1Hello", false),
+                ("a.b", false),
+                ("a-b", false),
+                ("a/b", false),
+            ],
+        );
+    }
+
+    #[test]
+    fn bus_names() {
+        let mut unique_max = ":".to_owned();
+        unique_max.push_str(&long_name(254));
+        let mut unique_too_long = ":".to_owned();
+        unique_too_long.push_str(&long_name(255));
+        check_table(
+            check_bus_name,
+            &[
+                ("org.freedesktop.DBus", true),
+                ("org.example-app.Name", true),
+                (long_name(255).as_str(), true),
+                (long_name(256).as_str(), false),
+                (":1.42", true),
+                (":1.0.a-b", true),
+                (unique_max.as_str(), true),
+                (unique_too_long.as_str(), false),
+                ("", false),
+                (":", false),
+                (":1", false),
+                (":.1", false),
+                ("::1.2", false),
+                ("org", false),
+                ("1org.example", false),
+                ("org.1example", false),
+                ("org..example", false),
+                ("org.example.", false),
+            ],
+        );
+    }
+
+    #[test]
+    fn exported_validators_set_errors() {
+        type Validator = extern "C" fn(*const libc::c_char, *mut DBusError) -> u32;
+        let table: [(Validator, &str, &str); 5] = [
+            (dbus_validate_path, "/org/example", "/org/"),
+            (dbus_validate_interface, "org.example.Iface", "org"),
+            (dbus_validate_member, "Method", "1Method"),
+            (dbus_validate_error_name, "org.example.Error", "org..Error"),
+            (dbus_validate_bus_name, ":1.7", ":1"),
+        ];
+
+        let mut err = std::mem::MaybeUninit::<DBusError>::uninit();
+        dbus_error_init(err.as_mut_ptr());
+        let mut err = unsafe { err.assume_init() };
+        for (validator, valid, invalid) in table.iter() {
+            let valid = CString::new(*valid).unwrap();
+            assert_eq!(validator(valid.as_ptr(), &mut err), 1, "{:?}", valid);
+            assert_eq!(dbus_error_is_set(&mut err), 0);
+
+            let invalid = CString::new(*invalid).unwrap();
+            assert_eq!(validator(invalid.as_ptr(), &mut err), 0, "{:?}", invalid);
+            assert_eq!(err.name(), DBUS_ERROR_INVALID_ARGS);
+            dbus_error_free(&mut err);
+
+            // NULL is invalid, the error is optional
+            assert_eq!(validator(std::ptr::null(), &mut err), 0);
+            assert_eq!(validator(invalid.as_ptr(), std::ptr::null_mut()), 0);
+        }
 
-    // TODO set error
-    dbus_bool(c_str.to_str().is_ok())
+        let not_utf8 = CString::new(vec![b'/', 0xff]).unwrap();
+        assert_eq!(dbus_validate_path(not_utf8.as_ptr(), &mut err), 0);
+        assert_eq!(err.name(), DBUS_ERROR_INVALID_ARGS);
+        dbus_error_free(&mut err);
+        assert_eq!(
+            dbus_validate_utf8(not_utf8.as_ptr(), std::ptr::null_mut()),
+            0
+        );
+        let utf8 = CString::new("grüße").unwrap();
+        assert_eq!(dbus_validate_utf8(utf8.as_ptr(), std::ptr::null_mut()), 1);
+    }
 }
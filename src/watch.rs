@@ -0,0 +1,137 @@
+//! Main loop integration. A DBusWatch tells the application which fd to poll for what, the
+//! application calls dbus_watch_handle once the fd is ready. Only servers use watches so far.
+
+use crate::*;
+use std::os::unix::io::RawFd;
+
+pub const DBUS_WATCH_READABLE: u32 = 1 << 0;
+pub const DBUS_WATCH_WRITABLE: u32 = 1 << 1;
+pub const DBUS_WATCH_ERROR: u32 = 1 << 2;
+pub const DBUS_WATCH_HANGUP: u32 = 1 << 3;
+
+pub struct DBusWatch {
+    fd: RawFd,
+    flags: u32,
+    pub enabled: bool,
+    /// Runs for dbus_watch_handle with owner and the flags the application saw. The watch may
+    /// be gone once it returns.
+    handle: fn(*mut std::ffi::c_void, u32) -> bool,
+    owner: *mut std::ffi::c_void,
+
+    data: *mut std::ffi::c_void,
+    free_data: Option<DBusFreeFunction>,
+}
+
+impl DBusWatch {
+    pub fn new(
+        fd: RawFd,
+        flags: u32,
+        handle: fn(*mut std::ffi::c_void, u32) -> bool,
+        owner: *mut std::ffi::c_void,
+    ) -> Self {
+        Self {
+            fd,
+            flags,
+            enabled: true,
+            handle,
+            owner,
+            data: std::ptr::null_mut(),
+            free_data: None,
+        }
+    }
+
+    fn free_data(&mut self) {
+        if let Some(free) = self.free_data.take() {
+            free(self.data);
+        }
+    }
+}
+
+impl Drop for DBusWatch {
+    fn drop(&mut self) {
+        self.free_data();
+    }
+}
+
+/// The functions an application uses to add watches to its main loop
+pub struct WatchFunctions {
+    pub add: DBusAddWatchFunction,
+    pub remove: Option<DBusRemoveWatchFunction>,
+    pub toggled: Option<DBusWatchToggledFunction>,
+    pub data: *mut std::ffi::c_void,
+    pub free: Option<DBusFreeFunction>,
+}
+
+impl Drop for WatchFunctions {
+    fn drop(&mut self) {
+        if let Some(free) = self.free {
+            free(self.data);
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_watch_get_unix_fd(watch: *mut DBusWatch) -> libc::c_int {
+    if watch.is_null() {
+        return -1;
+    }
+    unsafe { &*watch }.fd
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_watch_get_socket(watch: *mut DBusWatch) -> libc::c_int {
+    // librdbus only does unix sockets, so these are the same
+    dbus_watch_get_unix_fd(watch)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_watch_get_flags(watch: *mut DBusWatch) -> u32 {
+    if watch.is_null() {
+        return 0;
+    }
+    unsafe { &*watch }.flags
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_watch_get_enabled(watch: *mut DBusWatch) -> u32 {
+    if watch.is_null() {
+        return dbus_bool(false);
+    }
+    dbus_bool(unsafe { &*watch }.enabled)
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_watch_handle(watch: *mut DBusWatch, flags: u32) -> u32 {
+    if watch.is_null() {
+        return dbus_bool(false);
+    }
+    let w = unsafe { &*watch };
+    if !w.enabled {
+        return dbus_bool(true);
+    }
+    let (handle, owner) = (w.handle, w.owner);
+    dbus_bool(handle(owner, flags))
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_watch_get_data(watch: *mut DBusWatch) -> *mut std::ffi::c_void {
+    if watch.is_null() {
+        return std::ptr::null_mut();
+    }
+    unsafe { &*watch }.data
+}
+
+#[no_mangle]
+pub extern "C" fn dbus_watch_set_data(
+    watch: *mut DBusWatch,
+    data: *mut std::ffi::c_void,
+    free_data_function: Option<DBusFreeFunction>,
+) {
+    if watch.is_null() {
+        return;
+    }
+    let watch = unsafe { &mut *watch };
+    watch.free_data();
+    watch.data = data;
+    watch.free_data = free_data_function;
+}
@@ -0,0 +1,748 @@
+//! Drives the exported C ABI against the bus from librdbus::daemon, which the tests start on a
+//! temporary socket and announce through DBUS_SESSION_BUS_ADDRESS like a real session bus.
+
+use librdbus::bus::*;
+use librdbus::connection::*;
+use librdbus::daemon::*;
+use librdbus::error::*;
+use librdbus::message::*;
+use librdbus::message_iter::*;
+use librdbus::*;
+use std::ffi::{CStr, CString};
+use std::mem::MaybeUninit;
+
+fn start_bus() {
+    static START: std::sync::Once = std::sync::Once::new();
+    START.call_once(|| {
+        let path = std::env::temp_dir().join(format!("rdbus-test-bus-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut bus = Bus::bind(&path).unwrap();
+        std::env::set_var("DBUS_SESSION_BUS_ADDRESS", bus.address());
+        std::thread::spawn(move || bus.run());
+    });
+}
+
+fn new_error() -> DBusError {
+    let mut err = MaybeUninit::<DBusError>::uninit();
+    dbus_error_init(err.as_mut_ptr());
+    unsafe { err.assume_init() }
+}
+
+fn connect() -> *mut DBusConnection<'static> {
+    start_bus();
+    let mut err = new_error();
+    let con = dbus_bus_get(DBusBusType::DBUS_BUS_SESSION, &mut err);
+    assert!(!con.is_null(), "{}", err.message());
+    con
+}
+
+fn unique_name(con: *mut DBusConnection) -> String {
+    let name = dbus_bus_get_unique_name(con);
+    assert!(!name.is_null());
+    unsafe { CStr::from_ptr(name) }.to_str().unwrap().to_owned()
+}
+
+fn cstr(s: &str) -> CString {
+    CString::new(s).unwrap()
+}
+
+enum Arg<'s> {
+    Str(&'s str),
+    U32(u32),
+}
+
+fn append(msg: *mut DBusMessage, args: &[Arg]) {
+    let mut iter = MaybeUninit::<DBusMessageIter>::uninit();
+    dbus_message_iter_init_append(msg, iter.as_mut_ptr());
+    for arg in args {
+        let ok = match arg {
+            Arg::Str(s) => {
+                let s = cstr(s);
+                let mut ptr = s.as_ptr();
+                dbus_message_iter_append_basic(
+                    iter.as_mut_ptr(),
+                    DBUS_TYPE_STRING,
+                    &mut ptr as *mut *const libc::c_char as *mut std::ffi::c_void,
+                )
+            }
+            Arg::U32(mut v) => dbus_message_iter_append_basic(
+                iter.as_mut_ptr(),
+                DBUS_TYPE_UINT32,
+                &mut v as *mut u32 as *mut std::ffi::c_void,
+            ),
+        };
+        assert_eq!(ok, 1);
+    }
+}
+
+/// Call a method of the bus driver and wait for the reply, error replies give the error name
+fn try_driver_call(
+    con: *mut DBusConnection<'static>,
+    member: &str,
+    args: &[Arg],
+) -> Result<*mut DBusMessage<'static>, String> {
+    let (dest, path, iface, member) = (
+        cstr(DBUS_SERVICE_DBUS),
+        cstr(DBUS_PATH_DBUS),
+        cstr(DBUS_INTERFACE_DBUS),
+        cstr(member),
+    );
+    let call = dbus_message_new_method_call(
+        dest.as_ptr(),
+        path.as_ptr(),
+        iface.as_ptr(),
+        member.as_ptr(),
+    );
+    append(call, args);
+    let mut err = new_error();
+    let reply = dbus_connection_send_with_reply_and_block(con, call, 5000, &mut err);
+    dbus_message_unref(call);
+    if reply.is_null() {
+        let name = err.name().to_owned();
+        dbus_error_free(&mut err);
+        return Err(name);
+    }
+    Ok(reply)
+}
+
+/// Call a method of the bus driver that has to succeed
+fn driver_call(
+    con: *mut DBusConnection<'static>,
+    member: &str,
+    args: &[Arg],
+) -> *mut DBusMessage<'static> {
+    try_driver_call(con, member, args).unwrap_or_else(|e| panic!("{} failed: {}", member, e))
+}
+
+fn error_name(msg: *mut DBusMessage) -> Option<String> {
+    let name = dbus_message_get_error_name(msg);
+    if name.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(name) }.to_str().unwrap().to_owned())
+}
+
+/// An iterator on the first argument of a reply, which has to be of type typ
+fn first_iter(msg: *mut DBusMessage, typ: libc::c_int) -> MaybeUninit<DBusMessageIter> {
+    assert_eq!(error_name(msg), None);
+    let mut iter = MaybeUninit::<DBusMessageIter>::uninit();
+    assert_eq!(dbus_message_iter_init(msg, iter.as_mut_ptr()), 1);
+    assert_eq!(dbus_message_iter_get_arg_type(iter.as_mut_ptr()), typ);
+    iter
+}
+
+fn first_u32(msg: *mut DBusMessage, typ: libc::c_int) -> u32 {
+    let mut iter = first_iter(msg, typ);
+    let mut value = 0u32;
+    dbus_message_iter_get_basic(
+        iter.as_mut_ptr(),
+        &mut value as *mut u32 as *mut std::ffi::c_void,
+    );
+    value
+}
+
+fn first_string(msg: *mut DBusMessage) -> String {
+    let mut iter = first_iter(msg, DBUS_TYPE_STRING);
+    let mut s: *const libc::c_char = std::ptr::null();
+    dbus_message_iter_get_basic(
+        iter.as_mut_ptr(),
+        &mut s as *mut *const libc::c_char as *mut std::ffi::c_void,
+    );
+    unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_owned()
+}
+
+/// All arguments of a message that only has string arguments
+fn string_args(msg: *mut DBusMessage) -> Vec<String> {
+    let mut iter = first_iter(msg, DBUS_TYPE_STRING);
+    let mut strings = Vec::new();
+    loop {
+        let mut s: *const libc::c_char = std::ptr::null();
+        dbus_message_iter_get_basic(
+            iter.as_mut_ptr(),
+            &mut s as *mut *const libc::c_char as *mut std::ffi::c_void,
+        );
+        strings.push(unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_owned());
+        if dbus_message_iter_next(iter.as_mut_ptr()) == 0 {
+            return strings;
+        }
+    }
+}
+
+fn first_string_array(msg: *mut DBusMessage) -> Vec<String> {
+    let mut iter = first_iter(msg, DBUS_TYPE_ARRAY);
+    let mut sub = MaybeUninit::<DBusMessageIter>::uninit();
+    dbus_message_iter_recurse(iter.as_mut_ptr(), sub.as_mut_ptr());
+    let mut strings = Vec::new();
+    while dbus_message_iter_get_arg_type(sub.as_mut_ptr()) == DBUS_TYPE_STRING {
+        let mut s: *const libc::c_char = std::ptr::null();
+        dbus_message_iter_get_basic(
+            sub.as_mut_ptr(),
+            &mut s as *mut *const libc::c_char as *mut std::ffi::c_void,
+        );
+        strings.push(unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_owned());
+        dbus_message_iter_next(sub.as_mut_ptr());
+    }
+    strings
+}
+
+fn driver_u32(con: *mut DBusConnection<'static>, member: &str, args: &[Arg]) -> u32 {
+    let reply = driver_call(con, member, args);
+    let value = first_u32(reply, DBUS_TYPE_UINT32);
+    dbus_message_unref(reply);
+    value
+}
+
+fn driver_error(con: *mut DBusConnection<'static>, member: &str, args: &[Arg]) -> Option<String> {
+    match try_driver_call(con, member, args) {
+        Ok(reply) => {
+            dbus_message_unref(reply);
+            None
+        }
+        Err(name) => Some(name),
+    }
+}
+
+fn name_has_owner(con: *mut DBusConnection<'static>, name: &str) -> bool {
+    let reply = driver_call(con, "NameHasOwner", &[Arg::Str(name)]);
+    let value = first_u32(reply, DBUS_TYPE_BOOLEAN);
+    dbus_message_unref(reply);
+    value != 0
+}
+
+/// Read until a message that satisfies pred arrives, dropping everything else
+fn pop_until(
+    con: *mut DBusConnection<'static>,
+    pred: impl Fn(*mut DBusMessage) -> bool,
+) -> *mut DBusMessage<'static> {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while std::time::Instant::now() < deadline {
+        loop {
+            let msg = dbus_connection_pop_message(con);
+            if msg.is_null() {
+                break;
+            }
+            if pred(msg) {
+                return msg;
+            }
+            dbus_message_unref(msg);
+        }
+        assert_eq!(dbus_connection_read_write(con, 1000), 1);
+    }
+    panic!("expected message did not arrive");
+}
+
+fn has_member(msg: *mut DBusMessage, member: &str) -> bool {
+    let member = cstr(member);
+    dbus_message_has_member(msg, member.as_ptr()) != 0
+}
+
+#[test]
+fn hello_assigns_unique_names() {
+    let a = connect();
+    let b = connect();
+    let (name_a, name_b) = (unique_name(a), unique_name(b));
+    assert!(name_a.starts_with(":1."));
+    assert_ne!(name_a, name_b);
+
+    let reply = driver_call(a, "ListNames", &[]);
+    let names = first_string_array(reply);
+    dbus_message_unref(reply);
+    for name in &[DBUS_SERVICE_DBUS, &name_a, &name_b] {
+        assert!(names.iter().any(|n| n == name), "{} missing", name);
+    }
+
+    let reply = driver_call(b, "GetNameOwner", &[Arg::Str(&name_a)]);
+    assert_eq!(first_string(reply), name_a);
+    dbus_message_unref(reply);
+
+    dbus_connection_unref(a);
+    dbus_connection_unref(b);
+}
+
+#[test]
+fn silent_clients_do_not_block_the_bus() {
+    start_bus();
+    let address = std::env::var("DBUS_SESSION_BUS_ADDRESS").unwrap();
+    let path = address.strip_prefix("unix:path=").unwrap();
+    // one never says anything, the other stops in the middle of the handshake
+    let _silent = std::os::unix::net::UnixStream::connect(path).unwrap();
+    let mut halfway = std::os::unix::net::UnixStream::connect(path).unwrap();
+    std::io::Write::write_all(&mut halfway, b"\0AUTH EXTERNAL").unwrap();
+
+    let con = connect();
+    assert!(unique_name(con).starts_with(':'));
+    assert!(name_has_owner(con, DBUS_SERVICE_DBUS));
+    dbus_connection_unref(con);
+}
+
+#[test]
+fn name_ownership() {
+    let a = connect();
+    let b = connect();
+    let name = "io.rdbus.Test.Owned";
+
+    assert!(!name_has_owner(a, name));
+    let request = [Arg::Str(name), Arg::U32(0)];
+    assert_eq!(
+        driver_u32(a, "RequestName", &request),
+        DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+    );
+    assert_eq!(
+        driver_u32(a, "RequestName", &request),
+        DBUS_REQUEST_NAME_REPLY_ALREADY_OWNER
+    );
+    assert_eq!(
+        driver_u32(
+            b,
+            "RequestName",
+            &[Arg::Str(name), Arg::U32(DBUS_NAME_FLAG_DO_NOT_QUEUE)]
+        ),
+        DBUS_REQUEST_NAME_REPLY_EXISTS
+    );
+    assert!(name_has_owner(b, name));
+    let reply = driver_call(b, "GetNameOwner", &[Arg::Str(name)]);
+    assert_eq!(first_string(reply), unique_name(a));
+    dbus_message_unref(reply);
+
+    assert_eq!(
+        driver_u32(b, "ReleaseName", &[Arg::Str(name)]),
+        DBUS_RELEASE_NAME_REPLY_NOT_OWNER
+    );
+    assert_eq!(
+        driver_u32(a, "ReleaseName", &[Arg::Str(name)]),
+        DBUS_RELEASE_NAME_REPLY_RELEASED
+    );
+    assert_eq!(
+        driver_u32(a, "ReleaseName", &[Arg::Str(name)]),
+        DBUS_RELEASE_NAME_REPLY_NON_EXISTENT
+    );
+    assert!(!name_has_owner(a, name));
+    assert_eq!(
+        driver_error(a, "GetNameOwner", &[Arg::Str(name)]).as_deref(),
+        Some(DBUS_ERROR_NAME_HAS_NO_OWNER)
+    );
+    assert_eq!(
+        driver_error(a, "RequestName", &[Arg::Str(":1.1"), Arg::U32(0)]).as_deref(),
+        Some(DBUS_ERROR_INVALID_ARGS)
+    );
+
+    dbus_connection_unref(a);
+    dbus_connection_unref(b);
+}
+
+#[test]
+fn names_are_released_on_disconnect() {
+    let a = connect();
+    let b = connect();
+    let name = "io.rdbus.Test.Gone";
+    assert_eq!(
+        driver_u32(a, "RequestName", &[Arg::Str(name), Arg::U32(0)]),
+        DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+    );
+    dbus_connection_unref(a);
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while name_has_owner(b, name) {
+        assert!(std::time::Instant::now() < deadline);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    dbus_connection_unref(b);
+}
+
+#[test]
+fn unicast_calls_and_replies() {
+    let service = connect();
+    let client = connect();
+    let name = "io.rdbus.Test.Service";
+    assert_eq!(
+        driver_u32(service, "RequestName", &[Arg::Str(name), Arg::U32(0)]),
+        DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+    );
+
+    let (dest, path, iface, member) = (
+        cstr(name),
+        cstr("/io/rdbus/Test"),
+        cstr("io.rdbus.Test"),
+        cstr("Echo"),
+    );
+    let call = dbus_message_new_method_call(
+        dest.as_ptr(),
+        path.as_ptr(),
+        iface.as_ptr(),
+        member.as_ptr(),
+    );
+    append(call, &[Arg::Str("hello")]);
+    let mut serial = 0;
+    assert_eq!(dbus_connection_send(client, call, &mut serial), 1);
+    dbus_message_unref(call);
+    dbus_connection_flush(client);
+
+    let received = pop_until(service, |msg| has_member(msg, "Echo"));
+    let sender = dbus_message_get_sender(received);
+    assert_eq!(
+        unsafe { CStr::from_ptr(sender) }.to_str().unwrap(),
+        unique_name(client)
+    );
+    assert_eq!(first_string(received), "hello");
+    let dump = librdbus::dump::dump_message(unsafe { &mut *received });
+    assert!(dump.starts_with("method call sender=:1."), "{}", dump);
+    assert!(
+        dump.ends_with("member=Echo\n   string \"hello\"\n"),
+        "{}",
+        dump
+    );
+    let reply = dbus_message_new_method_return(received);
+    append(reply, &[Arg::Str("hello back")]);
+    assert_eq!(
+        dbus_connection_send(service, reply, std::ptr::null_mut()),
+        1
+    );
+    dbus_message_unref(reply);
+    dbus_message_unref(received);
+    dbus_connection_flush(service);
+
+    let reply = pop_until(client, |msg| dbus_message_get_reply_serial(msg) == serial);
+    assert_eq!(first_string(reply), "hello back");
+    dbus_message_unref(reply);
+
+    let (dest, path, iface, member) = (
+        cstr("io.rdbus.Test.Nobody"),
+        cstr("/"),
+        cstr("io.rdbus.Test"),
+        cstr("Echo"),
+    );
+    let call = dbus_message_new_method_call(
+        dest.as_ptr(),
+        path.as_ptr(),
+        iface.as_ptr(),
+        member.as_ptr(),
+    );
+    let mut err = new_error();
+    let reply = dbus_connection_send_with_reply_and_block(client, call, 5000, &mut err);
+    assert!(reply.is_null());
+    assert_eq!(err.name(), DBUS_ERROR_SERVICE_UNKNOWN);
+    dbus_error_free(&mut err);
+    dbus_message_unref(call);
+
+    dbus_connection_unref(service);
+    dbus_connection_unref(client);
+}
+
+#[test]
+fn signals_follow_match_rules() {
+    let emitter = connect();
+    let listener = connect();
+    let bystander = connect();
+    let rule = "type='signal',interface='io.rdbus.Test.Signals'";
+
+    let mut err = new_error();
+    let rule_c = cstr(rule);
+    dbus_bus_add_match(listener, rule_c.as_ptr(), &mut err);
+    // the driver handles calls in order, so AddMatch is done once this returns
+    dbus_message_unref(driver_call(listener, "ListNames", &[]));
+
+    let (path, iface, member) = (
+        cstr("/io/rdbus/Test"),
+        cstr("io.rdbus.Test.Signals"),
+        cstr("Changed"),
+    );
+    let signal = dbus_message_new_signal(path.as_ptr(), iface.as_ptr(), member.as_ptr());
+    assert_eq!(
+        dbus_connection_send(emitter, signal, std::ptr::null_mut()),
+        1
+    );
+    dbus_message_unref(signal);
+    dbus_connection_flush(emitter);
+
+    let received = pop_until(listener, |msg| has_member(msg, "Changed"));
+    dbus_message_unref(received);
+
+    assert_eq!(dbus_connection_read_write(bystander, 200), 1);
+    assert!(dbus_connection_pop_message(bystander).is_null());
+
+    assert_eq!(
+        driver_error(listener, "RemoveMatch", &[Arg::Str("type='error'")]).as_deref(),
+        Some(DBUS_ERROR_MATCH_RULE_NOT_FOUND)
+    );
+    assert_eq!(
+        driver_error(listener, "RemoveMatch", &[Arg::Str(rule)]),
+        None
+    );
+
+    let invalid = cstr("type='signal',path='relative'");
+    dbus_bus_add_match(listener, invalid.as_ptr(), &mut err);
+    let name = cstr(DBUS_ERROR_MATCH_RULE_INVALID);
+    assert_eq!(dbus_error_has_name(&mut err, name.as_ptr()), 1);
+    dbus_error_free(&mut err);
+    assert_eq!(
+        driver_error(listener, "AddMatch", &[Arg::Str("arg0namespace='.'")]).as_deref(),
+        Some(DBUS_ERROR_MATCH_RULE_INVALID)
+    );
+
+    dbus_connection_unref(emitter);
+    dbus_connection_unref(listener);
+    dbus_connection_unref(bystander);
+}
+
+/// Wait for a signal from the driver with exactly these arguments
+fn expect_driver_signal(con: *mut DBusConnection<'static>, member: &str, args: &[&str]) {
+    let signal = pop_until(con, |msg| {
+        dbus_message_get_type(msg) == DBUS_MESSAGE_TYPE_SIGNAL
+            && has_member(msg, member)
+            && string_args(msg) == args
+    });
+    dbus_message_unref(signal);
+}
+
+#[test]
+fn name_queueing_and_replacement() {
+    let a = connect();
+    let b = connect();
+    let c = connect();
+    let watcher = connect();
+    let name = "io.rdbus.Test.Queued";
+    let (name_a, name_b, name_c) = (unique_name(a), unique_name(b), unique_name(c));
+
+    let rule = format!(
+        "type='signal',sender='{}',member='NameOwnerChanged'",
+        DBUS_SERVICE_DBUS
+    );
+    assert_eq!(driver_error(watcher, "AddMatch", &[Arg::Str(&rule)]), None);
+
+    let request = |con, flags| driver_u32(con, "RequestName", &[Arg::Str(name), Arg::U32(flags)]);
+    assert_eq!(
+        request(a, DBUS_NAME_FLAG_ALLOW_REPLACEMENT),
+        DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+    );
+    expect_driver_signal(a, "NameAcquired", &[name]);
+    expect_driver_signal(watcher, "NameOwnerChanged", &[name, "", &name_a]);
+
+    assert_eq!(request(b, 0), DBUS_REQUEST_NAME_REPLY_IN_QUEUE);
+    assert_eq!(
+        request(c, DBUS_NAME_FLAG_DO_NOT_QUEUE),
+        DBUS_REQUEST_NAME_REPLY_EXISTS
+    );
+    let reply = driver_call(c, "ListQueuedOwners", &[Arg::Str(name)]);
+    assert_eq!(
+        first_string_array(reply),
+        vec![name_a.clone(), name_b.clone()]
+    );
+    dbus_message_unref(reply);
+
+    // a allowed replacement, so c takes over and a waits right behind it
+    assert_eq!(
+        request(c, DBUS_NAME_FLAG_REPLACE_EXISTING),
+        DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+    );
+    expect_driver_signal(a, "NameLost", &[name]);
+    expect_driver_signal(c, "NameAcquired", &[name]);
+    expect_driver_signal(watcher, "NameOwnerChanged", &[name, &name_a, &name_c]);
+
+    assert_eq!(
+        driver_u32(c, "ReleaseName", &[Arg::Str(name)]),
+        DBUS_RELEASE_NAME_REPLY_RELEASED
+    );
+    expect_driver_signal(a, "NameAcquired", &[name]);
+    expect_driver_signal(watcher, "NameOwnerChanged", &[name, &name_c, &name_a]);
+
+    // the next in line gets the name when the owner goes away
+    dbus_connection_unref(a);
+    expect_driver_signal(b, "NameAcquired", &[name]);
+    expect_driver_signal(watcher, "NameOwnerChanged", &[name, &name_a, &name_b]);
+    expect_driver_signal(watcher, "NameOwnerChanged", &[&name_a, &name_a, ""]);
+
+    dbus_connection_unref(b);
+    dbus_connection_unref(c);
+    dbus_connection_unref(watcher);
+}
+
+extern "C" fn record_member(
+    _con: *mut DBusConnection,
+    msg: *mut DBusMessage,
+    data: *mut std::ffi::c_void,
+) -> DBusHandlerResult {
+    let seen = unsafe { &mut *(data as *mut Vec<String>) };
+    let member = dbus_message_get_member(msg);
+    if !member.is_null() {
+        seen.push(
+            unsafe { CStr::from_ptr(member) }
+                .to_str()
+                .unwrap()
+                .to_owned(),
+        );
+    }
+    // a normal connection would answer unhandled calls with UnknownMethod
+    DBusHandlerResult::DBUS_HANDLER_RESULT_NOT_YET_HANDLED
+}
+
+#[test]
+fn monitors_see_everything_and_stay_silent() {
+    let mut seen: Vec<String> = Vec::new();
+    let monitor = connect();
+    let monitor_name = unique_name(monitor);
+    let caller = connect();
+    let callee = connect();
+
+    let (dest, path, iface, member) = (
+        cstr(DBUS_SERVICE_DBUS),
+        cstr(DBUS_PATH_DBUS),
+        cstr(DBUS_INTERFACE_MONITORING),
+        cstr("BecomeMonitor"),
+    );
+    let call = dbus_message_new_method_call(
+        dest.as_ptr(),
+        path.as_ptr(),
+        iface.as_ptr(),
+        member.as_ptr(),
+    );
+    let mut iter = MaybeUninit::<DBusMessageIter>::uninit();
+    let mut rules = MaybeUninit::<DBusMessageIter>::uninit();
+    let sig = cstr("s");
+    let mut flags = 0u32;
+    dbus_message_iter_init_append(call, iter.as_mut_ptr());
+    assert_eq!(
+        dbus_message_iter_open_container(
+            iter.as_mut_ptr(),
+            DBUS_TYPE_ARRAY,
+            sig.as_ptr(),
+            rules.as_mut_ptr()
+        ),
+        1
+    );
+    assert_eq!(
+        dbus_message_iter_close_container(iter.as_mut_ptr(), rules.as_mut_ptr()),
+        1
+    );
+    assert_eq!(
+        dbus_message_iter_append_basic(
+            iter.as_mut_ptr(),
+            DBUS_TYPE_UINT32,
+            &mut flags as *mut u32 as *mut std::ffi::c_void,
+        ),
+        1
+    );
+    let mut err = new_error();
+    let reply = dbus_connection_send_with_reply_and_block(monitor, call, 5000, &mut err);
+    dbus_message_unref(call);
+    assert!(!reply.is_null(), "{}", err.message());
+    assert_eq!(error_name(reply), None);
+    dbus_message_unref(reply);
+    assert!(!name_has_owner(caller, &monitor_name));
+
+    dbus_connection_add_filter(
+        monitor,
+        record_member,
+        &mut seen as *mut Vec<String> as *mut std::ffi::c_void,
+        None,
+    );
+
+    // a call between two other connections that nobody answers
+    let (dest, path, iface, member) = (
+        cstr(&unique_name(callee)),
+        cstr("/io/rdbus/Test"),
+        cstr("io.rdbus.Test"),
+        cstr("Unanswered"),
+    );
+    let call = dbus_message_new_method_call(
+        dest.as_ptr(),
+        path.as_ptr(),
+        iface.as_ptr(),
+        member.as_ptr(),
+    );
+    assert_eq!(dbus_connection_send(caller, call, std::ptr::null_mut()), 1);
+    dbus_message_unref(call);
+    dbus_connection_flush(caller);
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while !seen.iter().any(|m| m == "Unanswered") {
+        assert!(std::time::Instant::now() < deadline);
+        assert_eq!(dbus_connection_read_write_dispatch(monitor, 1000), 1);
+    }
+    assert!(unsafe { &*monitor }.out_queue.is_empty());
+
+    let (path, iface, member) = (
+        cstr("/io/rdbus/Test"),
+        cstr("io.rdbus.Test"),
+        cstr("Chatter"),
+    );
+    let signal = dbus_message_new_signal(path.as_ptr(), iface.as_ptr(), member.as_ptr());
+    assert_eq!(
+        dbus_connection_send(monitor, signal, std::ptr::null_mut()),
+        0
+    );
+    dbus_message_unref(signal);
+
+    dbus_connection_unref(monitor);
+    dbus_connection_unref(caller);
+    dbus_connection_unref(callee);
+}
+
+#[test]
+fn rust_api_calls_and_replies() {
+    use librdbus::api::{Connection, MessageBuilder};
+    use rustbus::params::{Base, Container, Param};
+    use std::time::Duration;
+
+    start_bus();
+    let mut service = Connection::session().unwrap();
+    let mut client = Connection::session().unwrap();
+    let service_name = service.unique_name().unwrap();
+    assert!(service_name.starts_with(":1."));
+
+    let mut call = MessageBuilder::method_call("/io/rdbus/Test", "Echo")
+        .interface("io.rdbus.Test")
+        .destination(&service_name)
+        .arg("hello")
+        .arg(Container::Array(rustbus::params::Array {
+            element_sig: rustbus::signature::Type::Base(rustbus::signature::Base::Uint32),
+            values: vec![Param::Base(Base::Uint32(7)), Param::Base(Base::Uint32(8))],
+        }))
+        .build()
+        .unwrap();
+    let mut pending = client.send_with_reply(&mut call, None).unwrap();
+    client.flush();
+
+    let received = loop {
+        assert!(service.read_write(Some(Duration::from_secs(5))));
+        if let Some(msg) = service.pop_message() {
+            if msg.member() == Some("Echo") {
+                break msg;
+            }
+        }
+    };
+    assert_eq!(received.sender(), client.unique_name().as_deref());
+    assert_eq!(received.signature(), "sau");
+    let args: Vec<_> = received.args().collect();
+    assert_eq!(args[0].as_str(), Some("hello"));
+    let values: Vec<_> = args[1].iter().filter_map(|v| v.as_u32()).collect();
+    assert_eq!(values, [7, 8]);
+    assert!(received
+        .to_string()
+        .ends_with("   string \"hello\"\n   array [\n      uint32 7\n      uint32 8\n   ]\n"));
+
+    let mut reply = received.method_return().arg(42i32).build().unwrap();
+    service.send(&mut reply).unwrap();
+    service.flush();
+    pending.block();
+    assert!(pending.completed());
+    let reply = pending.steal_reply().unwrap();
+    assert_eq!(reply.reply_serial(), call.serial());
+    assert_eq!(reply.args().next().and_then(|a| a.as_i32()), Some(42));
+
+    let mut call = MessageBuilder::method_call("/org/freedesktop/DBus", "GetNameOwner")
+        .interface("org.freedesktop.DBus")
+        .destination("org.freedesktop.DBus")
+        .arg("io.rdbus.Test.Nobody")
+        .build()
+        .unwrap();
+    let err = client.call(&mut call, None).unwrap_err();
+    assert_eq!(err.name, DBUS_ERROR_NAME_HAS_NO_OWNER);
+
+    assert_eq!(
+        MessageBuilder::method_call("no/path", "Echo")
+            .build()
+            .unwrap_err()
+            .name,
+        DBUS_ERROR_INVALID_ARGS
+    );
+}